@@ -0,0 +1,73 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::Serialize;
+
+/// 單個服務的混淆計數
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ServiceAccuracy {
+    pub true_positives: u64,
+    pub false_positives: u64,
+    pub false_negatives: u64,
+}
+
+impl ServiceAccuracy {
+    /// 判為該服務的包中判對的比例；沒有判為該服務的包時為 None
+    pub fn precision(&self) -> Option<f64> {
+        ratio(self.true_positives, self.true_positives + self.false_positives)
+    }
+    
+    /// 標註為該服務的包中被判出的比例；沒有標註為該服務的包時為 None
+    pub fn recall(&self) -> Option<f64> {
+        ratio(self.true_positives, self.true_positives + self.false_negatives)
+    }
+}
+
+/// 按逐包標註評估分類結果
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct AccuracyReport {
+    pub packets: u64,
+    pub correct: u64,
+    pub services: BTreeMap<String, ServiceAccuracy>,
+}
+
+impl AccuracyReport {
+    /// 記錄一個包的標註和分類結果
+    pub fn record(&mut self, expected: &str, actual: &str) {
+        self.packets += 1;
+        if expected == actual {
+            self.correct += 1;
+            self.services.entry(expected.to_string()).or_default().true_positives += 1;
+        } else {
+            self.services.entry(expected.to_string()).or_default().false_negatives += 1;
+            self.services.entry(actual.to_string()).or_default().false_positives += 1;
+        }
+    }
+    
+    /// 整體準確率；沒有包時為 None
+    pub fn accuracy(&self) -> Option<f64> {
+        ratio(self.correct, self.packets)
+    }
+}
+
+impl fmt::Display for AccuracyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<20} {:>9} {:>9} {:>6} {:>6} {:>6}", "service", "precision", "recall", "tp", "fp", "fn")?;
+        for (service, counts) in &self.services {
+            writeln!(
+                f, "{:<20} {:>9} {:>9} {:>6} {:>6} {:>6}",
+                service, percent(counts.precision()), percent(counts.recall()),
+                counts.true_positives, counts.false_positives, counts.false_negatives
+            )?;
+        }
+        write!(f, "accuracy {} ({}/{})", percent(self.accuracy()), self.correct, self.packets)
+    }
+}
+
+fn ratio(numerator: u64, denominator: u64) -> Option<f64> {
+    (denominator > 0).then(|| numerator as f64 / denominator as f64)
+}
+
+fn percent(value: Option<f64>) -> String {
+    value.map_or_else(|| "-".to_string(), |v| format!("{:.1}%", v * 100.0))
+}
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::config::ServiceAlertConfig;
+use crate::stats::TrafficStats;
+
+/// 檢查停止標誌的間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// 一條告警通知
+#[derive(Debug, Clone, PartialEq)]
+pub enum AlertEvent {
+    Breach { service: String, bytes_per_sec: f64, threshold: f64 },
+    Recovery { service: String, threshold: f64 },
+}
+
+impl fmt::Display for AlertEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AlertEvent::Breach { service, bytes_per_sec, threshold } => write!(
+                f, "⚠️ {} rate {:.0} B/s exceeds threshold {:.0} B/s", service, bytes_per_sec, threshold
+            ),
+            AlertEvent::Recovery { service, threshold } => write!(
+                f, "✅ {} recovered below threshold {:.0} B/s", service, threshold
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct AlertState {
+    breached: bool,
+    /// 本次越限已經通知過（冷卻期內越限不通知，恢復時也不通知）
+    notified: bool,
+    /// 越限後回落到閾值以下的起始時間
+    below_since: Option<SystemTime>,
+    last_notified: Option<SystemTime>,
+}
+
+/// 按服務跟蹤告警狀態：越限時通知一次，回落持續 `recovery_secs` 後發恢復通知，
+/// 兩次越限通知至少間隔 `cooldown_secs`
+#[derive(Debug)]
+pub struct ServiceAlerts {
+    rules: Vec<ServiceAlertConfig>,
+    states: HashMap<String, AlertState>,
+}
+
+impl ServiceAlerts {
+    pub fn new(rules: &[ServiceAlertConfig]) -> Self {
+        Self { rules: rules.to_vec(), states: HashMap::new() }
+    }
+    
+    /// 處理一次各服務的速率（字節/秒），沒有出現的服務按 0 計算
+    pub fn observe(&mut self, rates: &HashMap<String, f64>, now: SystemTime) -> Vec<AlertEvent> {
+        let mut events = Vec::new();
+        
+        for rule in &self.rules {
+            let rate = rates.get(&rule.service).copied().unwrap_or(0.0);
+            let state = self.states.entry(rule.service.clone()).or_default();
+            
+            if rate > rule.bytes_per_sec {
+                state.breached = true;
+                state.below_since = None;
+                let cooled = state.last_notified
+                    .is_none_or(|last| elapsed(last, now) >= Duration::from_secs(rule.cooldown_secs));
+                if !state.notified && cooled {
+                    state.notified = true;
+                    state.last_notified = Some(now);
+                    events.push(AlertEvent::Breach {
+                        service: rule.service.clone(),
+                        bytes_per_sec: rate,
+                        threshold: rule.bytes_per_sec,
+                    });
+                }
+            } else if state.breached {
+                let since = *state.below_since.get_or_insert(now);
+                if elapsed(since, now) >= Duration::from_secs(rule.recovery_secs) {
+                    if state.notified {
+                        events.push(AlertEvent::Recovery { service: rule.service.clone(), threshold: rule.bytes_per_sec });
+                    }
+                    state.breached = false;
+                    state.notified = false;
+                    state.below_since = None;
+                }
+            }
+        }
+        
+        events
+    }
+    
+    /// 當前處於越限狀態的服務
+    pub fn is_breached(&self, service: &str) -> bool {
+        self.states.get(service).is_some_and(|state| state.breached)
+    }
+}
+
+fn elapsed(since: SystemTime, now: SystemTime) -> Duration {
+    now.duration_since(since).unwrap_or_default()
+}
+
+/// 輪轉當前統計後計算最近 `window` 內各服務的平均字節速率
+pub fn window_rates(stats: &TrafficStats, window: Duration) -> HashMap<String, f64> {
+    stats.flush();
+    let secs = window.as_secs_f64();
+    stats.window_bytes(window).into_iter()
+        .map(|(service, bytes)| (service, bytes as f64 / secs))
+        .collect()
+}
+
+/// 在後台線程中按固定間隔檢查告警並輸出通知
+pub struct AlertMonitor {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl AlertMonitor {
+    /// 每隔 `interval` 按最近 `interval` 內的流量計算速率，沒有流量時按 0 計算，
+    /// 不依賴其他讀取方觸發輪轉
+    pub fn start(stats: Arc<TrafficStats>, rules: &[ServiceAlertConfig], interval: Duration) -> Self {
+        let mut alerts = ServiceAlerts::new(rules);
+        let stop = Arc::new(AtomicBool::new(false));
+        
+        let handle = {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let mut next = Instant::now() + interval;
+                while !stop.load(Ordering::SeqCst) {
+                    let now = Instant::now();
+                    if now >= next {
+                        next = now + interval;
+                        for event in alerts.observe(&window_rates(&stats, interval), SystemTime::now()) {
+                            eprintln!("{}", event);
+                        }
+                    }
+                    thread::park_timeout(POLL_INTERVAL.min(next.saturating_duration_since(now)));
+                }
+            })
+        };
+        
+        Self { stop, handle: Some(handle) }
+    }
+}
+
+impl Drop for AlertMonitor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn rates(service: &str, rate: f64) -> HashMap<String, f64> {
+        HashMap::from([(service.to_string(), rate)])
+    }
+    
+    #[test]
+    fn test_breach_recover_breach() {
+        let rule = ServiceAlertConfig {
+            service: "netflix".to_string(),
+            bytes_per_sec: 1000.0,
+            recovery_secs: 120,
+            cooldown_secs: 300,
+        };
+        let mut alerts = ServiceAlerts::new(&[rule]);
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        
+        let mut events = Vec::new();
+        for (secs, rate) in [
+            (0, 5000.0),
+            // 持續越限不重復通知
+            (60, 6000.0),
+            // 短暫回落後又越限，不算恢復
+            (120, 100.0),
+            (180, 5000.0),
+            // 回落滿 120 秒才恢復
+            (240, 100.0),
+            (300, 100.0),
+            (360, 100.0),
+            // 冷卻期已過，再次越限
+            (420, 8000.0),
+        ] {
+            events.extend(alerts.observe(&rates("netflix", rate), at(secs)));
+        }
+        
+        assert_eq!(events, vec![
+            AlertEvent::Breach { service: "netflix".to_string(), bytes_per_sec: 5000.0, threshold: 1000.0 },
+            AlertEvent::Recovery { service: "netflix".to_string(), threshold: 1000.0 },
+            AlertEvent::Breach { service: "netflix".to_string(), bytes_per_sec: 8000.0, threshold: 1000.0 },
+        ]);
+        assert!(alerts.is_breached("netflix"));
+        assert_eq!(events[1].to_string(), "✅ netflix recovered below threshold 1000 B/s");
+        
+        // 抖動：恢復後冷卻期內再次越限不通知，其恢復也不通知
+        assert_eq!(alerts.observe(&HashMap::new(), at(480)), vec![]);
+        assert_eq!(alerts.observe(&HashMap::new(), at(600)).len(), 1);
+        assert_eq!(alerts.observe(&rates("netflix", 9000.0), at(610)), vec![]);
+        assert_eq!(alerts.observe(&HashMap::new(), at(620)), vec![]);
+        assert_eq!(alerts.observe(&HashMap::new(), at(740)), vec![]);
+        assert!(!alerts.is_breached("netflix"));
+        // 冷卻期過後照常通知
+        assert_eq!(alerts.observe(&rates("netflix", 9000.0), at(750)).len(), 1);
+    }
+    
+    #[test]
+    fn test_window_rates_drop_to_zero_without_traffic() {
+        let stats = TrafficStats::new();
+        let window = Duration::from_millis(20);
+        stats.add_traffic("netflix", 6000, 4);
+        
+        let rates = window_rates(&stats, window);
+        assert_eq!(rates.get("netflix").copied(), Some(300_000.0));
+        
+        // 窗口過後沒有新流量，速率歸零，恢復判斷不需要等待輪轉
+        thread::sleep(window * 2);
+        assert!(window_rates(&stats, window).is_empty());
+    }
+}
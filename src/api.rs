@@ -0,0 +1,580 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::config::{self, parse_cidr, Config, ServiceConfig};
+use crate::nftables::{validate_service_names, CommandRunner, NftablesClassifier, SystemRunner};
+use crate::stats::TrafficStats;
+
+/// 請求體的大小上限
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// `GET /stats` 分頁時每頁的默認和最大條數
+const DEFAULT_PAGE_LIMIT: usize = 50;
+const MAX_PAGE_LIMIT: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    /// 頭部名稱已轉為小寫
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// 讀取一個請求；格式錯誤時返回 InvalidData
+    pub fn read_from(reader: &mut impl BufRead) -> io::Result<Self> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+        
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+            return Err(invalid("malformed request line"));
+        };
+        let (method, path) = (method.to_string(), path.to_string());
+        
+        let mut headers = HashMap::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').ok_or_else(|| invalid("malformed header"))?;
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+        
+        let length = match headers.get("content-length") {
+            Some(length) => length.parse().map_err(|_| invalid("invalid Content-Length"))?,
+            None => 0,
+        };
+        if length > MAX_BODY_BYTES {
+            return Err(invalid("request body too large"));
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        
+        Ok(Self { method, path, headers, body })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: Value,
+}
+
+impl HttpResponse {
+    pub fn json(status: u16, body: Value) -> Self {
+        Self { status, body }
+    }
+    
+    pub fn error(status: u16, message: &str) -> Self {
+        Self::json(status, json!({ "error": message }))
+    }
+    
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let body = self.body.to_string();
+        let challenge = if self.status == 401 { "WWW-Authenticate: Bearer\r\n" } else { "" };
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\n{}Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status, reason_phrase(self.status), challenge, body.len(), body
+        )
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        _ => "",
+    }
+}
+
+/// 運行時管理接口：
+/// - `POST /services`：添加或更新服務（JSON 格式的 ServiceConfig），重建其 nftables 集合和規則並寫回配置文件
+/// - `GET /stats`：各服務的字節數和包數；帶 `sort`、`order`、`limit`、`offset` 參數時
+///   返回排序後的一頁服務和服務總數，見 [`StatsQuery`]
+///
+/// 設置 token 後所有請求都需要 `Authorization: Bearer <token>`
+pub struct ApiServer {
+    config: Arc<Mutex<Config>>,
+    config_path: Option<PathBuf>,
+    nft: NftablesClassifier,
+    runner: Box<dyn CommandRunner + Send + Sync>,
+    stats: Option<Arc<TrafficStats>>,
+    token: Option<String>,
+}
+
+impl ApiServer {
+    pub fn new(config: Arc<Mutex<Config>>, nft: NftablesClassifier) -> Self {
+        Self {
+            config,
+            config_path: None,
+            nft,
+            runner: Box::new(SystemRunner),
+            stats: None,
+            token: None,
+        }
+    }
+    
+    /// 更新服務後寫回的配置文件
+    pub fn with_config_path(mut self, path: PathBuf) -> Self {
+        self.config_path = Some(path);
+        self
+    }
+    
+    pub fn with_runner(mut self, runner: Box<dyn CommandRunner + Send + Sync>) -> Self {
+        self.runner = runner;
+        self
+    }
+    
+    /// 由 `GET /stats` 導出的統計
+    pub fn with_stats(mut self, stats: Arc<TrafficStats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+    
+    /// 要求請求攜帶的 bearer token，None 表示不校驗
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+    
+    pub fn handle(&self, request: &HttpRequest) -> HttpResponse {
+        if !self.authorized(request) {
+            return HttpResponse::error(401, "unauthorized");
+        }
+        
+        let (path, query) = request.path.split_once('?').unwrap_or((request.path.as_str(), ""));
+        match (request.method.as_str(), path) {
+            ("POST", "/services") => self.post_service(&request.body),
+            (_, "/services") => HttpResponse::error(405, "method not allowed"),
+            ("GET", "/stats") if self.stats.is_some() => self.get_stats(query),
+            _ => HttpResponse::error(404, "not found"),
+        }
+    }
+    
+    fn authorized(&self, request: &HttpRequest) -> bool {
+        let Some(token) = &self.token else {
+            return true;
+        };
+        request.headers.get("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| constant_time_eq(given.trim().as_bytes(), token.as_bytes()))
+    }
+    
+    fn get_stats(&self, query: &str) -> HttpResponse {
+        let Some(stats) = &self.stats else {
+            return HttpResponse::json(200, json!({ "services": {} }));
+        };
+        if !query.is_empty() {
+            return match StatsQuery::parse(query) {
+                Ok(query) => self.get_stats_page(stats, &query),
+                Err(message) => HttpResponse::error(400, &message),
+            };
+        }
+        
+        let services: serde_json::Map<String, Value> = stats.export_stats().into_iter()
+            .map(|(service, data)| (service, json!({ "bytes": data.bytes, "packets": data.packets })))
+            .collect();
+        let interfaces: serde_json::Map<String, Value> = stats.export_interface_stats().into_iter()
+            .map(|(interface, services)| {
+                let services: serde_json::Map<String, Value> = services.into_iter()
+                    .map(|(service, (bytes, packets))| (service, json!({ "bytes": bytes, "packets": packets })))
+                    .collect();
+                (interface, Value::Object(services))
+            })
+            .collect();
+        HttpResponse::json(200, json!({ "services": services, "interfaces": interfaces }))
+    }
+    
+    /// 排序後的一頁服務；不含按接口的統計
+    fn get_stats_page(&self, stats: &TrafficStats, query: &StatsQuery) -> HttpResponse {
+        let mut services = stats.export_stats();
+        services.sort_by(|(a_name, a), (b_name, b)| {
+            let ordering = match query.sort {
+                StatsSort::Bytes => a.bytes.cmp(&b.bytes),
+                StatsSort::Packets => a.packets.cmp(&b.packets),
+                StatsSort::Name => std::cmp::Ordering::Equal,
+            };
+            let ordering = if query.descending { ordering.reverse() } else { ordering };
+            // 相同時按服務名排列，翻頁時順序穩定
+            let by_name = if query.descending && query.sort == StatsSort::Name { b_name.cmp(a_name) } else { a_name.cmp(b_name) };
+            ordering.then(by_name)
+        });
+        
+        let total = services.len();
+        let page: Vec<Value> = services.into_iter()
+            .skip(query.offset)
+            .take(query.limit)
+            .map(|(service, data)| json!({ "service": service, "bytes": data.bytes, "packets": data.packets }))
+            .collect();
+        HttpResponse::json(200, json!({
+            "services": page,
+            "total": total,
+            "offset": query.offset,
+            "limit": query.limit,
+        }))
+    }
+    
+    fn post_service(&self, body: &[u8]) -> HttpResponse {
+        let service: ServiceConfig = match serde_json::from_slice(body) {
+            Ok(service) => service,
+            Err(e) => return HttpResponse::error(400, &format!("invalid service: {}", e)),
+        };
+        
+        let errors = validate_service(&service);
+        if !errors.is_empty() {
+            return HttpResponse::json(400, json!({ "errors": errors }));
+        }
+        
+        // 持有配置鎖直到寫回文件，避免並發請求交錯
+        let mut config = self.config.lock().unwrap();
+        let previous = config.services.iter().find(|s| s.name == service.name);
+        let rules = match self.nft.upsert_service(&service, previous, self.runner.as_ref()) {
+            Ok(rules) => rules,
+            Err(e) => return HttpResponse::error(500, &e.to_string()),
+        };
+        
+        if let Some(path) = &self.config_path {
+            if let Err(e) = config::save_service(path, &service) {
+                return HttpResponse::error(500, &format!("failed to save config: {}", e));
+            }
+        }
+        
+        let status = if config.upsert_service(service) { 200 } else { 201 };
+        HttpResponse::json(status, json!({ "rules": rules }))
+    }
+    
+    /// 在 `addr` 上逐個處理連接，直到 running 變為 false
+    pub fn serve(&self, addr: &str, running: &AtomicBool) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        
+        while running.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = self.handle_connection(stream) {
+                        eprintln!("API connection error: {}", e);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(100)),
+                Err(e) => return Err(e),
+            }
+        }
+        
+        Ok(())
+    }
+    
+    fn handle_connection(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        
+        let mut reader = BufReader::new(&stream);
+        let response = match HttpRequest::read_from(&mut reader) {
+            Ok(request) => self.handle(&request),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => HttpResponse::error(400, &e.to_string()),
+            Err(e) => return Err(e),
+        };
+        
+        response.write_to(&mut &stream)
+    }
+}
+
+/// `GET /stats` 的排序字段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StatsSort {
+    Bytes,
+    Packets,
+    Name,
+}
+
+/// `GET /stats` 的查詢參數：
+/// - `sort`：bytes（默認）、packets 或 name
+/// - `order`：asc 或 desc；默認 bytes 和 packets 降序，name 升序
+/// - `limit`：每頁條數，1 到 1000，默認 50
+/// - `offset`：跳過的條數，默認 0，超出總數時返回空頁
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct StatsQuery {
+    sort: StatsSort,
+    descending: bool,
+    limit: usize,
+    offset: usize,
+}
+
+impl StatsQuery {
+    /// 未知參數或無效的值返回錯誤信息
+    fn parse(query: &str) -> Result<Self, String> {
+        let mut sort = StatsSort::Bytes;
+        let mut order = None;
+        let mut limit = DEFAULT_PAGE_LIMIT;
+        let mut offset = 0;
+        
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            match name {
+                "sort" => {
+                    sort = match value {
+                        "bytes" => StatsSort::Bytes,
+                        "packets" => StatsSort::Packets,
+                        "name" => StatsSort::Name,
+                        _ => return Err(format!("invalid sort {:?}: expected bytes, packets or name", value)),
+                    }
+                }
+                "order" => {
+                    order = match value {
+                        "asc" => Some(false),
+                        "desc" => Some(true),
+                        _ => return Err(format!("invalid order {:?}: expected asc or desc", value)),
+                    }
+                }
+                "limit" => {
+                    limit = value.parse().ok()
+                        .filter(|limit| (1..=MAX_PAGE_LIMIT).contains(limit))
+                        .ok_or_else(|| format!("invalid limit {:?}: expected 1 to {}", value, MAX_PAGE_LIMIT))?;
+                }
+                "offset" => {
+                    offset = value.parse()
+                        .map_err(|_| format!("invalid offset {:?}: expected a non-negative integer", value))?;
+                }
+                _ => return Err(format!("unknown query parameter {:?}", name)),
+            }
+        }
+        
+        let descending = order.unwrap_or(sort != StatsSort::Name);
+        Ok(Self { sort, descending, limit, offset })
+    }
+}
+
+/// 比較耗時只取決於長度，不洩露 token 內容
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// 服務名和地址範圍的校驗錯誤
+fn validate_service(service: &ServiceConfig) -> Vec<String> {
+    let mut errors = Vec::new();
+    
+    if let Err(e) = validate_service_names(std::slice::from_ref(service)) {
+        errors.push(e.to_string());
+    }
+    for range in &service.ip_ranges {
+        if let Err(e) = parse_cidr(range) {
+            errors.push(format!("ip_ranges: {}", e));
+        }
+    }
+    
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// `nft -a list chain` 返回預設的規則列表，其他命令返回空輸出
+    struct ListingRunner(&'static str);
+    
+    impl CommandRunner for ListingRunner {
+        fn run(&self, _program: &str, args: &[String]) -> anyhow::Result<String> {
+            Ok(if args.first().map(String::as_str) == Some("-a") { self.0 } else { "" }.to_string())
+        }
+    }
+    
+    fn post(body: &str) -> HttpRequest {
+        let raw = format!("POST /services HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        HttpRequest::read_from(&mut raw.as_bytes()).unwrap()
+    }
+    
+    #[test]
+    fn test_post_service() {
+        let path = std::env::temp_dir().join(format!("trafficmon-api-{}.conf", std::process::id()));
+        std::fs::write(&path, "\
+# 測試配置
+interface = \"br-lan\"
+report_interval = 60
+log_unknown_traffic = false
+time_rules = []
+user_rules = []
+blocked_domains = []
+pattern_rules = []
+
+[[services]]
+name = \"netflix\"
+ports = [443]
+ip_ranges = [\"198.38.96.0/19\"]
+blocked = false
+
+[[services]]
+name = \"youtube\"
+ports = [443]
+ip_ranges = []
+blocked = false
+").unwrap();
+        
+        let config = Arc::new(Mutex::new(Config::from_file(&path).unwrap()));
+        // 舊版本留下的平鋪計數規則和初始化時建好的 vmap 分派規則
+        let listing = "table inet trafficmon {\n\tchain traffic_stats {\n\t\tip daddr @netflix_ips tcp dport { 443 } counter packets 0 bytes 0 accept comment \"netflix traffic\" # handle 12\n\t\tip daddr vmap @service_daddr_vmap # handle 14\n\t}\n}\n";
+        let server = ApiServer::new(Arc::clone(&config), NftablesClassifier::new("trafficmon", "forward"))
+            .with_config_path(path.clone())
+            .with_runner(Box::new(ListingRunner(listing)));
+        
+        let response = server.handle(&post(r#"{"name": "disney", "ports": [443], "ip_ranges": ["192.0.2.0/24"], "blocked": false, "bidirectional": false}"#));
+        assert_eq!(response.status, 201);
+        assert_eq!(response.body["rules"], json!([
+            "add set inet trafficmon disney_ips { type ipv4_addr; flags interval; }",
+            "flush set inet trafficmon disney_ips",
+            "add element inet trafficmon disney_ips { 192.0.2.0/24 }",
+            "add chain inet trafficmon svc_disney",
+            "flush chain inet trafficmon svc_disney",
+            "add rule inet trafficmon svc_disney ip daddr { 192.0.2.0/24 } counter accept comment \"disney traffic\"",
+            "add map inet trafficmon service_daddr_vmap { type ipv4_addr : verdict; flags interval; }",
+            "add element inet trafficmon service_daddr_vmap { 192.0.2.0/24 : jump svc_disney }",
+        ]));
+        assert_eq!(config.lock().unwrap().services.last().unwrap().ip_ranges, vec!["192.0.2.0/24"]);
+        
+        // 更新已有服務時先刪除舊規則
+        let response = server.handle(&post(r#"{"name": "netflix", "ports": [443, 8443], "ip_ranges": [], "blocked": false}"#));
+        assert_eq!(response.status, 200);
+        let rules = response.body["rules"].as_array().unwrap();
+        assert_eq!(rules[0], "delete rule inet trafficmon traffic_stats handle 12");
+        // 舊的 vmap 元素換成按端口分派到重建的計數鏈
+        assert_eq!(rules[1], "delete element inet trafficmon service_daddr_vmap { 198.38.96.0/19 }");
+        assert!(rules.contains(&json!("flush chain inet trafficmon svc_netflix")));
+        assert_eq!(rules.last().unwrap(), "add rule inet trafficmon traffic_stats tcp dport { 443, 8443 } jump svc_netflix");
+        assert!(!rules.iter().any(|rule| rule.as_str().unwrap().contains("add element inet trafficmon service_daddr_vmap")));
+        assert_eq!(config.lock().unwrap().services[0].ports, vec![443, 8443]);
+        
+        // 寫回的文件可以重新加載
+        let saved = Config::from_file(&path).unwrap();
+        assert_eq!(saved.services.iter().map(|s| s.name.as_str()).collect::<Vec<_>>(), vec!["netflix", "youtube", "disney"]);
+        assert_eq!(saved.services[0].ports, vec![443, 8443]);
+        assert_eq!(saved.interface, "br-lan");
+        
+        // 無效的請求不改變配置
+        assert_eq!(server.handle(&post(r#"{"name": "bad name", "ports": [], "ip_ranges": ["300.0.0.0/8"], "blocked": false}"#)).status, 400);
+        assert_eq!(server.handle(&post("not json")).status, 400);
+        assert_eq!(config.lock().unwrap().services.len(), 3);
+        
+        std::fs::remove_file(&path).unwrap();
+    }
+    
+    #[test]
+    fn test_bearer_token_required() {
+        let stats = Arc::new(TrafficStats::new());
+        stats.add_traffic("netflix", 1500, 1);
+        let server = ApiServer::new(Arc::new(Mutex::new(Config::default())), NftablesClassifier::new("trafficmon", "forward"))
+            .with_runner(Box::new(ListingRunner("")))
+            .with_stats(stats)
+            .with_token(Some("s3cret".to_string()));
+        let get = |auth: Option<&str>| {
+            let header = auth.map(|value| format!("Authorization: {}\r\n", value)).unwrap_or_default();
+            let raw = format!("GET /stats HTTP/1.1\r\nHost: localhost\r\n{}\r\n", header);
+            server.handle(&HttpRequest::read_from(&mut raw.as_bytes()).unwrap())
+        };
+        
+        let response = get(Some("Bearer s3cret"));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body["services"]["netflix"], json!({ "bytes": 1500, "packets": 1 }));
+        
+        for auth in [None, Some("Bearer wrong!"), Some("Bearer s3cret2"), Some("Basic s3cret")] {
+            assert_eq!(get(auth).status, 401, "{:?}", auth);
+        }
+        // 其他接口同樣需要 token
+        assert_eq!(server.handle(&post("{}")).status, 401);
+        
+        let mut response = Vec::new();
+        HttpResponse::error(401, "unauthorized").write_to(&mut response).unwrap();
+        assert!(String::from_utf8(response).unwrap().contains("WWW-Authenticate: Bearer\r\n"));
+        
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+    }
+    
+    #[test]
+    fn test_stats_sorted_and_paginated() {
+        let stats = Arc::new(TrafficStats::new());
+        stats.add_traffic("netflix", 9000, 6);
+        stats.add_traffic("youtube", 3000, 30);
+        stats.add_traffic("zoom", 6000, 10);
+        let server = ApiServer::new(Arc::new(Mutex::new(Config::default())), NftablesClassifier::new("trafficmon", "forward"))
+            .with_runner(Box::new(ListingRunner("")))
+            .with_stats(stats);
+        let get = |path: &str| {
+            let raw = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+            server.handle(&HttpRequest::read_from(&mut raw.as_bytes()).unwrap())
+        };
+        let names = |response: &HttpResponse| -> Vec<String> {
+            response.body["services"].as_array().unwrap().iter()
+                .map(|service| service["service"].as_str().unwrap().to_string())
+                .collect()
+        };
+        
+        let response = get("/stats?sort=bytes&order=desc&limit=50&offset=0");
+        assert_eq!(response.status, 200);
+        assert_eq!(names(&response), ["netflix", "zoom", "youtube"]);
+        assert_eq!(response.body["services"][0], json!({ "service": "netflix", "bytes": 9000, "packets": 6 }));
+        assert_eq!(response.body["total"], 3);
+        assert_eq!(names(&get("/stats?sort=packets")), ["youtube", "zoom", "netflix"]);
+        assert_eq!(names(&get("/stats?sort=packets&order=asc")), ["netflix", "zoom", "youtube"]);
+        assert_eq!(names(&get("/stats?sort=name")), ["netflix", "youtube", "zoom"]);
+        
+        // 分頁邊界：最後一頁不滿，超出總數時為空頁，總數不變
+        assert_eq!(names(&get("/stats?limit=2")), ["netflix", "zoom"]);
+        assert_eq!(names(&get("/stats?limit=2&offset=2")), ["youtube"]);
+        let response = get("/stats?limit=2&offset=3");
+        assert!(names(&response).is_empty());
+        assert_eq!((response.body["total"].clone(), response.body["offset"].clone()), (json!(3), json!(3)));
+        assert_eq!(names(&get("/stats?limit=1000&offset=0")).len(), 3);
+        
+        for query in ["sort=rate", "order=up", "limit=0", "limit=1001", "limit=-1", "offset=x", "offset=", "page=2"] {
+            let response = get(&format!("/stats?{}", query));
+            assert_eq!(response.status, 400, "{}", query);
+            assert!(response.body["error"].is_string());
+        }
+        
+        // 不帶參數時保持原格式
+        assert_eq!(get("/stats").body["services"]["zoom"], json!({ "bytes": 6000, "packets": 10 }));
+    }
+    
+    #[test]
+    fn test_read_request() {
+        let raw = b"POST /services HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n{}extra";
+        let request = HttpRequest::read_from(&mut &raw[..]).unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("POST", "/services"));
+        assert_eq!(request.headers["content-type"], "application/json");
+        assert_eq!(request.body, b"{}");
+        
+        let too_large = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_BYTES + 1);
+        let err = HttpRequest::read_from(&mut too_large.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        
+        let mut response = Vec::new();
+        HttpResponse::error(404, "not found").write_to(&mut response).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 404 Not Found\r\n"));
+    }
+}
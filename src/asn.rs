@@ -0,0 +1,118 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::config::{parse_cidr, prefix_mask_v4, prefix_mask_v6};
+
+/// 用戶提供的前綴 → 起源 ASN 表，按最長前綴匹配
+#[derive(Debug, Default)]
+pub struct AsnTable {
+    v4: BTreeMap<u8, HashMap<u32, u32>>,
+    v6: BTreeMap<u8, HashMap<u128, u32>>,
+}
+
+impl AsnTable {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        Ok(Self::parse(&text)?)
+    }
+    
+    /// 每行一條 "前綴 ASN"（ASN 可帶 AS 前綴），或 `bgpdump -m` 輸出的 RIB 記錄，
+    /// 後者取 AS 路徑的最後一跳作為起源
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut table = Self::default();
+        
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            
+            let (prefix, asn) = if line.contains('|') {
+                // TABLE_DUMP2|時間|B|對端IP|對端AS|前綴|AS路徑|...
+                let fields: Vec<&str> = line.split('|').collect();
+                let (Some(prefix), Some(path)) = (fields.get(5), fields.get(6)) else {
+                    return Err(format!("line {}: malformed RIB entry", lineno + 1));
+                };
+                // 起源為 AS 集合時無法確定唯一 ASN，跳過
+                match path.split_whitespace().last().and_then(|asn| asn.parse().ok()) {
+                    Some(asn) => (*prefix, asn),
+                    None => continue,
+                }
+            } else {
+                let mut fields = line.split_whitespace();
+                let (Some(prefix), Some(asn)) = (fields.next(), fields.next()) else {
+                    return Err(format!("line {}: expected \"prefix asn\"", lineno + 1));
+                };
+                let asn = asn.trim_start_matches("AS").parse()
+                    .map_err(|_| format!("line {}: invalid ASN {:?}", lineno + 1, asn))?;
+                (prefix, asn)
+            };
+            
+            let (addr, len) = parse_cidr(prefix).map_err(|e| format!("line {}: {}", lineno + 1, e))?;
+            table.insert(addr, len, asn);
+        }
+        
+        Ok(table)
+    }
+    
+    pub fn insert(&mut self, addr: IpAddr, len: u8, asn: u32) {
+        match addr {
+            IpAddr::V4(addr) => {
+                self.v4.entry(len).or_default().insert(u32::from(addr) & prefix_mask_v4(len), asn);
+            }
+            IpAddr::V6(addr) => {
+                self.v6.entry(len).or_default().insert(u128::from(addr) & prefix_mask_v6(len), asn);
+            }
+        }
+    }
+    
+    /// 最長前綴匹配
+    pub fn lookup(&self, addr: &IpAddr) -> Option<u32> {
+        match addr {
+            IpAddr::V4(addr) => {
+                let addr = u32::from(*addr);
+                self.v4.iter().rev().find_map(|(len, prefixes)| prefixes.get(&(addr & prefix_mask_v4(*len))).copied())
+            }
+            IpAddr::V6(addr) => {
+                let addr = u128::from(*addr);
+                self.v6.iter().rev().find_map(|(len, prefixes)| prefixes.get(&(addr & prefix_mask_v6(*len))).copied())
+            }
+        }
+    }
+    
+    pub fn len(&self) -> usize {
+        self.v4.values().map(HashMap::len).sum::<usize>() + self.v6.values().map(HashMap::len).sum::<usize>()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_longest_prefix_match() {
+        let table = AsnTable::parse("
+            # 前綴 ASN
+            8.0.0.0/8 3356
+            8.8.8.0/24 AS15169
+            2001:4860::/32 15169
+            TABLE_DUMP2|1700000000|B|192.0.2.1|64500|1.1.1.0/24|64500 13335|IGP
+        ").unwrap();
+        
+        assert_eq!(table.len(), 4);
+        assert_eq!(table.lookup(&"8.8.8.8".parse().unwrap()), Some(15169));
+        assert_eq!(table.lookup(&"8.8.4.4".parse().unwrap()), Some(3356));
+        assert_eq!(table.lookup(&"1.1.1.1".parse().unwrap()), Some(13335));
+        assert_eq!(table.lookup(&"2001:4860:4860::8888".parse().unwrap()), Some(15169));
+        assert_eq!(table.lookup(&"9.9.9.9".parse().unwrap()), None);
+        
+        assert!(AsnTable::parse("8.8.8.0/33 15169").is_err());
+    }
+}
@@ -0,0 +1,571 @@
+use pcap::{Active, Capture, Inactive, Precision};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::config::{CaptureConfig, Config};
+use crate::packet::LinkType;
+
+/// 抓到的一個包；data 為實際捕獲的內容（可能被 snaplen 截斷），
+/// wire_len 為線路上的原始長度，用於字節統計
+#[derive(Debug, Clone, Copy)]
+pub struct RawPacket<'a> {
+    pub data: &'a [u8],
+    pub wire_len: u32,
+    pub link: LinkType,
+    /// 抓包時的時間戳；來源不帶時間戳（如內存來源）時為 None，按處理時間計
+    pub timestamp: Option<SystemTime>,
+}
+
+/// pcap 時間戳轉為 SystemTime，`fraction` 按 `nanos` 為納秒或微秒；超出範圍時為 None
+pub fn capture_time(secs: u64, fraction: u64, nanos: bool) -> Option<SystemTime> {
+    let nanos = if nanos { fraction } else { fraction.checked_mul(1000)? };
+    let nanos = u32::try_from(nanos).ok().filter(|n| *n < 1_000_000_000)?;
+    SystemTime::UNIX_EPOCH.checked_add(Duration::new(secs, nanos))
+}
+
+fn timeval_time(ts: &libc::timeval, nanos: bool) -> Option<SystemTime> {
+    capture_time(u64::try_from(ts.tv_sec).ok()?, u64::try_from(ts.tv_usec).ok()?, nanos)
+}
+
+/// 包來源的抽象，使處理流程可以脫離 libpcap 測試
+pub trait CaptureSource {
+    /// 返回下一個包；`Ok(None)` 表示暫時沒有包（超時），
+    /// `Err(pcap::Error::NoMorePackets)` 表示來源已耗盡
+    fn next_packet(&mut self) -> Result<Option<RawPacket<'_>>, pcap::Error>;
+
+    /// 輸出來源自身的統計（例如丟包數）
+    fn report_stats(&mut self) {}
+}
+
+/// pcap 抓包句柄構建器的抽象，便於驗證配置是否生效
+pub trait CaptureBuilder: Sized {
+    fn promisc(self, on: bool) -> Self;
+    fn snaplen(self, len: i32) -> Self;
+    fn timeout(self, ms: i32) -> Self;
+    fn buffer_size(self, size: i32) -> Self;
+    fn immediate_mode(self, on: bool) -> Self;
+    fn precision(self, precision: Precision) -> Self;
+}
+
+impl CaptureBuilder for Capture<Inactive> {
+    fn promisc(self, on: bool) -> Self {
+        Capture::promisc(self, on)
+    }
+    
+    fn snaplen(self, len: i32) -> Self {
+        Capture::snaplen(self, len)
+    }
+    
+    fn timeout(self, ms: i32) -> Self {
+        Capture::timeout(self, ms)
+    }
+    
+    fn buffer_size(self, size: i32) -> Self {
+        Capture::buffer_size(self, size)
+    }
+    
+    fn immediate_mode(self, on: bool) -> Self {
+        Capture::immediate_mode(self, on)
+    }
+    
+    fn precision(self, precision: Precision) -> Self {
+        Capture::precision(self, precision)
+    }
+}
+
+pub fn configure_capture<B: CaptureBuilder>(builder: B, config: &CaptureConfig) -> B {
+    let builder = builder
+        .promisc(true)
+        .snaplen(config.snaplen)
+        .timeout(config.timeout_ms)
+        .immediate_mode(config.immediate);
+    let builder = if config.nanosecond_timestamps {
+        builder.precision(Precision::Nano)
+    } else {
+        builder
+    };
+    
+    match config.buffer_size {
+        Some(size) => builder.buffer_size(size),
+        None => builder,
+    }
+}
+
+pub struct PcapSource {
+    cap: Capture<Active>,
+    link: LinkType,
+    /// 非阻塞模式下 poll 的等待時間，None 表示阻塞讀取
+    poll_timeout: Option<i32>,
+    /// 句柄按納秒精度返回時間戳（tv_usec 中是納秒）
+    nanos: bool,
+}
+
+impl PcapSource {
+    pub fn open(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let interface = config.resolve_interface()?;
+        
+        let mut cap = configure_capture(Capture::from_device(interface.as_str())?, &config.capture)
+            .open()?;
+        
+        if let Some(ref filter) = config.filter {
+            cap.filter(filter, true)?;
+        }
+        
+        let poll_timeout = if config.capture.nonblocking {
+            cap = cap.setnonblock()?;
+            Some(config.capture.timeout_ms)
+        } else {
+            None
+        };
+        
+        // `any` 接口使用 Linux cooked capture 而不是以太網頭
+        let dlt = cap.get_datalink().0;
+        let link = LinkType::from_dlt(dlt)
+            .ok_or_else(|| format!("Unsupported link type {} on {}", dlt, interface))?;
+        
+        Ok(Self { cap, link, poll_timeout, nanos: config.capture.nanosecond_timestamps })
+    }
+    
+    /// 打開的網卡實際使用的鏈路層類型
+    pub fn link_type(&self) -> LinkType {
+        self.link
+    }
+    
+    /// 等待句柄可讀，超時返回 false
+    fn wait_readable(&self, timeout_ms: i32) -> bool {
+        let mut fds = libc::pollfd {
+            fd: self.cap.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        
+        // poll 出錯（如被信號打斷）時也交給下一次讀取處理
+        unsafe { libc::poll(&mut fds, 1, timeout_ms) != 0 }
+    }
+}
+
+impl CaptureSource for PcapSource {
+    fn next_packet(&mut self) -> Result<Option<RawPacket<'_>>, pcap::Error> {
+        if let Some(timeout_ms) = self.poll_timeout {
+            if !self.wait_readable(timeout_ms) {
+                return Ok(None);
+            }
+        }
+        
+        match self.cap.next_packet() {
+            Ok(packet) => Ok(Some(RawPacket {
+                data: packet.data,
+                wire_len: packet.header.len,
+                link: self.link,
+                timestamp: timeval_time(&packet.header.ts, self.nanos),
+            })),
+            Err(pcap::Error::TimeoutExpired) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    
+    fn report_stats(&mut self) {
+        match self.cap.stats() {
+            Ok(stat) => println!(
+                "pcap stats: received {}, dropped {}, if_dropped {}",
+                stat.received, stat.dropped, stat.if_dropped
+            ),
+            Err(e) => eprintln!("Failed to read pcap stats: {}", e),
+        }
+    }
+}
+
+/// 連續出錯多少次後認為設備已失效
+const MAX_CONSECUTIVE_ERRORS: u32 = 10;
+
+/// 設備消失（如 WAN 閃斷）時關閉句柄，按指數退避重新打開
+pub struct ReconnectingSource<S, F> {
+    source: Option<S>,
+    open: F,
+    errors: u32,
+    /// 句柄已失效，下次讀取前關閉
+    failed: bool,
+    backoff: Duration,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    next_attempt: Instant,
+}
+
+impl<S, F> ReconnectingSource<S, F>
+where
+    S: CaptureSource,
+    F: FnMut() -> Result<S, Box<dyn Error>>,
+{
+    pub fn new(source: S, open: F) -> Self {
+        Self {
+            source: Some(source),
+            open,
+            errors: 0,
+            failed: false,
+            backoff: Duration::from_secs(1),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            next_attempt: Instant::now(),
+        }
+    }
+    
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.backoff = initial;
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+    
+    pub fn is_connected(&self) -> bool {
+        self.source.is_some()
+    }
+    
+    fn reconnect(&mut self) {
+        let now = Instant::now();
+        if now < self.next_attempt {
+            // 分段等待，讓抓包循環能及時響應停止信號
+            thread::sleep((self.next_attempt - now).min(Duration::from_millis(200)));
+            return;
+        }
+        
+        match (self.open)() {
+            Ok(source) => {
+                eprintln!("Capture device is back, resuming capture");
+                self.source = Some(source);
+                self.backoff = self.initial_backoff;
+            }
+            Err(e) => {
+                eprintln!("Failed to reopen capture device: {} (retrying in {:?})", e, self.backoff);
+                self.next_attempt = Instant::now() + self.backoff;
+                self.backoff = (self.backoff * 2).min(self.max_backoff);
+            }
+        }
+    }
+}
+
+impl<S, F> CaptureSource for ReconnectingSource<S, F>
+where
+    S: CaptureSource,
+    F: FnMut() -> Result<S, Box<dyn Error>>,
+{
+    fn next_packet(&mut self) -> Result<Option<RawPacket<'_>>, pcap::Error> {
+        if self.failed {
+            eprintln!("Capture device failed after {} consecutive errors, closing handle", self.errors);
+            self.source = None;
+            self.failed = false;
+            self.errors = 0;
+            self.next_attempt = Instant::now() + self.backoff;
+        }
+        
+        if self.source.is_none() {
+            self.reconnect();
+            return Ok(None);
+        }
+        
+        let Some(source) = self.source.as_mut() else {
+            return Ok(None);
+        };
+        match source.next_packet() {
+            Err(pcap::Error::NoMorePackets) => Err(pcap::Error::NoMorePackets),
+            Err(e) => {
+                self.errors += 1;
+                if self.errors >= MAX_CONSECUTIVE_ERRORS {
+                    self.failed = true;
+                    return Ok(None);
+                }
+                Err(e)
+            }
+            result => {
+                self.errors = 0;
+                result
+            }
+        }
+    }
+    
+    fn report_stats(&mut self) {
+        if let Some(source) = self.source.as_mut() {
+            source.report_stats();
+        }
+    }
+}
+
+/// 依次返回預先準備好的包，用於測試
+pub struct MemorySource {
+    packets: VecDeque<(Vec<u8>, u32)>,
+    current: Vec<u8>,
+    link: LinkType,
+}
+
+impl MemorySource {
+    pub fn new(packets: Vec<Vec<u8>>) -> Self {
+        Self::with_wire_lengths(packets.into_iter().map(|p| {
+            let len = p.len() as u32;
+            (p, len)
+        }).collect())
+    }
+    
+    /// 模擬被 snaplen 截斷的包：每個包附帶線路上的原始長度
+    pub fn with_wire_lengths(packets: Vec<(Vec<u8>, u32)>) -> Self {
+        Self {
+            packets: packets.into(),
+            current: Vec::new(),
+            link: LinkType::Ethernet,
+        }
+    }
+    
+    pub fn with_link_type(mut self, link: LinkType) -> Self {
+        self.link = link;
+        self
+    }
+}
+
+impl CaptureSource for MemorySource {
+    fn next_packet(&mut self) -> Result<Option<RawPacket<'_>>, pcap::Error> {
+        let (data, wire_len) = self.packets.pop_front().ok_or(pcap::Error::NoMorePackets)?;
+        self.current = data;
+        Ok(Some(RawPacket { data: &self.current, wire_len, link: self.link, timestamp: None }))
+    }
+}
+
+/// 經典 pcap 文件頭的魔數（微秒和納秒時間戳）
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+
+/// 單個記錄允許的最大長度，超過視為文件損壞
+const PCAP_MAX_RECORD: u32 = 256 * 1024;
+
+/// 逐個讀取經典 pcap 文件中的包，用於離線回放；不支持 pcapng
+pub struct PcapFileSource<R> {
+    reader: R,
+    big_endian: bool,
+    /// 文件頭魔數表明記錄中的時間戳為納秒
+    nanos: bool,
+    link: LinkType,
+    current: Vec<u8>,
+}
+
+impl PcapFileSource<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(path)
+            .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+        Self::from_reader(BufReader::new(file))
+    }
+}
+
+impl<R: Read> PcapFileSource<R> {
+    pub fn from_reader(mut reader: R) -> Result<Self, Box<dyn Error>> {
+        let mut header = [0u8; 24];
+        reader.read_exact(&mut header)?;
+        
+        let magic = [header[0], header[1], header[2], header[3]];
+        let big_endian = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+            (PCAP_MAGIC_MICROS | PCAP_MAGIC_NANOS, _) => false,
+            (_, PCAP_MAGIC_MICROS | PCAP_MAGIC_NANOS) => true,
+            _ => return Err("Not a pcap file (pcapng is not supported)".into()),
+        };
+        let nanos = read_u32(magic, big_endian) == PCAP_MAGIC_NANOS;
+        
+        let dlt = read_u32([header[20], header[21], header[22], header[23]], big_endian);
+        let link = LinkType::from_dlt(dlt as i32)
+            .ok_or_else(|| format!("Unsupported link type {} in pcap file", dlt))?;
+        
+        Ok(Self { reader, big_endian, nanos, link, current: Vec::new() })
+    }
+    
+    pub fn link_type(&self) -> LinkType {
+        self.link
+    }
+}
+
+impl<R: Read> CaptureSource for PcapFileSource<R> {
+    fn next_packet(&mut self) -> Result<Option<RawPacket<'_>>, pcap::Error> {
+        let mut header = [0u8; 16];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(pcap::Error::NoMorePackets),
+            Err(e) => return Err(pcap::Error::PcapError(e.to_string())),
+        }
+        
+        let secs = read_u32([header[0], header[1], header[2], header[3]], self.big_endian);
+        let fraction = read_u32([header[4], header[5], header[6], header[7]], self.big_endian);
+        let caplen = read_u32([header[8], header[9], header[10], header[11]], self.big_endian);
+        let wire_len = read_u32([header[12], header[13], header[14], header[15]], self.big_endian);
+        if caplen > PCAP_MAX_RECORD {
+            return Err(pcap::Error::PcapError(format!("Invalid pcap record length {}", caplen)));
+        }
+        
+        self.current.resize(caplen as usize, 0);
+        self.reader.read_exact(&mut self.current)
+            .map_err(|e| pcap::Error::PcapError(format!("Truncated pcap record: {}", e)))?;
+        Ok(Some(RawPacket {
+            data: &self.current,
+            wire_len,
+            link: self.link,
+            timestamp: capture_time(u64::from(secs), u64::from(fraction), self.nanos),
+        }))
+    }
+}
+
+fn read_u32(bytes: [u8; 4], big_endian: bool) -> u32 {
+    if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[derive(Default)]
+    struct RecordingBuilder {
+        calls: Vec<String>,
+    }
+    
+    impl CaptureBuilder for RecordingBuilder {
+        fn promisc(mut self, on: bool) -> Self {
+            self.calls.push(format!("promisc={}", on));
+            self
+        }
+        
+        fn snaplen(mut self, len: i32) -> Self {
+            self.calls.push(format!("snaplen={}", len));
+            self
+        }
+        
+        fn timeout(mut self, ms: i32) -> Self {
+            self.calls.push(format!("timeout={}", ms));
+            self
+        }
+        
+        fn buffer_size(mut self, size: i32) -> Self {
+            self.calls.push(format!("buffer_size={}", size));
+            self
+        }
+        
+        fn immediate_mode(mut self, on: bool) -> Self {
+            self.calls.push(format!("immediate={}", on));
+            self
+        }
+        
+        fn precision(mut self, precision: Precision) -> Self {
+            self.calls.push(format!("precision={:?}", precision));
+            self
+        }
+    }
+    
+    #[test]
+    fn test_capture_builder_uses_config() {
+        let config = CaptureConfig {
+            snaplen: 256,
+            buffer_size: Some(8 * 1024 * 1024),
+            immediate: true,
+            timeout_ms: 250,
+            nonblocking: false,
+            stats_interval: 30,
+            queue_capacity: 1024,
+            workers: 1,
+            nanosecond_timestamps: true,
+        };
+        
+        let builder = configure_capture(RecordingBuilder::default(), &config);
+        assert_eq!(builder.calls, vec![
+            "promisc=true", "snaplen=256", "timeout=250", "immediate=true", "precision=Nano", "buffer_size=8388608",
+        ]);
+        
+        let builder = configure_capture(RecordingBuilder::default(), &CaptureConfig::default());
+        assert!(!builder.calls.iter().any(|c| c.starts_with("buffer_size") || c.starts_with("precision")));
+    }
+    
+    #[test]
+    fn test_capture_timeout_from_config() {
+        let config: CaptureConfig = toml::from_str("timeout_ms = 100\nnonblocking = true").unwrap();
+        assert!(config.nonblocking);
+        
+        let builder = configure_capture(RecordingBuilder::default(), &config);
+        assert!(builder.calls.contains(&"timeout=100".to_string()));
+        
+        let builder = configure_capture(RecordingBuilder::default(), &CaptureConfig::default());
+        assert!(builder.calls.contains(&"timeout=1000".to_string()));
+    }
+    
+    #[test]
+    fn test_pcap_file_timestamps() {
+        let file = |magic: u32, fraction: u32| {
+            let mut out = Vec::new();
+            out.extend_from_slice(&magic.to_le_bytes());
+            out.extend_from_slice(&[2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            out.extend_from_slice(&65535u32.to_le_bytes());
+            out.extend_from_slice(&1u32.to_le_bytes());
+            out.extend_from_slice(&1_700_000_000u32.to_le_bytes());
+            out.extend_from_slice(&fraction.to_le_bytes());
+            out.extend_from_slice(&[1, 0, 0, 0, 1, 0, 0, 0, 0xff]);
+            out
+        };
+        let timestamp = |bytes: Vec<u8>| {
+            let mut source = PcapFileSource::from_reader(io::Cursor::new(bytes)).unwrap();
+            source.next_packet().unwrap().unwrap().timestamp
+        };
+        
+        let expected = SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        assert_eq!(timestamp(file(PCAP_MAGIC_NANOS, 123_456_789)), Some(expected));
+        assert_eq!(timestamp(file(PCAP_MAGIC_MICROS, 123_456)), Some(expected - Duration::from_nanos(789)));
+        // 小數部分超出一秒的記錄不可信
+        assert_eq!(timestamp(file(PCAP_MAGIC_MICROS, 1_000_000)), None);
+    }
+    
+    /// 沒有內部來源時模擬已經消失的設備，每次讀取都出錯
+    struct FlakyDevice(Option<MemorySource>);
+    
+    impl CaptureSource for FlakyDevice {
+        fn next_packet(&mut self) -> Result<Option<RawPacket<'_>>, pcap::Error> {
+            match &mut self.0 {
+                Some(source) => source.next_packet(),
+                None => Err(pcap::Error::PcapError("The interface went down".to_string())),
+            }
+        }
+    }
+    
+    #[test]
+    fn test_reconnect_after_device_failure() {
+        let mut attempts = 0;
+        let open = || -> Result<FlakyDevice, Box<dyn Error>> {
+            attempts += 1;
+            if attempts == 1 {
+                return Err("No such device exists".into());
+            }
+            Ok(FlakyDevice(Some(MemorySource::new(vec![vec![1, 2, 3]]))))
+        };
+        
+        let mut source = ReconnectingSource::new(FlakyDevice(None), open)
+            .with_backoff(Duration::ZERO, Duration::ZERO);
+        
+        // 偶發錯誤照常返回給調用方
+        for _ in 0..MAX_CONSECUTIVE_ERRORS - 1 {
+            assert!(source.next_packet().is_err());
+        }
+        // 持續出錯後關閉句柄並開始重連
+        assert!(matches!(source.next_packet(), Ok(None)));
+        
+        let mut packet = None;
+        for _ in 0..5 {
+            if let Some(p) = source.next_packet().unwrap() {
+                packet = Some(p.data.to_vec());
+                break;
+            }
+        }
+        
+        assert_eq!(packet, Some(vec![1, 2, 3]));
+        assert!(source.is_connected());
+        drop(source);
+        // 第一次重新打開失敗，第二次成功
+        assert_eq!(attempts, 2);
+    }
+}
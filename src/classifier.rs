@@ -1,87 +1,3056 @@
-use pcap::{Capture, Device};
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
-use std::sync::Arc;
-use std::net::Ipv4Addr;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::config::Config;
-use crate::stats::TrafficStats;
+use chrono::Local;
+use serde::Serialize;
+
+use crate::accuracy::AccuracyReport;
+use crate::alert::AlertMonitor;
+use crate::api::ApiServer;
+use crate::asn::AsnTable;
+use crate::capture::{CaptureSource, PcapFileSource, PcapSource, RawPacket, ReconnectingSource};
+use crate::config::{
+    cidr_contains, parse_cidr, ByteAccounting, ClassificationMethod, ClassificationPriority, ClassificationWeights, Config,
+    EmailReportConfig, KafkaConfig, ProcessAttributionConfig, SniLearningConfig,
+};
+use crate::decisions::{Decision, DecisionLog};
+use crate::dedup::Deduplicator;
+use crate::dns::{parse_dns_answers, DnsCache};
+use crate::http::{parse_connect_target, parse_http_request_until, HttpRequestInfo};
+use crate::flow::{FlowKey, FlowRecord, FlowTable, LatencyTracker, RetransmitTracker};
+use crate::learned::LearnedAddresses;
+use crate::nftables::NftablesClassifier;
+use crate::process::{ProcessResolver, SocketOwners};
+#[cfg(feature = "ebpf")]
+use crate::process::EbpfSocketOwners;
+use crate::packet::{
+    parse_arp, parse_ethernet, parse_frame, ArpPacket, LinkType, PacketInfo, ARP_REPLY, ARP_REQUEST, IPPROTO_DCCP,
+    IPPROTO_SCTP, IPPROTO_UDP,
+};
+use crate::scan::{ScanDetector, ScanState};
+use crate::ttl::{SourceTtl, TtlTracker};
+use crate::schedule::{BusinessHours, TimeBucket};
+use crate::selftest::{probe_frames, SelfTestCase, SelfTestReport};
+use crate::socket::StatsSocket;
+use crate::stats::{FlushTimer, PrometheusTextfile, TrafficStats};
+use crate::kafka::{EventPublisher, EventWorker};
+use crate::logfmt::LogfmtExporter;
+#[cfg(feature = "kafka")]
+use crate::kafka::KafkaProducer;
+use crate::matrix::TrafficMatrix;
+use crate::report::{SmtpTransport, Summary, SummaryMailer};
+use crate::sample::{PayloadSample, PayloadSampler, Redactor};
+use crate::tls::{parse_client_hello, parse_sni, parse_sni_until};
+
+/// STUN 訊息頭中固定的 magic cookie (RFC 5389)
+const STUN_MAGIC_COOKIE: u32 = 0x2112A442;
+
+/// 檢查摘要郵件是否到期的間隔
+const REPORT_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct TrafficClassifier {
     config: Config,
     stats: Arc<TrafficStats>,
+    scan_detector: Option<Mutex<ScanDetector>>,
+    ttl_tracker: Option<Mutex<TtlTracker>>,
+    /// 由 SNI 學到的服務地址
+    learned: Option<Mutex<LearnedAddresses>>,
+    dedup: Option<Mutex<Deduplicator>>,
+    blocker: Option<Arc<NftablesClassifier>>,
+    latency: Mutex<LatencyTracker>,
+    retransmits: Mutex<RetransmitTracker>,
+    dns_cache: Mutex<DnsCache>,
+    domain_bytes: Mutex<HashMap<String, u64>>,
+    flows: Option<Mutex<FlowTable>>,
+    client_os: Mutex<HashMap<String, u64>>,
+    asn_table: Option<AsnTable>,
+    asn_bytes: Mutex<HashMap<u32, u64>>,
+    tcp_flags: Mutex<HashMap<String, TcpFlagCounts>>,
+    /// 從 GRE/IP-in-IP 隧道中解出的流量（字節數, 包數），按內層分類的服務
+    tunneled: Mutex<HashMap<String, (u64, u64)>>,
+    /// 配置中各服務的地址範圍，按配置順序
+    service_ranges: Vec<((IpAddr, u8), String)>,
+    /// HTTP CONNECT 隧道（客戶端 → 代理方向的流）歸屬的服務和最後一個包的時間
+    proxy_tunnels: Mutex<HashMap<FlowKey, (String, SystemTime)>>,
+    /// 按 SNMP 版本（v1、v2c、v3）統計的包數
+    snmp_versions: Mutex<HashMap<String, u64>>,
+    payload_sampler: Option<Mutex<PayloadSampler>>,
+    /// 日誌中地址和端口的寫法，payload_samples.redact 時隱去
+    redactor: Redactor,
+    matrix: Option<Mutex<TrafficMatrix>>,
+    /// 流記錄的外部發布（如 Kafka），在專用線程上發送
+    events: Option<EventWorker>,
+    socket_owners: Option<Mutex<ProcessResolver>>,
+    business_hours: Option<BusinessHours>,
+    /// 按（服務, 工作時間內/外）統計的字節數
+    time_bucket_bytes: Mutex<HashMap<(String, TimeBucket), u64>>,
+    /// 按接口的 ARP 計數，配置了 arp_stats 時才統計
+    arp_counts: Mutex<HashMap<String, ArpCounts>>,
+    /// 按（服務, 進程名）統計的字節數
+    process_bytes: Mutex<HashMap<(String, String), u64>>,
+    /// 權重投票時各方法結果不一致的包數，按（勝者, 得分最高的落選者）
+    vote_conflicts: Mutex<HashMap<(String, String), u64>>,
+    decision_log: Option<Mutex<DecisionLog>>,
+}
+
+/// 一個包的分類結果及其解析出的五元組
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Classification {
+    pub service: String,
+    pub protocol: u8,
+    pub src_port: Option<u16>,
+    pub dst_port: Option<u16>,
+    pub src_ip: IpAddr,
+    pub dst_ip: IpAddr,
+}
+
+impl Classification {
+    pub fn new(service: String, info: &PacketInfo) -> Self {
+        Self {
+            service,
+            protocol: info.protocol,
+            src_port: info.src_port,
+            dst_port: info.dst_port,
+            src_ip: info.src_ip,
+            dst_ip: info.dst_ip,
+        }
+    }
+}
+
+/// 權重投票的結果：勝者及其得分，其餘候選按得分從高到低
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClassificationDecision {
+    pub service: String,
+    pub score: f64,
+    pub runners_up: Vec<(String, f64)>,
+}
+
+/// 每個服務看到的 TCP 控制包數，用於區分建連/斷連和數據傳輸
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TcpFlagCounts {
+    /// 不帶 ACK 的 SYN，即新連接請求
+    pub syn: u64,
+    pub fin: u64,
+    pub rst: u64,
+}
+
+/// 一個接口上看到的 ARP 包數
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ArpCounts {
+    pub requests: u64,
+    pub replies: u64,
+    /// 免費 ARP（發送方與目標 IP 相同），短時間內大量出現可能是欺騙
+    pub gratuitous: u64,
+}
+
+/// 已配置服務及其實時統計，供儀表盤一次取得
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ServiceInfo {
+    pub name: String,
+    pub ports: Vec<u16>,
+    pub ip_ranges: Vec<String>,
+    pub blocked: bool,
+    pub bytes: u64,
+    pub packets: u64,
 }
 
 impl TrafficClassifier {
+    /// 按同一份配置構建統計
+    pub fn from_config(config: Config) -> Self {
+        let stats = Arc::new(TrafficStats::from_config(&config));
+        Self::new(config, stats)
+    }
+    
     pub fn new(config: Config, stats: Arc<TrafficStats>) -> Self {
+        let scan_detector = config.scan_detection.as_ref()
+            .map(|c| Mutex::new(ScanDetector::new(c)));
+        let ttl_tracker = config.ttl_tracking.as_ref()
+            .map(|c| Mutex::new(TtlTracker::new(c)));
+        let learned = config.sni_learning.as_ref().map(|c| {
+            let mut learned = LearnedAddresses::new(c);
+            if let Err(e) = learned.load() {
+                eprintln!("無法載入學到的服務地址: {}", e);
+            }
+            Mutex::new(learned)
+        });
+        let dedup = config.dedup.as_ref()
+            .map(|c| Mutex::new(Deduplicator::new(c)));
+        // 掃描自動封鎖和把學到的地址加入集合都需要操作 nftables
+        let needs_blocker = config.scan_detection.as_ref().is_some_and(|c| c.block_seconds.is_some())
+            || config.sni_learning.as_ref().is_some_and(|c| c.add_to_nftables);
+        let blocker = needs_blocker.then(|| NftablesClassifier::from_config(&config)).and_then(|nft| match nft {
+            Ok(nft) => Some(Arc::new(nft)),
+            Err(e) => {
+                eprintln!("無法初始化 nftables 封鎖: {}", e);
+                None
+            }
+        });
+        let flows = config.flow_export.as_ref()
+            .map(|c| Mutex::new(FlowTable::new(Duration::from_secs(c.timeout_secs.unwrap_or(config.idle_timeout)))));
+        let events = Self::kafka_publisher(config.kafka.as_ref());
+        let socket_owners = Self::socket_owners(config.process_attribution.as_ref());
+        let business_hours = config.business_hours.as_ref().and_then(BusinessHours::from_config);
+        let matrix = config.traffic_matrix.clone()
+            .map(|c| Mutex::new(TrafficMatrix::new(c, &config.services)));
+        let payload_sampler = config.payload_samples.clone()
+            .map(|c| Mutex::new(PayloadSampler::new(c)));
+        let redactor = Redactor::new(config.payload_samples.as_ref().is_some_and(|c| c.redact));
+        let asn_table = config.asn_table.as_ref().and_then(|path| match AsnTable::load(path) {
+            Ok(table) => Some(table),
+            Err(e) => {
+                eprintln!("無法載入 ASN 表 {}: {}", path.display(), e);
+                None
+            }
+        });
+        let decision_log = config.decision_log.as_ref().and_then(|c| match DecisionLog::open(&c.path, c.max_bytes) {
+            Ok(log) => Some(Mutex::new(log)),
+            Err(e) => {
+                eprintln!("無法打開分類決定日誌 {}: {}", c.path.display(), e);
+                None
+            }
+        });
+        let service_ranges = config.services.iter()
+            .flat_map(|service| service.ip_ranges.iter().map(move |range| (range, &service.name)))
+            .filter_map(|(range, name)| Some((parse_cidr(range).ok()?, name.clone())))
+            .collect();
+        
         Self {
             config,
             stats,
+            scan_detector,
+            ttl_tracker,
+            learned,
+            dedup,
+            blocker,
+            latency: Mutex::new(LatencyTracker::new(Duration::from_secs(10))),
+            retransmits: Mutex::new(RetransmitTracker::new()),
+            dns_cache: Mutex::new(DnsCache::new()),
+            domain_bytes: Mutex::new(HashMap::new()),
+            flows,
+            client_os: Mutex::new(HashMap::new()),
+            asn_table,
+            asn_bytes: Mutex::new(HashMap::new()),
+            tcp_flags: Mutex::new(HashMap::new()),
+            tunneled: Mutex::new(HashMap::new()),
+            service_ranges,
+            proxy_tunnels: Mutex::new(HashMap::new()),
+            snmp_versions: Mutex::new(HashMap::new()),
+            payload_sampler,
+            redactor,
+            matrix,
+            events,
+            socket_owners,
+            process_bytes: Mutex::new(HashMap::new()),
+            business_hours,
+            time_bucket_bytes: Mutex::new(HashMap::new()),
+            arp_counts: Mutex::new(HashMap::new()),
+            vote_conflicts: Mutex::new(HashMap::new()),
+            decision_log,
         }
     }
-
+    
+    #[cfg(feature = "kafka")]
+    fn kafka_publisher(config: Option<&KafkaConfig>) -> Option<EventWorker> {
+        let config = config?;
+        match KafkaProducer::new(config) {
+            Ok(producer) => Some(EventWorker::start(EventPublisher::new(Box::new(producer), config))),
+            Err(e) => {
+                eprintln!("{}", e);
+                None
+            }
+        }
+    }
+    
+    #[cfg(not(feature = "kafka"))]
+    fn kafka_publisher(config: Option<&KafkaConfig>) -> Option<EventWorker> {
+        if config.is_some() {
+            eprintln!("配置了 [kafka]，但編譯時未啟用 kafka 特性，忽略");
+        }
+        None
+    }
+    
+    #[cfg(feature = "ebpf")]
+    fn socket_owners(config: Option<&ProcessAttributionConfig>) -> Option<Mutex<ProcessResolver>> {
+        let config = config?;
+        match EbpfSocketOwners::open(&config.pinned_map) {
+            Ok(owners) => Some(Mutex::new(ProcessResolver::new(Box::new(owners)))),
+            Err(e) => {
+                eprintln!("{}, process attribution disabled", e);
+                None
+            }
+        }
+    }
+    
+    #[cfg(not(feature = "ebpf"))]
+    fn socket_owners(config: Option<&ProcessAttributionConfig>) -> Option<Mutex<ProcessResolver>> {
+        if config.is_some() {
+            eprintln!("配置了 [process_attribution]，但編譯時未啟用 ebpf 特性，忽略");
+        }
+        None
+    }
+    
+    /// 用指定的映射按進程歸屬流量，替代配置中的 eBPF 映射
+    pub fn with_socket_owners(mut self, owners: Box<dyn SocketOwners>) -> Self {
+        self.socket_owners = Some(Mutex::new(ProcessResolver::new(owners)));
+        self
+    }
+    
+    /// 把流記錄發布到指定的事件發布器，替代配置中的 Kafka
+    pub fn with_event_publisher(mut self, publisher: EventPublisher) -> Self {
+        self.events = Some(EventWorker::start(publisher));
+        self
+    }
+    
+    /// 使用指定的前綴 → ASN 表，替代配置中的文件
+    pub fn with_asn_table(mut self, table: AsnTable) -> Self {
+        self.asn_table = Some(table);
+        self
+    }
+    
+    /// 替換用於自動封鎖和加入學到的地址的 nftables 分類器
+    pub fn with_blocker(mut self, blocker: Arc<NftablesClassifier>) -> Self {
+        self.blocker = Some(blocker);
+        self
+    }
+    
     pub fn start_capture(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let device = Device::lookup()?
-            .ok_or("No network device found")?;
-        
-        let mut cap = Capture::from_device(device)?
-            .promisc(true)
-            .snaplen(65535)
-            .timeout(1000)
-            .open()?;
+        let source = PcapSource::open(&self.config)?;
         
-        if let Some(ref filter) = self.config.filter {
-            cap.filter(filter, true)?;
+        // 按網卡的鏈路層類型跑一遍自檢，偏移或配置不對時提前提示
+        let report = self.self_test(source.link_type());
+        if !report.passed() {
+            eprintln!("{}", report);
         }
         
+        let mut source = ReconnectingSource::new(source, || PcapSource::open(&self.config));
+        
         println!("Starting traffic capture for monitoring (no filtering)");
         
-        while crate::RUNNING.load(std::sync::atomic::Ordering::SeqCst) {
-            match cap.next_packet() {
-                Ok(packet) => {
-                    self.process_packet(&packet);
+        // 抓包結束（包括收到停止信號）時 drop，把剩餘統計寫入存儲
+        let _flush_timer = self.config.stats_flush_interval
+            .map(|secs| FlushTimer::start(Arc::clone(&self.stats), Duration::from_secs(secs)));
+        let _stats_socket = self.config.stats_socket.as_ref()
+            .and_then(|path| match StatsSocket::start(Path::new(path), &self.stats) {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    eprintln!("Failed to open stats socket {}: {}", path, e);
+                    None
+                }
+            });
+        let _textfile = self.config.prometheus_textfile.as_ref()
+            .map(|path| PrometheusTextfile::start(Arc::clone(&self.stats), PathBuf::from(path), Duration::from_secs(self.config.report_interval)));
+        let _logfmt = self.config.logfmt_export.as_ref()
+            .and_then(|c| match LogfmtExporter::start(Arc::clone(&self.stats), &c.path, c.max_bytes, Duration::from_secs(self.config.report_interval)) {
+                Ok(exporter) => Some(exporter),
+                Err(e) => {
+                    eprintln!("Failed to open logfmt export {}: {}", c.path.display(), e);
+                    None
+                }
+            });
+        let _alert_monitor = (!self.config.service_alerts.is_empty()).then(|| {
+            AlertMonitor::start(Arc::clone(&self.stats), &self.config.service_alerts, Duration::from_secs(self.config.report_interval))
+        });
+        
+        let stop_reports = AtomicBool::new(false);
+        let api_running = AtomicBool::new(true);
+        thread::scope(|scope| {
+            scope.spawn(|| self.run_report_ticks(&stop_reports));
+            if let Some(email) = &self.config.email_report {
+                scope.spawn(|| self.run_email_reports(email, &stop_reports));
+            }
+            if let Some(learning) = &self.config.sni_learning {
+                scope.spawn(|| self.run_learned_compaction(learning, &stop_reports));
+            }
+            if let Some(addr) = &self.config.api_listen {
+                scope.spawn(|| self.run_api(addr, &api_running));
+            }
+            self.capture_queued(&mut source);
+            stop_reports.store(true, Ordering::SeqCst);
+            api_running.store(false, Ordering::SeqCst);
+        });
+        Ok(())
+    }
+    
+    /// 每個報告周期檢查一次鏈路使用率，直到 `stop`；周期從 report_interval 開始，
+    /// 配置了 adaptive_report 時按當前速率調整
+    fn run_report_ticks(&self, stop: &AtomicBool) {
+        let mut interval = self.config.report_interval;
+        let mut last_report = Instant::now();
+        while !stop.load(Ordering::SeqCst) {
+            if last_report.elapsed() >= Duration::from_secs(interval) {
+                last_report = Instant::now();
+                interval = self.report_tick(interval);
+            }
+            thread::sleep(REPORT_POLL_INTERVAL);
+        }
+    }
+    
+    /// 一次報告周期的檢查，返回下一個周期的秒數
+    fn report_tick(&self, interval: u64) -> u64 {
+        self.check_link_utilization();
+        self.config.next_report_interval(interval, self.stats.byte_rate())
+    }
+    
+    /// 每天按 send_at 把最近 24 小時的摘要發到配置的郵箱，直到 `stop`
+    fn run_email_reports(&self, email: &EmailReportConfig, stop: &AtomicBool) {
+        let mut mailer = SummaryMailer::new(email, Box::new(SmtpTransport::new(email)), Local::now().fixed_offset());
+        while !stop.load(Ordering::SeqCst) {
+            let now = Local::now().fixed_offset();
+            if mailer.is_due(now) {
+                let summary = Summary::collect(&self.stats, &self.config, &self.traffic_matrix(), email.top_n, now);
+                mailer.send(&summary, now);
+            }
+            thread::sleep(REPORT_POLL_INTERVAL);
+        }
+    }
+    
+    /// 在 api_listen 上提供管理接口，直到 `running` 變為 false；
+    /// 更新的服務寫回 `Config::load` 讀取的配置文件
+    fn run_api(&self, addr: &str, running: &AtomicBool) {
+        let nft = match NftablesClassifier::from_config(&self.config) {
+            Ok(nft) => nft,
+            Err(e) => {
+                eprintln!("Failed to start management API: {}", e);
+                return;
+            }
+        };
+        let mut server = ApiServer::new(Arc::new(Mutex::new(self.config.clone())), nft)
+            .with_stats(Arc::clone(&self.stats))
+            .with_token(self.config.api_bearer_token());
+        if let Some(path) = Config::find_path() {
+            server = server.with_config_path(path.to_path_buf());
+        }
+        if let Err(e) = server.serve(addr, running) {
+            eprintln!("Management API on {} stopped: {}", addr, e);
+        }
+    }
+    
+    /// 每隔 compact_interval_secs 合併並保存學到的地址，直到 `stop`
+    fn run_learned_compaction(&self, learning: &SniLearningConfig, stop: &AtomicBool) {
+        let interval = Duration::from_secs(learning.compact_interval_secs);
+        let mut last_compact = Instant::now();
+        while !stop.load(Ordering::SeqCst) {
+            if last_compact.elapsed() >= interval {
+                last_compact = Instant::now();
+                self.compact_learned_addresses(SystemTime::now());
+            }
+            thread::sleep(REPORT_POLL_INTERVAL);
+        }
+    }
+    
+    /// 從任意抓包來源讀取並處理包，直到停止或來源耗盡
+    pub fn capture_from<S: CaptureSource>(&self, source: &mut S) {
+        self.read_packets(source, |packet| self.process_packet(packet));
+        self.emit_flow_records(self.drain_flows());
+        self.flush_events();
+        self.save_learned_addresses();
+    }
+    
+    /// 抓包和分類分屬不同線程，中間用有界隊列連接；
+    /// 分類跟不上時直接丟包並計數，而不是無限緩存。
+    /// 多個分類線程時按流分派，保證同一流的包按順序處理
+    pub fn capture_queued<S: CaptureSource + Send>(&self, source: &mut S) {
+        let workers = self.config.capture.workers.max(1);
+        let capacity = (self.config.capture.queue_capacity / workers).max(1);
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..workers)
+            .map(|_| mpsc::sync_channel::<(LinkType, u32, Option<SystemTime>, Vec<u8>)>(capacity))
+            .unzip();
+        
+        thread::scope(|scope| {
+            for rx in receivers {
+                scope.spawn(move || {
+                    for (link, wire_len, timestamp, data) in rx {
+                        self.process_packet(&RawPacket { data: &data, wire_len, link, timestamp });
+                    }
+                });
+            }
+            
+            self.read_packets(source, |packet| {
+                let worker = if workers > 1 { flow_shard(packet, workers) } else { 0 };
+                let queued = (packet.link, packet.wire_len, packet.timestamp, packet.data.to_vec());
+                if senders[worker].try_send(queued).is_err() {
+                    self.stats.add_queue_drops(1);
                 }
-                Err(pcap::Error::TimeoutExpired) => continue,
+            });
+            
+            // 關閉發送端，讓分類線程處理完剩餘的包後退出
+            drop(senders);
+        });
+        
+        // 分類線程都已退出，隊列末尾的包學到的地址也在內
+        self.emit_flow_records(self.drain_flows());
+        self.flush_events();
+        self.save_learned_addresses();
+    }
+    
+    fn read_packets<S: CaptureSource>(&self, source: &mut S, mut handle: impl FnMut(&RawPacket)) {
+        let stats_interval = Duration::from_secs(self.config.capture.stats_interval);
+        let mut last_stats = Instant::now();
+        let mut last_flow_check = Instant::now();
+        
+        while crate::RUNNING.load(std::sync::atomic::Ordering::SeqCst) {
+            if last_flow_check.elapsed() >= Duration::from_secs(1) {
+                last_flow_check = Instant::now();
+                self.emit_flow_records(self.expire_flows(SystemTime::now()));
+                self.flush_events();
+            }
+            
+            // 定期輸出丟包統計，便於調整 buffer_size
+            if last_stats.elapsed() >= stats_interval {
+                last_stats = Instant::now();
+                source.report_stats();
+                self.dns_cache.lock().unwrap().expire(last_stats);
+            }
+            
+            match source.next_packet() {
+                Ok(Some(packet)) => handle(&packet),
+                Ok(None) => continue,
+                Err(pcap::Error::NoMorePackets) => break,
                 Err(e) => eprintln!("Error reading packet: {}", e),
             }
         }
+    }
+    
+    /// 結束空閒超過 idle_timeout 的流和代理隧道，返回流記錄；未開啟流導出時為空。
+    /// 聚合統計不受影響，按統計自身的保留時間保留
+    pub fn expire_flows(&self, now: SystemTime) -> Vec<FlowRecord> {
+        let idle_timeout = Duration::from_secs(self.config.idle_timeout);
+        self.proxy_tunnels.lock().unwrap()
+            .retain(|_, (_, last)| now.duration_since(*last).unwrap_or_default() < idle_timeout);
         
-        Ok(())
+        self.flows.as_ref()
+            .map(|flows| flows.lock().unwrap().expire(now))
+            .unwrap_or_default()
+    }
+    
+    fn drain_flows(&self) -> Vec<FlowRecord> {
+        self.flows.as_ref()
+            .map(|flows| flows.lock().unwrap().drain())
+            .unwrap_or_default()
     }
     
-    fn process_packet(&self, packet: &pcap::Packet) {
+    /// 每條流記錄輸出為一行 JSON，並發布到事件流
+    fn emit_flow_records(&self, records: Vec<FlowRecord>) {
+        for record in records {
+            match serde_json::to_string(&record) {
+                Ok(line) => println!("{}", line),
+                Err(e) => eprintln!("Failed to serialize flow record: {}", e),
+            }
+            if let Some(events) = &self.events {
+                events.publish(&record);
+            }
+        }
+    }
+    
+    /// 發送未攢滿一批的事件，寫出緩衝的分類決定
+    fn flush_events(&self) {
+        if let Some(events) = &self.events {
+            events.flush();
+        }
+        if let Some(log) = &self.decision_log {
+            if let Err(e) = log.lock().unwrap().flush() {
+                eprintln!("Failed to write classification decisions: {}", e);
+            }
+        }
+    }
+    
+    /// 計算監控接口的鏈路使用率，超過設定閾值時輸出告警
+    pub fn check_link_utilization(&self) -> Option<f64> {
+        let link = self.config.link_capacity(&self.config.interface)?;
+        let utilization = link.utilization_percent(self.stats.byte_rate());
+        
+        if link.is_saturated(utilization) {
+            eprintln!(
+                "⚠️ {} utilization {:.1}% exceeds threshold {:.1}% of {} Mbps",
+                link.interface, utilization, link.alert_threshold, link.capacity_mbps
+            );
+        }
+        
+        Some(utilization)
+    }
+    
+    fn process_packet(&self, packet: &RawPacket) {
         if packet.data.len() < 34 { // 以太網頭 + IP 頭
             return;
         }
         
+        let info = parse_frame(packet.link, packet.data);
+        if info.as_ref().is_some_and(|i| self.is_ignored(i)) {
+            return;
+        }
+        if let (Some(dedup), Some(info)) = (&self.dedup, &info) {
+            if dedup.lock().unwrap().is_duplicate(info, packet.wire_len, Instant::now()) {
+                return;
+            }
+        }
+        
+        // ARP 不是 IP 包，單獨歸類
+        let arp = info.is_none().then(|| parse_arp(packet.link, packet.data)).flatten();
+        if let Some(arp) = &arp {
+            self.track_arp(arp);
+        }
+        
         // 簡單的流量分類和統計
-        let service = self.classify_packet(&packet.data);
-        let packet_size = packet.data.len() as u64;
+        let (service, method) = if arp.is_some() {
+            ("arp".to_string(), "arp")
+        } else if info.as_ref().is_some_and(|i| self.detect_scan(i)) {
+            ("portscan".to_string(), "scan")
+        } else {
+            self.classify_with_method(info.as_ref())
+        };
+        let packet_size = self.accounted_bytes(packet, info.as_ref());
+        let packet_count = self.estimate_packet_count(packet.wire_len as usize);
+        // 以抓包時間戳為準，不受排隊和分類延遲影響
+        let seen_at = packet.timestamp.unwrap_or_else(SystemTime::now);
+        
+        match &info {
+            Some(info) => self.stats.add_family_traffic_at(&service, info.dst_ip, packet_size, packet_count, seen_at),
+            None => self.stats.add_traffic_at(&service, packet_size, packet_count, seen_at),
+        }
+        self.track_time_bucket(&service, packet_size, seen_at);
+        let vlan = info.as_ref().and_then(|i| i.vlan).unwrap_or(0);
+        self.stats.add_vlan_traffic(vlan, &service, packet_size, packet_count);
+        self.stats.add_interface_traffic(&self.config.interface, &service, packet_size, packet_count);
+        if let Some(info) = &info {
+            self.log_decision(&Classification::new(service.clone(), info), method, seen_at);
+            if method == "sni" {
+                self.learn_address(&service, info, seen_at);
+            }
+            if info.tunnel.is_some() {
+                let mut tunneled = self.tunneled.lock().unwrap();
+                let entry = tunneled.entry(service.clone()).or_insert((0, 0));
+                entry.0 += packet_size;
+                entry.1 += packet_count;
+            }
+            self.track_flow(info, packet_size, packet_count);
+            self.track_tls(info);
+            self.sample_payload(info, &service);
+            if let Some(matrix) = &self.matrix {
+                matrix.lock().unwrap().observe(info.src_ip, info.dst_ip, packet_size);
+            }
+            self.track_latency(info, &service);
+            self.track_retransmits(info, &service);
+            self.track_tcp_flags(info, &service);
+            self.track_ttl(info);
+            self.track_snmp_version(info, &service);
+            self.track_domain(info, packet_size);
+            self.track_asn(info, packet_size);
+            self.track_process(info, &service, packet_size);
+        }
+    }
+    
+    fn is_ignored(&self, info: &PacketInfo) -> bool {
+        if self.config.exclude_multicast && group_destination(info).is_some() {
+            return true;
+        }
         
-        self.stats.add_traffic(&service, packet_size, 1);
+        [info.src_port, info.dst_port].iter()
+            .flatten()
+            .any(|port| self.config.ignore_ports.contains(port))
     }
     
-    fn classify_packet(&self, data: &[u8]) -> String {
-        // 簡單的基於目標端口的分類
-        if data.len() < 36 {
-            return "unknown".to_string();
-        }
-        
-        // 提取目標端口（TCP/UDP 頭中的第2-3字節）
-        let dport = u16::from_be_bytes([data[34], data[35]]);
-        
-        match dport {
-            80 | 8080 => "http".to_string(),
-            443 => "https".to_string(),
-            53 => "dns".to_string(),
-            1935 => "rtmp".to_string(),
-            3478 | 5349 => "webrtc".to_string(),
-            _ => {
-                if dport >= 8000 && dport <= 9000 {
-                    "streaming".to_string()
-                } else {
-                    "other".to_string()
+    /// 按配置順序列出所有服務，合併統計中的字節數和包數
+    pub fn list_services(&self) -> Vec<ServiceInfo> {
+        let stats = self.stats.get_stats();
+        self.config.services.iter()
+            .map(|service| {
+                let (bytes, packets) = stats.get(&service.name).copied().unwrap_or((0, 0));
+                ServiceInfo {
+                    name: service.name.clone(),
+                    ports: service.ports.clone(),
+                    ip_ranges: service.ip_ranges.clone(),
+                    blocked: service.blocked,
+                    bytes,
+                    packets,
                 }
+            })
+            .collect()
+    }
+    
+    /// 各 SNMP 版本的包數
+    pub fn snmp_versions(&self) -> HashMap<String, u64> {
+        self.snmp_versions.lock().unwrap().clone()
+    }
+    
+    fn track_snmp_version(&self, info: &PacketInfo, service: &str) {
+        if service != "snmp" {
+            return;
+        }
+        let version = match snmp_version(info.payload) {
+            Some(0) => "v1",
+            Some(1) => "v2c",
+            Some(3) => "v3",
+            _ => return,
+        };
+        *self.snmp_versions.lock().unwrap().entry(version.to_string()).or_insert(0) += 1;
+    }
+    
+    /// 經隧道傳輸的流量，按內層流分類的服務統計（字節數, 包數）
+    pub fn tunneled_traffic(&self) -> HashMap<String, (u64, u64)> {
+        self.tunneled.lock().unwrap().clone()
+    }
+    
+    /// 按 DNS 解析結果歸屬到各域名的字節數
+    pub fn domain_traffic(&self) -> HashMap<String, u64> {
+        self.domain_bytes.lock().unwrap().clone()
+    }
+    
+    /// 從 HTTP User-Agent 識別出的客戶端系統及其請求數
+    pub fn client_os_counts(&self) -> HashMap<String, u64> {
+        self.client_os.lock().unwrap().clone()
+    }
+    
+    /// 記錄 DNS 回應中的地址，並把流量歸屬到 HTTP Host 或目標 IP 對應的域名
+    fn track_domain(&self, info: &PacketInfo, bytes: u64) {
+        if let Some(request) = self.http_request(info, &|| false) {
+            if let Some(os) = request.client_os() {
+                *self.client_os.lock().unwrap().entry(os.to_string()).or_insert(0) += 1;
+            }
+            if let Some(host) = request.host {
+                *self.domain_bytes.lock().unwrap().entry(host).or_insert(0) += bytes;
+                return;
+            }
+        }
+        
+        let now = Instant::now();
+        let mut dns_cache = self.dns_cache.lock().unwrap();
+        
+        if info.protocol == IPPROTO_UDP && info.src_port == Some(53) {
+            for answer in parse_dns_answers(info.payload) {
+                dns_cache.record(&answer, now);
+            }
+            return;
+        }
+        
+        if let Some(domain) = dns_cache.lookup(&info.dst_ip, now) {
+            *self.domain_bytes.lock().unwrap().entry(domain.to_string()).or_insert(0) += bytes;
+        }
+    }
+    
+    /// 按目標 ASN 統計的字節數；未配置 ASN 表時為空
+    pub fn asn_traffic(&self) -> HashMap<u32, u64> {
+        self.asn_bytes.lock().unwrap().clone()
+    }
+    
+    /// 按（服務, 工作時間內/外）的字節數；未配置 business_hours 時為空
+    pub fn time_bucket_traffic(&self) -> HashMap<(String, TimeBucket), u64> {
+        self.time_bucket_bytes.lock().unwrap().clone()
+    }
+    
+    fn track_time_bucket(&self, service: &str, bytes: u64, at: SystemTime) {
+        if let Some(hours) = &self.business_hours {
+            let key = (service.to_string(), hours.bucket(at));
+            *self.time_bucket_bytes.lock().unwrap().entry(key).or_insert(0) += bytes;
+        }
+    }
+    
+    /// 按接口的 ARP 請求、回應和免費 ARP 數；未開啟 arp_stats 時為空
+    pub fn arp_counts(&self) -> HashMap<String, ArpCounts> {
+        self.arp_counts.lock().unwrap().clone()
+    }
+    
+    /// 只抓一個接口，按配置的接口名計數
+    fn track_arp(&self, arp: &ArpPacket) {
+        if !self.config.arp_stats {
+            return;
+        }
+        
+        let mut counts = self.arp_counts.lock().unwrap();
+        let counts = counts.entry(self.config.interface.clone()).or_default();
+        match arp.operation {
+            ARP_REQUEST => counts.requests += 1,
+            ARP_REPLY => counts.replies += 1,
+            _ => {}
+        }
+        if arp.is_gratuitous() {
+            counts.gratuitous += 1;
+        }
+    }
+    
+    /// 按（服務, 進程名）的字節數；未配置進程歸屬或找不到所屬進程的包不計入
+    pub fn process_traffic(&self) -> HashMap<(String, String), u64> {
+        self.process_bytes.lock().unwrap().clone()
+    }
+    
+    fn track_process(&self, info: &PacketInfo, service: &str, bytes: u64) {
+        let (Some(owners), Some(key)) = (&self.socket_owners, info.flow_key()) else {
+            return;
+        };
+        if let Some(process) = owners.lock().unwrap().resolve(&key) {
+            *self.process_bytes.lock().unwrap().entry((service.to_string(), process)).or_insert(0) += bytes;
+        }
+    }
+    
+    fn track_asn(&self, info: &PacketInfo, bytes: u64) {
+        if let Some(asn) = self.asn_table.as_ref().and_then(|table| table.lookup(&info.dst_ip)) {
+            *self.asn_bytes.lock().unwrap().entry(asn).or_insert(0) += bytes;
+        }
+    }
+    
+    fn track_flow(&self, info: &PacketInfo, bytes: u64, packets: u64) {
+        if let (Some(flows), Some(key)) = (&self.flows, info.flow_key()) {
+            flows.lock().unwrap().observe(key, bytes, packets, SystemTime::now());
+        }
+    }
+    
+    /// (源端點, 目標端點) → 字節數，未開啟 traffic_matrix 時為空
+    pub fn traffic_matrix(&self) -> HashMap<(String, String), u64> {
+        self.matrix.as_ref()
+            .map(|matrix| matrix.lock().unwrap().matrix())
+            .unwrap_or_default()
+    }
+    
+    /// 已採樣的負載，按服務分組
+    pub fn payload_samples(&self) -> HashMap<String, Vec<PayloadSample>> {
+        self.payload_sampler.as_ref()
+            .map(|sampler| sampler.lock().unwrap().samples().clone())
+            .unwrap_or_default()
+    }
+    
+    fn sample_payload(&self, info: &PacketInfo, service: &str) {
+        let Some(sampler) = &self.payload_sampler else {
+            return;
+        };
+        if let Some(sample) = sampler.lock().unwrap().observe(service, info.payload) {
+            eprintln!(
+                "負載樣本 {} {} -> {} ({} 字節): {}",
+                service,
+                self.redactor.endpoint(info.src_ip, info.src_port),
+                self.redactor.endpoint(info.dst_ip, info.dst_port),
+                sample.len, sample.to_hex()
+            );
+        }
+    }
+    
+    /// 把 ClientHello 中的 SNI 和 ALPN 記到流上
+    fn track_tls(&self, info: &PacketInfo) {
+        let (Some(flows), Some(key)) = (&self.flows, info.flow_key()) else {
+            return;
+        };
+        if let Some(hello) = parse_client_hello(info.payload) {
+            let protocol = hello.protocol_hint().map(str::to_string);
+            flows.lock().unwrap().annotate_tls(&key, hello.server_name, protocol);
+        }
+    }
+    
+    /// 各服務的平均首字節時間
+    pub fn average_ttfb(&self) -> HashMap<String, Duration> {
+        self.latency.lock().unwrap().average_ttfb()
+    }
+    
+    fn track_latency(&self, info: &PacketInfo, service: &str) {
+        let (Some(flags), Some(key)) = (info.tcp_flags, info.flow_key()) else {
+            return;
+        };
+        
+        let mut latency = self.latency.lock().unwrap();
+        let now = Instant::now();
+        
+        // 只有 SYN（不帶 ACK）才是新流的首包
+        if flags & TCP_SYN != 0 && flags & TCP_ACK == 0 {
+            latency.start_flow(key, service, now);
+        } else {
+            latency.observe_response(key, info.payload.len(), now);
+        }
+    }
+    
+    /// 各服務的 TCP 重傳率
+    pub fn retransmit_rates(&self) -> HashMap<String, f64> {
+        self.retransmits.lock().unwrap().retransmit_rates()
+    }
+    
+    fn track_retransmits(&self, info: &PacketInfo, service: &str) {
+        let (Some(flags), Some(seq), Some(key)) = (info.tcp_flags, info.tcp_seq, info.flow_key()) else {
+            return;
+        };
+        
+        let mut retransmits = self.retransmits.lock().unwrap();
+        
+        // 連接重新建立或關閉時丟棄舊序列號
+        if flags & (TCP_SYN | TCP_RST) != 0 {
+            retransmits.forget(&key);
+            return;
+        }
+        
+        retransmits.observe(key, service, seq, info.payload.len());
+    }
+    
+    /// 各服務的 SYN/FIN/RST 計數
+    pub fn tcp_flag_counts(&self) -> HashMap<String, TcpFlagCounts> {
+        self.tcp_flags.lock().unwrap().clone()
+    }
+    
+    fn track_tcp_flags(&self, info: &PacketInfo, service: &str) {
+        let Some(flags) = info.tcp_flags else {
+            return;
+        };
+        if flags & (TCP_SYN | TCP_FIN | TCP_RST) == 0 {
+            return;
+        }
+        
+        let mut counts = self.tcp_flags.lock().unwrap();
+        let entry = counts.entry(service.to_string()).or_default();
+        if flags & TCP_SYN != 0 && flags & TCP_ACK == 0 {
+            entry.syn += 1;
+        }
+        if flags & TCP_FIN != 0 {
+            entry.fin += 1;
+        }
+        if flags & TCP_RST != 0 {
+            entry.rst += 1;
+        }
+    }
+    
+    /// 各源地址最近的 TTL、推斷的系統類別和 TTL 大幅變化的次數；未啟用 ttl_tracking 時為空
+    pub fn ttl_sources(&self) -> HashMap<IpAddr, SourceTtl> {
+        self.ttl_tracker.as_ref()
+            .map(|tracker| tracker.lock().unwrap().sources().clone())
+            .unwrap_or_default()
+    }
+    
+    /// 由 SNI 學到的某個服務的網段；未啟用 sni_learning 時為空
+    pub fn learned_ranges(&self, service: &str) -> Vec<(IpAddr, u8)> {
+        self.learned.as_ref()
+            .map(|learned| learned.lock().unwrap().ranges(service))
+            .unwrap_or_default()
+    }
+    
+    /// 合併學到的網段、丟棄過期的，重建有網段被移除的服務地址集合並保存
+    pub fn compact_learned_addresses(&self, now: SystemTime) {
+        if let Some(learned) = &self.learned {
+            let stale = learned.lock().unwrap().compact(now);
+            self.rebuild_learned_sets(&stale);
+        }
+        self.save_learned_addresses();
+    }
+    
+    /// 配置了 add_to_nftables 時按學到的網段重建這些服務的地址集合，
+    /// 被淘汰或過期的地址從集合中移除
+    fn rebuild_learned_sets(&self, services: &[String]) {
+        let (Some(learned), Some(learning), Some(blocker)) = (&self.learned, &self.config.sni_learning, &self.blocker) else {
+            return;
+        };
+        if !(learning.add_to_nftables && learning.require_dns_answer) {
+            return;
+        }
+        
+        for name in services {
+            let Some(service) = self.config.services.iter().find(|service| &service.name == name) else {
+                continue;
+            };
+            let ranges = learned.lock().unwrap().ranges(name);
+            if let Err(e) = blocker.rebuild_service_set(service, &ranges) {
+                eprintln!("Failed to rebuild the {} address set: {}", name, e);
+            }
+        }
+    }
+    
+    /// 記下 SNI 歸類出的服務的目標地址；配置了 add_to_nftables 時同時加入服務的 nftables 集合。
+    /// 配置了 require_dns_answer 時目標地址須是 DNS 緩存中 SNI 域名的解析結果，
+    /// 沒有這一佐證的地址不會進入 nftables 集合
+    fn learn_address(&self, service: &str, info: &PacketInfo, seen_at: SystemTime) {
+        let (Some(learned), Some(learning)) = (&self.learned, &self.config.sni_learning) else {
+            return;
+        };
+        let addr = info.dst_ip;
+        if learning.require_dns_answer {
+            let resolved = parse_sni(info.payload).is_some_and(|sni| {
+                self.dns_cache.lock().unwrap()
+                    .lookup(&addr, Instant::now())
+                    .is_some_and(|name| name.eq_ignore_ascii_case(&sni))
+            });
+            if !resolved {
+                return;
+            }
+        }
+        if !learned.lock().unwrap().learn(service, addr, seen_at) {
+            return;
+        }
+        
+        if let (true, Some(blocker)) = (learning.add_to_nftables && learning.require_dns_answer, &self.blocker) {
+            if let Err(e) = blocker.add_service_address(service, addr) {
+                eprintln!(
+                    "Failed to add {} to the {} address set: {}",
+                    self.redactor.addr(addr), service, self.redactor.scrub(&e.to_string(), addr)
+                );
+            }
+        }
+    }
+    
+    fn save_learned_addresses(&self) {
+        if let Some(learned) = &self.learned {
+            if let Err(e) = learned.lock().unwrap().save() {
+                eprintln!("Failed to save learned service addresses: {}", e);
             }
         }
     }
-}
\ No newline at end of file
+    
+    fn track_ttl(&self, info: &PacketInfo) {
+        let (Some(tracker), Some(ttl)) = (&self.ttl_tracker, info.ttl) else {
+            return;
+        };
+        let mut tracker = tracker.lock().unwrap();
+        let anomaly = tracker.observe(info.src_ip, ttl);
+        self.stats.set_ttl_sources(tracker.sources().len() as u64);
+        let Some(anomaly) = anomaly else {
+            return;
+        };
+        self.stats.add_ttl_anomalies(1);
+        if tracker.should_warn(anomaly.source, Instant::now()) {
+            eprintln!(
+                "⚠️ TTL from {} changed from {} to {} (possible spoofing)",
+                self.redactor.addr(anomaly.source), anomaly.previous, anomaly.current
+            );
+        }
+    }
+    
+    /// 檢查來源是否在掃描端口/主機，首次發現時按配置臨時封鎖
+    fn detect_scan(&self, info: &PacketInfo) -> bool {
+        let (Some(detector), Some(dport)) = (&self.scan_detector, info.dst_port) else {
+            return false;
+        };
+        let source = info.src_ip;
+        
+        let state = detector.lock().unwrap()
+            .observe(source, info.dst_ip, dport, Instant::now());
+        
+        if state == ScanState::Detected {
+            eprintln!("⚠️ Port scan detected from {}", self.redactor.addr(source));
+            
+            let block_seconds = self.config.scan_detection.as_ref().and_then(|c| c.block_seconds);
+            if let (Some(blocker), Some(seconds)) = (&self.blocker, block_seconds) {
+                if let Err(e) = blocker.block_ip_temporarily(&source.to_string(), seconds) {
+                    eprintln!("Failed to block {}: {}", self.redactor.addr(source), self.redactor.scrub(&e.to_string(), source));
+                }
+            }
+        }
+        
+        state != ScanState::Normal
+    }
+    
+    /// 按 byte_accounting 計入的字節數；線路長度取 wire_len，截斷後的 data 只用於解析
+    fn accounted_bytes(&self, packet: &RawPacket, info: Option<&PacketInfo>) -> u64 {
+        match (self.config.byte_accounting, info.and_then(|info| info.ip_len)) {
+            (ByteAccounting::Inner, Some(ip_len)) => u64::from(ip_len),
+            _ => u64::from(packet.wire_len),
+        }
+    }
+    
+    /// GRO/LRO/TSO 會把多個線上包合併成一個超過 MTU 的段，
+    /// 啟用估算時按 MSS 拆分計算實際包數
+    fn estimate_packet_count(&self, frame_len: usize) -> u64 {
+        let ip_len = frame_len.saturating_sub(14) as u64;
+        
+        if !self.config.estimate_offload_segments || ip_len <= self.config.mtu as u64 {
+            return 1;
+        }
+        
+        let mss = self.config.mss() as u64;
+        ip_len.div_ceil(mss)
+    }
+    
+    /// 把按 `link` 封裝的 HTTP、HTTPS、DNS 合成包送入與抓包相同的解析和分類路徑，
+    /// 用於啟動前檢查配置和鏈路層偏移；不計入統計
+    pub fn self_test(&self, link: LinkType) -> SelfTestReport {
+        let cases = probe_frames(link).into_iter()
+            .map(|(name, expected, frame)| SelfTestCase { name, expected, actual: self.classify_frame(link, &frame) })
+            .collect();
+        
+        SelfTestReport { link, cases }
+    }
+    
+    /// 回放 pcap 文件，把每個包的分類結果與 `labels` 中逐包的標註比較；
+    /// 標註數必須與包數相同。不計入統計
+    pub fn evaluate(&self, pcap_path: &Path, labels: &[String]) -> Result<AccuracyReport, Box<dyn std::error::Error>> {
+        let mut source = PcapFileSource::open(pcap_path)?;
+        let mut report = AccuracyReport::default();
+        let mut labels = labels.iter();
+        
+        loop {
+            let packet = match source.next_packet() {
+                Ok(Some(packet)) => packet,
+                Ok(None) => continue,
+                Err(pcap::Error::NoMorePackets) => break,
+                Err(e) => return Err(e.into()),
+            };
+            let expected = labels.next()
+                .ok_or_else(|| format!("{} has more packets than labels", pcap_path.display()))?;
+            report.record(expected, &self.classify_frame(packet.link, packet.data));
+        }
+        
+        if labels.next().is_some() {
+            return Err(format!("{} has fewer packets than labels", pcap_path.display()).into());
+        }
+        Ok(report)
+    }
+    
+    /// 把當前的分類順序（各分類方法、地址範圍、端口表和服務）輸出為 Graphviz DOT，
+    /// 可用 `dot -Tsvg` 查看一個包是如何被歸類的
+    pub fn decision_tree_dot(&self) -> String {
+        let mut dot = String::from("digraph classification {\n    rankdir=LR;\n    node [shape=box];\n");
+        let mut line = |text: String| {
+            dot.push_str("    ");
+            dot.push_str(&text);
+            dot.push('\n');
+        };
+        let service = |name: &str| dot_id(&format!("service:{}", name));
+        
+        line(r#""packet" [shape=ellipse];"#.to_string());
+        let mut first = "group";
+        if self.config.name_service_stats {
+            first = "names";
+            line(r#""names" [label="LLMNR / NetBIOS-NS\nudp 5355, 137"];"#.to_string());
+            line(r#""names" -> "group" [style=dashed];"#.to_string());
+            for (_, name) in NAME_SERVICE_PORTS {
+                line(format!(r#""names" -> {};"#, service(name)));
+            }
+        }
+        line(r#""group" [label="multicast / broadcast destination"];"#.to_string());
+        let proxy_ports: Vec<String> = self.config.proxy_ports.iter().map(u16::to_string).collect();
+        line(format!(r#""proxy" [label={}];"#, dot_id(&format!("HTTP CONNECT proxy\nports {}", proxy_ports.join(", ")))));
+        line(format!(r#""packet" -> "{}";"#, first));
+        line(r#""group" -> "proxy";"#.to_string());
+        for name in ["multicast", "broadcast"] {
+            line(format!(r#""group" -> {};"#, service(name)));
+        }
+        line(format!(r#""proxy" -> {} [label="unknown target"];"#, service("http-proxy")));
+        
+        // 各方法按配置順序串聯，前一個沒有結果時進入下一個
+        let mut previous = "proxy".to_string();
+        for (index, method) in self.config.classification_methods.iter().enumerate() {
+            let node = format!("method:{:?}", method).to_lowercase();
+            let label = match method {
+                ClassificationMethod::Sni => "TLS SNI",
+                ClassificationMethod::Http => "HTTP Host",
+                ClassificationMethod::Dns => "DNS answer cache",
+                ClassificationMethod::Port => "address / port",
+            };
+            line(format!("{} [label={}];", dot_id(&node), dot_id(&format!("{}. {}", index + 1, label))));
+            line(format!("{} -> {} [style=dashed];", dot_id(&previous), dot_id(&node)));
+            
+            if *method != ClassificationMethod::Port {
+                for s in &self.config.services {
+                    line(format!(r#"{} -> {} [label="domain label"];"#, dot_id(&node), service(&s.name)));
+                }
+            }
+            previous = node;
+        }
+        line(format!(r#"{} -> {} [style=dashed];"#, dot_id(&previous), service("other")));
+        
+        if self.config.classification_methods.contains(&ClassificationMethod::Port) {
+            let first = match self.config.classification_priority {
+                ClassificationPriority::IpRange => "ip range before port",
+                ClassificationPriority::Port => "port before ip range",
+            };
+            line(r#""method:port" -> "payload" -> "priority";"#.to_string());
+            line(r#""payload" [label="payload signature"];"#.to_string());
+            for name in ["stun", "wireguard", "openvpn", "ntp", "snmp", "sctp", "dccp"] {
+                line(format!(r#""payload" -> {};"#, service(name)));
+            }
+            line(format!(r#""priority" [label={}, shape=diamond];"#, dot_id(first)));
+            line(r#""priority" -> "ranges";"#.to_string());
+            line(r#""priority" -> "ports";"#.to_string());
+            line(r#""ranges" [label="service ip ranges"];"#.to_string());
+            for s in self.config.services.iter().filter(|s| !s.ip_ranges.is_empty()) {
+                line(format!(r#""ranges" -> {} [label={}];"#, service(&s.name), dot_id(&s.ip_ranges.join("\n"))));
+            }
+            
+            line(r#""ports" [label="destination port"];"#.to_string());
+            let mut by_service: Vec<(&str, Vec<String>)> = Vec::new();
+            for (port, name) in PORT_SERVICES {
+                match by_service.iter_mut().find(|(s, _)| s == name) {
+                    Some((_, ports)) => ports.push(port.to_string()),
+                    None => by_service.push((name, vec![port.to_string()])),
+                }
+            }
+            for (ports, name) in PORT_RANGES {
+                by_service.push((name, vec![format!("{}-{}", ports.start(), ports.end())]));
+            }
+            for (name, ports) in by_service {
+                line(format!(r#""ports" -> {} [label={}];"#, service(name), dot_id(&ports.join(", "))));
+            }
+            line(format!(r#""ports" -> {} [style=dashed];"#, service("other")));
+        }
+        
+        for s in &self.config.services {
+            line(format!("{} [label={}, shape=ellipse, style=bold];", service(&s.name), dot_id(&s.name)));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+    
+    /// 與抓包相同的解析、忽略和分類路徑，無法解析的返回 "unparsed"，被忽略的返回 "ignored"
+    fn classify_frame(&self, link: LinkType, frame: &[u8]) -> String {
+        match parse_frame(link, frame) {
+            None if parse_arp(link, frame).is_some() => "arp".to_string(),
+            None => "unparsed".to_string(),
+            Some(info) if self.is_ignored(&info) => "ignored".to_string(),
+            Some(info) => self.classify_info(Some(&info)),
+        }
+    }
+    
+    /// 解析並分類一個以太網幀，無法解析時返回 None
+    fn classify_packet(&self, data: &[u8]) -> Option<Classification> {
+        let info = parse_ethernet(data)?;
+        Some(Classification::new(self.classify_info(Some(&info)), &info))
+    }
+    
+    fn classify_info(&self, info: Option<&PacketInfo>) -> String {
+        self.classify_with_method(info).0
+    }
+    
+    /// 按配置的順序嘗試各分類方法，先得到結果的為準；同時返回得出結果的方法
+    fn classify_with_method(&self, info: Option<&PacketInfo>) -> (String, &'static str) {
+        self.classify_within(info, &Instant::now)
+    }
+    
+    /// 同 `classify_with_method`，用 `clock` 計時：每個負載解析方法開始前和 SNI/HTTP
+    /// 解析過程中檢查 classification_budget_us，已經超時則不再解析，改按地址和端口分類
+    fn classify_within(&self, info: Option<&PacketInfo>, clock: &dyn Fn() -> Instant) -> (String, &'static str) {
+        let Some(info) = info else {
+            return ("unknown".to_string(), "unparsed");
+        };
+        
+        // Windows 名稱解析多為多播或廣播，先於 multicast/broadcast 識別
+        if self.config.name_service_stats {
+            if let Some(service) = name_service(info) {
+                return (service.to_string(), "name-service");
+            }
+        }
+        
+        // 多播/廣播不屬於某台主機的流量，單獨歸類
+        if let Some(service) = group_destination(info) {
+            return (service.to_string(), "group");
+        }
+        
+        if let Some(service) = self.proxy_service(info) {
+            return (service, "proxy");
+        }
+        
+        // 不限時間時不讀時鐘
+        let budget = self.config.classification_budget_us.map(|us| (clock(), Duration::from_micros(us)));
+        let over_budget = || budget.is_some_and(|(started, budget)| clock().saturating_duration_since(started) > budget);
+        
+        if let Some(weights) = &self.config.classification_weights {
+            let Some(decision) = self.vote(info, weights, &over_budget) else {
+                return self.classification_timeout(info);
+            };
+            if let Some((runner_up, _)) = decision.runners_up.first() {
+                *self.vote_conflicts.lock().unwrap()
+                    .entry((decision.service.clone(), runner_up.clone()))
+                    .or_insert(0) += 1;
+            }
+            return (decision.service, "vote");
+        }
+        
+        for method in &self.config.classification_methods {
+            if *method != ClassificationMethod::Port && over_budget() {
+                return self.classification_timeout(info);
+            }
+            if let Some(service) = self.method_service(*method, info, &over_budget) {
+                return (service, method_name(*method));
+            }
+            // 端口方法總有結果；負載解析途中超時放棄時也返回 None
+            if over_budget() {
+                return self.classification_timeout(info);
+            }
+        }
+        ("other".to_string(), "none")
+    }
+    
+    fn classification_timeout(&self, info: &PacketInfo) -> (String, &'static str) {
+        self.stats.add_classification_timeouts(1);
+        (self.classify_address_or_port(info), "timeout")
+    }
+    
+    /// 配置了 decision_log 時追加一條分類決定；決定先緩衝在內存中，
+    /// 抓包循環每秒寫出一次，不在每個包上做文件 I/O
+    fn log_decision(&self, classification: &Classification, method: &str, at: SystemTime) {
+        let Some(log) = &self.decision_log else {
+            return;
+        };
+        let decision = Decision {
+            timestamp: at.into(),
+            src_ip: classification.src_ip,
+            dst_ip: classification.dst_ip,
+            protocol: classification.protocol,
+            src_port: classification.src_port,
+            dst_port: classification.dst_port,
+            service: classification.service.clone(),
+            method: method.to_string(),
+        };
+        if let Err(e) = log.lock().unwrap().record(&decision) {
+            eprintln!("Failed to write classification decision: {}", e);
+        }
+    }
+    
+    /// 按單個方法分類；`stop` 返回 true 時 SNI/HTTP 解析中途放棄並返回 None
+    fn method_service(&self, method: ClassificationMethod, info: &PacketInfo, stop: &dyn Fn() -> bool) -> Option<String> {
+        match method {
+            ClassificationMethod::Sni => parse_sni_until(info.payload, stop)
+                .and_then(|host| self.service_for_domain(&host)),
+            ClassificationMethod::Http => self.http_request(info, stop)
+                .and_then(|request| request.host)
+                .and_then(|host| self.service_for_domain(&host)),
+            ClassificationMethod::Dns => self.dns_cache.lock().unwrap()
+                .lookup(&info.dst_ip, Instant::now())
+                .and_then(|domain| self.service_for_domain(domain)),
+            ClassificationMethod::Port => Some(self.classify_address_or_port(info)),
+        }
+    }
+    
+    /// 所有配置的方法都參與，同一服務的權重相加；同分時先配置的方法的結果優先。
+    /// 端口方法只得到 "other" 時不算投票；負載解析方法開始前或解析途中 `over_budget` 為 true 時返回 None
+    fn vote(&self, info: &PacketInfo, weights: &ClassificationWeights, over_budget: &dyn Fn() -> bool) -> Option<ClassificationDecision> {
+        let mut scores: Vec<(String, f64)> = Vec::new();
+        for method in &self.config.classification_methods {
+            let parsed = *method != ClassificationMethod::Port;
+            if parsed && over_budget() {
+                return None;
+            }
+            let service = self.method_service(*method, info, over_budget);
+            if parsed && service.is_none() && over_budget() {
+                return None;
+            }
+            let Some(service) = service.filter(|s| s != "other") else {
+                continue;
+            };
+            let weight = weights.weight(*method);
+            match scores.iter_mut().find(|(name, _)| *name == service) {
+                Some((_, score)) => *score += weight,
+                None => scores.push((service, weight)),
+            }
+        }
+        
+        scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+        let mut scores = scores.into_iter();
+        let (service, score) = scores.next().unwrap_or_else(|| ("other".to_string(), 0.0));
+        Some(ClassificationDecision { service, score, runners_up: scores.collect() })
+    }
+    
+    /// 權重投票中各方法結果不一致的包數，按（勝者, 得分最高的落選者）；未配置權重時為空
+    pub fn vote_conflicts(&self) -> HashMap<(String, String), u64> {
+        self.vote_conflicts.lock().unwrap().clone()
+    }
+    
+    /// 經 HTTP CONNECT 代理的流按請求中的目標歸屬，之後兩個方向的包都沿用；
+    /// 目標無法解析或不屬於任何服務時歸入 http-proxy。連接關閉或空閒超時後忘記該流
+    fn proxy_service(&self, info: &PacketInfo) -> Option<String> {
+        let key = info.flow_key()?;
+        let (src_port, dst_port) = (info.src_port?, info.dst_port?);
+        let key = if self.config.proxy_ports.contains(&dst_port) {
+            key
+        } else if self.config.proxy_ports.contains(&src_port) {
+            key.reversed()
+        } else {
+            return None;
+        };
+        
+        let mut tunnels = self.proxy_tunnels.lock().unwrap();
+        if info.payload.starts_with(b"CONNECT ") && key.dst_port == dst_port {
+            let service = parse_connect_target(info.payload)
+                .and_then(|(host, _)| self.service_for_domain(&host))
+                .unwrap_or_else(|| "http-proxy".to_string());
+            tunnels.insert(key, (service.clone(), SystemTime::now()));
+            return Some(service);
+        }
+        
+        let service = tunnels.get_mut(&key).map(|(service, last)| {
+            *last = SystemTime::now();
+            service.clone()
+        });
+        if info.tcp_flags.is_some_and(|flags| flags & (TCP_FIN | TCP_RST) != 0) {
+            tunnels.remove(&key);
+        }
+        service
+    }
+    
+    /// 明文 HTTP 端口上的請求頭，只解析前 http_parse_bytes 字節
+    fn http_request(&self, info: &PacketInfo, stop: &dyn Fn() -> bool) -> Option<HttpRequestInfo> {
+        if !matches!(info.dst_port, Some(80 | 8080)) {
+            return None;
+        }
+        
+        parse_http_request_until(info.payload, self.config.http_parse_bytes, stop)
+    }
+    
+    /// 域名中任一標籤與服務名相同時歸屬該服務，如 www.netflix.com → netflix
+    fn service_for_domain(&self, domain: &str) -> Option<String> {
+        self.config.services.iter()
+            .find(|s| domain.split('.').any(|label| label.eq_ignore_ascii_case(&s.name)))
+            .map(|s| s.name.clone())
+    }
+    
+    /// 按負載格式識別的協議優先；否則目標地址落在某服務的地址範圍內且端口也有映射時，
+    /// 按 classification_priority 取捨。端口只落到 "other" 時不算映射
+    fn classify_address_or_port(&self, info: &PacketInfo) -> String {
+        if let Some(protocol) = self.classify_payload(info) {
+            return protocol;
+        }
+        let Some(by_range) = self.service_for_address(info.dst_ip) else {
+            return self.classify_port(info);
+        };
+        
+        let by_port = info.dst_port.map_or_else(|| "other".to_string(), port_service);
+        
+        match self.config.classification_priority {
+            ClassificationPriority::IpRange => by_range,
+            ClassificationPriority::Port if by_port == "other" => by_range,
+            ClassificationPriority::Port => by_port,
+        }
+    }
+    
+    /// 配置的地址範圍優先，其次是由 SNI 學到的地址
+    fn service_for_address(&self, addr: IpAddr) -> Option<String> {
+        self.service_ranges.iter()
+            .find(|(network, _)| cidr_contains(*network, addr))
+            .map(|(_, service)| service.clone())
+            .or_else(|| self.learned.as_ref()?.lock().unwrap().lookup(&addr).map(str::to_string))
+    }
+    
+    fn classify_port(&self, info: &PacketInfo) -> String {
+        // 簡單的基於目標端口的分類
+        let Some(dport) = info.dst_port else {
+            return "other".to_string();
+        };
+        
+        self.classify_payload(info).unwrap_or_else(|| port_service(dport))
+    }
+    
+    /// 按負載格式識別協議，與端口映射無關
+    fn classify_payload(&self, info: &PacketInfo) -> Option<String> {
+        let dport = info.dst_port?;
+        
+        // 端口表只適用於 TCP/UDP，SCTP 和 DCCP 按協議歸類
+        match info.protocol {
+            IPPROTO_SCTP => return Some("sctp".to_string()),
+            IPPROTO_DCCP => return Some("dccp".to_string()),
+            _ => {}
+        }
+        
+        // STUN/TURN：檢查 UDP 負載第 4 字節起的 magic cookie
+        if matches!(dport, 3478 | 5349) && is_stun_message(info.payload) {
+            return Some("stun".to_string());
+        }
+        
+        // VPN 握手包格式固定，不依賴端口識別
+        if info.protocol == IPPROTO_UDP {
+            if is_wireguard_handshake(info.payload) {
+                return Some("wireguard".to_string());
+            }
+            // 固定端口的協議（DNS、QUIC 等）偶爾會有形似重置包的負載，只在 1194 和非知名端口上識別
+            if (dport == 1194 || !is_well_known_port(dport)) && is_openvpn_reset(info.payload) {
+                return Some("openvpn".to_string());
+            }
+            
+            // 回應發往客戶端的臨時端口，按源端口和負載格式識別
+            let ports = [Some(dport), info.src_port];
+            if ports.contains(&Some(123)) && is_ntp_packet(info.payload) {
+                return Some("ntp".to_string());
+            }
+            if ports.iter().flatten().any(|port| matches!(port, 161 | 162)) && snmp_version(info.payload).is_some() {
+                return Some("snmp".to_string());
+            }
+        }
+        
+        None
+    }
+}
+
+/// DOT 中加引號的標識符或標籤
+fn dot_id(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+}
+
+/// 按流選擇分類線程，兩個方向落在同一線程；無法解析的包交給第一個線程
+fn flow_shard(packet: &RawPacket, workers: usize) -> usize {
+    let Some(info) = parse_frame(packet.link, packet.data) else {
+        return 0;
+    };
+    
+    let a = (info.src_ip, info.src_port);
+    let b = (info.dst_ip, info.dst_port);
+    let mut hasher = DefaultHasher::new();
+    (a.min(b), a.max(b), info.protocol).hash(&mut hasher);
+    hasher.finish() as usize % workers
+}
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_ACK: u8 = 0x10;
+
+/// 分類方法在決定日誌中的名稱，與配置中的寫法相同
+fn method_name(method: ClassificationMethod) -> &'static str {
+    match method {
+        ClassificationMethod::Sni => "sni",
+        ClassificationMethod::Http => "http",
+        ClassificationMethod::Dns => "dns",
+        ClassificationMethod::Port => "port",
+    }
+}
+
+/// 目標為多播（224.0.0.0/4、ff00::/8）或受限廣播地址時返回對應的服務名
+fn group_destination(info: &PacketInfo) -> Option<&'static str> {
+    match info.dst_ip {
+        IpAddr::V4(addr) if addr.is_broadcast() => Some("broadcast"),
+        addr if addr.is_multicast() => Some("multicast"),
+        _ => None,
+    }
+}
+
+/// LLMNR 和 NetBIOS 名稱服務的 UDP 端口，請求和回應兩個方向都識別
+const NAME_SERVICE_PORTS: &[(u16, &str)] = &[
+    (5355, "llmnr"),
+    (137, "netbios-ns"),
+];
+
+fn name_service(info: &PacketInfo) -> Option<&'static str> {
+    if info.protocol != IPPROTO_UDP {
+        return None;
+    }
+    
+    NAME_SERVICE_PORTS.iter()
+        .find(|(port, _)| info.dst_port == Some(*port) || info.src_port == Some(*port))
+        .map(|(_, service)| *service)
+}
+
+/// 目標端口到服務的映射，不在表中的端口見 `port_service`
+const PORT_SERVICES: &[(u16, &str)] = &[
+    (80, "http"),
+    (8080, "http"),
+    (443, "https"),
+    (22, "ssh"),
+    (53, "dns"),
+    (1935, "rtmp"),
+    (3478, "webrtc"),
+    (5349, "webrtc"),
+    // 局域網服務發現（多播）
+    (5353, "mdns"),
+    (1900, "ssdp"),
+    (51820, "wireguard"),
+    (1194, "openvpn"),
+    (123, "ntp"),
+    (161, "snmp"),
+    (162, "snmp"),
+    // 郵件：隱式 TLS 的端口單獨歸類
+    (25, "smtp"),
+    (587, "smtp-submission"),
+    (465, "smtps"),
+    (143, "imap"),
+    (993, "imaps"),
+    (110, "pop3"),
+    (995, "pop3s"),
+    // 遠程桌面
+    (3389, "rdp"),
+];
+
+/// 表外落在這些範圍內的端口按範圍歸類（VNC 顯示 :0 到 :6 對應 5900-5906）
+const PORT_RANGES: &[(std::ops::RangeInclusive<u16>, &str)] = &[
+    (5900..=5906, "vnc"),
+    (8000..=9000, "streaming"),
+];
+
+/// 系統端口（< 1024）或端口表中的端口
+fn is_well_known_port(port: u16) -> bool {
+    port < 1024 || PORT_SERVICES.iter().any(|(known, _)| *known == port)
+}
+
+/// 按目標端口映射服務
+fn port_service(dport: u16) -> String {
+    PORT_SERVICES.iter()
+        .find(|(port, _)| *port == dport)
+        .map(|(_, service)| *service)
+        .or_else(|| PORT_RANGES.iter().find(|(ports, _)| ports.contains(&dport)).map(|(_, service)| *service))
+        .unwrap_or("other")
+        .to_string()
+}
+
+fn is_stun_message(payload: &[u8]) -> bool {
+    // STUN 訊息頭固定 20 字節，且類型欄位最高兩位必須為 0
+    if payload.len() < 20 || payload[0] & 0xC0 != 0 {
+        return false;
+    }
+    
+    u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]) == STUN_MAGIC_COOKIE
+}
+
+/// WireGuard 握手訊息：類型字節 1–3 後跟 3 個保留的 0 字節，長度固定
+fn is_wireguard_handshake(payload: &[u8]) -> bool {
+    let expected_len = match payload.first() {
+        Some(1) => 148, // Handshake Initiation
+        Some(2) => 92,  // Handshake Response
+        Some(3) => 64,  // Cookie Reply
+        _ => return false,
+    };
+    
+    payload.len() == expected_len && payload[1..4] == [0, 0, 0]
+}
+
+/// OpenVPN（UDP，未啟用 tls-auth）客戶端的 P_CONTROL_HARD_RESET_CLIENT_V2：首字節高 5 位為操作碼 7，
+/// 低 3 位為 key id 0，其後是 8 字節會話 ID、空的 ACK 數組和值為 0 的 4 字節包 ID，共 14 字節
+fn is_openvpn_reset(payload: &[u8]) -> bool {
+    const P_CONTROL_HARD_RESET_CLIENT_V2: u8 = 7;
+    
+    payload.len() == 14
+        && payload[0] == P_CONTROL_HARD_RESET_CLIENT_V2 << 3
+        && payload[9] == 0
+        && payload[10..14] == [0, 0, 0, 0]
+}
+
+/// NTP 包頭至少 48 字節；首字節低 3 位為模式（1–5 為對稱/客戶端/服務器/廣播），
+/// 中間 3 位為版本號
+fn is_ntp_packet(payload: &[u8]) -> bool {
+    let Some(&first) = payload.first() else {
+        return false;
+    };
+    let (version, mode) = ((first >> 3) & 0x07, first & 0x07);
+    payload.len() >= 48 && (1..=4).contains(&version) && (1..=5).contains(&mode)
+}
+
+/// SNMP 訊息是 BER 編碼的 SEQUENCE，第一個元素為 INTEGER 版本號（0 = v1，1 = v2c，3 = v3）
+fn snmp_version(payload: &[u8]) -> Option<u8> {
+    if *payload.first()? != 0x30 {
+        return None;
+    }
+    // 長度為短格式（< 0x80）或長格式（0x81/0x82 後跟 1–2 字節長度）
+    let length_octets = match *payload.get(1)? {
+        len if len < 0x80 => 0,
+        0x81 => 1,
+        0x82 => 2,
+        _ => return None,
+    };
+    let version = payload.get(2 + length_octets..5 + length_octets)?;
+    match version {
+        [0x02, 0x01, version @ (0 | 1 | 3)] => Some(*version),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::MemorySource;
+    use crate::config::{AdaptiveReportConfig, DedupConfig, TtlTrackingConfig};
+    use crate::packet::{walk_ipv6_extensions, Tunnel, IPV6_FRAGMENT};
+    use crate::ttl::OsClass;
+    use std::net::Ipv6Addr;
+    use std::sync::atomic::AtomicUsize;
+    
+    fn test_classifier() -> TrafficClassifier {
+        TrafficClassifier::new(Config::default(), Arc::new(TrafficStats::new()))
+    }
+    
+    // 以太網 + IPv4 + UDP 頭，後接指定負載
+    fn udp_frame(sport: u16, dport: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; 12];
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(&[0x45, 0x00]);
+        frame.extend_from_slice(&((28 + payload.len()) as u16).to_be_bytes());
+        frame.extend_from_slice(&[0x00, 0x00, 0x40, 0x00, 0x40, 17, 0x00, 0x00]);
+        frame.extend_from_slice(&[192, 168, 1, 100, 203, 0, 113, 1]);
+        frame.extend_from_slice(&sport.to_be_bytes());
+        frame.extend_from_slice(&dport.to_be_bytes());
+        frame.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        frame.extend_from_slice(&[0x00, 0x00]);
+        frame.extend_from_slice(payload);
+        frame
+    }
+    
+    #[test]
+    fn test_offload_segment_estimation() {
+        let config = Config { estimate_offload_segments: true, ..Config::default() };
+        let classifier = TrafficClassifier::new(config, Arc::new(TrafficStats::new()));
+        
+        // 60KB 的 TSO 段，MSS 1460
+        assert_eq!(classifier.estimate_packet_count(14 + 60_000), 42);
+        assert_eq!(classifier.estimate_packet_count(14 + 1500), 1);
+        
+        // 關閉估算時仍計為單個包
+        assert_eq!(test_classifier().estimate_packet_count(14 + 60_000), 1);
+    }
+    
+    #[test]
+    fn test_stun_binding_request() {
+        let classifier = test_classifier();
+        
+        // Binding Request，長度 0，magic cookie + 12 字節 transaction ID
+        let mut stun = vec![0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xA4, 0x42];
+        stun.extend_from_slice(&[0x5a; 12]);
+        
+        assert_eq!(classifier.classify_packet(&udp_frame(54321, 3478, &stun)).unwrap().service, "stun");
+        
+        // 同端口但不是 STUN 的負載仍歸為 webrtc
+        assert_eq!(classifier.classify_packet(&udp_frame(54321, 3478, &[0xff; 20])).unwrap().service, "webrtc");
+    }
+    
+    #[test]
+    fn test_vpn_handshakes() {
+        let classifier = test_classifier();
+        
+        // WireGuard Handshake Initiation：類型 1 + 3 字節保留 + 144 字節
+        let mut initiation = vec![0x01, 0x00, 0x00, 0x00];
+        initiation.extend_from_slice(&[0x42; 144]);
+        assert_eq!(classifier.classify_packet(&udp_frame(40000, 51820, &initiation)).unwrap().service, "wireguard");
+        // 非標準端口上的握手也能識別
+        assert_eq!(classifier.classify_packet(&udp_frame(40000, 4500, &initiation)).unwrap().service, "wireguard");
+        
+        let mut response = vec![0x02, 0x00, 0x00, 0x00];
+        response.extend_from_slice(&[0x42; 88]);
+        assert_eq!(classifier.classify_packet(&udp_frame(51820, 40000, &response)).unwrap().service, "wireguard");
+        
+        // 長度不符的不是握手
+        assert_eq!(classifier.classify_packet(&udp_frame(40000, 4500, &initiation[..100])).unwrap().service, "other");
+        
+        // OpenVPN P_CONTROL_HARD_RESET_CLIENT_V2：0x38 + 會話 ID + 空 ACK 數組 + 包 ID
+        let mut reset = vec![0x38];
+        reset.extend_from_slice(&[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88]);
+        reset.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(classifier.classify_packet(&udp_frame(40000, 1194, &reset)).unwrap().service, "openvpn");
+        assert_eq!(classifier.classify_packet(&udp_frame(40000, 4500, &reset)).unwrap().service, "openvpn");
+        // 知名端口上按端口歸類，不會把形似重置包的 DNS 或 QUIC 誤判
+        assert_eq!(classifier.classify_packet(&udp_frame(40000, 53, &reset)).unwrap().service, "dns");
+        assert_eq!(classifier.classify_packet(&udp_frame(40000, 443, &reset)).unwrap().service, "https");
+        
+        // 長度、非空 ACK 數組、key id 或其他操作碼不匹配
+        let mut longer = reset.clone();
+        longer.push(0x00);
+        assert_eq!(classifier.classify_packet(&udp_frame(40000, 1194, &longer)).unwrap().service, "openvpn");
+        assert_eq!(classifier.classify_packet(&udp_frame(40000, 4500, &longer)).unwrap().service, "other");
+        let mut acked = reset.clone();
+        acked[9] = 0x01;
+        assert_eq!(classifier.classify_packet(&udp_frame(40000, 4500, &acked)).unwrap().service, "other");
+        reset[0] = 0x38 | 0x01;
+        assert_eq!(classifier.classify_packet(&udp_frame(40000, 4500, &reset)).unwrap().service, "other");
+        reset[0] = 0x40;
+        assert_eq!(classifier.classify_packet(&udp_frame(40000, 4500, &reset)).unwrap().service, "other");
+    }
+    
+    #[test]
+    fn test_local_discovery_traffic() {
+        let classifier = test_classifier();
+        
+        // mDNS 查詢 _googlecast._tcp.local PTR
+        let mut mdns = vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        mdns.extend_from_slice(b"\x0b_googlecast\x04_tcp\x05local\x00\x00\x0c\x00\x01");
+        assert_eq!(classifier.classify_packet(&udp_frame(5353, 5353, &mdns)).unwrap().service, "mdns");
+        
+        let ssdp = b"M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nST: ssdp:all\r\n\r\n";
+        assert_eq!(classifier.classify_packet(&udp_frame(50000, 1900, ssdp)).unwrap().service, "ssdp");
+    }
+    
+    #[test]
+    fn test_name_service_traffic() {
+        let frame_to = |dst: [u8; 4], sport: u16, dport: u16, payload: &[u8]| {
+            let mut frame = udp_frame(sport, dport, payload);
+            frame[30..34].copy_from_slice(&dst);
+            frame
+        };
+        
+        // LLMNR 查詢 "printer" A 記錄，發往 224.0.0.252；回應從 5355 單播返回
+        let mut llmnr = vec![0x12, 0x34, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        llmnr.extend_from_slice(b"\x07printer\x00\x00\x01\x00\x01");
+        let query = frame_to([224, 0, 0, 252], 50000, 5355, &llmnr);
+        let reply = udp_frame(5355, 50000, &llmnr);
+        
+        // NetBIOS 名稱查詢，發往子網廣播
+        let mut nbns = vec![0x56, 0x78, 0x01, 0x10, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20];
+        nbns.extend_from_slice(&[b'A'; 32]);
+        nbns.extend_from_slice(&[0x00, 0x00, 0x20, 0x00, 0x01]);
+        let netbios = frame_to([192, 168, 1, 255], 137, 137, &nbns);
+        
+        let classifier = test_classifier();
+        assert_eq!(classifier.classify_packet(&query).unwrap().service, "llmnr");
+        assert_eq!(classifier.classify_packet(&reply).unwrap().service, "llmnr");
+        assert_eq!(classifier.classify_packet(&netbios).unwrap().service, "netbios-ns");
+        
+        // 關閉後照常按目標地址和端口分類
+        let config = Config { name_service_stats: false, ..Config::default() };
+        let classifier = TrafficClassifier::new(config, Arc::new(TrafficStats::new()));
+        assert_eq!(classifier.classify_packet(&query).unwrap().service, "multicast");
+        assert_eq!(classifier.classify_packet(&netbios).unwrap().service, "other");
+    }
+    
+    #[test]
+    fn test_pipeline_with_memory_source() {
+        let stats = Arc::new(TrafficStats::new());
+        let classifier = TrafficClassifier::new(Config::default(), Arc::clone(&stats));
+        
+        let dns = udp_frame(53000, 53, &[0u8; 30]);
+        let mdns = udp_frame(53001, 5353, &[0u8; 12]);
+        let mut source = MemorySource::new(vec![dns.clone(), dns.clone(), mdns.clone()]);
+        
+        classifier.capture_from(&mut source);
+        
+        let result = stats.get_stats();
+        assert_eq!(result["dns"], (2 * dns.len() as u64, 2));
+        assert_eq!(result["mdns"], (mdns.len() as u64, 1));
+        assert_eq!(result.len(), 2);
+    }
+    
+    #[test]
+    fn test_time_bucket_traffic() {
+        let config = Config {
+            business_hours: Some(crate::config::BusinessHoursConfig {
+                start_time: "09:00".to_string(),
+                end_time: "18:00".to_string(),
+                weekdays: vec!["mon".to_string(), "tue".to_string(), "wed".to_string(), "thu".to_string(), "fri".to_string()],
+                utc_offset: Some("+00:00".to_string()),
+            }),
+            ..Config::default()
+        };
+        let classifier = TrafficClassifier::new(config, Arc::new(TrafficStats::new()));
+        // 2024-01-01（星期一）的 UTC 時間
+        let at = |hour: u64| SystemTime::UNIX_EPOCH + Duration::from_secs(1_704_067_200 + hour * 3600);
+        
+        classifier.track_time_bucket("netflix", 1000, at(10));
+        classifier.track_time_bucket("netflix", 500, at(21));
+        classifier.track_time_bucket("netflix", 200, at(3));
+        classifier.track_time_bucket("dns", 50, at(12));
+        
+        assert_eq!(classifier.time_bucket_traffic(), HashMap::from([
+            (("netflix".to_string(), TimeBucket::BusinessHours), 1000),
+            (("netflix".to_string(), TimeBucket::OffHours), 700),
+            (("dns".to_string(), TimeBucket::BusinessHours), 50),
+        ]));
+        
+        // 未配置工作時間時不分桶
+        let classifier = test_classifier();
+        classifier.track_time_bucket("netflix", 1000, at(10));
+        assert!(classifier.time_bucket_traffic().is_empty());
+    }
+    
+    // 以太網上的 ARP 包，補齊到最小幀長 60 字節
+    fn arp_frame(operation: u16, sender_ip: [u8; 4], target_ip: [u8; 4]) -> Vec<u8> {
+        let mut frame = vec![0xff; 6];
+        frame.extend_from_slice(&[0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06]);
+        frame.extend_from_slice(&[0x00, 0x01, 0x08, 0x00, 6, 4]);
+        frame.extend_from_slice(&operation.to_be_bytes());
+        frame.extend_from_slice(&[0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
+        frame.extend_from_slice(&sender_ip);
+        frame.extend_from_slice(&[0u8; 6]);
+        frame.extend_from_slice(&target_ip);
+        frame.resize(60, 0);
+        frame
+    }
+    
+    #[test]
+    fn test_ttl_os_classes() {
+        let config = Config {
+            ttl_tracking: Some(TtlTrackingConfig { change_threshold: 16, max_sources: 1024 }),
+            ..Config::default()
+        };
+        let classifier = TrafficClassifier::new(config, Arc::new(TrafficStats::new()));
+        let frame = |host: u8, ttl: u8| {
+            let mut frame = udp_frame(53000, 53, &[0u8; 30]);
+            frame[22] = ttl;
+            frame[29] = host;
+            frame
+        };
+        
+        for (host, ttl) in [(10, 64), (11, 52), (12, 128), (13, 117), (14, 255)] {
+            let frame = frame(host, ttl);
+            classifier.process_packet(&RawPacket { data: &frame, wire_len: frame.len() as u32, link: LinkType::Ethernet, timestamp: None });
+        }
+        
+        let sources = classifier.ttl_sources();
+        let os = |host: u8| sources[&IpAddr::from([192, 168, 1, host])].os;
+        assert_eq!([os(10), os(11), os(12), os(13), os(14)], [
+            OsClass::Unix, OsClass::Unix, OsClass::Windows, OsClass::Windows, OsClass::Network,
+        ]);
+        assert_eq!(os(12).to_string(), "windows");
+        
+        // 同一地址 TTL 突變記為異常
+        let spoofed = frame(10, 128);
+        classifier.process_packet(&RawPacket { data: &spoofed, wire_len: spoofed.len() as u32, link: LinkType::Ethernet, timestamp: None });
+        assert_eq!(classifier.ttl_sources()[&IpAddr::from([192, 168, 1, 10])].anomalies, 1);
+        
+        // 異常和跟蹤的源地址數也出現在統計導出中
+        let body = classifier.stats.export_prometheus();
+        assert!(body.contains("trafficmon_ttl_anomalies_total 1\n"));
+        assert!(body.contains("trafficmon_ttl_sources 5\n"));
+    }
+    
+    #[test]
+    fn test_arp_frames() {
+        let request = arp_frame(ARP_REQUEST, [192, 168, 1, 100], [192, 168, 1, 1]);
+        let arp = parse_arp(LinkType::Ethernet, &request).unwrap();
+        assert_eq!(arp.operation, ARP_REQUEST);
+        assert_eq!(arp.sender_mac, [0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(arp.sender_ip, std::net::Ipv4Addr::new(192, 168, 1, 100));
+        assert_eq!(arp.target_ip, std::net::Ipv4Addr::new(192, 168, 1, 1));
+        assert!(!arp.is_gratuitous());
+        
+        let stats = Arc::new(TrafficStats::new());
+        let config = Config { arp_stats: true, ..Config::default() };
+        let classifier = TrafficClassifier::new(config, Arc::clone(&stats));
+        assert_eq!(classifier.classify_frame(LinkType::Ethernet, &request), "arp");
+        
+        let reply = arp_frame(ARP_REPLY, [192, 168, 1, 1], [192, 168, 1, 100]);
+        let gratuitous = arp_frame(ARP_REPLY, [192, 168, 1, 1], [192, 168, 1, 1]);
+        let mut source = MemorySource::new(vec![request.clone(), request, reply, gratuitous]);
+        classifier.capture_from(&mut source);
+        
+        assert_eq!(stats.get_stats()["arp"], (240, 4));
+        let interface = classifier.config.interface.clone();
+        assert_eq!(classifier.arp_counts(), HashMap::from([
+            (interface, ArpCounts { requests: 2, replies: 2, gratuitous: 1 }),
+        ]));
+        
+        // 未開啟 arp_stats 時照常歸入 arp，但不按接口計數
+        let classifier = test_classifier();
+        classifier.capture_from(&mut MemorySource::new(vec![arp_frame(ARP_REQUEST, [10, 0, 0, 2], [10, 0, 0, 1])]));
+        assert!(classifier.arp_counts().is_empty());
+    }
+    
+    /// 固定的套接字 → PID → 進程名映射，代替 eBPF；記錄讀取進程名的次數
+    struct CannedOwners {
+        pids: HashMap<FlowKey, u32>,
+        names: HashMap<u32, String>,
+        name_reads: Arc<AtomicUsize>,
+    }
+    
+    impl SocketOwners for CannedOwners {
+        fn pid(&self, key: &FlowKey) -> Option<u32> {
+            self.pids.get(key).copied()
+        }
+        
+        fn process_name(&self, pid: u32) -> Option<String> {
+            self.name_reads.fetch_add(1, Ordering::Relaxed);
+            self.names.get(&pid).cloned()
+        }
+    }
+    
+    #[test]
+    fn test_process_attribution() {
+        let socket = |sport| FlowKey {
+            src_ip: "192.168.1.100".parse().unwrap(),
+            dst_ip: "203.0.113.1".parse().unwrap(),
+            src_port: sport,
+            dst_port: 53,
+            protocol: IPPROTO_UDP,
+        };
+        let owners = CannedOwners {
+            pids: HashMap::from([(socket(53000), 100), (socket(53001), 200), (socket(53002), 300)]),
+            names: HashMap::from([(100, "firefox".to_string()), (200, "systemd-resolve".to_string())]),
+            name_reads: Arc::default(),
+        };
+        // 入站包按反方向的套接字查找
+        assert_eq!(crate::process::owning_process(&owners, &socket(53000).reversed()), Some("firefox".to_string()));
+        owners.name_reads.store(0, Ordering::Relaxed);
+        let name_reads = Arc::clone(&owners.name_reads);
+        
+        let classifier = TrafficClassifier::new(Config::default(), Arc::new(TrafficStats::new()))
+            .with_socket_owners(Box::new(owners));
+        let (firefox, resolver) = (udp_frame(53000, 53, &[0u8; 30]), udp_frame(53001, 53, &[0u8; 20]));
+        // 進程已退出或套接字不在映射中的包只計入服務統計
+        let exited = udp_frame(53002, 53, &[0u8; 20]);
+        let unknown = udp_frame(53003, 53, &[0u8; 20]);
+        let mut source = MemorySource::new(vec![firefox.clone(), firefox.clone(), resolver.clone(), exited, unknown]);
+        classifier.capture_from(&mut source);
+        
+        assert_eq!(classifier.process_traffic(), HashMap::from([
+            (("dns".to_string(), "firefox".to_string()), 2 * firefox.len() as u64),
+            (("dns".to_string(), "systemd-resolve".to_string()), resolver.len() as u64),
+        ]));
+        // 同一套接字的進程名只讀一次；找不到名字的不緩存
+        assert_eq!(name_reads.load(Ordering::Relaxed), 3);
+    }
+    
+    // 以太網 + IPv4 + TCP 頭（PSH|ACK），後接指定負載
+    fn tcp_frame(sport: u16, dport: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0u8; 12];
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(&tcp_ipv4_packet(sport, dport, payload));
+        frame
+    }
+    
+    fn tcp_ipv4_packet(sport: u16, dport: u16, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0x00];
+        packet.extend_from_slice(&((40 + payload.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&[0x00, 0x00, 0x40, 0x00, 0x40, 6, 0x00, 0x00]);
+        packet.extend_from_slice(&[192, 168, 1, 100, 93, 184, 216, 34]);
+        packet.extend_from_slice(&sport.to_be_bytes());
+        packet.extend_from_slice(&dport.to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 1, 0x50, 0x18, 0xff, 0xff, 0, 0, 0, 0]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+    
+    #[test]
+    fn test_classification_fields() {
+        let classification = test_classifier().classify_packet(&tcp_frame(50000, 443, b"\x16\x03\x01")).unwrap();
+        assert_eq!(classification, Classification {
+            service: "https".to_string(),
+            protocol: 6,
+            src_port: Some(50000),
+            dst_port: Some(443),
+            src_ip: IpAddr::from([192, 168, 1, 100]),
+            dst_ip: IpAddr::from([93, 184, 216, 34]),
+        });
+        assert!(test_classifier().classify_packet(&[0u8; 20]).is_none());
+    }
+    
+    #[test]
+    fn test_email_ports() {
+        let classifier = test_classifier();
+        for (port, service) in [
+            (25, "smtp"),
+            (587, "smtp-submission"),
+            (465, "smtps"),
+            (143, "imap"),
+            (993, "imaps"),
+            (110, "pop3"),
+            (995, "pop3s"),
+        ] {
+            assert_eq!(classifier.classify_packet(&tcp_frame(50000, port, b"")).unwrap().service, service, "port {}", port);
+        }
+    }
+    
+    #[test]
+    fn test_remote_desktop_ports() {
+        let classifier = test_classifier();
+        assert_eq!(classifier.classify_packet(&tcp_frame(50000, 3389, b"")).unwrap().service, "rdp");
+        for port in 5900..=5906 {
+            assert_eq!(classifier.classify_packet(&tcp_frame(50000, port, b"")).unwrap().service, "vnc", "port {}", port);
+        }
+        // 範圍之外不算 VNC
+        assert_eq!(classifier.classify_packet(&tcp_frame(50000, 5907, b"")).unwrap().service, "other");
+        assert_eq!(classifier.classify_packet(&tcp_frame(50000, 5899, b"")).unwrap().service, "other");
+    }
+    
+    #[test]
+    fn test_sctp_and_dccp_ports() {
+        let classifier = test_classifier();
+        let ipv4_frame = |protocol: u8, transport: &[u8]| {
+            let mut frame = vec![0u8; 12];
+            frame.extend_from_slice(&[0x08, 0x00, 0x45, 0x00]);
+            frame.extend_from_slice(&((20 + transport.len()) as u16).to_be_bytes());
+            frame.extend_from_slice(&[0x00, 0x00, 0x40, 0x00, 0x40, protocol, 0x00, 0x00]);
+            frame.extend_from_slice(&[192, 168, 1, 100, 203, 0, 113, 1]);
+            frame.extend_from_slice(transport);
+            frame
+        };
+        
+        // Diameter over SCTP：公共頭（端口、驗證標籤、校驗和）後接 INIT 塊
+        let mut sctp = vec![0x0f, 0x1c, 0x0f, 0x1c, 0, 0, 0, 0, 0x12, 0x34, 0x56, 0x78];
+        sctp.extend_from_slice(&[0x01, 0x00, 0x00, 0x14]);
+        sctp.extend_from_slice(&[0x5a; 16]);
+        let frame = ipv4_frame(IPPROTO_SCTP, &sctp);
+        let info = parse_ethernet(&frame).unwrap();
+        assert_eq!((info.src_port, info.dst_port), (Some(3868), Some(3868)));
+        assert_eq!(info.payload.len(), 20);
+        assert_eq!(classifier.classify_packet(&frame).unwrap().service, "sctp");
+        
+        // DCCP-Data：數據偏移 3（12 字節頭），短序列號
+        let mut dccp = vec![0x13, 0x89, 0x13, 0x8c, 0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x01];
+        dccp.extend_from_slice(b"rtp");
+        let frame = ipv4_frame(IPPROTO_DCCP, &dccp);
+        let info = parse_ethernet(&frame).unwrap();
+        assert_eq!((info.src_port, info.dst_port), (Some(5001), Some(5004)));
+        assert_eq!(info.payload, b"rtp");
+        assert_eq!(classifier.classify_packet(&frame).unwrap().service, "dccp");
+        
+        // 頭不完整時不讀端口
+        let info = parse_ethernet(&ipv4_frame(IPPROTO_SCTP, &sctp[..8])).map(|info| info.dst_port);
+        assert_eq!(info, Some(None));
+    }
+    
+    #[test]
+    fn test_ip_range_and_port_priority() {
+        // 發往 Netflix 地址範圍的 SSH 連接
+        let mut frame = tcp_frame(50000, 22, b"");
+        frame[30..34].copy_from_slice(&[108, 175, 32, 10]);
+        let classify = |priority, frame: &[u8]| {
+            let config = Config { classification_priority: priority, ..Config::default() };
+            TrafficClassifier::new(config, Arc::new(TrafficStats::new())).classify_packet(frame).unwrap().service
+        };
+        
+        assert_eq!(classify(ClassificationPriority::IpRange, &frame), "netflix");
+        assert_eq!(classify(ClassificationPriority::Port, &frame), "ssh");
+        
+        // 端口沒有映射時兩種設置都按地址範圍
+        frame[36..38].copy_from_slice(&40000u16.to_be_bytes());
+        assert_eq!(classify(ClassificationPriority::Port, &frame), "netflix");
+        assert_eq!(test_classifier().classify_packet(&tcp_frame(50000, 22, b"")).unwrap().service, "ssh");
+    }
+    
+    #[test]
+    fn test_mirrored_duplicates_counted_once() {
+        let stats = Arc::new(TrafficStats::new());
+        let config = Config {
+            dedup: Some(DedupConfig { window_ms: 1000, max_entries: 16 }),
+            ..Config::default()
+        };
+        let classifier = TrafficClassifier::new(config, Arc::clone(&stats));
+        
+        // 入向和出向各一份拷貝
+        let segment = tcp_frame(50000, 443, b"\x16\x03\x01");
+        let mut source = MemorySource::new(vec![segment.clone(), segment.clone()]);
+        classifier.capture_from(&mut source);
+        
+        assert_eq!(stats.get_stats()["https"], (segment.len() as u64, 1));
+    }
+    
+    #[test]
+    fn test_http_connect_proxy() {
+        let stats = Arc::new(TrafficStats::new());
+        let classifier = TrafficClassifier::new(Config::default(), Arc::clone(&stats));
+        
+        let connect = tcp_frame(50000, 3128, b"CONNECT www.netflix.com:443 HTTP/1.1\r\nHost: www.netflix.com:443\r\n\r\n");
+        let tunneled = tcp_frame(50000, 3128, &[0x17, 0x03, 0x03, 0x00, 0x10]);
+        // 代理的回應方向：交換源和目標地址
+        let mut response = tcp_frame(3128, 50000, b"HTTP/1.1 200 Connection established\r\n\r\n");
+        let (src, dst) = response[26..34].split_at_mut(4);
+        src.swap_with_slice(dst);
+        let garbled = tcp_frame(50001, 8080, b"CONNECT nowhere HTTP/1.1\r\n\r\n");
+        
+        let mut source = MemorySource::new(vec![connect.clone(), response.clone(), tunneled.clone(), garbled.clone()]);
+        classifier.capture_from(&mut source);
+        
+        let result = stats.get_stats();
+        assert_eq!(result["netflix"], ((connect.len() + response.len() + tunneled.len()) as u64, 3));
+        assert_eq!(result["http-proxy"], (garbled.len() as u64, 1));
+        
+        // 普通的 HTTP 請求不受影響
+        assert_eq!(classifier.classify_packet(&tcp_frame(50002, 8080, b"GET / HTTP/1.1\r\n\r\n")).unwrap().service, "http");
+    }
+    
+    /// 以太網 + IPv6 頭，`extensions` 為 (協議號, 擴展頭) 鏈，最後接 TCP 段
+    fn ipv6_tcp_frame(extensions: &[(u8, Vec<u8>)], dport: u16) -> Vec<u8> {
+        let mut tcp = 50000u16.to_be_bytes().to_vec();
+        tcp.extend_from_slice(&dport.to_be_bytes());
+        tcp.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 1, 0x50, 0x18, 0xff, 0xff, 0, 0, 0, 0]);
+        
+        let mut payload = Vec::new();
+        for (i, (_, header)) in extensions.iter().enumerate() {
+            let mut header = header.clone();
+            header[0] = extensions.get(i + 1).map_or(6, |(next, _)| *next);
+            payload.extend_from_slice(&header);
+        }
+        payload.extend_from_slice(&tcp);
+        
+        let mut frame = vec![0u8; 12];
+        frame.extend_from_slice(&[0x86, 0xdd, 0x60, 0x00, 0x00, 0x00]);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        frame.push(extensions.first().map_or(6, |(next, _)| *next));
+        frame.push(64);
+        frame.extend_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).octets());
+        frame.extend_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2).octets());
+        frame.extend_from_slice(&payload);
+        frame
+    }
+    
+    #[test]
+    fn test_ipv6_extension_headers() {
+        let classifier = test_classifier();
+        // 8 字節的逐跳選項頭（PadN 填充）
+        let hop_by_hop = vec![0, 0, 1, 4, 0, 0, 0, 0];
+        let frame = ipv6_tcp_frame(&[(0, hop_by_hop.clone())], 443);
+        
+        let info = parse_ethernet(&frame).unwrap();
+        assert_eq!((info.protocol, info.dst_port), (6, Some(443)));
+        assert_eq!(classifier.classify_packet(&frame).unwrap().service, "https");
+        
+        // 逐跳 + 目的選項 + 首個分片
+        let chain = [(0, hop_by_hop.clone()), (60, vec![0, 0, 1, 4, 0, 0, 0, 0]), (44, vec![0, 0, 0, 1, 0, 0, 0, 7])];
+        assert_eq!(classifier.classify_packet(&ipv6_tcp_frame(&chain, 80)).unwrap().service, "http");
+        
+        // 非首個分片沒有 TCP 頭
+        let later_fragment = ipv6_tcp_frame(&[(44, vec![0, 0, 0x05, 0x01, 0, 0, 0, 7])], 443);
+        assert_eq!(parse_ethernet(&later_fragment).unwrap().protocol, IPV6_FRAGMENT);
+        
+        // 擴展頭長度超出包長或頭鏈過長時不解析
+        let truncated = ipv6_tcp_frame(&[(0, vec![0, 9, 1, 4, 0, 0, 0, 0])], 443);
+        assert!(parse_ethernet(&truncated).is_none());
+        let looping: Vec<_> = (0..9).map(|_| (60, vec![0, 0, 1, 4, 0, 0, 0, 0])).collect();
+        assert!(parse_ethernet(&ipv6_tcp_frame(&looping, 443)).is_none());
+        assert_eq!(walk_ipv6_extensions(6, &[]), Some((6, 0)));
+    }
+    
+    #[test]
+    fn test_pppoe_encapsulated_tcp() {
+        let classifier = test_classifier();
+        let ip = tcp_ipv4_packet(50000, 443, b"\x16\x03\x01");
+        
+        let mut frame = vec![0u8; 12];
+        frame.extend_from_slice(&[0x88, 0x64]);
+        // PPPoE 版本/類型、代碼、會話 ID、長度
+        frame.extend_from_slice(&[0x11, 0x00, 0x00, 0x2a]);
+        frame.extend_from_slice(&((ip.len() + 2) as u16).to_be_bytes());
+        // PPP 協議號：IPv4
+        frame.extend_from_slice(&[0x00, 0x21]);
+        frame.extend_from_slice(&ip);
+        
+        assert_eq!(classifier.classify_packet(&frame).unwrap().service, "https");
+        assert_eq!(classifier.classify_packet(&tcp_frame(50000, 443, b"")).unwrap().service, "https");
+    }
+    
+    #[test]
+    fn test_retransmit_rate_per_service() {
+        let classifier = test_classifier();
+        let segment = tcp_frame(50000, 443, b"GET / HTTP/1.1\r\n");
+        
+        // 同一序列號的段出現兩次，第二次是重傳
+        let mut source = MemorySource::new(vec![segment.clone(), segment]);
+        classifier.capture_from(&mut source);
+        
+        assert_eq!(classifier.retransmit_rates()["https"], 0.5);
+    }
+    
+    #[test]
+    fn test_dns_answer_attributes_flow() {
+        let classifier = test_classifier();
+        let answer = crate::dns::test_response("www.example.com", std::net::Ipv4Addr::new(93, 184, 216, 34), 300);
+        let https = tcp_frame(50000, 443, b"\x16\x03\x01");
+        
+        // 解析之前的流量不歸屬任何域名
+        let mut source = MemorySource::new(vec![https.clone(), udp_frame(53, 53000, &answer), https.clone()]);
+        classifier.capture_from(&mut source);
+        
+        let domains = classifier.domain_traffic();
+        assert_eq!(domains["www.example.com"], https.len() as u64);
+        assert_eq!(domains.len(), 1);
+    }
+    
+    #[test]
+    fn test_ignored_ports_not_counted() {
+        let stats = Arc::new(TrafficStats::new());
+        let config = Config { ignore_ports: vec![1900], ..Config::default() };
+        let classifier = TrafficClassifier::new(config, Arc::clone(&stats));
+        
+        // 兩個方向都被忽略
+        let request = udp_frame(50000, 1900, b"M-SEARCH");
+        let reply = udp_frame(1900, 50000, b"HTTP/1.1 200 OK");
+        let dns = udp_frame(53000, 53, &[0u8; 30]);
+        let mut source = MemorySource::new(vec![request, reply, dns]);
+        classifier.capture_from(&mut source);
+        
+        let result = stats.get_stats();
+        assert!(!result.contains_key("ssdp"));
+        assert_eq!(result.len(), 1);
+        assert!(result.contains_key("dns"));
+    }
+    
+    #[test]
+    fn test_queue_drops_when_consumer_is_slow() {
+        let stats = Arc::new(TrafficStats::new());
+        let mut config = Config::default();
+        config.capture.queue_capacity = 2;
+        let classifier = TrafficClassifier::new(config, Arc::clone(&stats));
+        
+        let total = 50;
+        let mut source = MemorySource::new(vec![udp_frame(53000, 53, &[0u8; 30]); total]);
+        
+        thread::scope(|scope| {
+            // 佔住 DNS 緩存鎖，讓分類線程卡在第一個包上
+            let blocked = classifier.dns_cache.lock().unwrap();
+            let capture = scope.spawn(|| classifier.capture_queued(&mut source));
+            
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while stats.queue_drops() < (total - 3) as u64 && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(1));
+            }
+            
+            drop(blocked);
+            capture.join().unwrap();
+        });
+        
+        let processed = stats.get_stats()["dns"].1;
+        assert!(processed <= 3, "processed {}", processed);
+        assert_eq!(processed + stats.queue_drops(), total as u64);
+    }
+    
+    #[test]
+    fn test_any_interface_sll_frames() {
+        let stats = Arc::new(TrafficStats::new());
+        let classifier = TrafficClassifier::new(Config::default(), Arc::clone(&stats));
+        let ip = tcp_ipv4_packet(50000, 443, b"\x16\x03\x01");
+        
+        // SLL：包類型、ARPHRD、地址長度、8 字節地址、協議號
+        let mut sll = vec![0x00, 0x04, 0x00, 0x01, 0x00, 0x06];
+        sll.extend_from_slice(&[0x02, 0x42, 0xac, 0x11, 0x00, 0x02, 0x00, 0x00]);
+        sll.extend_from_slice(&[0x08, 0x00]);
+        sll.extend_from_slice(&ip);
+        
+        let mut source = MemorySource::new(vec![sll.clone()]).with_link_type(LinkType::LinuxSll);
+        classifier.capture_from(&mut source);
+        assert_eq!(stats.get_stats()["https"], (sll.len() as u64, 1));
+        
+        // 同一幀按以太網解析會得到錯誤的結果
+        assert_ne!(classifier.classify_packet(&sll).map(|c| c.service).as_deref(), Some("https"));
+        
+        let config = Config { interface: "any".to_string(), ..Config::default() };
+        assert_eq!(config.resolve_interface().unwrap(), "any");
+    }
+    
+    #[test]
+    fn test_any_interface_sll2_frames() {
+        let stats = Arc::new(TrafficStats::new());
+        let classifier = TrafficClassifier::new(Config::default(), Arc::clone(&stats));
+        let ip = tcp_ipv4_packet(50000, 443, b"\x16\x03\x01");
+        
+        // SLL2：協議號、保留、接口索引、ARPHRD、包類型、地址長度、8 字節地址
+        let mut sll2 = vec![0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x01, 0x04, 0x06];
+        sll2.extend_from_slice(&[0x02, 0x42, 0xac, 0x11, 0x00, 0x02, 0x00, 0x00]);
+        sll2.extend_from_slice(&ip);
+        
+        let info = parse_frame(LinkType::LinuxSll2, &sll2).unwrap();
+        assert_eq!((info.src_port, info.dst_port), (Some(50000), Some(443)));
+        assert_eq!(LinkType::from_dlt(276), Some(LinkType::LinuxSll2));
+        
+        let mut source = MemorySource::new(vec![sll2.clone()]).with_link_type(LinkType::LinuxSll2);
+        classifier.capture_from(&mut source);
+        assert_eq!(stats.get_stats()["https"], (sll2.len() as u64, 1));
+        
+        // 按 SLL 的偏移解析會取錯端口
+        assert_ne!(parse_frame(LinkType::LinuxSll, &sll2).and_then(|info| info.dst_port), Some(443));
+    }
+    
+    #[test]
+    fn test_flow_records_from_pipeline() {
+        let config = Config {
+            flow_export: Some(crate::config::FlowExportConfig { timeout_secs: Some(30) }),
+            ..Config::default()
+        };
+        let classifier = TrafficClassifier::new(config, Arc::new(TrafficStats::new()));
+        
+        let dns = udp_frame(53000, 53, &[0u8; 30]);
+        for _ in 0..3 {
+            classifier.process_packet(&RawPacket { data: &dns, wire_len: dns.len() as u32, link: LinkType::Ethernet, timestamp: None });
+        }
+        
+        assert!(classifier.expire_flows(SystemTime::now()).is_empty());
+        
+        let records = classifier.expire_flows(SystemTime::now() + Duration::from_secs(31));
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].packet_delta_count, 3);
+        assert_eq!(records[0].octet_delta_count, 3 * dns.len() as u64);
+    }
+    
+    #[test]
+    fn test_idle_timeout_finalizes_flows() {
+        let stats = Arc::new(TrafficStats::new());
+        let config = Config {
+            idle_timeout: 120,
+            flow_export: Some(crate::config::FlowExportConfig { timeout_secs: None }),
+            ..Config::default()
+        };
+        let classifier = TrafficClassifier::new(config, Arc::clone(&stats));
+        
+        let dns = udp_frame(53000, 53, &[0u8; 30]);
+        let connect = tcp_frame(50000, 3128, b"CONNECT www.netflix.com:443 HTTP/1.1\r\n\r\n");
+        let tunneled = tcp_frame(50000, 3128, &[0x17, 0x03, 0x03, 0x00, 0x10]);
+        for frame in [&dns, &connect, &tunneled] {
+            classifier.process_packet(&RawPacket { data: frame, wire_len: frame.len() as u32, link: LinkType::Ethernet, timestamp: None });
+        }
+        
+        let start = SystemTime::now();
+        assert!(classifier.expire_flows(start + Duration::from_secs(60)).is_empty());
+        assert_eq!(classifier.classify_packet(&tunneled).unwrap().service, "netflix");
+        
+        let records = classifier.expire_flows(start + Duration::from_secs(181));
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].octet_delta_count + records[1].octet_delta_count, (dns.len() + connect.len() + tunneled.len()) as u64);
+        // 空閒的隧道也已結束，之後的包不再沿用 CONNECT 的目標
+        assert_ne!(classifier.classify_packet(&tunneled).unwrap().service, "netflix");
+        
+        // 聚合統計仍然保留
+        let result = stats.get_stats();
+        assert_eq!(result["dns"], (dns.len() as u64, 1));
+        assert_eq!(result["netflix"].1, 2);
+    }
+    
+    #[test]
+    fn test_decision_log_from_pipeline() {
+        let path = std::env::temp_dir().join(format!("trafficmon-decisions-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = Config {
+            decision_log: Some(crate::config::DecisionLogConfig { path: path.clone(), max_bytes: 1 << 20 }),
+            ..Config::default()
+        };
+        let classifier = TrafficClassifier::new(config, Arc::new(TrafficStats::new()));
+        
+        let dns = udp_frame(53000, 53, &[0u8; 30]);
+        let connect = tcp_frame(50000, 3128, b"CONNECT www.netflix.com:443 HTTP/1.1\r\n\r\n");
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        for (second, frame) in [&dns, &connect].into_iter().enumerate() {
+            let timestamp = Some(start + Duration::from_secs(second as u64));
+            classifier.process_packet(&RawPacket { data: frame, wire_len: frame.len() as u32, link: LinkType::Ethernet, timestamp });
+        }
+        classifier.flush_events();
+        
+        let decisions = crate::decisions::replay(&path, &Default::default()).unwrap();
+        let logged: Vec<(&str, &str, Option<u16>)> = decisions.iter()
+            .map(|d| (d.service.as_str(), d.method.as_str(), d.dst_port))
+            .collect();
+        assert_eq!(logged, vec![("dns", "port", Some(53)), ("netflix", "proxy", Some(3128))]);
+        assert_eq!(SystemTime::from(decisions[1].timestamp), start + Duration::from_secs(1));
+        
+        std::fs::remove_file(&path).unwrap();
+    }
+    
+    #[test]
+    fn test_payload_samples_from_pipeline() {
+        let config = Config {
+            payload_samples: Some(crate::config::PayloadSampleConfig { bytes: 8, packets: 2, max_services: 8, redact: false }),
+            ..Config::default()
+        };
+        let classifier = TrafficClassifier::new(config, Arc::new(TrafficStats::new()));
+        
+        let frames = [
+            tcp_frame(50000, 40000, b"\x01\x02\x03\x04\x05\x06\x07\x08\x09\x0a"),
+            tcp_frame(50000, 40000, b"\xff"),
+            tcp_frame(50000, 40000, b"\xee"),
+        ];
+        let mut source = MemorySource::new(frames.to_vec());
+        classifier.capture_from(&mut source);
+        
+        let samples = classifier.payload_samples();
+        let hex: Vec<String> = samples["other"].iter().map(PayloadSample::to_hex).collect();
+        assert_eq!(hex, vec!["0102030405060708", "ff"]);
+        
+        // 未開啟時不採樣
+        assert!(test_classifier().payload_samples().is_empty());
+    }
+    
+    #[test]
+    fn test_flow_records_alpn() {
+        let config = Config {
+            flow_export: Some(crate::config::FlowExportConfig { timeout_secs: Some(30) }),
+            ..Config::default()
+        };
+        let classifier = TrafficClassifier::new(config, Arc::new(TrafficStats::new()));
+        
+        let hello = tcp_frame(50000, 443, &crate::tls::test_client_hello_with_alpn("www.netflix.com", &["h2", "http/1.1"]));
+        let data = tcp_frame(50000, 443, &[0u8; 100]);
+        for frame in [&hello, &data] {
+            classifier.process_packet(&RawPacket { data: frame, wire_len: frame.len() as u32, link: LinkType::Ethernet, timestamp: None });
+        }
+        
+        let records = classifier.expire_flows(SystemTime::now() + Duration::from_secs(31));
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].application_protocol.as_deref(), Some("h2"));
+        assert_eq!(records[0].server_name.as_deref(), Some("www.netflix.com"));
+        
+        let json = serde_json::to_value(&records[0]).unwrap();
+        assert_eq!(json["applicationProtocol"], "h2");
+    }
+    
+    #[test]
+    fn test_classification_method_order() {
+        let hello = crate::tls::test_client_hello("www.netflix.com");
+        let frame = tcp_frame(50000, 443, &hello);
+        
+        assert_eq!(test_classifier().classify_packet(&frame).unwrap().service, "netflix");
+        
+        // 關閉 SNI 後退回到端口分類
+        let config = Config {
+            classification_methods: vec![ClassificationMethod::Dns, ClassificationMethod::Port],
+            ..Config::default()
+        };
+        let classifier = TrafficClassifier::new(config, Arc::new(TrafficStats::new()));
+        assert_eq!(classifier.classify_packet(&frame).unwrap().service, "https");
+        
+        let methods: Config = toml::from_str(r#"
+            interface = "eth0"
+            report_interval = 60
+            log_unknown_traffic = false
+            services = []
+            time_rules = []
+            user_rules = []
+            blocked_domains = []
+            pattern_rules = []
+            classification_methods = ["port"]
+        "#).unwrap();
+        assert_eq!(methods.classification_methods, vec![ClassificationMethod::Port]);
+    }
+    
+    #[test]
+    fn test_classification_budget_falls_back_to_port() {
+        let stats = Arc::new(TrafficStats::new());
+        let config = Config { classification_budget_us: Some(1000), ..Config::default() };
+        let classifier = TrafficClassifier::new(config, Arc::clone(&stats));
+        let frame = tcp_frame(50000, 80, b"GET / HTTP/1.1\r\nHost: www.youtube.com\r\n\r\n");
+        let info = parse_ethernet(&frame).unwrap();
+        
+        // SNI 方法耗時 5 毫秒，超出 1 毫秒的預算，不再解析 HTTP 頭
+        let start = Instant::now();
+        let calls = std::cell::Cell::new(0);
+        let slow_sni = || {
+            calls.set(calls.get() + 1);
+            start + Duration::from_millis(if calls.get() <= 2 { 0 } else { 5 })
+        };
+        assert_eq!(classifier.classify_within(Some(&info), &slow_sni), ("http".to_string(), "timeout"));
+        assert_eq!(calls.get(), 3);
+        assert_eq!(stats.classification_timeouts(), 1);
+        assert!(stats.export_prometheus().contains("trafficmon_classification_timeouts_total 1\n"));
+        
+        // 預算內照常按 Host 分類
+        assert_eq!(classifier.classify_within(Some(&info), &|| start), ("youtube".to_string(), "http"));
+        assert_eq!(stats.classification_timeouts(), 1);
+    }
+    
+    #[test]
+    fn test_classification_budget_stops_slow_parse() {
+        // Host 頭前有幾千行頭部，HTTP 解析本身就超出 100 微秒的預算
+        let mut request = b"GET / HTTP/1.1\r\n".to_vec();
+        let lines = 6000;
+        for _ in 0..lines {
+            request.extend_from_slice(b"X-Pad: 0\r\n");
+        }
+        request.extend_from_slice(b"Host: www.youtube.com\r\n\r\n");
+        let frame = tcp_frame(50000, 80, &request);
+        let info = parse_ethernet(&frame).unwrap();
+        
+        let config = Config { http_parse_bytes: request.len(), ..Config::default() };
+        let unlimited = TrafficClassifier::new(config.clone(), Arc::new(TrafficStats::new()));
+        assert_eq!(unlimited.classify_with_method(Some(&info)), ("youtube".to_string(), "http"));
+        
+        let stats = Arc::new(TrafficStats::new());
+        let config = Config { classification_budget_us: Some(100), ..config };
+        let classifier = TrafficClassifier::new(config, Arc::clone(&stats));
+        let reads = std::cell::Cell::new(0);
+        let clock = || {
+            reads.set(reads.get() + 1);
+            Instant::now()
+        };
+        assert_eq!(classifier.classify_within(Some(&info), &clock), ("http".to_string(), "timeout"));
+        assert_eq!(stats.classification_timeouts(), 1);
+        // 在解析途中放棄，沒有讀完所有頭部
+        assert!(reads.get() > 4 && reads.get() < lines / 2, "clock read {} times", reads.get());
+    }
+    
+    #[test]
+    fn test_sni_learned_addresses() {
+        let config = Config {
+            sni_learning: Some(SniLearningConfig {
+                max_addresses: 16,
+                path: None,
+                add_to_nftables: false,
+                require_dns_answer: false,
+                compact_interval_secs: 300,
+                ttl_secs: 0,
+            }),
+            ..Config::default()
+        };
+        let stats = Arc::new(TrafficStats::new());
+        let classifier = TrafficClassifier::new(config.clone(), Arc::clone(&stats));
+        let youtube = IpAddr::from([93, 184, 216, 34]);
+        let process = |frame: &[u8]| {
+            classifier.process_packet(&RawPacket { data: frame, wire_len: frame.len() as u32, link: LinkType::Ethernet, timestamp: None });
+        };
+        
+        // 學習之前沒有 SNI 的包按端口歸類
+        assert_eq!(classifier.classify_packet(&tcp_frame(50001, 443, b"")).unwrap().service, "https");
+        process(&tcp_frame(50000, 443, &crate::tls::test_client_hello("www.youtube.com")));
+        assert_eq!(classifier.learned_ranges("youtube"), [(youtube, 32)]);
+        
+        // 之後同一地址上沒有 SNI 的加密流量也歸入 youtube
+        process(&tcp_frame(50001, 443, b"\x17\x03\x03"));
+        assert_eq!(stats.get_stats()["youtube"].1, 2);
+        assert!(classifier.learned_ranges("netflix").is_empty());
+        
+        // 要求 DNS 解析結果時，沒見過同名回應的 SNI 不學習
+        let mut config = config;
+        if let Some(learning) = &mut config.sni_learning {
+            learning.require_dns_answer = true;
+        }
+        let classifier = TrafficClassifier::new(config, Arc::new(TrafficStats::new()));
+        let hello = tcp_frame(50000, 443, &crate::tls::test_client_hello("www.youtube.com"));
+        let process = |frame: &[u8]| {
+            classifier.process_packet(&RawPacket { data: frame, wire_len: frame.len() as u32, link: LinkType::Ethernet, timestamp: None });
+        };
+        process(&hello);
+        assert!(classifier.learned_ranges("youtube").is_empty());
+        process(&udp_frame(53, 50002, &crate::dns::test_response("youtube.com", std::net::Ipv4Addr::new(93, 184, 216, 34), 300)));
+        process(&hello);
+        assert!(classifier.learned_ranges("youtube").is_empty());
+        process(&udp_frame(53, 50002, &crate::dns::test_response("WWW.youtube.com", std::net::Ipv4Addr::new(93, 184, 216, 34), 300)));
+        process(&hello);
+        assert_eq!(classifier.learned_ranges("youtube"), [(youtube, 32)]);
+    }
+    
+    #[test]
+    fn test_weighted_classification_vote() {
+        // SNI 說 youtube，目標地址卻在 netflix 的範圍內
+        let mut frame = tcp_frame(50000, 443, &crate::tls::test_client_hello("www.youtube.com"));
+        frame[30..34].copy_from_slice(&[108, 175, 32, 1]);
+        let voting = |weights: ClassificationWeights| {
+            let config = Config { classification_weights: Some(weights), ..Config::default() };
+            TrafficClassifier::new(config, Arc::new(TrafficStats::new()))
+        };
+        
+        let classifier = voting(ClassificationWeights::default());
+        let info = parse_ethernet(&frame).unwrap();
+        assert_eq!(classifier.vote(&info, &ClassificationWeights::default(), &|| false), Some(ClassificationDecision {
+            service: "youtube".to_string(),
+            score: 4.0,
+            runners_up: vec![("netflix".to_string(), 1.0)],
+        }));
+        assert_eq!(classifier.classify_packet(&frame).unwrap().service, "youtube");
+        assert_eq!(classifier.vote_conflicts(), HashMap::from([
+            (("youtube".to_string(), "netflix".to_string()), 1),
+        ]));
+        
+        // 地址範圍更可信時端口方法勝出
+        let trust_ranges = ClassificationWeights { port: 5.0, ..ClassificationWeights::default() };
+        assert_eq!(voting(trust_ranges).classify_packet(&frame).unwrap().service, "netflix");
+        
+        // 各方法一致時不算衝突
+        let mut agreed = tcp_frame(50000, 443, &crate::tls::test_client_hello("www.netflix.com"));
+        agreed[30..34].copy_from_slice(&[108, 175, 32, 1]);
+        let classifier = voting(ClassificationWeights::default());
+        assert_eq!(classifier.classify_packet(&agreed).unwrap().service, "netflix");
+        assert!(classifier.vote_conflicts().is_empty());
+    }
+    
+    #[test]
+    fn test_http_host_attribution() {
+        let classifier = test_classifier();
+        
+        let youtube = tcp_frame(50000, 80, b"GET / HTTP/1.1\r\nHost: www.youtube.com\r\n\r\n");
+        assert_eq!(classifier.classify_packet(&youtube).unwrap().service, "youtube");
+        
+        let request = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\nUser-Agent: Mozilla/5.0 (Windows NT 10.0; Win64; x64)\r\n\r\n";
+        let frame = tcp_frame(50001, 80, request);
+        assert_eq!(classifier.classify_packet(&frame).unwrap().service, "http");
+        
+        // 沒有請求頭的後續數據段仍按端口歸為 http
+        assert_eq!(classifier.classify_packet(&tcp_frame(50001, 80, b"body")).unwrap().service, "http");
+        
+        let mut source = MemorySource::new(vec![frame.clone()]);
+        classifier.capture_from(&mut source);
+        assert_eq!(classifier.domain_traffic()["example.com"], frame.len() as u64);
+        assert_eq!(classifier.client_os_counts()["windows"], 1);
+    }
+    
+    #[test]
+    fn test_tcp_flag_counts() {
+        let classifier = test_classifier();
+        
+        // 把 PSH|ACK 改成單獨的 SYN
+        let mut syn = tcp_frame(50000, 443, b"");
+        syn[47] = TCP_SYN;
+        assert_eq!(parse_ethernet(&syn).unwrap().tcp_flags, Some(TCP_SYN));
+        
+        let mut rst = tcp_frame(50000, 443, b"");
+        rst[47] = TCP_RST | TCP_ACK;
+        
+        for frame in [&syn, &syn, &rst, &tcp_frame(50000, 443, b"data")] {
+            classifier.process_packet(&RawPacket { data: frame, wire_len: frame.len() as u32, link: LinkType::Ethernet, timestamp: None });
+        }
+        
+        let counts = classifier.tcp_flag_counts();
+        assert_eq!(counts["https"], TcpFlagCounts { syn: 2, fin: 0, rst: 1 });
+        assert_eq!(counts.len(), 1);
+    }
+    
+    #[test]
+    fn test_multicast_and_broadcast() {
+        // 把 udp_frame 的目標地址換成指定地址
+        let frame_to = |dst: [u8; 4], dport: u16| {
+            let mut frame = udp_frame(50000, dport, b"hello");
+            frame[30..34].copy_from_slice(&dst);
+            frame
+        };
+        let mdns = frame_to([224, 0, 0, 251], 5353);
+        let dhcp = frame_to([255, 255, 255, 255], 67);
+        
+        let stats = Arc::new(TrafficStats::new());
+        let classifier = TrafficClassifier::new(Config::default(), Arc::clone(&stats));
+        assert_eq!(classifier.classify_packet(&mdns).unwrap().service, "multicast");
+        assert_eq!(classifier.classify_packet(&dhcp).unwrap().service, "broadcast");
+        
+        let mut source = MemorySource::new(vec![mdns.clone(), dhcp.clone(), udp_frame(50000, 53, b"query")]);
+        classifier.capture_from(&mut source);
+        assert_eq!(stats.get_stats().len(), 3);
+        
+        // 開啟排除後只統計單播
+        let stats = Arc::new(TrafficStats::new());
+        let config = Config { exclude_multicast: true, ..Config::default() };
+        let classifier = TrafficClassifier::new(config, Arc::clone(&stats));
+        
+        let mut source = MemorySource::new(vec![mdns, dhcp, udp_frame(50000, 53, b"query")]);
+        classifier.capture_from(&mut source);
+        let result = stats.get_stats();
+        assert_eq!(result.keys().collect::<Vec<_>>(), vec!["dns"]);
+    }
+    
+    #[test]
+    fn test_bytes_counted_from_wire_length() {
+        let stats = Arc::new(TrafficStats::new());
+        let classifier = TrafficClassifier::new(Config::default(), Arc::clone(&stats));
+        
+        // snaplen 截斷：只捕獲了頭部，實際是 1400 字節的包
+        let frame = tcp_frame(50000, 443, &[0u8; 1346]);
+        let captured = frame[..96].to_vec();
+        
+        let mut source = MemorySource::with_wire_lengths(vec![(captured, frame.len() as u32)]);
+        classifier.capture_from(&mut source);
+        
+        assert_eq!(stats.get_stats()["https"], (1400, 1));
+    }
+    
+    #[test]
+    fn test_vlan_stats_segregated() {
+        let stats = Arc::new(TrafficStats::new());
+        let classifier = TrafficClassifier::new(Config::default(), Arc::clone(&stats));
+        
+        // 在源 MAC 之後插入 802.1Q 標籤
+        let tagged = |vlan: u16, frame: Vec<u8>| {
+            let mut tagged = frame[..12].to_vec();
+            tagged.extend_from_slice(&[0x81, 0x00]);
+            tagged.extend_from_slice(&vlan.to_be_bytes());
+            tagged.extend_from_slice(&frame[12..]);
+            tagged
+        };
+        let https = tcp_frame(50000, 443, &[0u8; 100]);
+        
+        let mut source = MemorySource::new(vec![
+            tagged(10, https.clone()),
+            tagged(10, https.clone()),
+            tagged(20, https.clone()),
+            tagged(20, udp_frame(54321, 53, &[0u8; 30])),
+            https.clone(),
+        ]);
+        classifier.capture_from(&mut source);
+        
+        let vlans = stats.get_vlan_stats();
+        let tagged_len = https.len() as u64 + 4;
+        assert_eq!(vlans[&10], HashMap::from([("https".to_string(), (2 * tagged_len, 2))]));
+        assert_eq!(vlans[&20]["https"], (tagged_len, 1));
+        assert!(vlans[&20].contains_key("dns"));
+        // 未打標籤的流量歸入 VLAN 0
+        assert_eq!(vlans[&0]["https"], (https.len() as u64, 1));
+        
+        // 總統計不區分 VLAN
+        assert_eq!(stats.get_stats()["https"].1, 4);
+    }
+    
+    #[test]
+    fn test_gre_encapsulated_tcp() {
+        let stats = Arc::new(TrafficStats::new());
+        let classifier = TrafficClassifier::new(Config::default(), Arc::clone(&stats));
+        
+        // 外層 IPv4（協議 47）+ 帶密鑰的 GRE 頭 + 內層 IPv4/TCP
+        let encapsulate = |protocol: u8, inner: &[u8]| {
+            let mut packet = vec![0x45, 0x00];
+            packet.extend_from_slice(&((20 + inner.len()) as u16).to_be_bytes());
+            packet.extend_from_slice(&[0x00, 0x00, 0x40, 0x00, 0x40, protocol, 0x00, 0x00]);
+            packet.extend_from_slice(&[203, 0, 113, 1, 198, 51, 100, 1]);
+            packet.extend_from_slice(inner);
+            packet
+        };
+        let gre = |inner: &[u8]| {
+            let mut payload = vec![0x20, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x2a];
+            payload.extend_from_slice(inner);
+            encapsulate(47, &payload)
+        };
+        let frame = |packet: Vec<u8>| {
+            let mut frame = vec![0u8; 12];
+            frame.extend_from_slice(&[0x08, 0x00]);
+            frame.extend_from_slice(&packet);
+            frame
+        };
+        
+        let inner = tcp_ipv4_packet(50000, 443, &[0u8; 100]);
+        let tunneled = frame(gre(&inner));
+        let info = parse_ethernet(&tunneled).unwrap();
+        assert_eq!(info.tunnel, Some(Tunnel::Gre));
+        assert_eq!((info.src_ip.to_string(), info.dst_port), ("192.168.1.100".to_string(), Some(443)));
+        assert_eq!(info.payload.len(), 100);
+        
+        // IP-in-IP 同樣按內層分類
+        assert_eq!(parse_ethernet(&frame(encapsulate(4, &inner))).unwrap().tunnel, Some(Tunnel::IpInIp));
+        
+        // 超過層數上限時不再解封裝
+        let mut nested = inner.clone();
+        for _ in 0..5 {
+            nested = gre(&nested);
+        }
+        let nested = frame(nested);
+        let deep = parse_ethernet(&nested).unwrap();
+        assert_eq!((deep.protocol, deep.dst_port), (47, None));
+        
+        let mut source = MemorySource::new(vec![tunneled.clone(), tcp_frame(50000, 443, &[0u8; 100])]);
+        classifier.capture_from(&mut source);
+        assert_eq!(stats.get_stats()["https"].1, 2);
+        assert_eq!(classifier.tunneled_traffic(), HashMap::from([("https".to_string(), (tunneled.len() as u64, 1))]));
+        
+        // 按內層計數時扣除以太網頭、外層 IPv4 和 GRE 頭
+        let inner_stats = Arc::new(TrafficStats::new());
+        let config = Config { byte_accounting: ByteAccounting::Inner, ..Config::default() };
+        let inner_classifier = TrafficClassifier::new(config, Arc::clone(&inner_stats));
+        let mut source = MemorySource::new(vec![tunneled.clone()]);
+        inner_classifier.capture_from(&mut source);
+        assert_eq!(tunneled.len() - inner.len(), 14 + 20 + 8);
+        assert_eq!(inner_stats.get_stats()["https"], (inner.len() as u64, 1));
+        assert_eq!(inner_classifier.tunneled_traffic()["https"], (inner.len() as u64, 1));
+    }
+    
+    #[test]
+    fn test_ntp_and_snmp() {
+        let classifier = test_classifier();
+        
+        // NTPv4 客戶端請求（LI 0，版本 4，模式 3），其餘字段為 0
+        let mut ntp = vec![0x23];
+        ntp.extend_from_slice(&[0u8; 47]);
+        assert_eq!(classifier.classify_packet(&udp_frame(50123, 123, &ntp)).unwrap().service, "ntp");
+        // 服務器回應（模式 4）發往客戶端的臨時端口
+        ntp[0] = 0x24;
+        assert_eq!(classifier.classify_packet(&udp_frame(123, 50123, &ntp)).unwrap().service, "ntp");
+        assert_eq!(classifier.classify_packet(&udp_frame(123, 50123, &ntp[..20])).unwrap().service, "other");
+        
+        // SNMPv2c GetRequest：SEQUENCE { INTEGER 1, OCTET STRING "public", GetRequest-PDU ... }
+        let mut snmp = vec![0x30, 0x26, 0x02, 0x01, 0x01, 0x04, 0x06];
+        snmp.extend_from_slice(b"public");
+        snmp.extend_from_slice(&[0xa0, 0x19, 0x02, 0x04, 0x12, 0x34, 0x56, 0x78, 0x02, 0x01, 0x00, 0x02, 0x01, 0x00]);
+        snmp.extend_from_slice(&[0x30, 0x0b, 0x30, 0x09, 0x06, 0x05, 0x2b, 0x06, 0x01, 0x02, 0x01, 0x05, 0x00]);
+        assert_eq!(classifier.classify_packet(&udp_frame(50161, 161, &snmp)).unwrap().service, "snmp");
+        assert_eq!(classifier.classify_packet(&udp_frame(161, 50161, &snmp)).unwrap().service, "snmp");
+        assert_eq!(snmp_version(&snmp), Some(1));
+        assert_eq!(snmp_version(&[0x30, 0x81, 0x80, 0x02, 0x01, 0x03]), Some(3));
+        assert_eq!(snmp_version(&[0x30, 0x05, 0x02, 0x01, 0x02]), None);
+        
+        // SNMPv1 trap 和 v2c 請求分別計數
+        let stats = Arc::new(TrafficStats::new());
+        let classifier = TrafficClassifier::new(Config::default(), Arc::clone(&stats));
+        let mut trap = snmp.clone();
+        trap[4] = 0x00;
+        let mut source = MemorySource::new(vec![
+            udp_frame(50161, 161, &snmp),
+            udp_frame(161, 50161, &snmp),
+            udp_frame(50162, 162, &trap),
+        ]);
+        classifier.capture_from(&mut source);
+        assert_eq!(classifier.snmp_versions(), HashMap::from([("v2c".to_string(), 2), ("v1".to_string(), 1)]));
+        assert_eq!(stats.get_stats()["snmp"].1, 3);
+    }
+    
+    #[test]
+    fn test_worker_pool_totals() {
+        let stats = Arc::new(TrafficStats::new());
+        let mut config = Config::default();
+        config.capture.workers = 4;
+        config.capture.queue_capacity = 100_000;
+        let classifier = TrafficClassifier::new(config, Arc::clone(&stats));
+        
+        // 多個流的請求和回應交錯，負載長度各不相同
+        let mut frames = Vec::new();
+        let mut expected = HashMap::<&str, (u64, u64)>::new();
+        for i in 0..5000u16 {
+            let payload = vec![0u8; (i % 50) as usize];
+            let (service, frame) = match i % 3 {
+                0 => ("dns", udp_frame(40000 + i % 100, 53, &payload)),
+                1 => ("https", tcp_frame(40000 + i % 100, 443, &payload)),
+                _ => ("http", tcp_frame(40000 + i % 100, 80, &payload)),
+            };
+            let entry = expected.entry(service).or_default();
+            entry.0 += frame.len() as u64;
+            entry.1 += 1;
+            frames.push(frame);
+        }
+        
+        classifier.capture_queued(&mut MemorySource::new(frames));
+        
+        assert_eq!(stats.queue_drops(), 0);
+        let result = stats.get_stats();
+        for (service, totals) in expected {
+            assert_eq!(result[service], totals, "{}", service);
+        }
+    }
+    
+    #[test]
+    fn test_list_services_with_stats() {
+        let stats = Arc::new(TrafficStats::new());
+        let mut config = Config::default();
+        config.services[1].blocked = true;
+        let classifier = TrafficClassifier::new(config, Arc::clone(&stats));
+        stats.add_traffic("netflix", 4096, 3);
+        stats.add_traffic("unknown", 100, 1);
+        
+        // 按配置順序，未配置的服務不出現，沒有流量的計為 0
+        assert_eq!(classifier.list_services(), vec![
+            ServiceInfo {
+                name: "netflix".to_string(),
+                ports: vec![80, 443, 1935],
+                ip_ranges: vec!["108.175.32.0/20".to_string(), "198.38.96.0/19".to_string()],
+                blocked: false,
+                bytes: 4096,
+                packets: 3,
+            },
+            ServiceInfo {
+                name: "youtube".to_string(),
+                ports: vec![80, 443, 1935],
+                ip_ranges: vec!["173.194.0.0/16".to_string(), "74.125.0.0/16".to_string()],
+                blocked: true,
+                bytes: 0,
+                packets: 0,
+            },
+        ]);
+    }
+    
+    #[test]
+    fn test_decision_tree_dot() {
+        let config = Config {
+            classification_methods: vec![ClassificationMethod::Sni, ClassificationMethod::Port],
+            ..Config::default()
+        };
+        let dot = TrafficClassifier::new(config, Arc::new(TrafficStats::new())).decision_tree_dot();
+        
+        assert!(dot.starts_with("digraph classification {\n"));
+        assert!(dot.ends_with("}\n"));
+        // 方法按配置順序串聯
+        assert!(dot.contains(r#""method:sni" [label="1. TLS SNI"];"#));
+        assert!(dot.contains(r#""method:port" [label="2. address / port"];"#));
+        assert!(dot.contains(r#""proxy" -> "method:sni" [style=dashed];"#));
+        assert!(dot.contains(r#""method:sni" -> "method:port" [style=dashed];"#));
+        assert!(!dot.contains("method:dns"));
+        // 配置的服務、地址範圍和端口表
+        assert!(dot.contains(r#""service:netflix" [label="netflix", shape=ellipse, style=bold];"#));
+        assert!(dot.contains(r#""service:youtube" [label="youtube", shape=ellipse, style=bold];"#));
+        assert!(dot.contains(r#""method:sni" -> "service:netflix" [label="domain label"];"#));
+        assert!(dot.contains(r#""ranges" -> "service:youtube" [label="173.194.0.0/16\n74.125.0.0/16"];"#));
+        assert!(dot.contains(r#""ports" -> "service:http" [label="80, 8080"];"#));
+        assert!(dot.contains(r#""ports" -> "service:streaming" [label="8000-9000"];"#));
+        assert!(dot.contains(r#""priority" [label="ip range before port", shape=diamond];"#));
+        // 名稱服務在多播/廣播之前識別
+        assert!(dot.contains(r#""packet" -> "names";"#));
+        assert!(dot.contains(r#""names" -> "service:netbios-ns";"#));
+    }
+    
+    #[test]
+    fn test_self_test_report() {
+        let stats = Arc::new(TrafficStats::new());
+        let classifier = TrafficClassifier::new(Config::default(), Arc::clone(&stats));
+        
+        for link in [LinkType::Ethernet, LinkType::LinuxSll, LinkType::LinuxSll2] {
+            let report = classifier.self_test(link);
+            assert!(report.passed(), "{}", report);
+            assert_eq!(report.cases.len(), 3);
+        }
+        assert!(stats.get_stats().is_empty());
+        
+        let report = classifier.self_test(LinkType::LinuxSll2);
+        assert_eq!(report.to_string(), "Self-test (LinuxSll2):\n  PASS HTTP: http\n  PASS HTTPS: https\n  PASS DNS: dns\n3/3 passed");
+        
+        // 配置忽略了 DNS 端口時自檢失敗
+        let config = Config { ignore_ports: vec![53], ..Config::default() };
+        let report = TrafficClassifier::new(config, Arc::new(TrafficStats::new())).self_test(LinkType::Ethernet);
+        assert!(!report.passed());
+        assert!(report.to_string().contains("FAIL DNS: expected dns, got ignored"));
+        assert!(report.to_string().ends_with("2/3 passed"));
+    }
+    
+    /// 以小端、微秒時間戳寫出經典 pcap 文件
+    fn write_pcap(path: &Path, dlt: u32, frames: &[Vec<u8>]) {
+        let mut out = Vec::new();
+        out.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+        out.extend_from_slice(&[2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        out.extend_from_slice(&65535u32.to_le_bytes());
+        out.extend_from_slice(&dlt.to_le_bytes());
+        for (i, frame) in frames.iter().enumerate() {
+            out.extend_from_slice(&(1_700_000_000 + i as u32).to_le_bytes());
+            out.extend_from_slice(&0u32.to_le_bytes());
+            out.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            out.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            out.extend_from_slice(frame);
+        }
+        std::fs::write(path, out).unwrap();
+    }
+    
+    #[test]
+    fn test_capture_timestamps_in_stats() {
+        // 納秒精度的 pcap 文件，兩個 DNS 包相隔 1.5 秒
+        let dns = udp_frame(50000, 53, b"query");
+        let mut file = Vec::new();
+        file.extend_from_slice(&0xa1b2_3c4du32.to_le_bytes());
+        file.extend_from_slice(&[2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        file.extend_from_slice(&65535u32.to_le_bytes());
+        file.extend_from_slice(&1u32.to_le_bytes());
+        for (secs, nanos) in [(1_700_000_000u32, 123_456_789u32), (1_700_000_001, 623_456_789)] {
+            file.extend_from_slice(&secs.to_le_bytes());
+            file.extend_from_slice(&nanos.to_le_bytes());
+            file.extend_from_slice(&(dns.len() as u32).to_le_bytes());
+            file.extend_from_slice(&(dns.len() as u32).to_le_bytes());
+            file.extend_from_slice(&dns);
+        }
+        
+        let stats = Arc::new(TrafficStats::new());
+        let classifier = TrafficClassifier::new(Config::default(), Arc::clone(&stats));
+        let mut source = PcapFileSource::from_reader(std::io::Cursor::new(file)).unwrap();
+        classifier.capture_from(&mut source);
+        
+        let data = &stats.get_detailed_stats()["dns"];
+        let epoch = SystemTime::UNIX_EPOCH;
+        assert_eq!(data.first_seen, epoch + Duration::new(1_700_000_000, 123_456_789));
+        assert_eq!(data.last_seen, epoch + Duration::new(1_700_000_001, 623_456_789));
+        assert_eq!(data.duration(), Duration::from_millis(1500));
+    }
+    
+    #[test]
+    fn test_evaluate_labeled_pcap() {
+        let mut frames: Vec<Vec<u8>> = probe_frames(LinkType::Ethernet).into_iter().map(|(_, _, frame)| frame).collect();
+        // HTTP 包改為發往 8080，標註為 streaming，但按端口會判為 http
+        let mut streaming = frames[0].clone();
+        streaming[36..38].copy_from_slice(&8080u16.to_be_bytes());
+        frames.push(streaming);
+        
+        let path = std::env::temp_dir().join(format!("trafficmon-eval-{}.pcap", std::process::id()));
+        write_pcap(&path, 1, &frames);
+        let labels: Vec<String> = ["http", "https", "dns", "streaming"].iter().map(|s| s.to_string()).collect();
+        
+        let stats = Arc::new(TrafficStats::new());
+        let classifier = TrafficClassifier::new(Config::default(), Arc::clone(&stats));
+        let report = classifier.evaluate(&path, &labels).unwrap();
+        assert!(stats.get_stats().is_empty());
+        
+        assert_eq!(report.packets, 4);
+        assert_eq!(report.correct, 3);
+        assert_eq!(report.accuracy(), Some(0.75));
+        assert_eq!(report.services["http"].precision(), Some(0.5));
+        assert_eq!(report.services["http"].recall(), Some(1.0));
+        assert_eq!(report.services["https"].precision(), Some(1.0));
+        assert_eq!(report.services["dns"].recall(), Some(1.0));
+        assert_eq!(report.services["streaming"].precision(), None);
+        assert_eq!(report.services["streaming"].recall(), Some(0.0));
+        assert!(report.to_string().ends_with("accuracy 75.0% (3/4)"));
+        
+        // 標註數與包數不一致
+        assert!(classifier.evaluate(&path, &labels[..3]).is_err());
+        assert!(classifier.evaluate(&path, &[labels.clone(), labels].concat()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+    
+    #[test]
+    fn test_report_tick_adapts_interval() {
+        let config = Config {
+            adaptive_report: Some(AdaptiveReportConfig {
+                min_interval: 10,
+                max_interval: 120,
+                busy_bytes_per_sec: 1_000_000.0,
+                idle_bytes_per_sec: 1_000.0,
+            }),
+            ..Config::default()
+        };
+        let stats = Arc::new(TrafficStats::new());
+        let classifier = TrafficClassifier::new(config, Arc::clone(&stats));
+        
+        // 空閒時拉長，繁忙時縮短
+        assert_eq!(classifier.report_tick(60), 120);
+        stats.add_traffic("netflix", 100_000_000, 1000);
+        assert_eq!(classifier.report_tick(60), 30);
+        
+        // 未配置時固定為 report_interval
+        assert_eq!(TrafficClassifier::new(Config::default(), Arc::new(TrafficStats::new())).report_tick(30), 60);
+    }
+}
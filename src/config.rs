@@ -1,26 +1,362 @@
-use serde::Deserialize;
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
 use std::fs;
-use std::path::Path;
+use std::io::{self, Read};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
+    /// 抓包接口；留空或設為 "auto" 時使用默認路由所在的接口
+    #[serde(default)]
     pub interface: String,
     pub report_interval: u64,
+    /// 輪詢 nft 計數的間隔（秒），不設時與 report_interval 相同
+    #[serde(default)]
+    pub nft_poll_interval: Option<u64>,
     pub log_unknown_traffic: bool,
     pub filter: Option<String>,
     pub services: Vec<ServiceConfig>,
     pub time_rules: Vec<TimeRule>,
     pub user_rules: Vec<UserRule>,
     pub blocked_domains: Vec<String>,
+    /// 按域名和查詢類型過濾 DNS 查詢
+    #[serde(default)]
+    pub dns_rules: Vec<DnsRule>,
     pub pattern_rules: Vec<PatternRule>,
+    /// 按國家代碼封鎖或放行的地址
+    #[serde(default)]
+    pub geoip_rules: Vec<GeoIpRule>,
+    /// 按國家劃分的地址列表目錄，每個國家一個 `<cc>.zone` 文件（每行一個 CIDR）
+    #[serde(default = "default_geoip_dir")]
+    pub geoip_dir: String,
+    #[serde(default)]
+    pub link_capacities: Vec<LinkCapacity>,
+    #[serde(default = "default_mtu")]
+    pub mtu: u32,
+    #[serde(default)]
+    pub estimate_offload_segments: bool,
+    /// 服務統計的字節數口徑：線路字節數，或不含封裝開銷的最內層 IP 包長度
+    #[serde(default)]
+    pub byte_accounting: ByteAccounting,
+    #[serde(default = "default_nft_family")]
+    pub nft_family: String,
+    /// trafficmon 創建和管理的 nftables 表名
+    #[serde(default = "default_nft_table")]
+    pub nft_table: String,
+    /// 掛在 forward 鉤子上的主鏈名
+    #[serde(default = "default_nft_chain")]
+    pub nft_chain: String,
+    /// 一次最多創建的 nftables 規則和集合元素總數，超出時拒絕應用
+    #[serde(default = "default_nft_max_rules")]
+    pub nft_max_rules: usize,
+    /// 計數規則的採樣分母：只計數 1/N 的包，報告時按 N 倍還原；1 表示全部計數
+    #[serde(default = "default_nft_sample_rate")]
+    pub nft_sample_rate: u32,
+    #[serde(default)]
+    pub scan_detection: Option<ScanDetectionConfig>,
+    /// 按源地址記錄 TTL，推斷系統類別並標記 TTL 大幅變化的地址
+    #[serde(default)]
+    pub ttl_tracking: Option<TtlTrackingConfig>,
+    /// 由 SNI 學習服務使用的地址，之後沒有 SNI 的加密流量也能按地址歸類
+    #[serde(default)]
+    pub sni_learning: Option<SniLearningConfig>,
+    /// 端口分類器的額外映射，優先於內置映射
+    #[serde(default)]
+    pub port_map: Vec<PortMapping>,
+    /// 內置分類之外的自定義分類名（如 backup、iot），可用於服務和 port_map 的 category
+    #[serde(default)]
+    pub custom_categories: Vec<String>,
+    /// 已知惡意地址，匹配的流量歸入 malicious 分類
+    #[serde(default)]
+    pub malicious_ips: Vec<String>,
+    /// 發現惡意流量時的處理：記錄、告警、臨時封鎖
+    #[serde(default)]
+    pub malicious_response: Option<MaliciousResponseConfig>,
+    #[serde(default)]
+    pub capture: CaptureConfig,
+    #[serde(default)]
+    pub category_limits: Vec<CategoryLimit>,
+    /// 按分類給包打 mark，供 tc 整形
+    #[serde(default)]
+    pub category_marks: Vec<CategoryMark>,
+    #[serde(default)]
+    pub adaptive_report: Option<AdaptiveReportConfig>,
+    /// 丟棄鏡像端口（SPAN）上重複出現的同一個包
+    #[serde(default)]
+    pub dedup: Option<DedupConfig>,
+    /// 流結束時輸出流記錄（JSON lines）
+    #[serde(default)]
+    pub flow_export: Option<FlowExportConfig>,
+    /// 流和會話（如代理隧道）空閒多久（秒）後結束並輸出記錄，與統計數據的保留時間無關
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// 為排查誤分類記錄每個服務前幾個包的負載
+    #[serde(default)]
+    pub payload_samples: Option<PayloadSampleConfig>,
+    /// 為排查誤分類把每個包的分類決定追加寫入文件，可用 --replay-decisions 回放
+    #[serde(default)]
+    pub decision_log: Option<DecisionLogConfig>,
+    /// 統計端點（服務或主機）之間的字節數矩陣
+    #[serde(default)]
+    pub traffic_matrix: Option<TrafficMatrixConfig>,
+    /// 把流記錄發布到 Kafka（需要 kafka 特性）
+    #[serde(default)]
+    pub kafka: Option<KafkaConfig>,
+    /// 每天定時通過 SMTP 發送流量摘要郵件
+    #[serde(default)]
+    pub email_report: Option<EmailReportConfig>,
+    /// 工作時間，配置後各服務的流量按工作時間內/外分開累計
+    #[serde(default)]
+    pub business_hours: Option<BusinessHoursConfig>,
+    /// 按本機進程歸屬流量（需要 ebpf 特性，只適用於終端上的部署）
+    #[serde(default)]
+    pub process_attribution: Option<ProcessAttributionConfig>,
+    /// 分類方法及其嘗試順序，可關閉開銷較大的方法
+    #[serde(default = "default_classification_methods")]
+    pub classification_methods: Vec<ClassificationMethod>,
+    /// 設置後各方法都參與投票，按權重之和取勝者；不設則按上面的順序取第一個結果
+    #[serde(default)]
+    pub classification_weights: Option<ClassificationWeights>,
+    /// 每個包分類的時間預算（微秒），超出後不再解析負載，改按地址和端口分類；不設則不限制
+    #[serde(default)]
+    pub classification_budget_us: Option<u64>,
+    /// 服務地址範圍和端口映射衝突時的優先級，同時決定 nftables 中的規則順序
+    #[serde(default)]
+    pub classification_priority: ClassificationPriority,
+    /// 解析明文 HTTP 請求頭時最多讀取的字節數
+    #[serde(default = "default_http_parse_bytes")]
+    pub http_parse_bytes: usize,
+    /// HTTP 代理端口：CONNECT 隧道按請求中的目標歸屬，無法識別的歸入 http-proxy
+    #[serde(default = "default_proxy_ports")]
+    pub proxy_ports: Vec<u16>,
+    /// 完全忽略的端口（源或目標端口匹配即跳過，不計入統計）
+    #[serde(default)]
+    pub ignore_ports: Vec<u16>,
+    /// 本地網段，用於判斷流量方向（目標在本地為接收，否則為發送）
+    #[serde(default = "default_local_networks")]
+    pub local_networks: Vec<String>,
+    /// 用 `nft monitor trace` 實時讀取規則命中，代替定期輪詢計數
+    #[serde(default)]
+    pub nft_monitor: bool,
+    /// nft_monitor 只跟蹤這些服務的包，其餘的包不打開 nftrace
+    #[serde(default)]
+    pub nft_trace_services: Vec<String>,
+    /// 啟動後只有計數規則生效的秒數，之後才加入封鎖規則，配置有誤時可在此期間 Ctrl+C
+    #[serde(default)]
+    pub block_grace_seconds: u64,
+    /// 重新初始化時讀出現有規則的計數，寫回同一鏈中註釋相同的新規則，重建後計數不歸零
+    #[serde(default)]
+    pub preserve_counters: bool,
+    /// 通過 SSH 輪詢計數的遠程路由器
+    #[serde(default)]
+    pub remote_hosts: Vec<RemoteHostConfig>,
+    /// 多播和廣播包不計入統計（否則歸入 multicast/broadcast 服務）
+    #[serde(default)]
+    pub exclude_multicast: bool,
+    /// LLMNR（UDP 5355）和 NetBIOS 名稱服務（UDP 137）單獨歸入 llmnr/netbios-ns，
+    /// 而不是 multicast/broadcast；關閉時按目標地址和端口照常分類
+    #[serde(default = "default_true")]
+    pub name_service_stats: bool,
+    /// 按接口統計 ARP 請求、回應和免費 ARP 數，用於發現 ARP 風暴或欺騙
+    #[serde(default)]
+    pub arp_stats: bool,
+    /// 前綴 → ASN 表文件，用於按目標 ASN 統計流量
+    #[serde(default)]
+    pub asn_table: Option<PathBuf>,
+    /// 報告中字節數的單位：binary（KiB/MiB）或 si（KB/MB）
+    #[serde(default)]
+    pub byte_units: ByteUnits,
+    /// 按分類名（web、streaming 等）自定義報告中的名稱、顏色和圖標
+    #[serde(default)]
+    pub category_styles: HashMap<String, CategoryStyle>,
+    /// 關閉時不保留歷史桶，只累計當前統計（適合內存很小的路由器）
+    #[serde(default = "default_true")]
+    pub history_enabled: bool,
+    /// 詳細統計最多合併最近多少個歷史桶，不設則合併保留期內的全部
+    #[serde(default)]
+    pub max_history_buckets: Option<usize>,
+    /// 內存中統計數據的估算上限（字節），超出時淘汰最久沒有流量的服務，不設則不限
+    #[serde(default)]
+    pub stats_memory_budget: Option<usize>,
+    /// 長連接服務（如 VoIP），輪轉後保留 first_seen，會話時長不會被重置
+    #[serde(default)]
+    pub sticky_services: Vec<String>,
+    /// 只在內部統計、不出現在 Prometheus/Grafana/JSON 等導出中的服務
+    #[serde(default)]
+    pub private_services: Vec<String>,
+    /// 導出時只保留字節數最多的服務數，其餘合併為 "__other__"，各接口內同樣處理；不設則全部導出
+    #[serde(default)]
+    pub export_top_services: Option<usize>,
+    /// 最多跟蹤的不同服務數，之後新出現的服務（如各種 other:PORT）計入 "overflow"；不設則不限
+    #[serde(default)]
+    pub max_services: Option<usize>,
+    /// 同一服務的 IPv4 和 IPv6 流量合併統計；關閉時 IPv6 記為帶 "6" 後綴的服務（如 netflix6）
+    #[serde(default = "default_true")]
+    pub merge_address_families: bool,
+    /// 按服務的流量告警：越限、恢復通知及冷卻時間
+    #[serde(default)]
+    pub service_alerts: Vec<ServiceAlertConfig>,
+    /// 定時把緩衝的統計寫入存儲的間隔（秒），不設則只在輪轉時寫入
+    #[serde(default)]
+    pub stats_flush_interval: Option<u64>,
+    /// 每次輪轉向該 Unix 域套接字的客戶端推送一行 JSON 快照，不設則不啟動
+    #[serde(default)]
+    pub stats_socket: Option<String>,
+    /// 啟動時及每個 report_interval 寫入 Prometheus 格式統計的文件（放在 node_exporter 的 textfile 目錄下，以 .prom 結尾）
+    #[serde(default)]
+    pub prometheus_textfile: Option<String>,
+    /// 每個 report_interval 輪轉一次統計，以 logfmt 追加各服務統計的文件
+    #[serde(default)]
+    pub logfmt_export: Option<LogfmtExportConfig>,
+    /// 端口分類緩存條目的有效期（秒），過期後按當前規則重新分類
+    #[serde(default = "default_classifier_cache_ttl")]
+    pub classifier_cache_ttl: u64,
+    /// 配置重新載入時如何處理端口分類緩存
+    #[serde(default)]
+    pub classifier_reload_flush: ReloadCacheFlush,
+    /// 管理 API 的監聽地址（如 "127.0.0.1:8080"），不設則不啟動
+    #[serde(default)]
+    pub api_listen: Option<String>,
+    /// 管理 API 要求的 bearer token，環境變量 TRAFFICMON_API_TOKEN 優先；都不設則不校驗
+    #[serde(default)]
+    pub api_token: Option<String>,
 }
 
+/// 按分類限制總速率（Mbps），由 nftables 的命名 limit 實現
 #[derive(Debug, Clone, Deserialize)]
+pub struct CategoryLimit {
+    pub category: String,
+    pub mbps: u64,
+}
+
+/// 分類中各服務的包在 nftables 中設置的 meta mark
+#[derive(Debug, Clone, Deserialize)]
+pub struct CategoryMark {
+    pub category: String,
+    pub mark: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CaptureConfig {
+    #[serde(default = "default_snaplen")]
+    pub snaplen: i32,
+    /// pcap 內核緩衝區大小（字節），未設置時使用 libpcap 默認值
+    #[serde(default)]
+    pub buffer_size: Option<i32>,
+    #[serde(default)]
+    pub immediate: bool,
+    #[serde(default = "default_capture_timeout")]
+    pub timeout_ms: i32,
+    /// 使用非阻塞模式，以 poll 等待包到達（最多 timeout_ms）
+    #[serde(default)]
+    pub nonblocking: bool,
+    /// 輸出 pcap 丟包統計的間隔
+    #[serde(default = "default_capture_stats_interval")]
+    pub stats_interval: u64,
+    /// 抓包線程和分類線程之間隊列的最大包數，滿時丟包
+    #[serde(default = "default_queue_capacity")]
+    pub queue_capacity: usize,
+    /// 分類線程數；同一個流（含兩個方向）總由同一線程處理，隊列容量平分給各線程
+    #[serde(default = "default_capture_workers")]
+    pub workers: usize,
+    /// 讓 libpcap 返回納秒精度的時間戳（需要網卡驅動和 libpcap 1.5 以上支持）
+    #[serde(default)]
+    pub nanosecond_timestamps: bool,
+}
+
+fn default_snaplen() -> i32 {
+    65535
+}
+
+fn default_capture_timeout() -> i32 {
+    1000
+}
+
+fn default_capture_stats_interval() -> u64 {
+    60
+}
+
+fn default_queue_capacity() -> usize {
+    4096
+}
+
+fn default_capture_workers() -> usize {
+    1
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            snaplen: default_snaplen(),
+            buffer_size: None,
+            immediate: false,
+            timeout_ms: default_capture_timeout(),
+            nonblocking: false,
+            stats_interval: default_capture_stats_interval(),
+            queue_capacity: default_queue_capacity(),
+            workers: default_capture_workers(),
+            nanosecond_timestamps: false,
+        }
+    }
+}
+
+fn default_mtu() -> u32 {
+    1500
+}
+
+fn default_nft_family() -> String {
+    "inet".to_string()
+}
+
+fn default_nft_table() -> String {
+    "trafficmon".to_string()
+}
+
+pub fn default_nft_max_rules() -> usize {
+    100_000
+}
+
+fn default_nft_sample_rate() -> u32 {
+    1
+}
+
+fn default_nft_chain() -> String {
+    "forward".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ServiceConfig {
     pub name: String,
     pub ports: Vec<u16>,
+    /// CIDR 列表；`@file:<路徑>` 項在載入時替換為文件中的地址範圍
     pub ip_ranges: Vec<String>,
     pub blocked: bool,
+    #[serde(default = "default_true")]
+    pub bidirectional: bool,
+    #[serde(default)]
+    pub category: Option<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// 端口 + 協議 → 應用名，可選指定分類（web、streaming 等）
+#[derive(Debug, Clone, Deserialize)]
+pub struct PortMapping {
+    pub port: u16,
+    #[serde(default = "default_port_protocol")]
+    pub protocol: String,
+    pub application: String,
+    #[serde(default)]
+    pub category: Option<String>,
+}
+
+fn default_port_protocol() -> String {
+    "tcp".to_string()
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -44,11 +380,621 @@ pub struct PatternRule {
     pub action: String,
 }
 
+/// 查詢 `domains` 中的域名且查詢類型屬於 `record_types` 時執行 `action`；
+/// `record_types` 為空時匹配所有類型
+#[derive(Debug, Clone, Deserialize)]
+pub struct DnsRule {
+    pub name: String,
+    pub domains: Vec<String>,
+    /// 類型名（如 "AAAA"、"TXT"）或數字
+    #[serde(default)]
+    pub record_types: Vec<String>,
+    #[serde(default = "default_geoip_action")]
+    pub action: String,
+}
+
+impl DnsRule {
+    /// 查詢類型的 QTYPE 值，有無法識別的類型時返回該類型
+    pub fn qtypes(&self) -> Result<Vec<u16>, String> {
+        self.record_types.iter()
+            .map(|name| record_type_code(name).ok_or_else(|| name.clone()))
+            .collect()
+    }
+}
+
+/// DNS 記錄類型名或數字對應的 QTYPE 值
+pub fn record_type_code(name: &str) -> Option<u16> {
+    let code = match name.to_ascii_uppercase().as_str() {
+        "A" => 1,
+        "NS" => 2,
+        "CNAME" => 5,
+        "SOA" => 6,
+        "PTR" => 12,
+        "MX" => 15,
+        "TXT" => 16,
+        "AAAA" => 28,
+        "SRV" => 33,
+        "SVCB" => 64,
+        "HTTPS" => 65,
+        "ANY" => 255,
+        other => return other.parse().ok(),
+    };
+    Some(code)
+}
+
+/// 源或目的地址屬於 `country`（ISO 3166-1 二字母代碼）時執行 `action`
+#[derive(Debug, Clone, Deserialize)]
+pub struct GeoIpRule {
+    pub country: String,
+    #[serde(default = "default_geoip_action")]
+    pub action: String,
+}
+
+fn default_geoip_action() -> String {
+    "drop".to_string()
+}
+
+fn default_geoip_dir() -> String {
+    "/etc/trafficmon/geoip".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LinkCapacity {
+    pub interface: String,
+    pub capacity_mbps: f64,
+    #[serde(default = "default_alert_threshold")]
+    pub alert_threshold: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServiceAlertConfig {
+    pub service: String,
+    /// 速率超過此值（字節/秒）視為越限
+    pub bytes_per_sec: f64,
+    /// 回落到閾值以下持續多久（秒）才發送恢復通知
+    #[serde(default = "default_alert_recovery")]
+    pub recovery_secs: u64,
+    /// 兩次越限通知之間的最短間隔（秒），避免抖動時反復告警
+    #[serde(default = "default_alert_cooldown")]
+    pub cooldown_secs: u64,
+}
+
+fn default_alert_recovery() -> u64 {
+    60
+}
+
+fn default_alert_cooldown() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScanDetectionConfig {
+    #[serde(default = "default_scan_window")]
+    pub window_secs: u64,
+    #[serde(default = "default_scan_threshold")]
+    pub port_threshold: usize,
+    #[serde(default = "default_scan_threshold")]
+    pub host_threshold: usize,
+    pub block_seconds: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TtlTrackingConfig {
+    /// 同一地址前後兩個包的 TTL 相差超過此值時視為異常
+    #[serde(default = "default_ttl_change_threshold")]
+    pub change_threshold: u8,
+    /// 最多記錄的源地址數
+    #[serde(default = "default_ttl_max_sources")]
+    pub max_sources: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SniLearningConfig {
+    /// 每個服務最多記住的地址（網段）數，超出時淘汰最久未見的
+    #[serde(default = "default_sni_learning_max_addresses")]
+    pub max_addresses: usize,
+    /// 保存學到的地址的 JSON 文件，啟動時載入；不設則只保留在內存中
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// 同時把學到的地址加入服務的 nftables 集合（<服務>_ips），需要同時開啟 require_dns_answer
+    #[serde(default)]
+    pub add_to_nftables: bool,
+    /// 只學習之前 DNS 回應中解析到 SNI 同名域名的地址（默認開啟）；SNI 由客戶端填寫，
+    /// 關閉時偽造的 SNI 可以讓任意地址被學到
+    #[serde(default = "default_true")]
+    pub require_dns_answer: bool,
+    /// 後台合併相鄰網段、清理過期地址並保存的間隔
+    #[serde(default = "default_sni_learning_compact_interval")]
+    pub compact_interval_secs: u64,
+    /// 超過這麼久沒再見到的地址在合併時丟棄，0 表示不過期
+    #[serde(default = "default_sni_learning_ttl")]
+    pub ttl_secs: u64,
+}
+
+fn default_sni_learning_max_addresses() -> usize {
+    256
+}
+
+fn default_sni_learning_compact_interval() -> u64 {
+    300
+}
+
+fn default_sni_learning_ttl() -> u64 {
+    7 * 24 * 3600
+}
+
+fn default_ttl_change_threshold() -> u8 {
+    16
+}
+
+fn default_ttl_max_sources() -> usize {
+    65536
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MaliciousResponseConfig {
+    #[serde(default = "default_true")]
+    pub log: bool,
+    #[serde(default)]
+    pub alert: bool,
+    /// 設置時把地址加入 dynamic_block 集合，超時後自動解除
+    pub block_seconds: Option<u32>,
+    /// 同一地址在此時間（秒）內只處理一次
+    #[serde(default = "default_malicious_debounce")]
+    pub debounce_secs: u64,
+}
+
+fn default_malicious_debounce() -> u64 {
+    300
+}
+
+fn default_scan_window() -> u64 {
+    60
+}
+
+fn default_scan_threshold() -> usize {
+    100
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClassificationMethod {
+    /// TLS ClientHello 中的 SNI
+    Sni,
+    /// 明文 HTTP 請求的 Host 頭
+    Http,
+    /// 之前 DNS 回應中的 IP → 域名映射
+    Dns,
+    /// 目標端口
+    Port,
+}
+
+/// 各分類方法的投票權重，缺省時 SNI 最高、端口最低
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct ClassificationWeights {
+    #[serde(default = "default_sni_weight")]
+    pub sni: f64,
+    #[serde(default = "default_http_weight")]
+    pub http: f64,
+    #[serde(default = "default_dns_weight")]
+    pub dns: f64,
+    #[serde(default = "default_port_weight")]
+    pub port: f64,
+}
+
+impl ClassificationWeights {
+    pub fn weight(&self, method: ClassificationMethod) -> f64 {
+        match method {
+            ClassificationMethod::Sni => self.sni,
+            ClassificationMethod::Http => self.http,
+            ClassificationMethod::Dns => self.dns,
+            ClassificationMethod::Port => self.port,
+        }
+    }
+}
+
+impl Default for ClassificationWeights {
+    fn default() -> Self {
+        Self {
+            sni: default_sni_weight(),
+            http: default_http_weight(),
+            dns: default_dns_weight(),
+            port: default_port_weight(),
+        }
+    }
+}
+
+fn default_sni_weight() -> f64 {
+    4.0
+}
+
+fn default_http_weight() -> f64 {
+    3.0
+}
+
+fn default_dns_weight() -> f64 {
+    2.0
+}
+
+fn default_port_weight() -> f64 {
+    1.0
+}
+
+/// 目標同時落在某服務的地址範圍內且端口有已知映射時，哪一方優先
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClassificationPriority {
+    /// 服務的 ip_ranges 優先（如 Netflix 地址上的 22 端口仍算 netflix）
+    #[default]
+    IpRange,
+    /// 端口映射優先（同上例算 ssh）
+    Port,
+}
+
+/// 計入服務統計的字節數
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ByteAccounting {
+    /// 抓包看到的線路長度，含鏈路層頭、PPPoE 和隧道外層
+    #[default]
+    Wire,
+    /// 最內層 IP 包的總長度，扣除鏈路層、PPPoE 和 GRE/IP-in-IP 封裝；非 IP 包仍按線路長度
+    Inner,
+}
+
+/// 配置重新載入後端口分類緩存的處理方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReloadCacheFlush {
+    /// 清空全部緩存
+    #[default]
+    All,
+    /// 只丟棄按新規則分類結果會改變的條目
+    Stale,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RemoteHostConfig {
+    /// SSH 目標，如 "root@192.168.1.1"
+    pub host: String,
+    /// SSH 命令及參數，目標主機和遠程命令追加在最後
+    #[serde(default = "default_ssh_command")]
+    pub ssh_command: Vec<String>,
+    /// 在遠程主機上執行的命令，輸出須為 `nft -j` 格式的 JSON
+    #[serde(default = "default_remote_nft_command")]
+    pub nft_command: String,
+}
+
+fn default_local_networks() -> Vec<String> {
+    ["10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16", "fc00::/7", "fe80::/10"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect()
+}
+
+fn default_ssh_command() -> Vec<String> {
+    ["ssh", "-o", "BatchMode=yes", "-o", "ConnectTimeout=5"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect()
+}
+
+fn default_remote_nft_command() -> String {
+    "nft -j list ruleset".to_string()
+}
+
+/// 未設置的字段使用內置默認值
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct CategoryStyle {
+    pub label: Option<String>,
+    /// 終端顏色名：red、green、yellow、blue、magenta、cyan、white
+    pub color: Option<String>,
+    pub emoji: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ByteUnits {
+    /// 1024 進制：KiB、MiB、GiB
+    #[default]
+    Binary,
+    /// 1000 進制：KB、MB、GB
+    Si,
+}
+
+impl ByteUnits {
+    /// 格式化為易讀的字節數，例如 "1.00 MiB"
+    pub fn format(self, bytes: u64) -> String {
+        let (base, units) = match self {
+            ByteUnits::Binary => (1024.0, ["KiB", "MiB", "GiB", "TiB"]),
+            ByteUnits::Si => (1000.0, ["KB", "MB", "GB", "TB"]),
+        };
+        
+        if (bytes as f64) < base {
+            return format!("{} B", bytes);
+        }
+        
+        let mut value = bytes as f64 / base;
+        let mut unit = units[0];
+        for next in &units[1..] {
+            if value < base {
+                break;
+            }
+            value /= base;
+            unit = next;
+        }
+        
+        format!("{:.2} {}", value, unit)
+    }
+}
+
+fn default_classification_methods() -> Vec<ClassificationMethod> {
+    vec![
+        ClassificationMethod::Sni,
+        ClassificationMethod::Http,
+        ClassificationMethod::Dns,
+        ClassificationMethod::Port,
+    ]
+}
+
+fn default_http_parse_bytes() -> usize {
+    1024
+}
+
+fn default_proxy_ports() -> Vec<u16> {
+    vec![3128, 8080]
+}
+
+fn default_classifier_cache_ttl() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DedupConfig {
+    /// 同一個包的兩份拷貝之間最多相隔多久（毫秒）
+    #[serde(default = "default_dedup_window")]
+    pub window_ms: u64,
+    /// 最多記住多少個最近的包
+    #[serde(default = "default_dedup_entries")]
+    pub max_entries: usize,
+}
+
+fn default_idle_timeout() -> u64 {
+    120
+}
+
+fn default_dedup_window() -> u64 {
+    5
+}
+
+fn default_dedup_entries() -> usize {
+    65536
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FlowExportConfig {
+    /// 流空閒多久視為結束，不設則使用 idle_timeout
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct KafkaConfig {
+    /// 逗號分隔的 broker 地址
+    pub brokers: String,
+    pub topic: String,
+    #[serde(default = "default_kafka_batch_size")]
+    pub batch_size: usize,
+    /// broker 不可用時最多緩存的事件數，超出後丟棄最舊的
+    #[serde(default = "default_kafka_buffer_capacity")]
+    pub buffer_capacity: usize,
+}
+
+fn default_kafka_batch_size() -> usize {
+    100
+}
+
+fn default_kafka_buffer_capacity() -> usize {
+    10_000
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProcessAttributionConfig {
+    /// 外部 eBPF 程序固定的五元組 → PID 映射
+    #[serde(default = "default_socket_pid_map")]
+    pub pinned_map: String,
+}
+
+fn default_socket_pid_map() -> String {
+    "/sys/fs/bpf/trafficmon/socket_pids".to_string()
+}
+
+/// 與 time_rules 相同的 HH:MM 時段，結束早於開始時跨過午夜
+#[derive(Debug, Clone, Deserialize)]
+pub struct BusinessHoursConfig {
+    pub start_time: String,
+    pub end_time: String,
+    /// 工作日（mon、tue 等），跨午夜的時段按開始那天算
+    #[serde(default = "default_business_days")]
+    pub weekdays: Vec<String>,
+    /// 判斷時段用的時區，如 "+08:00"；不設則用系統本地時區
+    #[serde(default)]
+    pub utc_offset: Option<String>,
+}
+
+fn default_business_days() -> Vec<String> {
+    ["mon", "tue", "wed", "thu", "fri"].iter().map(|day| day.to_string()).collect()
+}
+
+/// 每日摘要郵件；只支持明文 SMTP（不做 STARTTLS 和認證），適合發給本機或局域網內的中繼
+#[derive(Debug, Clone, Deserialize)]
+pub struct EmailReportConfig {
+    pub smtp_host: String,
+    #[serde(default = "default_smtp_port")]
+    pub smtp_port: u16,
+    pub from: String,
+    pub to: Vec<String>,
+    /// 每天發送的本地時間，HH:MM
+    #[serde(default = "default_email_send_at")]
+    pub send_at: String,
+    #[serde(default = "default_email_subject")]
+    pub subject: String,
+    /// 摘要中列出的服務數和主機數
+    #[serde(default = "default_email_top_n")]
+    pub top_n: usize,
+}
+
+fn default_smtp_port() -> u16 {
+    25
+}
+
+fn default_email_send_at() -> String {
+    "08:00".to_string()
+}
+
+fn default_email_subject() -> String {
+    "trafficmon daily summary".to_string()
+}
+
+fn default_email_top_n() -> usize {
+    5
+}
+
+/// 流量矩陣的端點聚合：不屬於任何服務的地址按前綴合併，控制矩陣大小
+#[derive(Debug, Clone, Deserialize)]
+pub struct TrafficMatrixConfig {
+    #[serde(default = "default_matrix_ipv4_prefix")]
+    pub ipv4_prefix: u8,
+    #[serde(default = "default_matrix_ipv6_prefix")]
+    pub ipv6_prefix: u8,
+    /// 最多記錄的端點對，超出的流量計入 ("other", "other")
+    #[serde(default = "default_matrix_max_pairs")]
+    pub max_pairs: usize,
+}
+
+fn default_matrix_ipv4_prefix() -> u8 {
+    24
+}
+
+fn default_matrix_ipv6_prefix() -> u8 {
+    64
+}
+
+fn default_matrix_max_pairs() -> usize {
+    4096
+}
+
+/// 負載採樣的上限，總內存不超過 max_services × packets × bytes
+#[derive(Debug, Clone, Deserialize)]
+pub struct PayloadSampleConfig {
+    /// 每個包保留的負載字節數
+    #[serde(default = "default_sample_bytes")]
+    pub bytes: usize,
+    /// 每個服務採樣的包數
+    #[serde(default = "default_sample_packets")]
+    pub packets: usize,
+    /// 最多採樣的服務數
+    #[serde(default = "default_sample_services")]
+    pub max_services: usize,
+    /// 只記錄負載長度，不保留內容；日誌中的地址和端口也隱去
+    #[serde(default)]
+    pub redact: bool,
+}
+
+fn default_sample_bytes() -> usize {
+    64
+}
+
+fn default_sample_packets() -> usize {
+    5
+}
+
+fn default_sample_services() -> usize {
+    32
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DecisionLogConfig {
+    pub path: PathBuf,
+    /// 文件超過這個大小（字節）後輪轉為 `<path>.1`
+    #[serde(default = "default_log_max_bytes")]
+    pub max_bytes: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LogfmtExportConfig {
+    pub path: PathBuf,
+    /// 文件超過這個大小（字節）後輪轉為 `<path>.1`
+    #[serde(default = "default_log_max_bytes")]
+    pub max_bytes: u64,
+}
+
+fn default_log_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+/// 按流量大小自動調整報告間隔：繁忙時縮短，空閒時拉長
+#[derive(Debug, Clone, Deserialize)]
+pub struct AdaptiveReportConfig {
+    #[serde(default = "default_min_report_interval")]
+    pub min_interval: u64,
+    #[serde(default = "default_max_report_interval")]
+    pub max_interval: u64,
+    /// 超過此速率（字節/秒）時縮短間隔
+    pub busy_bytes_per_sec: f64,
+    /// 低於此速率時拉長間隔
+    #[serde(default)]
+    pub idle_bytes_per_sec: f64,
+}
+
+fn default_min_report_interval() -> u64 {
+    5
+}
+
+fn default_max_report_interval() -> u64 {
+    300
+}
+
+impl AdaptiveReportConfig {
+    /// 根據當前速率計算下一次的報告間隔，每次最多減半或加倍
+    pub fn next_interval(&self, current: u64, bytes_per_sec: f64) -> u64 {
+        let next = if bytes_per_sec > self.busy_bytes_per_sec {
+            current / 2
+        } else if bytes_per_sec <= self.idle_bytes_per_sec {
+            current.saturating_mul(2)
+        } else {
+            current
+        };
+        
+        next.clamp(self.min_interval, self.max_interval.max(self.min_interval))
+    }
+}
+
+fn default_alert_threshold() -> f64 {
+    90.0
+}
+
+impl LinkCapacity {
+    /// 以百分比表示的鏈路使用率
+    pub fn utilization_percent(&self, bytes_per_sec: f64) -> f64 {
+        if self.capacity_mbps <= 0.0 {
+            return 0.0;
+        }
+        
+        bytes_per_sec * 8.0 / (self.capacity_mbps * 1_000_000.0) * 100.0
+    }
+    
+    pub fn is_saturated(&self, utilization_percent: f64) -> bool {
+        utilization_percent >= self.alert_threshold
+    }
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
             interface: "br-lan".to_string(),
             report_interval: 60,
+            nft_poll_interval: None,
             log_unknown_traffic: false,
             filter: Some("tcp or udp".to_string()),
             services: vec![
@@ -60,6 +1006,8 @@ impl Default for Config {
                         "198.38.96.0/19".to_string(),
                     ],
                     blocked: false,
+                    bidirectional: true,
+                    category: Some("streaming".to_string()),
                 },
                 ServiceConfig {
                     name: "youtube".to_string(),
@@ -69,6 +1017,8 @@ impl Default for Config {
                         "74.125.0.0/16".to_string(),
                     ],
                     blocked: false,
+                    bidirectional: true,
+                    category: Some("streaming".to_string()),
                 },
             ],
             time_rules: vec![],
@@ -84,25 +1034,795 @@ impl Default for Config {
                     action: "drop".to_string(),
                 },
             ],
+            dns_rules: vec![],
+            geoip_rules: vec![],
+            geoip_dir: default_geoip_dir(),
+            link_capacities: vec![],
+            mtu: default_mtu(),
+            estimate_offload_segments: false,
+            byte_accounting: ByteAccounting::default(),
+            nft_family: default_nft_family(),
+            nft_table: default_nft_table(),
+            nft_chain: default_nft_chain(),
+            nft_max_rules: default_nft_max_rules(),
+            nft_sample_rate: default_nft_sample_rate(),
+            scan_detection: None,
+            ttl_tracking: None,
+            sni_learning: None,
+            port_map: vec![],
+            custom_categories: vec![],
+            malicious_ips: vec![],
+            malicious_response: None,
+            capture: CaptureConfig::default(),
+            category_limits: vec![],
+            category_marks: vec![],
+            adaptive_report: None,
+            dedup: None,
+            flow_export: None,
+            idle_timeout: default_idle_timeout(),
+            payload_samples: None,
+            decision_log: None,
+            traffic_matrix: None,
+            kafka: None,
+            email_report: None,
+            business_hours: None,
+            process_attribution: None,
+            classification_methods: default_classification_methods(),
+            classification_weights: None,
+            classification_budget_us: None,
+            classification_priority: ClassificationPriority::default(),
+            http_parse_bytes: default_http_parse_bytes(),
+            proxy_ports: default_proxy_ports(),
+            ignore_ports: vec![],
+            exclude_multicast: false,
+            name_service_stats: true,
+            arp_stats: false,
+            local_networks: default_local_networks(),
+            nft_monitor: false,
+            nft_trace_services: vec![],
+            block_grace_seconds: 0,
+            preserve_counters: false,
+            remote_hosts: vec![],
+            asn_table: None,
+            byte_units: ByteUnits::default(),
+            category_styles: HashMap::new(),
+            history_enabled: true,
+            max_history_buckets: None,
+            stats_memory_budget: None,
+            sticky_services: vec![],
+            private_services: vec![],
+            export_top_services: None,
+            max_services: None,
+            merge_address_families: true,
+            service_alerts: vec![],
+            stats_flush_interval: None,
+            stats_socket: None,
+            prometheus_textfile: None,
+            logfmt_export: None,
+            classifier_cache_ttl: default_classifier_cache_ttl(),
+            classifier_reload_flush: ReloadCacheFlush::default(),
+            api_listen: None,
+            api_token: None,
         }
     }
 }
 
 impl Config {
+    /// 下一次報告間隔；未開啟自適應時固定為 report_interval
+    pub fn next_report_interval(&self, current: u64, bytes_per_sec: f64) -> u64 {
+        match &self.adaptive_report {
+            Some(adaptive) => adaptive.next_interval(current, bytes_per_sec),
+            None => self.report_interval,
+        }
+    }
+    
+    /// 管理 API 實際使用的 token；空或只有空白的值視為未設置，環境變量為空時回退到配置
+    pub fn api_bearer_token(&self) -> Option<String> {
+        let is_set = |token: &String| !token.trim().is_empty();
+        std::env::var("TRAFFICMON_API_TOKEN").ok()
+            .filter(is_set)
+            .or_else(|| self.api_token.clone().filter(is_set))
+    }
+    
+    /// nft 計數的輪詢間隔（秒）
+    pub fn poll_interval(&self) -> u64 {
+        self.nft_poll_interval.unwrap_or(self.report_interval)
+    }
+    
     pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
-        let config_paths = vec![
+        if let Some(path) = Self::find_path() {
+            return Self::from_file(path);
+        }
+        
+        eprintln!("No config file found, using defaults");
+        Ok(Config::default())
+    }
+    
+    /// `load` 讀取的配置文件，都不存在時為 None
+    pub fn find_path() -> Option<&'static Path> {
+        [
             "/etc/config/trafficmon.conf",
             "./config/trafficmon.conf",
-        ];
+        ]
+        .into_iter()
+        .map(Path::new)
+        .find(|path| path.exists())
+    }
+    
+    /// 讀取配置文件（支持 gzip），並合併 `include` 列出的文件；
+    /// 每次讀取都重新載入 `@file:` 引用的地址範圍
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let value = load_toml_value(path, &mut Vec::new())?;
+        let mut config: Config = value.try_into()?;
+        config.expand_range_files(path.parent().unwrap_or_else(|| Path::new(".")))?;
+        Ok(config)
+    }
+    
+    /// 把服務 `ip_ranges` 中的 `@file:` 項替換為文件中的 CIDR，相對路徑以 `base_dir` 為準
+    /// （`include` 的文件中的引用也是）；文件中有無效 CIDR 時報錯
+    pub fn expand_range_files(&mut self, base_dir: &Path) -> Result<(), Box<dyn Error>> {
+        for service in &mut self.services {
+            let mut ranges = Vec::with_capacity(service.ip_ranges.len());
+            for range in std::mem::take(&mut service.ip_ranges) {
+                match range.strip_prefix(RANGE_FILE_PREFIX) {
+                    Some(file) => {
+                        let loaded = load_range_file(&base_dir.join(file.trim()))
+                            .map_err(|e| format!("services.{}: {}", service.name, e))?;
+                        ranges.extend(loaded);
+                    }
+                    None => ranges.push(range),
+                }
+            }
+            service.ip_ranges = ranges;
+        }
+        Ok(())
+    }
+    
+    /// 由 MTU 推算的 TCP MSS（去掉 IPv4 + TCP 頭）
+    pub fn mss(&self) -> u32 {
+        self.mtu.saturating_sub(40).max(1)
+    }
+    
+    /// 屬於指定分類的服務（分類名不區分大小寫）
+    pub fn services_in_category(&self, category: &str) -> Vec<&ServiceConfig> {
+        self.services.iter()
+            .filter(|s| s.category.as_deref().is_some_and(|c| c.eq_ignore_ascii_case(category)))
+            .collect()
+    }
+    
+    pub fn link_capacity(&self, interface: &str) -> Option<&LinkCapacity> {
+        self.link_capacities.iter().find(|l| l.interface == interface)
+    }
+    
+    /// 檢查配置中各項的格式，返回所有錯誤（為空表示通過）
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        
+        if self.nft_poll_interval == Some(0) {
+            errors.push("nft_poll_interval: must be greater than 0".to_string());
+        }
+        if let Some(weights) = &self.classification_weights {
+            let all = [weights.sni, weights.http, weights.dns, weights.port];
+            if all.iter().any(|w| !w.is_finite() || *w < 0.0) {
+                errors.push("classification_weights: weights must be non-negative numbers".to_string());
+            }
+        }
+        if self.max_history_buckets == Some(0) {
+            errors.push("max_history_buckets: must be greater than 0".to_string());
+        }
+        if self.export_top_services == Some(0) {
+            errors.push("export_top_services: must be greater than 0".to_string());
+        }
+        if self.sni_learning.as_ref().is_some_and(|c| c.max_addresses == 0) {
+            errors.push("sni_learning.max_addresses: must be greater than 0".to_string());
+        }
+        if self.sni_learning.as_ref().is_some_and(|c| c.compact_interval_secs == 0) {
+            errors.push("sni_learning.compact_interval_secs: must be greater than 0".to_string());
+        }
+        if self.sni_learning.as_ref().is_some_and(|c| c.add_to_nftables && !c.require_dns_answer) {
+            errors.push("sni_learning.add_to_nftables: requires require_dns_answer, a forged SNI could add any address".to_string());
+        }
+        if self.max_services == Some(0) {
+            errors.push("max_services: must be greater than 0".to_string());
+        }
+        if self.classification_budget_us == Some(0) {
+            errors.push("classification_budget_us: must be greater than 0".to_string());
+        }
+        if self.idle_timeout == 0 {
+            errors.push("idle_timeout: must be greater than 0".to_string());
+        }
+        if self.nft_sample_rate == 0 {
+            errors.push("nft_sample_rate: must be greater than 0".to_string());
+        }
+        
+        if self.stats_flush_interval == Some(0) {
+            errors.push("stats_flush_interval: must be greater than 0".to_string());
+        }
+        
+        if !matches!(self.nft_family.as_str(), "ip" | "ip6" | "inet") {
+            errors.push(format!("nft_family: unsupported family {:?}", self.nft_family));
+        }
         
-        for path in config_paths {
-            if Path::new(path).exists() {
-                let content = fs::read_to_string(path)?;
-                return Ok(toml::from_str(&content)?);
+        for (field, name) in [("nft_table", &self.nft_table), ("nft_chain", &self.nft_chain)] {
+            if !is_valid_nft_identifier(name) {
+                errors.push(format!("{}: invalid nftables identifier {:?}", field, name));
             }
         }
         
-        println!("No config file found, using defaults");
-        Ok(Config::default())
+        for network in &self.local_networks {
+            if let Err(e) = parse_cidr(network) {
+                errors.push(format!("local_networks: {}", e));
+            }
+        }
+        
+        for mapping in &self.port_map {
+            if !matches!(mapping.protocol.as_str(), "tcp" | "udp") {
+                errors.push(format!("port_map.{}: unsupported protocol {:?}", mapping.port, mapping.protocol));
+            }
+        }
+        
+        for ip in &self.malicious_ips {
+            if ip.parse::<IpAddr>().is_err() {
+                errors.push(format!("malicious_ips: invalid address {:?}", ip));
+            }
+        }
+        
+        if let Some(filter) = &self.filter {
+            if let Err(e) = compile_filter(filter) {
+                errors.push(format!("filter {:?}: {}", filter, e));
+            }
+        }
+        
+        for service in &self.services {
+            for range in &service.ip_ranges {
+                if let Err(e) = parse_cidr(range) {
+                    errors.push(format!("services.{}: {}", service.name, e));
+                }
+            }
+        }
+        
+        for rule in &self.time_rules {
+            for time in [&rule.start_time, &rule.end_time] {
+                if !is_valid_time(time) {
+                    errors.push(format!("time_rules: invalid time {:?}, expected HH:MM", time));
+                }
+            }
+        }
+        
+        for rule in &self.user_rules {
+            if !is_valid_mac(&rule.mac_address) {
+                errors.push(format!("user_rules.{}: invalid MAC address {:?}", rule.name, rule.mac_address));
+            }
+        }
+        
+        for rule in &self.pattern_rules {
+            if !matches!(rule.action.as_str(), "accept" | "drop" | "reject" | "counter") {
+                errors.push(format!("pattern_rules.{}: unknown action {:?}", rule.name, rule.action));
+            }
+        }
+        
+        for (index, name) in self.custom_categories.iter().enumerate() {
+            if name.trim().is_empty() {
+                errors.push("custom_categories: empty category name".to_string());
+            } else if self.custom_categories[..index].iter().any(|n| n.eq_ignore_ascii_case(name)) {
+                errors.push(format!("custom_categories: duplicate category {:?}", name));
+            }
+        }
+        
+        for rule in &self.dns_rules {
+            if !is_valid_nft_identifier(&rule.name) {
+                errors.push(format!("dns_rules: invalid nftables identifier {:?}", rule.name));
+            }
+            if rule.domains.is_empty() {
+                errors.push(format!("dns_rules.{}: no domains", rule.name));
+            }
+            if let Err(record_type) = rule.qtypes() {
+                errors.push(format!("dns_rules.{}: unknown record type {:?}", rule.name, record_type));
+            }
+            if !matches!(rule.action.as_str(), "accept" | "drop" | "reject") {
+                errors.push(format!("dns_rules.{}: unknown action {:?}", rule.name, rule.action));
+            }
+        }
+        
+        for rule in &self.geoip_rules {
+            if rule.country.len() != 2 || !rule.country.chars().all(|c| c.is_ascii_alphabetic()) {
+                errors.push(format!("geoip_rules: invalid country code {:?}", rule.country));
+            }
+            if !matches!(rule.action.as_str(), "accept" | "drop" | "reject") {
+                errors.push(format!("geoip_rules.{}: unknown action {:?}", rule.country, rule.action));
+            }
+        }
+        
+        for (index, alert) in self.service_alerts.iter().enumerate() {
+            if alert.bytes_per_sec.is_nan() || alert.bytes_per_sec <= 0.0 {
+                errors.push(format!("service_alerts.{}: bytes_per_sec must be greater than 0", alert.service));
+            }
+            if self.service_alerts[..index].iter().any(|a| a.service == alert.service) {
+                errors.push(format!("service_alerts: duplicate service {:?}", alert.service));
+            }
+        }
+        
+        if let Some(email) = &self.email_report {
+            if !is_valid_time(&email.send_at) {
+                errors.push(format!("email_report: invalid send_at {:?}, expected HH:MM", email.send_at));
+            }
+            if email.to.is_empty() {
+                errors.push("email_report: no recipients in to".to_string());
+            }
+        }
+        
+        if let Some(hours) = &self.business_hours {
+            for time in [&hours.start_time, &hours.end_time] {
+                if !is_valid_time(time) {
+                    errors.push(format!("business_hours: invalid time {:?}, expected HH:MM", time));
+                }
+            }
+            for day in &hours.weekdays {
+                if day.parse::<chrono::Weekday>().is_err() {
+                    errors.push(format!("business_hours: unknown weekday {:?}", day));
+                }
+            }
+            if let Some(offset) = &hours.utc_offset {
+                if offset.parse::<chrono::FixedOffset>().is_err() {
+                    errors.push(format!("business_hours: invalid utc_offset {:?}, expected e.g. \"+08:00\"", offset));
+                }
+            }
+        }
+        
+        for limit in &self.category_limits {
+            if self.services_in_category(&limit.category).is_empty() {
+                errors.push(format!("category_limits: no service in category {:?}", limit.category));
+            }
+        }
+        
+        let mut marked = HashSet::new();
+        for category_mark in &self.category_marks {
+            if self.services_in_category(&category_mark.category).is_empty() {
+                errors.push(format!("category_marks: no service in category {:?}", category_mark.category));
+            }
+            if !marked.insert(category_mark.category.to_lowercase()) {
+                errors.push(format!("category_marks: duplicate category {:?}", category_mark.category));
+            }
+        }
+        
+        errors
     }
-}
\ No newline at end of file
+    
+    /// 添加服務，同名服務直接替換；返回是否替換了已有服務
+    pub fn upsert_service(&mut self, service: ServiceConfig) -> bool {
+        match self.services.iter_mut().find(|s| s.name == service.name) {
+            Some(existing) => {
+                *existing = service;
+                true
+            }
+            None => {
+                self.services.push(service);
+                false
+            }
+        }
+    }
+    
+    /// 解析後的本地網段，忽略格式錯誤的項（由 validate 報告）
+    pub fn parsed_local_networks(&self) -> Vec<(IpAddr, u8)> {
+        self.local_networks.iter()
+            .filter_map(|network| parse_cidr(network).ok())
+            .collect()
+    }
+    
+    /// 抓包網卡上 IPv4 地址所在的子網加上配置的 local_networks；
+    /// 網卡沒有可用的 IPv4 地址（或無法列出網卡）時只用配置，並給出提示
+    pub fn detect_local_networks(&self) -> Vec<(IpAddr, u8)> {
+        let interface = self.resolve_interface().unwrap_or_else(|_| self.interface.clone());
+        let addresses = pcap::Device::list()
+            .map(|devices| {
+                devices.into_iter()
+                    .find(|device| device.name == interface)
+                    .map(|device| device.addresses)
+                    .unwrap_or_default()
+            })
+            .unwrap_or_default();
+        self.local_networks_for(&interface, &addresses)
+    }
+    
+    /// 按給定的網卡地址計算本地網段，見 `detect_local_networks`
+    pub fn local_networks_for(&self, interface: &str, addresses: &[pcap::Address]) -> Vec<(IpAddr, u8)> {
+        let subnets: Vec<(IpAddr, u8)> = addresses.iter()
+            .filter_map(|address| match (address.addr, address.netmask) {
+                (IpAddr::V4(addr), Some(IpAddr::V4(netmask))) if !addr.is_loopback() && !addr.is_unspecified() => {
+                    let mask = u32::from(netmask);
+                    // 只接受連續的掩碼
+                    (mask.leading_ones() == mask.count_ones()).then(|| (IpAddr::V4(addr), mask.count_ones() as u8))
+                }
+                _ => None,
+            })
+            .collect();
+        
+        if subnets.is_empty() {
+            eprintln!(
+                "Interface {} has no usable IPv4 address; using configured local_networks for traffic direction",
+                interface
+            );
+        }
+        
+        let mut networks = subnets;
+        for network in self.parsed_local_networks() {
+            if !networks.contains(&network) {
+                networks.push(network);
+            }
+        }
+        networks
+    }
+    
+    /// 實際使用的抓包接口
+    pub fn resolve_interface(&self) -> Result<String, Box<dyn Error>> {
+        match self.interface.as_str() {
+            "" | "auto" => {
+                let table = fs::read_to_string("/proc/net/route")
+                    .map_err(|e| format!("Cannot read /proc/net/route: {}", e))?;
+                default_route_interface(&table)
+                    .ok_or_else(|| "No default route found; set `interface` in the config".into())
+            }
+            name => Ok(name.to_string()),
+        }
+    }
+}
+
+/// 解析 "地址/前綴長度" 或單個地址
+pub(crate) fn parse_cidr(range: &str) -> Result<(IpAddr, u8), String> {
+    let (addr, prefix) = match range.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (range, None),
+    };
+    
+    let addr: IpAddr = addr.parse().map_err(|_| format!("invalid address in {:?}", range))?;
+    let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(p) => p.parse::<u8>().ok().filter(|p| *p <= max_prefix)
+            .ok_or_else(|| format!("invalid prefix length in {:?}", range))?,
+        None => max_prefix,
+    };
+    
+    Ok((addr, prefix))
+}
+
+/// 地址是否落在 `network` 網段內；地址族不同時不匹配
+pub(crate) fn cidr_contains(network: (IpAddr, u8), addr: IpAddr) -> bool {
+    let (base, prefix) = network;
+    base.is_ipv4() == addr.is_ipv4() && network_address(base, prefix) == network_address(addr, prefix)
+}
+
+/// 只保留地址的前 `prefix` 位，即地址所在網段的網絡地址
+pub(crate) fn network_address(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => IpAddr::V4((u32::from(addr) & prefix_mask_v4(prefix)).into()),
+        IpAddr::V6(addr) => IpAddr::V6((u128::from(addr) & prefix_mask_v6(prefix)).into()),
+    }
+}
+
+pub(crate) fn prefix_mask_v4(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+}
+
+pub(crate) fn prefix_mask_v6(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)
+}
+
+fn is_valid_mac(mac: &str) -> bool {
+    let parts: Vec<&str> = mac.split(':').collect();
+    parts.len() == 6 && parts.iter().all(|p| p.len() == 2 && p.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// nft 集合、表、鏈名的最大長度（NFT_NAME_MAXLEN 含結尾 0）
+const NFT_NAME_MAX_LEN: usize = 255;
+
+/// nft 標識符：字母、`_` 或 `.` 開頭，後接字母、數字、`/`、`-`、`_`、`.`
+pub fn is_valid_nft_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    let valid_first = chars.next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '.');
+    
+    valid_first
+        && name.len() <= NFT_NAME_MAX_LEN
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '-' | '_' | '.'))
+}
+
+fn is_valid_time(time: &str) -> bool {
+    chrono::NaiveTime::parse_from_str(time, "%H:%M").is_ok()
+}
+
+/// 用 libpcap 編譯 BPF 過濾表達式，不需要打開網卡
+fn compile_filter(filter: &str) -> Result<(), pcap::Error> {
+    let cap = pcap::Capture::dead(pcap::Linktype::ETHERNET)?;
+    cap.compile(filter, true).map(|_| ())
+}
+
+/// 從 /proc/net/route 的內容中找出默認路由（目標和掩碼均為 0）的接口
+pub fn default_route_interface(route_table: &str) -> Option<String> {
+    route_table.lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|fields| fields.len() >= 8 && fields[1] == "00000000" && fields[7] == "00000000")
+        .map(|fields| fields[0].to_string())
+}
+
+/// 把服務寫回配置文件（同名則替換）；文件按 TOML 重新生成，註釋不會保留，
+/// `include` 的文件不受影響
+pub fn save_service(path: &Path, service: &ServiceConfig) -> Result<(), Box<dyn Error>> {
+    let mut value: toml::Value = toml::from_str(&read_config_file(path)?)?;
+    let table = value.as_table_mut().ok_or("Config root must be a table")?;
+    let services = table.entry("services")
+        .or_insert_with(|| toml::Value::Array(Vec::new()))
+        .as_array_mut()
+        .ok_or("`services` must be an array")?;
+    
+    let entry = toml::Value::try_from(service)?;
+    match services.iter_mut().find(|s| s.get("name").and_then(|n| n.as_str()) == Some(&service.name)) {
+        Some(existing) => *existing = entry,
+        None => services.push(entry),
+    }
+    
+    fs::write(path, toml::to_string(&value)?)?;
+    Ok(())
+}
+
+/// `ip_ranges` 中從文件載入地址範圍的前綴
+pub const RANGE_FILE_PREFIX: &str = "@file:";
+
+/// 每行一個 CIDR，忽略空行和 # 開頭的註釋行
+fn load_range_file(path: &Path) -> Result<Vec<String>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    let mut ranges = Vec::new();
+    
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        parse_cidr(line).map_err(|e| format!("{} line {}: {}", path.display(), lineno + 1, e))?;
+        ranges.push(line.to_string());
+    }
+    
+    Ok(ranges)
+}
+
+fn read_config_file(path: &Path) -> io::Result<String> {
+    let bytes = fs::read(path)?;
+    
+    // gzip 魔數
+    if bytes.starts_with(&[0x1f, 0x8b]) {
+        let mut content = String::new();
+        GzDecoder::new(&bytes[..]).read_to_string(&mut content)?;
+        return Ok(content);
+    }
+    
+    String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn load_toml_value(path: &Path, stack: &mut Vec<PathBuf>) -> Result<toml::Value, Box<dyn Error>> {
+    let canonical = fs::canonicalize(path)
+        .map_err(|e| format!("Cannot read config {}: {}", path.display(), e))?;
+    if stack.contains(&canonical) {
+        return Err(format!("Config include cycle detected at {}", path.display()).into());
+    }
+    stack.push(canonical);
+    
+    let mut value: toml::Value = toml::from_str(&read_config_file(path)?)?;
+    let includes = match value.as_table_mut().and_then(|table| table.remove("include")) {
+        Some(toml::Value::Array(items)) => items,
+        Some(_) => return Err("`include` must be an array of file names".into()),
+        None => Vec::new(),
+    };
+    
+    // 被包含的文件先合併，當前文件的值最後覆蓋
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut merged = toml::Value::Table(toml::value::Table::new());
+    for include in includes {
+        let name = include.as_str().ok_or("`include` entries must be strings")?;
+        merge_toml(&mut merged, load_toml_value(&base_dir.join(name), stack)?);
+    }
+    merge_toml(&mut merged, value);
+    
+    stack.pop();
+    Ok(merged)
+}
+
+/// 表格遞歸合併，數組拼接，其他值直接覆蓋
+fn merge_toml(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_toml(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (toml::Value::Array(base), toml::Value::Array(overlay)) => base.extend(overlay),
+        (base, overlay) => *base = overlay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_link_utilization() {
+        let link = LinkCapacity {
+            interface: "eth0".to_string(),
+            capacity_mbps: 100.0,
+            alert_threshold: 80.0,
+        };
+        
+        // 10 MB/s = 80 Mbps，即 100 Mbps 鏈路的 80%
+        let utilization = link.utilization_percent(10_000_000.0);
+        assert!((utilization - 80.0).abs() < 1e-9);
+        assert!(link.is_saturated(utilization));
+        assert!(!link.is_saturated(link.utilization_percent(5_000_000.0)));
+    }
+    
+    #[test]
+    fn test_adaptive_report_interval() {
+        let mut config = Config::default();
+        assert_eq!(config.next_report_interval(60, 1e9), 60);
+        
+        config.adaptive_report = Some(AdaptiveReportConfig {
+            min_interval: 10,
+            max_interval: 120,
+            busy_bytes_per_sec: 1_000_000.0,
+            idle_bytes_per_sec: 1_000.0,
+        });
+        
+        // 突發流量逐步縮短到下限
+        let mut interval = 60;
+        interval = config.next_report_interval(interval, 5_000_000.0);
+        assert_eq!(interval, 30);
+        interval = config.next_report_interval(interval, 5_000_000.0);
+        interval = config.next_report_interval(interval, 5_000_000.0);
+        assert_eq!(interval, 10);
+        
+        // 中等流量保持不變
+        assert_eq!(config.next_report_interval(interval, 50_000.0), 10);
+        
+        // 空閒時逐步拉長到上限
+        for _ in 0..10 {
+            interval = config.next_report_interval(interval, 0.0);
+        }
+        assert_eq!(interval, 120);
+    }
+    
+    #[test]
+    fn test_default_route_interface() {
+        let route = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+                     br-lan\t0001A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0\n\
+                     pppoe-wan\t00000000\t0100000A\t0003\t0\t0\t0\t00000000\t0\t0\t0\n";
+        assert_eq!(default_route_interface(route), Some("pppoe-wan".to_string()));
+        
+        let no_default = route.lines().take(2).collect::<Vec<_>>().join("\n");
+        assert_eq!(default_route_interface(&no_default), None);
+        
+        let config = Config { interface: "eth1".to_string(), ..Config::default() };
+        assert_eq!(config.resolve_interface().unwrap(), "eth1");
+    }
+    
+    #[test]
+    fn test_validate_reports_all_errors() {
+        assert!(Config::default().validate().is_empty());
+        
+        let mut config = Config::default();
+        config.services[0].ip_ranges.push("10.0.0.0/33".to_string());
+        config.user_rules.push(UserRule {
+            mac_address: "00:11:22:33:44".to_string(),
+            name: "kid".to_string(),
+            blocked_services: vec![],
+        });
+        config.pattern_rules[0].action = "block".to_string();
+        
+        let errors = config.validate();
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(errors[0].contains("10.0.0.0/33"));
+        assert!(errors[1].contains("MAC"));
+        assert!(errors[2].contains("block"));
+        
+        // 不合併任何歷史桶時詳細統計總為空
+        let config = Config { max_history_buckets: Some(0), ..Config::default() };
+        assert_eq!(config.validate(), ["max_history_buckets: must be greater than 0"]);
+    }
+    
+    #[test]
+    fn test_sni_learning_requires_dns_answer() {
+        let mut config: Config = toml::from_str(r#"
+            interface = "eth0"
+            report_interval = 60
+            log_unknown_traffic = false
+            services = []
+            time_rules = []
+            user_rules = []
+            blocked_domains = []
+            pattern_rules = []
+            [sni_learning]
+            add_to_nftables = true
+        "#).unwrap();
+        assert!(config.sni_learning.as_ref().unwrap().require_dns_answer);
+        assert!(config.validate().is_empty());
+        
+        // 沒有 DNS 佐證的地址不能進入 nftables 集合
+        config.sni_learning.as_mut().unwrap().require_dns_answer = false;
+        let errors = config.validate();
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].starts_with("sni_learning.add_to_nftables:"));
+    }
+    
+    #[test]
+    fn test_byte_units_format() {
+        assert_eq!(ByteUnits::Binary.format(1048576), "1.00 MiB");
+        assert_eq!(ByteUnits::Si.format(1048576), "1.05 MB");
+        assert_eq!(ByteUnits::Binary.format(512), "512 B");
+        assert_eq!(ByteUnits::Si.format(3_500_000_000), "3.50 GB");
+        
+        let config: Config = toml::from_str(r#"
+            interface = "eth0"
+            report_interval = 60
+            log_unknown_traffic = false
+            services = []
+            time_rules = []
+            user_rules = []
+            blocked_domains = []
+            pattern_rules = []
+            byte_units = "si"
+        "#).unwrap();
+        assert_eq!(config.byte_units, ByteUnits::Si);
+    }
+    
+    #[test]
+    fn test_config_include_and_gzip() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+        
+        let dir = std::env::temp_dir().join(format!("trafficmon-include-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        
+        fs::write(dir.join("base.toml"), r#"
+            include = ["services.toml.gz"]
+            interface = "eth0"
+            report_interval = 30
+            log_unknown_traffic = false
+            time_rules = []
+            user_rules = []
+            blocked_domains = []
+            pattern_rules = []
+            
+            [[services]]
+            name = "netflix"
+            ports = [443]
+            ip_ranges = []
+            blocked = false
+        "#).unwrap();
+        
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(br#"
+            [[services]]
+            name = "youtube"
+            ports = [443]
+            ip_ranges = ["74.125.0.0/16"]
+            blocked = true
+        "#).unwrap();
+        fs::write(dir.join("services.toml.gz"), encoder.finish().unwrap()).unwrap();
+        
+        let config = Config::from_file(&dir.join("base.toml")).unwrap();
+        let names: Vec<_> = config.services.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["youtube", "netflix"]);
+        assert_eq!(config.interface, "eth0");
+        
+        // 互相包含的文件必須報錯
+        fs::write(dir.join("a.toml"), "include = [\"b.toml\"]").unwrap();
+        fs::write(dir.join("b.toml"), "include = [\"a.toml\"]").unwrap();
+        let err = Config::from_file(&dir.join("a.toml")).unwrap_err();
+        assert!(err.to_string().contains("cycle"));
+        
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::net::IpAddr;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::rotate::{rotated_path, RotatingFile};
+
+/// 一次分類決定：五元組、歸屬的服務和得出結果的方法
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Decision {
+    pub timestamp: DateTime<Utc>,
+    pub src_ip: IpAddr,
+    pub dst_ip: IpAddr,
+    pub protocol: u8,
+    pub src_port: Option<u16>,
+    pub dst_port: Option<u16>,
+    pub service: String,
+    pub method: String,
+}
+
+/// 緩衝超過這麼多字節時不等下次 `flush` 直接寫出
+const FLUSH_BYTES: usize = 64 * 1024;
+
+/// 以 JSON lines 追加寫入分類決定，超過 `max_bytes` 時輪轉為 `<path>.1`；
+/// 決定先放在內存中，`flush`、緩衝寫滿或丟棄時才寫入文件，記錄時不做文件 I/O
+#[derive(Debug)]
+pub struct DecisionLog {
+    file: RotatingFile,
+    pending: Vec<u8>,
+}
+
+impl DecisionLog {
+    pub fn open(path: &Path, max_bytes: u64) -> io::Result<Self> {
+        Ok(Self { file: RotatingFile::open(path, max_bytes)?, pending: Vec::new() })
+    }
+    
+    pub fn record(&mut self, decision: &Decision) -> io::Result<()> {
+        serde_json::to_writer(&mut self.pending, decision)?;
+        self.pending.push(b'\n');
+        if self.pending.len() >= FLUSH_BYTES {
+            self.flush()?;
+        }
+        Ok(())
+    }
+    
+    /// 寫出緩衝的決定，只寫完整的行
+    pub fn flush(&mut self) -> io::Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+        self.file.write_lines(&pending)
+    }
+}
+
+impl Drop for DecisionLog {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("Failed to write classification decisions: {}", e);
+        }
+    }
+}
+
+/// 回放時的過濾條件，未設置的條件不過濾
+#[derive(Debug, Clone, Default)]
+pub struct DecisionFilter {
+    pub service: Option<String>,
+    /// 源或目的地址
+    pub addr: Option<IpAddr>,
+}
+
+impl DecisionFilter {
+    pub fn matches(&self, decision: &Decision) -> bool {
+        self.service.as_ref().is_none_or(|service| *service == decision.service)
+            && self.addr.is_none_or(|addr| addr == decision.src_ip || addr == decision.dst_ip)
+    }
+}
+
+/// 按寫入順序讀回日誌（先讀輪轉出的舊文件），跳過無法解析的行
+pub fn replay(path: &Path, filter: &DecisionFilter) -> io::Result<Vec<Decision>> {
+    let mut decisions = Vec::new();
+    
+    for file in [rotated_path(path), path.to_path_buf()] {
+        let file = match File::open(&file) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for line in BufReader::new(file).lines() {
+            if let Ok(decision) = serde_json::from_str::<Decision>(&line?) {
+                if filter.matches(&decision) {
+                    decisions.push(decision);
+                }
+            }
+        }
+    }
+    
+    Ok(decisions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    
+    fn decision(second: i64, service: &str) -> Decision {
+        Decision {
+            timestamp: DateTime::from_timestamp(1_700_000_000 + second, 0).unwrap(),
+            src_ip: "192.168.1.100".parse().unwrap(),
+            dst_ip: "198.38.96.1".parse().unwrap(),
+            protocol: 6,
+            src_port: Some(50000),
+            dst_port: Some(443),
+            service: service.to_string(),
+            method: "sni".to_string(),
+        }
+    }
+    
+    #[test]
+    fn test_log_rotation_and_replay() {
+        let dir = std::env::temp_dir().join(format!("trafficmon-decisions-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("decisions.jsonl");
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(rotated_path(&path));
+        
+        let line_len = serde_json::to_vec(&decision(0, "netflix")).unwrap().len() as u64 + 1;
+        let mut log = DecisionLog::open(&path, line_len * 2).unwrap();
+        let services = ["netflix", "youtube", "netflix", "other"];
+        for (second, service) in services.iter().enumerate() {
+            log.record(&decision(second as i64, service)).unwrap();
+        }
+        // 記錄時只寫入內存
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+        log.flush().unwrap();
+        
+        // 寫滿兩行後輪轉，舊文件和當前文件各兩行
+        assert!(fs::metadata(&path).unwrap().len() <= line_len * 2);
+        assert!(rotated_path(&path).exists());
+        
+        let all = replay(&path, &DecisionFilter::default()).unwrap();
+        let replayed: Vec<&str> = all.iter().map(|d| d.service.as_str()).collect();
+        assert_eq!(replayed, services);
+        assert_eq!(all[3], decision(3, "other"));
+        
+        let filter = DecisionFilter { service: Some("netflix".to_string()), addr: None };
+        assert_eq!(replay(&path, &filter).unwrap().len(), 2);
+        let filter = DecisionFilter { service: None, addr: Some("10.0.0.1".parse().unwrap()) };
+        assert!(replay(&path, &filter).unwrap().is_empty());
+        
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use crate::config::DedupConfig;
+use crate::packet::PacketInfo;
+
+/// 在很短的窗口內記住最近見過的包，用於丟棄鏡像端口上同一個包的第二份拷貝。
+/// 按五元組、IP 標識、TCP 序列號和線路長度判斷；IPv6 沒有標識字段，誤判的可能更大
+#[derive(Debug)]
+pub struct Deduplicator {
+    window: Duration,
+    max_entries: usize,
+    seen: HashMap<u64, Instant>,
+    /// 按到達順序排列，用於過期和容量淘汰
+    order: VecDeque<(u64, Instant)>,
+}
+
+impl Deduplicator {
+    pub fn new(config: &DedupConfig) -> Self {
+        Self {
+            window: Duration::from_millis(config.window_ms),
+            max_entries: config.max_entries.max(1),
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+    
+    /// 窗口內已經見過相同的包時返回 true，否則記住它
+    pub fn is_duplicate(&mut self, info: &PacketInfo, wire_len: u32, now: Instant) -> bool {
+        while let Some(&(hash, at)) = self.order.front() {
+            if now.saturating_duration_since(at) <= self.window {
+                break;
+            }
+            self.forget_oldest(hash, at);
+        }
+        
+        let hash = fingerprint(info, wire_len);
+        if self.seen.contains_key(&hash) {
+            return true;
+        }
+        
+        if self.order.len() >= self.max_entries {
+            if let Some(&(hash, at)) = self.order.front() {
+                self.forget_oldest(hash, at);
+            }
+        }
+        self.seen.insert(hash, now);
+        self.order.push_back((hash, now));
+        false
+    }
+    
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+    
+    fn forget_oldest(&mut self, hash: u64, at: Instant) {
+        self.order.pop_front();
+        if self.seen.get(&hash) == Some(&at) {
+            self.seen.remove(&hash);
+        }
+    }
+}
+
+fn fingerprint(info: &PacketInfo, wire_len: u32) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (info.src_ip, info.dst_ip, info.protocol, info.src_port, info.dst_port).hash(&mut hasher);
+    (info.ip_id, info.tcp_seq, wire_len).hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::parse_ethernet;
+    
+    fn udp_frame(ip_id: u16) -> Vec<u8> {
+        let mut frame = vec![0u8; 12];
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(&[0x45, 0x00, 0x00, 0x24]);
+        frame.extend_from_slice(&ip_id.to_be_bytes());
+        frame.extend_from_slice(&[0x40, 0x00, 0x40, 17, 0x00, 0x00]);
+        frame.extend_from_slice(&[192, 168, 1, 100, 8, 8, 8, 8]);
+        frame.extend_from_slice(&[0xc3, 0x50, 0x00, 0x35, 0x00, 0x10, 0x00, 0x00]);
+        frame.extend_from_slice(&[0u8; 8]);
+        frame
+    }
+    
+    #[test]
+    fn test_duplicates_within_window() {
+        let mut dedup = Deduplicator::new(&DedupConfig { window_ms: 5, max_entries: 2 });
+        let (first, second, third) = (udp_frame(1), udp_frame(2), udp_frame(3));
+        let packet = |frame| parse_ethernet(frame).unwrap();
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        
+        assert!(!dedup.is_duplicate(&packet(&first), 50, at(0)));
+        assert!(dedup.is_duplicate(&packet(&first), 50, at(2)));
+        // IP 標識或長度不同的不是同一個包
+        assert!(!dedup.is_duplicate(&packet(&second), 50, at(2)));
+        assert!(!dedup.is_duplicate(&packet(&first), 60, at(3)));
+        
+        // 超出窗口後重新計算
+        assert!(!dedup.is_duplicate(&packet(&second), 50, at(20)));
+        assert_eq!(dedup.len(), 1);
+        
+        // 達到容量時淘汰最舊的
+        assert!(!dedup.is_duplicate(&packet(&third), 50, at(21)));
+        assert!(!dedup.is_duplicate(&packet(&first), 50, at(22)));
+        assert_eq!(dedup.len(), 2);
+        assert!(!dedup.is_duplicate(&packet(&second), 50, at(23)));
+    }
+}
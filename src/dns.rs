@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+/// DNS 回應中的一條地址記錄
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsAnswer {
+    pub name: String,
+    pub addr: IpAddr,
+    pub ttl: u32,
+}
+
+/// 解析 DNS 回應報文中的 A/AAAA 記錄，查詢報文或格式錯誤時返回空
+pub fn parse_dns_answers(msg: &[u8]) -> Vec<DnsAnswer> {
+    let mut answers = Vec::new();
+    
+    // QR 位為 1 才是回應
+    if msg.len() < 12 || msg[2] & 0x80 == 0 {
+        return answers;
+    }
+    
+    let qdcount = u16::from_be_bytes([msg[4], msg[5]]);
+    let ancount = u16::from_be_bytes([msg[6], msg[7]]);
+    let mut pos = 12;
+    
+    for _ in 0..qdcount {
+        let Some((_, next)) = read_name(msg, pos) else {
+            return answers;
+        };
+        pos = next + 4;
+    }
+    
+    for _ in 0..ancount {
+        let Some((name, next)) = read_name(msg, pos) else {
+            break;
+        };
+        let Some(header) = msg.get(next..next + 10) else {
+            break;
+        };
+        
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let class = u16::from_be_bytes([header[2], header[3]]);
+        let ttl = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let rdlen = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let Some(rdata) = msg.get(next + 10..next + 10 + rdlen) else {
+            break;
+        };
+        
+        let addr = match (rtype, rdata.len()) {
+            (TYPE_A, 4) => Some(IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]))),
+            (TYPE_AAAA, 16) => <[u8; 16]>::try_from(rdata).ok().map(|b| IpAddr::V6(Ipv6Addr::from(b))),
+            _ => None,
+        };
+        
+        if let (Some(addr), CLASS_IN) = (addr, class) {
+            answers.push(DnsAnswer { name, addr, ttl });
+        }
+        
+        pos = next + 10 + rdlen;
+    }
+    
+    answers
+}
+
+/// 讀取域名（支持壓縮指針），返回域名和其後的偏移
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    let mut jumps = 0;
+    
+    loop {
+        let len = *msg.get(pos)? as usize;
+        
+        if len & 0xC0 == 0xC0 {
+            // 限制跳轉次數，防止惡意報文造成死循環
+            jumps += 1;
+            if jumps > 16 {
+                return None;
+            }
+            
+            let pointer = ((len & 0x3F) << 8) | *msg.get(pos + 1)? as usize;
+            end.get_or_insert(pos + 2);
+            pos = pointer;
+            continue;
+        }
+        
+        if len == 0 {
+            let end = end.unwrap_or(pos + 1);
+            return Some((labels.join("."), end));
+        }
+        
+        let label = msg.get(pos + 1..pos + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        pos += 1 + len;
+    }
+}
+
+/// 由 DNS 回應建立的 IP → 域名映射，按記錄的 TTL 過期
+#[derive(Debug, Default)]
+pub struct DnsCache {
+    entries: HashMap<IpAddr, (String, Instant)>,
+}
+
+impl DnsCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    pub fn record(&mut self, answer: &DnsAnswer, now: Instant) {
+        let expires = now + Duration::from_secs(answer.ttl as u64);
+        self.entries.insert(answer.addr, (answer.name.clone(), expires));
+    }
+    
+    pub fn lookup(&self, addr: &IpAddr, now: Instant) -> Option<&str> {
+        self.entries.get(addr)
+            .filter(|(_, expires)| now < *expires)
+            .map(|(name, _)| name.as_str())
+    }
+    
+    /// 清理已過期的記錄
+    pub fn expire(&mut self, now: Instant) {
+        self.entries.retain(|_, (_, expires)| now < *expires);
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn test_response(name: &str, addr: Ipv4Addr, ttl: u32) -> Vec<u8> {
+    // 事務 ID、標誌（標準回應）、1 個問題、1 個回答
+    let mut msg = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0];
+    for label in name.split('.') {
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.extend_from_slice(&[0, 0, 1, 0, 1]);
+    
+    // 回答的域名用指向問題的壓縮指針
+    msg.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1]);
+    msg.extend_from_slice(&ttl.to_be_bytes());
+    msg.extend_from_slice(&[0, 4]);
+    msg.extend_from_slice(&addr.octets());
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_answer_parsing_and_ttl() {
+        let addr = Ipv4Addr::new(93, 184, 216, 34);
+        let answers = parse_dns_answers(&test_response("www.Example.com", addr, 300));
+        
+        assert_eq!(answers, vec![DnsAnswer {
+            name: "www.example.com".to_string(),
+            addr: IpAddr::V4(addr),
+            ttl: 300,
+        }]);
+        
+        let mut cache = DnsCache::new();
+        let now = Instant::now();
+        cache.record(&answers[0], now);
+        
+        assert_eq!(cache.lookup(&IpAddr::V4(addr), now), Some("www.example.com"));
+        assert_eq!(cache.lookup(&IpAddr::V4(addr), now + Duration::from_secs(301)), None);
+    }
+}
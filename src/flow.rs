@@ -0,0 +1,353 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// 以五元組標識的單向流
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub src_ip: IpAddr,
+    pub dst_ip: IpAddr,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub protocol: u8,
+}
+
+impl FlowKey {
+    /// 反方向（回應）的流
+    pub fn reversed(&self) -> Self {
+        Self {
+            src_ip: self.dst_ip,
+            dst_ip: self.src_ip,
+            src_port: self.dst_port,
+            dst_port: self.src_port,
+            protocol: self.protocol,
+        }
+    }
+}
+
+/// 記錄新流首包時間，在回應的首個數據包到達時計算首字節時間 (TTFB)
+#[derive(Debug)]
+pub struct LatencyTracker {
+    timeout: Duration,
+    pending: HashMap<FlowKey, (String, Instant)>,
+    totals: HashMap<String, (Duration, u64)>,
+    unanswered: u64,
+}
+
+impl LatencyTracker {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            pending: HashMap::new(),
+            totals: HashMap::new(),
+            unanswered: 0,
+        }
+    }
+    
+    pub fn start_flow(&mut self, key: FlowKey, service: &str, at: Instant) {
+        self.expire(at);
+        self.pending.entry(key).or_insert_with(|| (service.to_string(), at));
+    }
+    
+    /// 處理回應方向的包，只有帶負載的首個包才結束計時
+    pub fn observe_response(&mut self, key: FlowKey, payload_len: usize, at: Instant) -> Option<Duration> {
+        if payload_len == 0 {
+            return None;
+        }
+        
+        let (service, started) = self.pending.remove(&key.reversed())?;
+        let ttfb = at.saturating_duration_since(started);
+        
+        let entry = self.totals.entry(service).or_insert((Duration::ZERO, 0));
+        entry.0 += ttfb;
+        entry.1 += 1;
+        
+        Some(ttfb)
+    }
+    
+    /// 丟棄超時仍未收到回應的流
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        let before = self.pending.len();
+        self.pending.retain(|_, (_, started)| now.saturating_duration_since(*started) < timeout);
+        self.unanswered += (before - self.pending.len()) as u64;
+    }
+    
+    pub fn average_ttfb(&self) -> HashMap<String, Duration> {
+        self.totals.iter()
+            .map(|(service, (total, count))| (service.clone(), *total / (*count as u32).max(1)))
+            .collect()
+    }
+    
+    pub fn unanswered(&self) -> u64 {
+        self.unanswered
+    }
+}
+
+/// 按流記錄已見過的最高序列號，序列號落在其之前的數據段計為重傳
+#[derive(Debug, Default)]
+pub struct RetransmitTracker {
+    next_seq: HashMap<FlowKey, u32>,
+    totals: HashMap<String, (u64, u64)>,
+}
+
+impl RetransmitTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// 處理一個 TCP 數據段，返回是否為重傳
+    pub fn observe(&mut self, key: FlowKey, service: &str, seq: u32, payload_len: usize) -> bool {
+        // 純 ACK 不佔序列號空間
+        if payload_len == 0 {
+            return false;
+        }
+        
+        let end = seq.wrapping_add(payload_len as u32);
+        let retransmit = match self.next_seq.get(&key) {
+            Some(&next) if seq_before(seq, next) => true,
+            _ => {
+                self.next_seq.insert(key, end);
+                false
+            }
+        };
+        
+        let entry = self.totals.entry(service.to_string()).or_insert((0, 0));
+        entry.0 += 1;
+        if retransmit {
+            entry.1 += 1;
+        }
+        
+        retransmit
+    }
+    
+    /// 各服務的重傳率（重傳段數 / 數據段總數）
+    pub fn retransmit_rates(&self) -> HashMap<String, f64> {
+        self.totals.iter()
+            .map(|(service, (segments, retransmits))| {
+                (service.clone(), *retransmits as f64 / (*segments).max(1) as f64)
+            })
+            .collect()
+    }
+    
+    pub fn forget(&mut self, key: &FlowKey) {
+        self.next_seq.remove(key);
+    }
+}
+
+/// 按 32 位序列號空間的模運算比較 a 是否在 b 之前 (RFC 1982)
+fn seq_before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// 流結束後導出的記錄，字段名沿用 IPFIX 信息元素名稱
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FlowRecord {
+    #[serde(rename = "sourceIPAddress")]
+    pub source_ip_address: IpAddr,
+    #[serde(rename = "destinationIPAddress")]
+    pub destination_ip_address: IpAddr,
+    pub source_transport_port: u16,
+    pub destination_transport_port: u16,
+    pub protocol_identifier: u8,
+    pub octet_delta_count: u64,
+    pub packet_delta_count: u64,
+    pub flow_start_milliseconds: u64,
+    pub flow_end_milliseconds: u64,
+    pub flow_duration_milliseconds: u64,
+    /// TLS SNI 主機名
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
+    /// 客戶端 ALPN 首選協議（h2、http/1.1 等）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub application_protocol: Option<String>,
+}
+
+#[derive(Debug)]
+struct FlowEntry {
+    bytes: u64,
+    packets: u64,
+    start: SystemTime,
+    end: SystemTime,
+    server_name: Option<String>,
+    application_protocol: Option<String>,
+}
+
+/// 活動流表，流在空閒超過 timeout 後過期並生成記錄
+#[derive(Debug)]
+pub struct FlowTable {
+    timeout: Duration,
+    flows: HashMap<FlowKey, FlowEntry>,
+}
+
+impl FlowTable {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            flows: HashMap::new(),
+        }
+    }
+    
+    pub fn observe(&mut self, key: FlowKey, bytes: u64, packets: u64, at: SystemTime) {
+        let entry = self.flows.entry(key).or_insert(FlowEntry {
+            bytes: 0,
+            packets: 0,
+            start: at,
+            end: at,
+            server_name: None,
+            application_protocol: None,
+        });
+        
+        entry.bytes += bytes;
+        entry.packets += packets;
+        entry.end = entry.end.max(at);
+    }
+    
+    /// 記錄流的 TLS 元數據（來自 ClientHello），只更新已有的流，缺失的字段保留原值
+    pub fn annotate_tls(&mut self, key: &FlowKey, server_name: Option<String>, application_protocol: Option<String>) {
+        if let Some(entry) = self.flows.get_mut(key) {
+            entry.server_name = server_name.or(entry.server_name.take());
+            entry.application_protocol = application_protocol.or(entry.application_protocol.take());
+        }
+    }
+    
+    /// 取出所有空閒超時的流，按開始時間排序
+    pub fn expire(&mut self, now: SystemTime) -> Vec<FlowRecord> {
+        let timeout = self.timeout;
+        let expired: Vec<FlowKey> = self.flows.iter()
+            .filter(|(_, entry)| now.duration_since(entry.end).unwrap_or_default() >= timeout)
+            .map(|(key, _)| *key)
+            .collect();
+        
+        self.take(expired)
+    }
+    
+    /// 取出全部流（例如退出時）
+    pub fn drain(&mut self) -> Vec<FlowRecord> {
+        let keys: Vec<FlowKey> = self.flows.keys().copied().collect();
+        self.take(keys)
+    }
+    
+    fn take(&mut self, keys: Vec<FlowKey>) -> Vec<FlowRecord> {
+        let mut records: Vec<FlowRecord> = keys.into_iter()
+            .filter_map(|key| self.flows.remove(&key).map(|entry| flow_record(&key, &entry)))
+            .collect();
+        records.sort_by_key(|r| r.flow_start_milliseconds);
+        records
+    }
+    
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+}
+
+fn flow_record(key: &FlowKey, entry: &FlowEntry) -> FlowRecord {
+    let millis = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    let start = millis(entry.start);
+    let end = millis(entry.end);
+    
+    FlowRecord {
+        source_ip_address: key.src_ip,
+        destination_ip_address: key.dst_ip,
+        source_transport_port: key.src_port,
+        destination_transport_port: key.dst_port,
+        protocol_identifier: key.protocol,
+        octet_delta_count: entry.bytes,
+        packet_delta_count: entry.packets,
+        flow_start_milliseconds: start,
+        flow_end_milliseconds: end,
+        flow_duration_milliseconds: end - start,
+        server_name: entry.server_name.clone(),
+        application_protocol: entry.application_protocol.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    
+    fn request_key() -> FlowKey {
+        FlowKey {
+            src_ip: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100)),
+            dst_ip: IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)),
+            src_port: 54321,
+            dst_port: 443,
+            protocol: 6,
+        }
+    }
+    
+    #[test]
+    fn test_ttfb_pairs_request_and_response() {
+        let mut tracker = LatencyTracker::new(Duration::from_secs(10));
+        let start = Instant::now();
+        let request = request_key();
+        
+        tracker.start_flow(request, "https", start);
+        
+        // 純 ACK 不算首字節
+        assert_eq!(tracker.observe_response(request.reversed(), 0, start + Duration::from_millis(20)), None);
+        
+        let ttfb = tracker.observe_response(request.reversed(), 1200, start + Duration::from_millis(85));
+        assert_eq!(ttfb, Some(Duration::from_millis(85)));
+        assert_eq!(tracker.average_ttfb()["https"], Duration::from_millis(85));
+        
+        // 沒有回應的流在超時後被丟棄
+        tracker.start_flow(request, "https", start);
+        tracker.expire(start + Duration::from_secs(11));
+        assert_eq!(tracker.unanswered(), 1);
+    }
+    
+    #[test]
+    fn test_retransmitted_segment() {
+        let mut tracker = RetransmitTracker::new();
+        let key = request_key();
+        
+        assert!(!tracker.observe(key, "https", 1000, 500));
+        assert!(!tracker.observe(key, "https", 1500, 500));
+        // 重發第二段
+        assert!(tracker.observe(key, "https", 1500, 500));
+        assert!(!tracker.observe(key, "https", 2000, 0));
+        
+        assert!((tracker.retransmit_rates()["https"] - 1.0 / 3.0).abs() < 1e-9);
+        
+        // 序列號跨越 2^32 回繞時不應誤判
+        let wrapped = key.reversed();
+        assert!(!tracker.observe(wrapped, "https", u32::MAX - 99, 100));
+        assert!(!tracker.observe(wrapped, "https", 0, 100));
+        assert!(tracker.observe(wrapped, "https", u32::MAX - 99, 100));
+    }
+    
+    #[test]
+    fn test_expired_flow_record() {
+        let mut table = FlowTable::new(Duration::from_secs(30));
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let key = request_key();
+        
+        table.observe(key, 600, 1, start);
+        table.observe(key, 1400, 2, start + Duration::from_millis(2500));
+        table.observe(key.reversed(), 100, 1, start + Duration::from_secs(40));
+        
+        // 請求方向空閒 30 秒後過期，回應方向仍然活動
+        let records = table.expire(start + Duration::from_millis(32_500));
+        assert_eq!(records.len(), 1);
+        assert_eq!(table.len(), 1);
+        
+        let record = &records[0];
+        assert_eq!(record.destination_transport_port, 443);
+        assert_eq!(record.octet_delta_count, 2000);
+        assert_eq!(record.packet_delta_count, 3);
+        assert_eq!(record.flow_start_milliseconds, 1_700_000_000_000);
+        assert_eq!(record.flow_duration_milliseconds, 2500);
+        
+        let json = serde_json::to_value(record).unwrap();
+        assert_eq!(json["sourceIPAddress"], "192.168.1.100");
+        assert_eq!(json["octetDeltaCount"], 2000);
+    }
+}
@@ -0,0 +1,134 @@
+const METHODS: [&str; 9] = ["GET", "POST", "HEAD", "PUT", "DELETE", "OPTIONS", "PATCH", "CONNECT", "TRACE"];
+
+/// 明文 HTTP 請求頭中提取的信息
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequestInfo {
+    pub host: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl HttpRequestInfo {
+    /// 從 User-Agent 推斷的客戶端系統
+    pub fn client_os(&self) -> Option<&'static str> {
+        let ua = self.user_agent.as_deref()?;
+        
+        // 順序重要：Android 的 UA 也帶 Linux，iOS 的 UA 也帶 Mac OS X
+        [
+            ("Android", "android"),
+            ("iPhone", "ios"),
+            ("iPad", "ios"),
+            ("Windows", "windows"),
+            ("Mac OS X", "macos"),
+            ("CrOS", "chromeos"),
+            ("Linux", "linux"),
+        ]
+        .iter()
+        .find(|(needle, _)| ua.contains(needle))
+        .map(|(_, os)| *os)
+    }
+}
+
+/// 解析 HTTP 請求的前 `max_bytes` 字節；不是請求行開頭時返回 None
+pub fn parse_http_request(payload: &[u8], max_bytes: usize) -> Option<HttpRequestInfo> {
+    parse_http_request_until(payload, max_bytes, &|| false)
+}
+
+/// 同 `parse_http_request`，每行頭部前調用 `stop`，返回 true 時放棄解析並返回 None
+pub fn parse_http_request_until(payload: &[u8], max_bytes: usize, stop: &dyn Fn() -> bool) -> Option<HttpRequestInfo> {
+    let head = &payload[..payload.len().min(max_bytes)];
+    let text = String::from_utf8_lossy(head);
+    // 被截斷的最後一行不完整，丟棄
+    let mut lines = text.split_inclusive("\r\n")
+        .filter_map(|line| line.strip_suffix("\r\n"));
+    
+    let request_line = lines.next()?;
+    let method = request_line.split(' ').next()?;
+    if !METHODS.contains(&method) || !request_line.contains(" HTTP/1.") {
+        return None;
+    }
+    
+    let mut info = HttpRequestInfo { host: None, user_agent: None };
+    for line in lines.take_while(|l| !l.is_empty()) {
+        if stop() {
+            return None;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        
+        if name.eq_ignore_ascii_case("host") {
+            // 去掉端口
+            let host = value.rsplit_once(':')
+                .filter(|(_, port)| port.chars().all(|c| c.is_ascii_digit()))
+                .map_or(value, |(host, _)| host);
+            info.host = Some(host.to_ascii_lowercase());
+        } else if name.eq_ignore_ascii_case("user-agent") {
+            info.user_agent = Some(value.to_string());
+        }
+    }
+    
+    Some(info)
+}
+
+/// 解析 `CONNECT host:port HTTP/1.x` 請求行中的隧道目標；IPv6 地址帶方括號
+pub fn parse_connect_target(payload: &[u8]) -> Option<(String, u16)> {
+    let line_end = payload.windows(2).position(|w| w == b"\r\n")?;
+    let request_line = std::str::from_utf8(&payload[..line_end]).ok()?;
+    
+    let mut parts = request_line.split(' ');
+    if parts.next() != Some("CONNECT") {
+        return None;
+    }
+    let target = parts.next()?;
+    if !parts.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+    
+    let (host, port) = target.rsplit_once(':')?;
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_ascii_lowercase(), port.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_parse_http_request() {
+        let request = b"GET /watch?v=1 HTTP/1.1\r\nHost: WWW.YouTube.com:80\r\nUser-Agent: Mozilla/5.0 (Linux; Android 14)\r\n\r\n";
+        let info = parse_http_request(request, 1024).unwrap();
+        
+        assert_eq!(info.host.as_deref(), Some("www.youtube.com"));
+        assert_eq!(info.client_os(), Some("android"));
+        
+        // 只看前 N 字節，Host 頭被截掉
+        assert_eq!(parse_http_request(request, 30).unwrap().host, None);
+        
+        assert_eq!(parse_http_request(b"HTTP/1.1 200 OK\r\n\r\n", 1024), None);
+        
+        // 第二行頭部前放棄
+        let checks = std::cell::Cell::new(0);
+        let stop = || {
+            checks.set(checks.get() + 1);
+            checks.get() > 1
+        };
+        assert_eq!(parse_http_request_until(request, 1024, &stop), None);
+        assert_eq!(checks.get(), 2);
+    }
+    
+    #[test]
+    fn test_parse_connect_target() {
+        let request = b"CONNECT www.Netflix.com:443 HTTP/1.1\r\nHost: www.netflix.com:443\r\n\r\n";
+        assert_eq!(parse_connect_target(request), Some(("www.netflix.com".to_string(), 443)));
+        assert_eq!(parse_connect_target(b"CONNECT [2001:db8::1]:8443 HTTP/1.1\r\n\r\n"), Some(("2001:db8::1".to_string(), 8443)));
+        
+        // 缺少端口、請求行不完整或不是 CONNECT
+        assert_eq!(parse_connect_target(b"CONNECT www.netflix.com HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(parse_connect_target(b"CONNECT www.netflix.com:443 HTTP/1.1"), None);
+        assert_eq!(parse_connect_target(b"GET http://example.com/ HTTP/1.1\r\n\r\n"), None);
+    }
+}
@@ -0,0 +1,408 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::config::KafkaConfig;
+
+/// 發布線程沒有新事件時，發送未攢滿一批的事件的間隔
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 把一批消息發到指定主題，返回與 `messages` 一一對應的送達結果
+pub trait EventProducer: Send {
+    fn send_batch(&mut self, topic: &str, messages: &[String]) -> Vec<Result<(), String>>;
+}
+
+/// 把事件序列化為 JSON 後分批發布；broker 不可用時先緩存，
+/// 緩存滿後丟棄最舊的事件
+pub struct EventPublisher {
+    producer: Box<dyn EventProducer>,
+    topic: String,
+    batch_size: usize,
+    capacity: usize,
+    buffer: VecDeque<String>,
+    dropped: u64,
+    /// 上次發送是否失敗，只在狀態變化時打印日誌
+    failing: bool,
+}
+
+impl EventPublisher {
+    pub fn new(producer: Box<dyn EventProducer>, config: &KafkaConfig) -> Self {
+        Self {
+            producer,
+            topic: config.topic.clone(),
+            batch_size: config.batch_size.max(1),
+            capacity: config.buffer_capacity.max(1),
+            buffer: VecDeque::new(),
+            dropped: 0,
+            failing: false,
+        }
+    }
+    
+    /// 緩存一個事件，攢滿一批時發送
+    pub fn publish<T: Serialize>(&mut self, event: &T) {
+        if let Some(message) = serialize(event) {
+            self.push(message);
+        }
+    }
+    
+    /// 緩存一條已序列化的事件，攢滿一批時發送
+    fn push(&mut self, message: String) {
+        if self.buffer.len() >= self.capacity {
+            self.buffer.pop_front();
+            self.dropped += 1;
+        }
+        self.buffer.push_back(message);
+        
+        if self.buffer.len() >= self.batch_size {
+            self.flush();
+        }
+    }
+    
+    /// 按批發送緩存中的全部事件；一批中有記錄未送達時停止，
+    /// 只把未送達的記錄按原順序留在緩存中，已送達的不會重發
+    pub fn flush(&mut self) -> bool {
+        while !self.buffer.is_empty() {
+            let len = self.buffer.len().min(self.batch_size);
+            let batch: Vec<String> = self.buffer.drain(..len).collect();
+            let results = self.producer.send_batch(&self.topic, &batch);
+            
+            let mut error = None;
+            let mut failed = Vec::new();
+            for (i, message) in batch.into_iter().enumerate() {
+                let result = results.get(i).cloned().unwrap_or_else(|| Err("no delivery report".to_string()));
+                if let Err(e) = result {
+                    error.get_or_insert(e);
+                    failed.push(message);
+                }
+            }
+            
+            if let Some(e) = error {
+                for message in failed.into_iter().rev() {
+                    self.buffer.push_front(message);
+                }
+                if !self.failing {
+                    eprintln!("Kafka unavailable, buffering events: {}", e);
+                    self.failing = true;
+                }
+                return false;
+            }
+            
+            if self.failing {
+                eprintln!("Kafka reachable again, sent buffered events");
+                self.failing = false;
+            }
+        }
+        
+        true
+    }
+    
+    /// 等待發送的事件數
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+    
+    /// 因緩存已滿而丟棄的事件數
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+fn serialize<T: Serialize>(event: &T) -> Option<String> {
+    match serde_json::to_string(event) {
+        Ok(message) => Some(message),
+        Err(e) => {
+            eprintln!("Failed to serialize event: {}", e);
+            None
+        }
+    }
+}
+
+enum Command {
+    Event(String),
+    Flush,
+}
+
+/// 在專用線程上運行 `EventPublisher`：調用方只把序列化好的事件放進有界隊列，
+/// 不等待 broker；隊列滿時丟棄新事件。釋放時停止線程並發送剩餘事件
+pub struct EventWorker {
+    sender: Option<SyncSender<Command>>,
+    dropped: AtomicU64,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl EventWorker {
+    pub fn start(mut publisher: EventPublisher) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(publisher.capacity);
+        let handle = thread::spawn(move || loop {
+            match receiver.recv_timeout(FLUSH_INTERVAL) {
+                Ok(Command::Event(message)) => publisher.push(message),
+                Ok(Command::Flush) | Err(RecvTimeoutError::Timeout) => {
+                    publisher.flush();
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    publisher.flush();
+                    return;
+                }
+            }
+        });
+        
+        Self { sender: Some(sender), dropped: AtomicU64::new(0), handle: Some(handle) }
+    }
+    
+    /// 把事件交給發布線程，不阻塞
+    pub fn publish<T: Serialize>(&self, event: &T) {
+        if let Some(message) = serialize(event) {
+            self.send(Command::Event(message));
+        }
+    }
+    
+    /// 讓發布線程發送未攢滿一批的事件，不等待結果
+    pub fn flush(&self) {
+        self.send(Command::Flush);
+    }
+    
+    /// 因隊列已滿而沒能交給發布線程的事件數
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+    
+    fn send(&self, command: Command) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        if let Err(TrySendError::Full(Command::Event(_))) = sender.try_send(command) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for EventWorker {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(feature = "kafka")]
+pub use producer::KafkaProducer;
+
+#[cfg(feature = "kafka")]
+mod producer {
+    use std::sync::Mutex;
+    use std::time::Duration;
+    
+    use rdkafka::client::ClientContext;
+    use rdkafka::config::ClientConfig;
+    use rdkafka::message::DeliveryResult;
+    use rdkafka::producer::{BaseProducer, BaseRecord, Producer, ProducerContext};
+    
+    use super::EventProducer;
+    use crate::config::KafkaConfig;
+    
+    /// 當前一批消息的送達報告，按 (批次號, 序號) 對應；
+    /// 上一批超時後才到的報告批次號不同，直接忽略
+    #[derive(Default)]
+    struct DeliveryReports {
+        batch: Mutex<Batch>,
+    }
+    
+    #[derive(Default)]
+    struct Batch {
+        id: u64,
+        results: Vec<Option<Result<(), String>>>,
+    }
+    
+    impl ClientContext for DeliveryReports {}
+    
+    impl ProducerContext for DeliveryReports {
+        type DeliveryOpaque = Box<(u64, usize)>;
+        
+        fn delivery(&self, result: &DeliveryResult<'_>, opaque: Self::DeliveryOpaque) {
+            let (id, index) = *opaque;
+            let mut batch = self.batch.lock().unwrap();
+            if batch.id != id {
+                return;
+            }
+            if let Some(slot) = batch.results.get_mut(index) {
+                *slot = Some(result.as_ref().map(|_| ()).map_err(|(e, _)| e.to_string()));
+            }
+        }
+    }
+    
+    /// 基於 librdkafka 的生產者，逐條跟蹤送達結果
+    pub struct KafkaProducer {
+        producer: BaseProducer<DeliveryReports>,
+        batch: u64,
+    }
+    
+    impl KafkaProducer {
+        pub fn new(config: &KafkaConfig) -> Result<Self, String> {
+            let producer = ClientConfig::new()
+                .set("bootstrap.servers", &config.brokers)
+                .set("message.timeout.ms", "5000")
+                .create_with_context(DeliveryReports::default())
+                .map_err(|e| format!("Failed to create Kafka producer: {}", e))?;
+            Ok(Self { producer, batch: 0 })
+        }
+    }
+    
+    impl EventProducer for KafkaProducer {
+        fn send_batch(&mut self, topic: &str, messages: &[String]) -> Vec<Result<(), String>> {
+            self.batch += 1;
+            *self.producer.context().batch.lock().unwrap() = Batch { id: self.batch, results: vec![None; messages.len()] };
+            
+            for (index, message) in messages.iter().enumerate() {
+                let record = BaseRecord::<(), _, _>::with_opaque_to(topic, Box::new((self.batch, index))).payload(message);
+                if let Err((e, _)) = self.producer.send(record) {
+                    self.producer.context().batch.lock().unwrap().results[index] = Some(Err(e.to_string()));
+                }
+            }
+            // 等待送達報告，超時未報告的記為失敗，之後重發
+            let _ = self.producer.flush(Duration::from_secs(5));
+            
+            let batch = std::mem::take(&mut *self.producer.context().batch.lock().unwrap());
+            batch.results.into_iter()
+                .map(|result| result.unwrap_or_else(|| Err("delivery timed out".to_string())))
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    
+    /// 每次發送的 (主題, 消息)
+    type Sent = Arc<Mutex<Vec<(String, Vec<String>)>>>;
+    
+    /// 記錄送達的消息；`up` 為 false 時模擬 broker 不可用，`rejected` 中的消息送達失敗
+    #[derive(Clone, Default)]
+    struct MockProducer {
+        up: Arc<Mutex<bool>>,
+        rejected: Arc<Mutex<Vec<String>>>,
+        sent: Sent,
+    }
+    
+    impl EventProducer for MockProducer {
+        fn send_batch(&mut self, topic: &str, messages: &[String]) -> Vec<Result<(), String>> {
+            if !*self.up.lock().unwrap() {
+                return messages.iter().map(|_| Err("broker down".to_string())).collect();
+            }
+            let rejected = self.rejected.lock().unwrap();
+            let delivered: Vec<String> = messages.iter().filter(|m| !rejected.contains(m)).cloned().collect();
+            self.sent.lock().unwrap().push((topic.to_string(), delivered));
+            messages.iter()
+                .map(|m| if rejected.contains(m) { Err("message rejected".to_string()) } else { Ok(()) })
+                .collect()
+        }
+    }
+    
+    fn config() -> KafkaConfig {
+        KafkaConfig {
+            brokers: "localhost:9092".to_string(),
+            topic: "trafficmon".to_string(),
+            batch_size: 2,
+            buffer_capacity: 3,
+        }
+    }
+    
+    #[test]
+    fn test_batches_published() {
+        let mock = MockProducer::default();
+        *mock.up.lock().unwrap() = true;
+        let mut publisher = EventPublisher::new(Box::new(mock.clone()), &config());
+        
+        for bytes in [100, 200, 300] {
+            publisher.publish(&serde_json::json!({ "service": "netflix", "bytes": bytes }));
+        }
+        assert_eq!(publisher.pending(), 1);
+        assert!(publisher.flush());
+        
+        let sent = mock.sent.lock().unwrap();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].0, "trafficmon");
+        assert_eq!(sent[0].1, vec![r#"{"bytes":100,"service":"netflix"}"#, r#"{"bytes":200,"service":"netflix"}"#]);
+        assert_eq!(sent[1].1.len(), 1);
+    }
+    
+    #[test]
+    fn test_bounded_buffer_while_broker_down() {
+        let mock = MockProducer::default();
+        let mut publisher = EventPublisher::new(Box::new(mock.clone()), &config());
+        
+        for n in 0..5 {
+            publisher.publish(&n);
+        }
+        // 只保留最新的 3 個
+        assert_eq!((publisher.pending(), publisher.dropped()), (3, 2));
+        
+        *mock.up.lock().unwrap() = true;
+        assert!(publisher.flush());
+        let sent: Vec<String> = mock.sent.lock().unwrap().iter().flat_map(|(_, batch)| batch.clone()).collect();
+        assert_eq!(sent, vec!["2", "3", "4"]);
+        assert_eq!(publisher.pending(), 0);
+    }    
+    #[test]
+    fn test_only_undelivered_records_resent() {
+        let mock = MockProducer::default();
+        *mock.up.lock().unwrap() = true;
+        mock.rejected.lock().unwrap().push("2".to_string());
+        let mut publisher = EventPublisher::new(Box::new(mock.clone()), &config());
+        
+        // 第一批 1、2 中只有 2 未送達
+        publisher.publish(&1);
+        publisher.publish(&2);
+        assert_eq!(publisher.pending(), 1);
+        assert!(!publisher.flush());
+        
+        mock.rejected.lock().unwrap().clear();
+        publisher.publish(&3);
+        assert!(publisher.flush());
+        let sent: Vec<String> = mock.sent.lock().unwrap().iter().flat_map(|(_, batch)| batch.clone()).collect();
+        assert_eq!(sent, vec!["1", "2", "3"]);
+    }
+    
+    /// 發送前等待 `gate` 放行，模擬很慢的 broker
+    struct SlowProducer {
+        gate: mpsc::Receiver<()>,
+        sent: Sent,
+    }
+    
+    impl EventProducer for SlowProducer {
+        fn send_batch(&mut self, topic: &str, messages: &[String]) -> Vec<Result<(), String>> {
+            let _ = self.gate.recv();
+            self.sent.lock().unwrap().push((topic.to_string(), messages.to_vec()));
+            messages.iter().map(|_| Ok(())).collect()
+        }
+    }
+    
+    #[test]
+    fn test_worker_does_not_block_publisher() {
+        let (open, gate) = mpsc::channel();
+        let sent = Sent::default();
+        let producer = SlowProducer { gate, sent: Arc::clone(&sent) };
+        let worker = EventWorker::start(EventPublisher::new(Box::new(producer), &config()));
+        
+        // broker 卡住時發布照常返回，超出隊列容量的事件被丟棄
+        for n in 0..10 {
+            worker.publish(&n);
+        }
+        worker.flush();
+        assert!(worker.dropped() > 0);
+        
+        for _ in 0..10 {
+            open.send(()).unwrap();
+        }
+        drop(worker);
+        let sent: Vec<String> = sent.lock().unwrap().iter().flat_map(|(_, batch)| batch.clone()).collect();
+        assert_eq!(sent.first().map(String::as_str), Some("0"));
+        assert!(!sent.is_empty() && sent.len() < 10);
+    }
+}
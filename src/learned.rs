@@ -0,0 +1,378 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{cidr_contains, parse_cidr, SniLearningConfig};
+
+/// 學到的一個網段和最後一次見到其中地址的時間
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LearnedRange {
+    network: (IpAddr, u8),
+    last_seen: SystemTime,
+}
+
+/// 保存到文件的格式，網段寫成 "地址/前綴長度"
+#[derive(Serialize, Deserialize)]
+struct SavedRange {
+    network: String,
+    last_seen: DateTime<Utc>,
+}
+
+/// 由 SNI 學到的服務地址：每個服務最多記住 `max_addresses` 個網段，超出時淘汰最久未見的；
+/// 同一地址只屬於最近一次學到它的服務。[`compact`](Self::compact) 合併相鄰網段並丟棄過期的
+#[derive(Debug)]
+pub struct LearnedAddresses {
+    max_addresses: usize,
+    ttl: Option<Duration>,
+    path: Option<PathBuf>,
+    services: HashMap<String, Vec<LearnedRange>>,
+    /// 按 (是否 IPv6, 前綴長度) 分組的網段地址到服務，查找時每個前綴長度只查一次；
+    /// 同一網段只屬於一個服務
+    index: BTreeMap<(bool, u8), HashMap<u128, String>>,
+    /// 上次合併後有網段被淘汰、轉給其他服務或載入的服務，其地址集合需要重建
+    stale: BTreeSet<String>,
+    /// 上次保存後有變化
+    dirty: bool,
+}
+
+impl LearnedAddresses {
+    pub fn new(config: &SniLearningConfig) -> Self {
+        Self {
+            max_addresses: config.max_addresses.max(1),
+            ttl: (config.ttl_secs > 0).then(|| Duration::from_secs(config.ttl_secs)),
+            path: config.path.clone(),
+            services: HashMap::new(),
+            index: BTreeMap::new(),
+            stale: BTreeSet::new(),
+            dirty: false,
+        }
+    }
+    
+    /// 載入之前保存的網段；文件不存在時為空。載入的服務在下次合併時重建地址集合
+    pub fn load(&mut self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let saved: BTreeMap<String, Vec<SavedRange>> = serde_json::from_str(&text)?;
+        for (service, ranges) in saved {
+            for range in ranges {
+                let network = parse_cidr(&range.network)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                self.learn_range(&service, network, range.last_seen.into());
+            }
+            self.stale.insert(service);
+        }
+        self.dirty = false;
+        Ok(())
+    }
+    
+    /// 記下服務使用的地址，返回是否為新學到的
+    pub fn learn(&mut self, service: &str, addr: IpAddr, now: SystemTime) -> bool {
+        self.learn_range(service, (addr, host_prefix(addr)), now)
+    }
+    
+    /// 記下服務使用的網段，返回是否為新學到的；已落在該服務的網段內時只更新最後見到的時間
+    pub fn learn_range(&mut self, service: &str, network: (IpAddr, u8), now: SystemTime) -> bool {
+        let network = normalize(network);
+        self.dirty = true;
+        let known = self.services.get_mut(service)
+            .and_then(|ranges| ranges.iter_mut().find(|range| covers(range.network, network)));
+        if let Some(range) = known {
+            range.last_seen = range.last_seen.max(now);
+            return false;
+        }
+        
+        for (owner, ranges) in self.services.iter_mut() {
+            if owner != service && ranges.iter().any(|range| range.network == network) {
+                ranges.retain(|range| range.network != network);
+                self.stale.insert(owner.clone());
+            }
+        }
+        let ranges = self.services.entry(service.to_string()).or_default();
+        ranges.push(LearnedRange { network, last_seen: now });
+        index_insert(&mut self.index, network, service);
+        if ranges.len() > self.max_addresses {
+            let oldest = ranges.iter().enumerate()
+                .min_by_key(|(_, range)| range.last_seen)
+                .map(|(i, _)| i);
+            if let Some(oldest) = oldest {
+                index_remove(&mut self.index, ranges.remove(oldest).network);
+                self.stale.insert(service.to_string());
+            }
+        }
+        true
+    }
+    
+    /// 包含該地址的最小網段所屬的服務，從最長的前綴開始查
+    pub fn lookup(&self, addr: &IpAddr) -> Option<&str> {
+        let (v6, bits) = to_bits(*addr);
+        let width = host_prefix(*addr);
+        self.index.range((v6, 0)..=(v6, width)).rev()
+            .find_map(|(&(_, prefix), networks)| networks.get(&mask_bits(bits, prefix, width)))
+            .map(String::as_str)
+    }
+    
+    /// 某個服務學到的網段
+    pub fn ranges(&self, service: &str) -> Vec<(IpAddr, u8)> {
+        self.services.get(service)
+            .map(|ranges| ranges.iter().map(|range| range.network).collect())
+            .unwrap_or_default()
+    }
+    
+    /// 丟棄超過 ttl 未見的網段，合併同一服務內相鄰的兩個兄弟網段（如兩個 /25 合成 /24），
+    /// 去掉被其他網段包含的網段；返回上次合併以來網段有變化的服務（按名稱排序），其地址集合需要重建
+    pub fn compact(&mut self, now: SystemTime) -> Vec<String> {
+        let ttl = self.ttl;
+        for (service, ranges) in self.services.iter_mut() {
+            let mut kept: Vec<LearnedRange> = ranges.iter()
+                .filter(|range| ttl.is_none_or(|ttl| now.duration_since(range.last_seen).unwrap_or_default() <= ttl))
+                .copied()
+                .collect();
+            kept = merge_ranges(&kept);
+            if kept != *ranges {
+                *ranges = kept;
+                self.dirty = true;
+                self.stale.insert(service.clone());
+            }
+        }
+        self.services.retain(|_, ranges| !ranges.is_empty());
+        
+        self.index.clear();
+        for (service, ranges) in &self.services {
+            for range in ranges {
+                index_insert(&mut self.index, range.network, service);
+            }
+        }
+        std::mem::take(&mut self.stale).into_iter().collect()
+    }
+    
+    /// 有變化時寫回文件：先寫 `<path>.tmp` 再改名，中途退出不會留下寫了一半的文件
+    pub fn save(&mut self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.dirty {
+            return Ok(());
+        }
+        
+        let saved: BTreeMap<&String, Vec<SavedRange>> = self.services.iter()
+            .filter(|(_, ranges)| !ranges.is_empty())
+            .map(|(service, ranges)| {
+                let ranges = ranges.iter()
+                    .map(|range| SavedRange {
+                        network: format!("{}/{}", range.network.0, range.network.1),
+                        last_seen: range.last_seen.into(),
+                    })
+                    .collect();
+                (service, ranges)
+            })
+            .collect();
+        let tmp = tmp_path(path);
+        fs::write(&tmp, serde_json::to_vec_pretty(&saved)?)?;
+        fs::rename(&tmp, path)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+/// 從最長的前綴開始兩兩合併兄弟網段，合併結果可以繼續向上合併；最後見到的時間取較晚的
+fn merge_ranges(ranges: &[LearnedRange]) -> Vec<LearnedRange> {
+    // 鍵為 (是否 IPv6, 前綴長度, 網段地址)
+    let mut networks: BTreeMap<(bool, u8, u128), SystemTime> = BTreeMap::new();
+    for range in ranges {
+        let (v6, bits) = to_bits(range.network.0);
+        let last_seen = networks.entry((v6, range.network.1, bits)).or_insert(range.last_seen);
+        *last_seen = (*last_seen).max(range.last_seen);
+    }
+    
+    for v6 in [false, true] {
+        let width = if v6 { 128 } else { 32 };
+        for prefix in (1..=width).rev() {
+            let sibling_bit = 1u128 << (width - prefix);
+            let lows: Vec<u128> = networks.range((v6, prefix, 0)..=(v6, prefix, u128::MAX))
+                .map(|(&(_, _, bits), _)| bits)
+                .filter(|bits| bits & sibling_bit == 0)
+                .collect();
+            for low in lows {
+                if let Some(high_seen) = networks.remove(&(v6, prefix, low | sibling_bit)) {
+                    let low_seen = networks.remove(&(v6, prefix, low)).unwrap_or(high_seen);
+                    let parent = networks.entry((v6, prefix - 1, low)).or_insert(low_seen);
+                    *parent = (*parent).max(low_seen).max(high_seen);
+                }
+            }
+        }
+    }
+    
+    // 前綴短的在前，被已有網段包含的併入該網段
+    let mut entries: Vec<_> = networks.into_iter().collect();
+    entries.sort_by_key(|&((v6, prefix, bits), _)| (prefix, v6, bits));
+    let mut merged: Vec<LearnedRange> = Vec::new();
+    for ((v6, prefix, bits), last_seen) in entries {
+        let network = (from_bits(v6, bits), prefix);
+        match merged.iter_mut().find(|range| covers(range.network, network)) {
+            Some(range) => range.last_seen = range.last_seen.max(last_seen),
+            None => merged.push(LearnedRange { network, last_seen }),
+        }
+    }
+    merged
+}
+
+fn index_insert(index: &mut BTreeMap<(bool, u8), HashMap<u128, String>>, network: (IpAddr, u8), service: &str) {
+    let (v6, bits) = to_bits(network.0);
+    index.entry((v6, network.1)).or_default().insert(bits, service.to_string());
+}
+
+fn index_remove(index: &mut BTreeMap<(bool, u8), HashMap<u128, String>>, network: (IpAddr, u8)) {
+    let (v6, bits) = to_bits(network.0);
+    if let Some(networks) = index.get_mut(&(v6, network.1)) {
+        networks.remove(&bits);
+        if networks.is_empty() {
+            index.remove(&(v6, network.1));
+        }
+    }
+}
+
+/// `outer` 網段是否包含整個 `inner` 網段
+fn covers(outer: (IpAddr, u8), inner: (IpAddr, u8)) -> bool {
+    outer.1 <= inner.1 && cidr_contains(outer, inner.0)
+}
+
+fn host_prefix(addr: IpAddr) -> u8 {
+    if addr.is_ipv4() { 32 } else { 128 }
+}
+
+fn to_bits(addr: IpAddr) -> (bool, u128) {
+    match addr {
+        IpAddr::V4(addr) => (false, u32::from(addr) as u128),
+        IpAddr::V6(addr) => (true, u128::from(addr)),
+    }
+}
+
+fn from_bits(v6: bool, bits: u128) -> IpAddr {
+    if v6 {
+        Ipv6Addr::from(bits).into()
+    } else {
+        Ipv4Addr::from(bits as u32).into()
+    }
+}
+
+/// 清掉網段地址中前綴之後的位
+fn normalize(network: (IpAddr, u8)) -> (IpAddr, u8) {
+    let (addr, prefix) = network;
+    let width = host_prefix(addr);
+    let prefix = prefix.min(width);
+    let (v6, bits) = to_bits(addr);
+    (from_bits(v6, mask_bits(bits, prefix, width)), prefix)
+}
+
+/// 只保留地址位中前 `prefix` 位，`width` 為地址位數
+fn mask_bits(bits: u128, prefix: u8, width: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        prefix => bits & (u128::MAX << (128 - prefix) >> (128 - width)),
+    }
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn config(max_addresses: usize, path: Option<PathBuf>) -> SniLearningConfig {
+        SniLearningConfig {
+            max_addresses,
+            path,
+            add_to_nftables: false,
+            require_dns_answer: false,
+            compact_interval_secs: 300,
+            ttl_secs: 3600,
+        }
+    }
+    
+    #[test]
+    fn test_bounded_and_persisted() {
+        let dir = std::env::temp_dir().join(format!("trafficmon-learned-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("learned.json");
+        let _ = fs::remove_file(&path);
+        let config = config(2, Some(path.clone()));
+        let addr = |last: u8| IpAddr::from([142, 250, 0, last]);
+        let at = |secs: u64| SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000 + secs);
+        
+        let mut learned = LearnedAddresses::new(&config);
+        assert!(learned.learn("youtube", addr(1), at(0)));
+        assert!(!learned.learn("youtube", addr(1), at(1)));
+        assert!(learned.learn("youtube", addr(2), at(2)));
+        // 超出上限時淘汰最久未見的
+        assert!(learned.learn("youtube", addr(3), at(3)));
+        assert_eq!(learned.ranges("youtube"), [(addr(2), 32), (addr(3), 32)]);
+        assert_eq!(learned.lookup(&addr(1)), None);
+        // 地址換了服務時從原服務移除
+        assert!(learned.learn("google", addr(3), at(4)));
+        assert_eq!(learned.ranges("youtube"), [(addr(2), 32)]);
+        // 被淘汰和被轉走的地址要從 youtube 的地址集合中移除，只新增了地址的 google 不需要重建
+        assert_eq!(learned.compact(at(5)), ["youtube"]);
+        assert!(learned.compact(at(6)).is_empty());
+        
+        learned.save().unwrap();
+        let mut reloaded = LearnedAddresses::new(&config);
+        reloaded.load().unwrap();
+        assert_eq!(reloaded.lookup(&addr(2)), Some("youtube"));
+        assert_eq!(reloaded.lookup(&addr(3)), Some("google"));
+        // 載入的地址還不在集合中
+        assert_eq!(reloaded.compact(at(7)), ["google", "youtube"]);
+        
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn test_compaction() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut learned = LearnedAddresses::new(&config(16, None));
+        learned.learn_range("google", parse_cidr("10.0.0.0/25").unwrap(), now - Duration::from_secs(60));
+        learned.learn_range("google", parse_cidr("10.0.0.128/25").unwrap(), now);
+        learned.learn("google", "10.0.0.7".parse().unwrap(), now);
+        // 超過 ttl（一小時）未見
+        learned.learn("google", "192.0.2.1".parse().unwrap(), now - Duration::from_secs(7200));
+        
+        assert_eq!(learned.compact(now), ["google"]);
+        assert_eq!(learned.ranges("google"), [parse_cidr("10.0.0.0/24").unwrap()]);
+        assert_eq!(learned.lookup(&"10.0.0.200".parse().unwrap()), Some("google"));
+        assert_eq!(learned.lookup(&"192.0.2.1".parse().unwrap()), None);
+    }
+    
+    #[test]
+    fn test_lookup_longest_prefix() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut learned = LearnedAddresses::new(&config(16, None));
+        learned.learn_range("google", parse_cidr("10.0.0.0/8").unwrap(), now);
+        learned.learn_range("youtube", parse_cidr("10.1.0.0/16").unwrap(), now);
+        learned.learn("meet", "10.1.2.3".parse().unwrap(), now);
+        learned.learn_range("netflix", parse_cidr("2a00:86c0::/32").unwrap(), now);
+        
+        let lookup = |addr: &str| learned.lookup(&addr.parse().unwrap());
+        assert_eq!(lookup("10.1.2.3"), Some("meet"));
+        assert_eq!(lookup("10.1.2.4"), Some("youtube"));
+        assert_eq!(lookup("10.200.0.1"), Some("google"));
+        assert_eq!(lookup("11.0.0.1"), None);
+        assert_eq!(lookup("2a00:86c0:1::1"), Some("netflix"));
+        // IPv4 網段不匹配前綴位相同的 IPv6 地址
+        assert_eq!(lookup("::a01:203"), None);
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::rotate::RotatingFile;
+use crate::stats::TrafficStats;
+use crate::store::Bucket;
+
+/// 檢查停止標誌的最長間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// 每隔 `interval` 輪轉一次統計，並把每個輪轉桶各服務的統計以 logfmt 追加到文件，每個服務一行：
+/// `ts=<RFC 3339> service=netflix bytes=123 packets=45`；不含私有服務
+pub struct LogfmtExporter {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl LogfmtExporter {
+    /// 文件超過 `max_bytes` 時輪轉為 `<path>.1`；其他讀取方觸發的輪轉同樣寫入，每個桶只寫一次
+    pub fn start(stats: Arc<TrafficStats>, path: &Path, max_bytes: u64, interval: Duration) -> io::Result<Self> {
+        let mut file = RotatingFile::open(path, max_bytes)?;
+        let rotations = stats.subscribe();
+        let private = stats.private_services().clone();
+        let stop = Arc::new(AtomicBool::new(false));
+        let path = path.to_path_buf();
+        
+        let handle = {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let mut next = Instant::now() + interval;
+                while !stop.load(Ordering::SeqCst) {
+                    let now = Instant::now();
+                    if now >= next {
+                        next = now + interval;
+                        stats.flush();
+                    }
+                    match rotations.recv_timeout(POLL_INTERVAL.min(next.saturating_duration_since(now))) {
+                        Ok((timestamp, bucket)) => {
+                            let lines = logfmt_lines(timestamp, &bucket, &private);
+                            if let Err(e) = file.write(lines.as_bytes()) {
+                                eprintln!("Failed to write {}: {}", path.display(), e);
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+            })
+        };
+        
+        Ok(Self { stop, handle: Some(handle) })
+    }
+}
+
+impl Drop for LogfmtExporter {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// 一個輪轉桶的 logfmt 行，按服務名排序
+pub fn logfmt_lines(timestamp: SystemTime, bucket: &Bucket, private: &HashSet<String>) -> String {
+    let ts = DateTime::<Utc>::from(timestamp).to_rfc3339_opts(SecondsFormat::Millis, true);
+    let mut services: Vec<_> = bucket.iter()
+        .filter(|(service, _)| !private.contains(*service))
+        .collect();
+    services.sort_by(|a, b| a.0.cmp(b.0));
+    
+    services.into_iter()
+        .map(|(service, data)| {
+            format!("ts={} service={} bytes={} packets={}\n", ts, escape_value(service), data.bytes, data.packets)
+        })
+        .collect()
+}
+
+/// 含空格、等號、引號或控制字符（以及空值）時加雙引號，並轉義引號和反斜杠
+fn escape_value(value: &str) -> String {
+    let needs_quotes = value.is_empty()
+        || value.chars().any(|c| c == ' ' || c == '=' || c == '"' || c == '\\' || c.is_control());
+    if !needs_quotes {
+        return value.to_string();
+    }
+    
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::TrafficData;
+    use std::fs;
+    use std::time::Instant;
+    
+    #[test]
+    fn test_logfmt_lines() {
+        let timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let data = |bytes, packets| TrafficData { bytes, packets, first_seen: timestamp, last_seen: timestamp };
+        let bucket = Bucket::from([
+            ("prime video".to_string(), data(123, 45)),
+            ("netflix".to_string(), data(1500, 1)),
+            ("intranet".to_string(), data(64, 1)),
+        ]);
+        
+        let lines = logfmt_lines(timestamp, &bucket, &HashSet::from(["intranet".to_string()]));
+        assert_eq!(lines, concat!(
+            "ts=2023-11-14T22:13:20.000Z service=netflix bytes=1500 packets=1\n",
+            "ts=2023-11-14T22:13:20.000Z service=\"prime video\" bytes=123 packets=45\n",
+        ));
+        assert_eq!(escape_value("a \"b\"\n"), r#""a \"b\"\n""#);
+        
+        // 每次輪轉追加到文件
+        let path = std::env::temp_dir().join(format!("trafficmon-logfmt-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let stats = Arc::new(TrafficStats::new());
+        // 沒有其他讀取方時按自己的定時器輪轉
+        let exporter = LogfmtExporter::start(Arc::clone(&stats), &path, 1 << 20, Duration::from_millis(20)).unwrap();
+        stats.add_traffic("prime video", 123, 45);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while fs::read_to_string(&path).unwrap().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        drop(exporter);
+        
+        let written = fs::read_to_string(&path).unwrap();
+        assert!(written.starts_with("ts=") && written.ends_with(" service=\"prime video\" bytes=123 packets=45\n"), "{}", written);
+        fs::remove_file(&path).unwrap();
+    }
+}
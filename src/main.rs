@@ -1,14 +1,57 @@
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+// 模擬模式下只用到部分配置
+#[allow(dead_code)]
+mod config;
+
+// 規則表管理，主程序只用來 --flush 和 --test-rule
+#[allow(dead_code)]
+#[path = "nftables.rs"]
+mod nft_rules;
+
+// 分類決定日誌：抓包時按 [decision_log] 寫入，--replay-decisions 回放
+#[path = "decisions.rs"]
+mod decisions;
+
+// decisions 依賴的輪轉文件
+#[allow(dead_code)]
+#[path = "rotate.rs"]
+mod rotate;
+
+// 報告和 nft 計數輪詢各按自己的間隔觸發
+#[allow(dead_code)]
+mod schedule;
+
+// --test-rule 用來解析樣本包
+#[allow(dead_code)]
+mod packet;
+#[allow(dead_code)]
+mod flow;
+#[allow(dead_code)]
+mod capture;
+
+// eBPF 進程歸屬只在開啟 ebpf 特性時編進來，保證該路徑會被編譯檢查
+#[cfg(feature = "ebpf")]
+#[allow(dead_code, unused_imports)]
+mod process;
 
 // 定義 nftables 模塊
 mod nftables {
-    use std::collections::HashMap;
+    use std::collections::{HashMap, HashSet};
+    use std::fmt;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
     use serde::{Deserialize, Serialize};
-
+    use crate::config::{cidr_contains, parse_cidr, Config, ReloadCacheFlush};
+    
     #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct ClassifiedTraffic {
         pub bytes: u64,
@@ -20,8 +63,41 @@ mod nftables {
         pub destination_port: Option<u16>,
         pub application: String,
         pub category: TrafficCategory,
+        pub method: ClassificationMethod,
+        /// 屬於配置中 blocked 的服務（按端口或目標地址範圍）；端口分類器只標記，不會丟棄
+        #[serde(default)]
+        pub blocked: bool,
     }
-
+    
+    impl ClassifiedTraffic {
+        /// 累加同一個流的後續包
+        pub fn merge(&mut self, bytes: u64, packets: u64) {
+            self.bytes += bytes;
+            self.packets += packets;
+        }
+    }
+    
+    /// 待分類的單個包的元數據
+    #[derive(Debug, Clone, Copy)]
+    pub struct PacketMeta<'a> {
+        pub source_ip: &'a str,
+        pub destination_ip: &'a str,
+        pub source_port: Option<u16>,
+        pub destination_port: Option<u16>,
+        pub protocol: &'a str,
+        pub bytes: u64,
+    }
+    
+    /// 分類結果的來源，供下游判斷可信度
+    #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+    pub enum ClassificationMethod {
+        PortHeuristic,
+        Sni,
+        Dns,
+        Dpi,
+        MaliciousIp,
+    }
+    
     #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
     pub enum TrafficCategory {
         Web,
@@ -32,24 +108,142 @@ mod nftables {
         Voip,
         Malicious,
         Unknown,
+        /// 配置中 custom_categories 定義的分類
+        Custom(String),
     }
-
+    
+    impl TrafficCategory {
+        pub const ALL: [TrafficCategory; 8] = [
+            TrafficCategory::Web,
+            TrafficCategory::Database,
+            TrafficCategory::Streaming,
+            TrafficCategory::FileTransfer,
+            TrafficCategory::Gaming,
+            TrafficCategory::Voip,
+            TrafficCategory::Malicious,
+            TrafficCategory::Unknown,
+        ];
+        
+        pub fn as_str(&self) -> &str {
+            match self {
+                TrafficCategory::Web => "web",
+                TrafficCategory::Database => "database",
+                TrafficCategory::Streaming => "streaming",
+                TrafficCategory::FileTransfer => "file_transfer",
+                TrafficCategory::Gaming => "gaming",
+                TrafficCategory::Voip => "voip",
+                TrafficCategory::Malicious => "malicious",
+                TrafficCategory::Unknown => "unknown",
+                TrafficCategory::Custom(name) => name,
+            }
+        }
+    }
+    
+    impl fmt::Display for TrafficCategory {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(self.as_str())
+        }
+    }
+    
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct ParseCategoryError(pub String);
+    
+    impl fmt::Display for ParseCategoryError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "未知的流量分類: {}", self.0)
+        }
+    }
+    
+    impl std::error::Error for ParseCategoryError {}
+    
+    impl FromStr for TrafficCategory {
+        type Err = ParseCategoryError;
+        
+        // 不區分大小寫，只解析內置分類
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            TrafficCategory::ALL.iter()
+                .find(|c| c.as_str().eq_ignore_ascii_case(s))
+                .cloned()
+                .ok_or_else(|| ParseCategoryError(s.to_string()))
+        }
+    }
+    
+    /// 分類緩存的鍵（五元組），查找時無需分配字符串
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct CacheKey {
+        pub source_ip: IpAddr,
+        pub destination_ip: IpAddr,
+        pub source_port: u16,
+        pub destination_port: u16,
+        pub protocol: u8,
+    }
+    
+    impl CacheKey {
+        /// 地址或協議無法解析時返回 None
+        pub fn new(
+            source_ip: &str,
+            destination_ip: &str,
+            source_port: Option<u16>,
+            destination_port: Option<u16>,
+            protocol: &str,
+        ) -> Option<Self> {
+            let protocol = match protocol {
+                "icmp" => 1,
+                "tcp" => 6,
+                "udp" => 17,
+                "dccp" => 33,
+                "icmpv6" => 58,
+                "sctp" => 132,
+                other => other.parse().ok()?,
+            };
+            
+            Some(Self {
+                source_ip: source_ip.parse().ok()?,
+                destination_ip: destination_ip.parse().ok()?,
+                source_port: source_port.unwrap_or(0),
+                destination_port: destination_port.unwrap_or(0),
+                protocol,
+            })
+        }
+    }
+    
+    /// 緩存條目的默認有效期
+    pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(300);
+    
     #[derive(Debug, Clone)]
     pub struct NftablesClassifier {
         rules: HashMap<String, TrafficCategory>,
         application_map: HashMap<(u16, String), String>,
-        #[allow(dead_code)]
         malicious_ips: Vec<String>,
-        cache: HashMap<String, ClassifiedTraffic>,
+        custom_categories: Vec<String>,
+        /// 配置中 blocked 的服務名（小寫）
+        blocked_applications: HashSet<String>,
+        /// blocked 服務的地址範圍，對應 nftables 中按 `<服務>_ips` 丟棄的規則
+        blocked_ranges: Vec<(IpAddr, u8)>,
+        /// 屬於 blocked 服務的字節數，即啟用封鎖後會丟棄的流量
+        would_block_bytes: u64,
+        // 值為分類時間和結果，過期後按當前規則重新分類
+        cache: HashMap<CacheKey, (Instant, ClassifiedTraffic)>,
+        cache_ttl: Duration,
+        // 可緩存的查找中命中和未命中（含已過期）的次數
+        cache_hits: u64,
+        cache_misses: u64,
     }
-
+    
     impl NftablesClassifier {
         pub fn new() -> Self {
             let mut classifier = Self {
                 rules: HashMap::new(),
                 application_map: HashMap::new(),
                 malicious_ips: Vec::new(),
+                custom_categories: Vec::new(),
+                blocked_applications: HashSet::new(),
+                blocked_ranges: Vec::new(),
+                would_block_bytes: 0,
                 cache: HashMap::new(),
+                cache_ttl: DEFAULT_CACHE_TTL,
+                cache_hits: 0,
+                cache_misses: 0,
             };
             
             classifier.initialize_application_map();
@@ -57,6 +251,66 @@ mod nftables {
             classifier
         }
         
+        /// 在內置映射之上加入配置中的服務和 port_map（後者優先），
+        /// 只有未限定地址範圍的服務按端口映射，其分類按服務名生效
+        pub fn with_config(config: &Config) -> Self {
+            let mut classifier = Self::new().with_cache_ttl(Duration::from_secs(config.classifier_cache_ttl));
+            classifier.custom_categories = config.custom_categories.clone();
+            
+            for service in &config.services {
+                if service.ip_ranges.is_empty() {
+                    for port in &service.ports {
+                        for protocol in ["tcp", "udp"] {
+                            classifier.application_map.insert((*port, protocol.to_string()), service.name.clone());
+                        }
+                    }
+                }
+                classifier.add_category_rule(&service.name, service.category.as_deref());
+                if service.blocked {
+                    classifier.blocked_applications.insert(service.name.to_lowercase());
+                    classifier.blocked_ranges.extend(service.ip_ranges.iter().filter_map(|range| parse_cidr(range).ok()));
+                }
+            }
+            
+            for mapping in &config.port_map {
+                classifier.application_map.insert((mapping.port, mapping.protocol.to_lowercase()), mapping.application.clone());
+                classifier.add_category_rule(&mapping.application, mapping.category.as_deref());
+            }
+            
+            for ip in &config.malicious_ips {
+                classifier.add_malicious_ip(ip);
+            }
+            
+            classifier
+        }
+        
+        fn add_category_rule(&mut self, application: &str, category: Option<&str>) {
+            let Some(category) = category else {
+                return;
+            };
+            match self.parse_category(category) {
+                Ok(category) => {
+                    self.rules.insert(application.to_lowercase(), category);
+                }
+                Err(e) => eprintln!("{}: {}", application, e),
+            }
+        }
+        
+        /// 內置分類優先，其次是配置中定義的自定義分類
+        fn parse_category(&self, name: &str) -> Result<TrafficCategory, ParseCategoryError> {
+            name.parse().or_else(|e| {
+                self.custom_categories.iter()
+                    .find(|c| c.eq_ignore_ascii_case(name))
+                    .map(|c| TrafficCategory::Custom(c.clone()))
+                    .ok_or(e)
+            })
+        }
+        
+        pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
+            self.cache_ttl = ttl;
+            self
+        }
+        
         fn initialize_application_map(&mut self) {
             // Web 流量
             self.application_map.insert((80, "tcp".to_string()), "HTTP".to_string());
@@ -71,6 +325,11 @@ mod nftables {
             // DNS
             self.application_map.insert((53, "udp".to_string()), "DNS".to_string());
             self.application_map.insert((53, "tcp".to_string()), "DNS".to_string());
+            
+            // STUN/TURN (WebRTC 等 VoIP)
+            self.application_map.insert((3478, "udp".to_string()), "STUN".to_string());
+            self.application_map.insert((3478, "tcp".to_string()), "STUN".to_string());
+            self.application_map.insert((5349, "tcp".to_string()), "STUN".to_string());
         }
         
         fn initialize_rules(&mut self) {
@@ -78,6 +337,7 @@ mod nftables {
             self.rules.insert("https".to_string(), TrafficCategory::Web);
             self.rules.insert("mysql".to_string(), TrafficCategory::Database);
             self.rules.insert("postgresql".to_string(), TrafficCategory::Database);
+            self.rules.insert("stun".to_string(), TrafficCategory::Voip);
         }
         
         pub fn classify_traffic(
@@ -89,20 +349,44 @@ mod nftables {
             protocol: &str,
             bytes: u64,
         ) -> ClassifiedTraffic {
-            let cache_key = format!(
-                "{}-{}-{}-{}-{}",
-                source_ip, destination_ip,
-                source_port.unwrap_or(0),
-                destination_port.unwrap_or(0),
-                protocol
-            );
+            self.classify(&PacketMeta { source_ip, destination_ip, source_port, destination_port, protocol, bytes })
+        }
+        
+        pub fn classify(&mut self, packet: &PacketMeta) -> ClassifiedTraffic {
+            self.classify_at(packet, Instant::now())
+        }
+        
+        /// 以 `now` 判斷緩存是否過期
+        pub fn classify_at(&mut self, packet: &PacketMeta, now: Instant) -> ClassifiedTraffic {
+            let PacketMeta { source_ip, destination_ip, source_port, destination_port, protocol, bytes } = *packet;
             
-            if let Some(cached) = self.cache.get(&cache_key) {
-                return cached.clone();
+            // 無法解析的地址不進入緩存
+            let cache_key = CacheKey::new(source_ip, destination_ip, source_port, destination_port, protocol);
+            
+            // 命中時把本包累加到緩存的流上，返回的仍是本包的字節數和包數
+            let mut previous = None;
+            if let Some((classified_at, cached)) = cache_key.and_then(|key| self.cache.get_mut(&key)) {
+                if now.saturating_duration_since(*classified_at) < self.cache_ttl {
+                    cached.merge(bytes, 1);
+                    let packet = ClassifiedTraffic { bytes, packets: 1, ..cached.clone() };
+                    self.cache_hits += 1;
+                    if packet.blocked {
+                        self.would_block_bytes += bytes;
+                    }
+                    return packet;
+                }
+                // 過期的流重新分類，已累計的字節數和包數保留
+                previous = Some((cached.bytes, cached.packets));
+            }
+            if cache_key.is_some() {
+                self.cache_misses += 1;
             }
             
-            let application = self.detect_application(destination_port, protocol);
-            let category = self.detect_category(&application, destination_port, protocol);
+            let (application, category, method) = self.decide(source_ip, destination_ip, destination_port, protocol);
+            let blocked = self.is_blocked(&application, destination_ip);
+            if blocked {
+                self.would_block_bytes += bytes;
+            }
             
             let classified = ClassifiedTraffic {
                 bytes,
@@ -112,24 +396,80 @@ mod nftables {
                 destination_ip: destination_ip.to_string(),
                 source_port,
                 destination_port,
-                application: application.clone(),
+                application,
                 category,
+                method,
+                blocked,
             };
             
-            self.cache.insert(cache_key, classified.clone());
+            if let Some(key) = cache_key {
+                let mut flow = classified.clone();
+                if let Some((bytes, packets)) = previous {
+                    flow.merge(bytes, packets);
+                }
+                self.cache.insert(key, (now, flow));
+            }
             classified
         }
         
+        /// 按當前規則得出應用、分類和方法，不經過緩存
+        fn decide(
+            &self,
+            source_ip: &str,
+            destination_ip: &str,
+            destination_port: Option<u16>,
+            protocol: &str,
+        ) -> (String, TrafficCategory, ClassificationMethod) {
+            if self.malicious_ip(source_ip, destination_ip).is_some() {
+                ("Malicious".to_string(), TrafficCategory::Malicious, ClassificationMethod::MaliciousIp)
+            } else {
+                let application = self.detect_application(destination_port, protocol);
+                let category = self.detect_category(&application, destination_port, protocol);
+                (application, category, ClassificationMethod::PortHeuristic)
+            }
+        }
+        
+        /// 應用屬於 blocked 服務，或目標地址落在 blocked 服務的地址範圍內
+        fn is_blocked(&self, application: &str, destination_ip: &str) -> bool {
+            self.blocked_applications.contains(&application.to_lowercase())
+                || destination_ip.parse().is_ok_and(|addr| self.blocked_ranges.iter().any(|range| cidr_contains(*range, addr)))
+        }
+        
+        /// 屬於 blocked 服務的累計字節數，用於在不使用 nftables 時預覽封鎖的效果
+        pub fn would_block_bytes(&self) -> u64 {
+            self.would_block_bytes
+        }
+        
+        /// 命中惡意列表的一端地址
+        pub fn malicious_ip<'a>(&self, source_ip: &'a str, destination_ip: &'a str) -> Option<&'a str> {
+            [source_ip, destination_ip].into_iter().find(|ip| self.malicious_ips.iter().any(|m| m == ip))
+        }
+        
         fn detect_application(&self, port: Option<u16>, protocol: &str) -> String {
             if let Some(port_num) = port {
                 if let Some(app) = self.application_map.get(&(port_num, protocol.to_string())) {
                     return app.clone();
                 }
                 
+                // 端口表只適用於 TCP/UDP
+                match protocol {
+                    "sctp" => return "SCTP".to_string(),
+                    "dccp" => return "DCCP".to_string(),
+                    _ => {}
+                }
+                
                 match port_num {
                     20..=21 => "FTP".to_string(),
                     22 => "SSH".to_string(),
                     25 => "SMTP".to_string(),
+                    587 => "SMTP-Submission".to_string(),
+                    465 => "SMTPS".to_string(),
+                    143 => "IMAP".to_string(),
+                    993 => "IMAPS".to_string(),
+                    110 => "POP3".to_string(),
+                    995 => "POP3S".to_string(),
+                    3389 => "RDP".to_string(),
+                    5900..=5906 => "VNC".to_string(),
                     53 => "DNS".to_string(),
                     80 => "HTTP".to_string(),
                     443 => "HTTPS".to_string(),
@@ -145,6 +485,10 @@ mod nftables {
         fn detect_category(&self, application: &str, port: Option<u16>, _protocol: &str) -> TrafficCategory {
             let app_lower = application.to_lowercase();
             
+            if let Some(category) = self.rules.get(&app_lower) {
+                return category.clone();
+            }
+            
             if app_lower.contains("http") || app_lower.contains("web") {
                 return TrafficCategory::Web;
             }
@@ -153,11 +497,16 @@ mod nftables {
                 return TrafficCategory::Database;
             }
             
+            if app_lower.contains("stun") {
+                return TrafficCategory::Voip;
+            }
+            
             if let Some(port_num) = port {
                 match port_num {
                     80 | 443 | 8080 | 8443 => TrafficCategory::Web,
                     3306 | 5432 | 27017 => TrafficCategory::Database,
                     21 | 22 => TrafficCategory::FileTransfer,
+                    3478 | 5349 => TrafficCategory::Voip,
                     _ => TrafficCategory::Unknown,
                 }
             } else {
@@ -165,7 +514,6 @@ mod nftables {
             }
         }
         
-        #[allow(dead_code)]
         pub fn add_malicious_ip(&mut self, ip: &str) {
             if !self.malicious_ips.contains(&ip.to_string()) {
                 self.malicious_ips.push(ip.to_string());
@@ -175,19 +523,77 @@ mod nftables {
         pub fn get_traffic_summary(&self) -> HashMap<TrafficCategory, u64> {
             let mut summary = HashMap::new();
             
-            for traffic in self.cache.values() {
+            for (_, traffic) in self.cache.values() {
                 *summary.entry(traffic.category.clone()).or_insert(0) += traffic.bytes;
             }
             
             summary
         }
         
-        #[allow(dead_code)]
+        /// 按應用（HTTP、MySQL、DNS 等）匯總字節數
+        pub fn get_application_summary(&self) -> HashMap<String, u64> {
+            let mut summary = HashMap::new();
+            
+            for (_, traffic) in self.cache.values() {
+                *summary.entry(traffic.application.clone()).or_insert(0) += traffic.bytes;
+            }
+            
+            summary
+        }
+        
+        /// 清空緩存並重置命中計數
         pub fn clear_cache(&mut self) {
             self.cache.clear();
+            self.cache_hits = 0;
+            self.cache_misses = 0;
+        }
+        
+        /// 上次清空緩存以來的（命中數, 未命中數），無法解析地址的包不計入
+        pub fn cache_stats(&self) -> (u64, u64) {
+            (self.cache_hits, self.cache_misses)
+        }
+        
+        /// 只丟棄按當前規則分類結果會改變的緩存條目，返回丟棄的條目數
+        pub fn invalidate_stale(&mut self) -> usize {
+            let before = self.cache.len();
+            let stale: Vec<CacheKey> = self.cache.iter()
+                .filter(|(_, (_, cached))| {
+                    let (application, category, method) = self.decide(
+                        &cached.source_ip, &cached.destination_ip, cached.destination_port, &cached.protocol,
+                    );
+                    application != cached.application || category != cached.category || method != cached.method
+                        || self.is_blocked(&cached.application, &cached.destination_ip) != cached.blocked
+                })
+                .map(|(key, _)| *key)
+                .collect();
+            for key in stale {
+                self.cache.remove(&key);
+            }
+            before - self.cache.len()
+        }
+        
+        /// 按新配置重建規則，並按 classifier_reload_flush 清空或只失效受影響的緩存，
+        /// 返回丟棄的緩存條目數
+        pub fn reload(&mut self, config: &Config) -> usize {
+            let cache = std::mem::take(&mut self.cache);
+            let (hits, misses) = self.cache_stats();
+            let would_block_bytes = self.would_block_bytes;
+            *self = Self::with_config(config);
+            self.cache = cache;
+            (self.cache_hits, self.cache_misses) = (hits, misses);
+            self.would_block_bytes = would_block_bytes;
+            
+            match config.classifier_reload_flush {
+                ReloadCacheFlush::All => {
+                    let dropped = self.cache.len();
+                    self.clear_cache();
+                    dropped
+                }
+                ReloadCacheFlush::Stale => self.invalidate_stale(),
+            }
         }
     }
-
+    
     impl Default for NftablesClassifier {
         fn default() -> Self {
             Self::new()
@@ -196,7 +602,8 @@ mod nftables {
 }
 
 // 使用模塊中的類型
-use nftables::{NftablesClassifier, TrafficCategory, ClassifiedTraffic};
+use nftables::{NftablesClassifier, TrafficCategory, ClassifiedTraffic, ClassificationMethod};
+use config::{ByteUnits, CategoryStyle};
 
 // 定義 TrafficStats 結構體
 #[derive(Debug, Clone)]
@@ -206,22 +613,30 @@ struct TrafficStats {
     packets_received: u64,
     packets_sent: u64,
     classified_traffic: HashMap<TrafficCategory, u64>,
+    // 目標地址在這些網段內的流量記為接收
+    local_networks: Vec<(IpAddr, u8)>,
 }
 
 impl TrafficStats {
-    fn new() -> Self {
+    fn new(local_networks: Vec<(IpAddr, u8)>) -> Self {
         Self {
             bytes_received: 0,
             bytes_sent: 0,
             packets_received: 0,
             packets_sent: 0,
             classified_traffic: HashMap::new(),
+            local_networks,
         }
     }
     
+    fn is_local(&self, addr: &str) -> bool {
+        addr.parse::<IpAddr>()
+            .is_ok_and(|addr| self.local_networks.iter().any(|net| config::cidr_contains(*net, addr)))
+    }
+    
     fn update(&mut self, classified: &ClassifiedTraffic) {
-        // 簡單假設:根據端口判斷是接收還是發送
-        if classified.destination_port == Some(80) || classified.destination_port == Some(443) {
+        // 發往本地網段的是接收，其餘（本地發出或無法判斷）記為發送
+        if self.is_local(&classified.destination_ip) {
             self.bytes_received += classified.bytes;
             self.packets_received += classified.packets;
         } else {
@@ -233,66 +648,548 @@ impl TrafficStats {
         *self.classified_traffic.entry(classified.category.clone()).or_insert(0) += classified.bytes;
     }
     
-    fn display_summary(&self) {
+    fn display_summary(&self, format: &ReportFormat) {
+        let units = format.units;
         println!("=== 流量統計 ===");
-        println!("接收: {} 字節, {} 包包", self.bytes_received, self.packets_received);
-        println!("發送: {} 字節, {} 包包", self.bytes_sent, self.packets_sent);
-        println!("總計: {} 字節", self.bytes_received + self.bytes_sent);
+        println!("接收: {}, {} 包包", units.format(self.bytes_received), self.packets_received);
+        println!("發送: {}, {} 包包", units.format(self.bytes_sent), self.packets_sent);
+        println!("總計: {}", units.format(self.bytes_received + self.bytes_sent));
         
         println!("\n=== 流量分類 ===");
         for (category, bytes) in &self.classified_traffic {
-            println!("{:?}: {} 字節", category, bytes);
+            println!("{}: {}", format.category(category), units.format(*bytes));
         }
         println!("================\n");
     }
 }
 
+// 臨時封鎖地址的後端，測試中可替換
+trait IpBlocker: Send {
+    fn block_ip_temporarily(&self, ip: &str, duration_seconds: u32) -> Result<(), String>;
+}
+
+// 把地址加入 nft_table 表的 dynamic_block 集合，超時後由 nftables 自動移除
+impl IpBlocker for nft_rules::NftablesClassifier {
+    fn block_ip_temporarily(&self, ip: &str, duration_seconds: u32) -> Result<(), String> {
+        nft_rules::NftablesClassifier::block_ip_temporarily(self, ip, duration_seconds).map_err(|e| e.to_string())
+    }
+}
+
+// 發現惡意流量時按配置記錄、告警或封鎖；同一地址在去抖時間內只處理一次
+struct MaliciousResponder {
+    config: config::MaliciousResponseConfig,
+    blocker: Box<dyn IpBlocker>,
+    last_handled: HashMap<String, Instant>,
+}
+
+impl MaliciousResponder {
+    fn new(config: config::MaliciousResponseConfig, blocker: Box<dyn IpBlocker>) -> Self {
+        Self {
+            config,
+            blocker,
+            last_handled: HashMap::new(),
+        }
+    }
+    
+    // 返回需要處理的惡意地址（非惡意流量或仍在去抖時間內時返回 None）；
+    // 只查分類器，處理交給 respond，調用方可以先釋放分類器的鎖
+    fn detect(&mut self, classifier: &NftablesClassifier, classified: &ClassifiedTraffic, now: Instant) -> Option<String> {
+        if classified.category != TrafficCategory::Malicious {
+            return None;
+        }
+        let ip = classifier.malicious_ip(&classified.source_ip, &classified.destination_ip)?;
+        
+        let debounce = Duration::from_secs(self.config.debounce_secs);
+        if self.last_handled.get(ip).is_some_and(|last| now.saturating_duration_since(*last) < debounce) {
+            return None;
+        }
+        // 順便清理過期的記錄
+        self.last_handled.retain(|_, last| now.saturating_duration_since(*last) < debounce);
+        self.last_handled.insert(ip.to_string(), now);
+        Some(ip.to_string())
+    }
+    
+    // 按配置記錄、告警或封鎖 detect 返回的地址
+    fn respond(&self, classified: &ClassifiedTraffic, ip: &str) {
+        if self.config.log {
+            eprintln!("發現惡意流量: {}:{} -> {}:{} [{}]",
+                classified.source_ip, classified.source_port.unwrap_or(0),
+                classified.destination_ip, classified.destination_port.unwrap_or(0), classified.protocol);
+        }
+        if self.config.alert {
+            println!("🚨 告警: 惡意地址 {} 出現在流量中", ip);
+        }
+        if let Some(seconds) = self.config.block_seconds {
+            match self.blocker.block_ip_temporarily(ip, seconds) {
+                Ok(()) => eprintln!("已封鎖 {} {} 秒", ip, seconds),
+                Err(e) => eprintln!("封鎖 {} 失敗: {}", ip, e),
+            }
+        }
+    }
+}
+
+// 報告輸出格式：字節單位和各分類的顯示樣式
+#[derive(Debug, Clone, Default)]
+struct ReportFormat {
+    units: ByteUnits,
+    category_styles: HashMap<String, CategoryStyle>,
+}
+
+impl ReportFormat {
+    fn from_config(config: &config::Config) -> Self {
+        Self {
+            units: config.byte_units,
+            category_styles: config.category_styles.clone(),
+        }
+    }
+    
+    // 內置的分類圖標
+    fn default_emoji(category: &TrafficCategory) -> &'static str {
+        match category {
+            TrafficCategory::Web => "🌐",
+            TrafficCategory::Database => "🗄️",
+            TrafficCategory::Streaming => "🎬",
+            TrafficCategory::FileTransfer => "📁",
+            TrafficCategory::Gaming => "🎮",
+            TrafficCategory::Voip => "📞",
+            TrafficCategory::Malicious => "⚠️",
+            TrafficCategory::Unknown => "❓",
+            TrafficCategory::Custom(_) => "🏷️",
+        }
+    }
+    
+    // 分類的顯示文本，配置中未設置的部分使用默認值
+    fn category(&self, category: &TrafficCategory) -> String {
+        let style = self.category_styles.get(category.as_str());
+        let label = style.and_then(|s| s.label.as_deref()).unwrap_or(category.as_str());
+        let emoji = style.and_then(|s| s.emoji.as_deref()).unwrap_or(Self::default_emoji(category));
+        let text = format!("{} {}", emoji, label);
+        
+        let color = match style.and_then(|s| s.color.as_deref()) {
+            Some("red") => "31",
+            Some("green") => "32",
+            Some("yellow") => "33",
+            Some("blue") => "34",
+            Some("magenta") => "35",
+            Some("cyan") => "36",
+            Some("white") => "37",
+            _ => return text,
+        };
+        format!("\x1b[{}m{}\x1b[0m", color, text)
+    }
+}
+
+// 逐包日誌級別，由 -v 的次數決定
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum LogLevel {
+    Quiet,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    fn from_verbosity(verbosity: usize) -> Self {
+        match verbosity {
+            0 => LogLevel::Quiet,
+            1 => LogLevel::Info,
+            _ => LogLevel::Debug,
+        }
+    }
+    
+    // Info 每 10 輪輸出一次，Debug 輸出每個包
+    fn should_log_packet(&self, packet_count: u64) -> bool {
+        match self {
+            LogLevel::Quiet => false,
+            LogLevel::Info => packet_count % 10 == 0,
+            LogLevel::Debug => true,
+        }
+    }
+}
+
+// 命令行參數
+#[derive(Debug, Default)]
+struct CliArgs {
+    verbosity: usize,
+    // 以 JSON lines 逐包輸出，取代間隔彙總
+    json_lines: bool,
+    // 只校驗配置後退出
+    check_config: bool,
+    // 刪除 trafficmon 創建的 nftables 表後退出
+    flush: bool,
+    // 回放分類決定日誌後退出，可按服務或主機過濾
+    replay_decisions: Option<PathBuf>,
+    replay_filter: decisions::DecisionFilter,
+    // 用樣本包（十六進制幀或 pcap 文件）預覽規則文件中的 TrafficRule 是否匹配後退出
+    test_rule: Option<(PathBuf, String)>,
+    // 報告中按服務對比抓包和 nftables 計數的字節數
+    interface_stats: bool,
+    config_path: Option<PathBuf>,
+}
+
+impl CliArgs {
+    fn parse<I: IntoIterator<Item = String>>(args: I) -> Self {
+        let mut cli = CliArgs::default();
+        let mut args = args.into_iter();
+        
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--verbose" => cli.verbosity += 1,
+                "--json" => cli.json_lines = true,
+                "--check-config" => cli.check_config = true,
+                "--flush" => cli.flush = true,
+                "--interface-stats" => cli.interface_stats = true,
+                "--replay-decisions" => match args.next() {
+                    Some(path) => cli.replay_decisions = Some(PathBuf::from(path)),
+                    None => eprintln!("--replay-decisions 需要指定文件路徑"),
+                },
+                "--service" => match args.next() {
+                    Some(service) => cli.replay_filter.service = Some(service),
+                    None => eprintln!("--service 需要指定服務名"),
+                },
+                "--host" => match args.next().map(|addr| addr.parse::<IpAddr>()) {
+                    Some(Ok(addr)) => cli.replay_filter.addr = Some(addr),
+                    Some(Err(e)) => eprintln!("--host 地址無效: {}", e),
+                    None => eprintln!("--host 需要指定 IP 地址"),
+                },
+                "--test-rule" => match (args.next(), args.next()) {
+                    (Some(rule), Some(packet)) => cli.test_rule = Some((PathBuf::from(rule), packet)),
+                    _ => eprintln!("--test-rule 需要指定規則文件和樣本包"),
+                },
+                "--config" => match args.next() {
+                    Some(path) => cli.config_path = Some(PathBuf::from(path)),
+                    None => eprintln!("--config 需要指定文件路徑"),
+                },
+                // 支持 -v、-vv、-vvv 等寫法
+                a if a.len() > 1 && a.starts_with('-') && a[1..].chars().all(|c| c == 'v') => {
+                    cli.verbosity += a.len() - 1;
+                }
+                other => eprintln!("忽略未知參數: {}", other),
+            }
+        }
+        
+        cli
+    }
+}
+
+// 載入並校驗配置，不修改防火牆也不開始抓包
+fn check_config(path: Option<&Path>) -> Result<(), Vec<String>> {
+    let config = match path {
+        Some(path) => config::Config::from_file(path),
+        None => config::Config::load(),
+    }
+    .map_err(|e| vec![e.to_string()])?;
+    
+    let errors = config.validate();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+// 刪除配置中 nft_table 表的全部規則和集合，並確認表已不存在；與正常退出不同，不保留任何狀態
+fn flush_nftables(path: Option<&Path>) -> Result<String, String> {
+    let config = match path {
+        Some(path) => config::Config::from_file(path),
+        None => config::Config::load(),
+    }
+    .map_err(|e| e.to_string())?;
+    
+    nft_rules::NftablesClassifier::from_config(&config)
+        .and_then(|classifier| classifier.flush())
+        .map(|()| format!("{} {}", config.nft_family, config.nft_table))
+        .map_err(|e| e.to_string())
+}
+
+// 按寫入順序輸出符合過濾條件的分類決定，返回輸出的條數
+fn replay_decisions<W: Write>(out: &mut W, path: &Path, filter: &decisions::DecisionFilter) -> io::Result<usize> {
+    let decisions = decisions::replay(path, filter)?;
+    for decision in &decisions {
+        let endpoint = |addr: IpAddr, port: Option<u16>| match port {
+            Some(port) => format!("{}:{}", addr, port),
+            None => addr.to_string(),
+        };
+        writeln!(
+            out, "{} {} -> {} proto {} {} ({})",
+            decision.timestamp.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            endpoint(decision.src_ip, decision.src_port),
+            endpoint(decision.dst_ip, decision.dst_port),
+            decision.protocol, decision.service, decision.method
+        )?;
+    }
+    Ok(decisions.len())
+}
+
+// 在用戶態判斷規則是否匹配樣本包，不改動防火牆；返回規則名和是否匹配
+fn test_rule(config: &config::Config, rule_path: &Path, sample: &str) -> Result<(String, bool), String> {
+    let text = std::fs::read_to_string(rule_path).map_err(|e| format!("{}: {}", rule_path.display(), e))?;
+    let rule: nft_rules::TrafficRule = toml::from_str(&text).map_err(|e| format!("{}: {}", rule_path.display(), e))?;
+    
+    let (link, frame) = load_sample_packet(sample)?;
+    let info = packet::parse_frame(link, &frame).ok_or("無法解析樣本包")?;
+    let matches = nft_rules::NftablesClassifier::from_config(config)
+        .and_then(|classifier| classifier.rule_matches(&rule, &info))
+        .map_err(|e| e.to_string())?;
+    Ok((rule.name, matches))
+}
+
+// 存在的文件按 pcap 讀取第一個包，否則按十六進制的以太網幀解析（可帶空白和冒號）
+fn load_sample_packet(sample: &str) -> Result<(packet::LinkType, Vec<u8>), String> {
+    let path = Path::new(sample);
+    if path.is_file() {
+        use capture::CaptureSource;
+        
+        let mut source = capture::PcapFileSource::open(path).map_err(|e| e.to_string())?;
+        return match source.next_packet() {
+            Ok(Some(packet)) => Ok((packet.link, packet.data.to_vec())),
+            Ok(None) | Err(pcap::Error::NoMorePackets) => Err(format!("{} 中沒有包", sample)),
+            Err(e) => Err(e.to_string()),
+        };
+    }
+    
+    let digits: String = sample.chars().filter(|c| !c.is_whitespace() && *c != ':').collect();
+    if digits.is_empty() || digits.len() % 2 != 0 {
+        return Err("樣本包不是有效的十六進制".to_string());
+    }
+    let frame = (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| "樣本包不是有效的十六進制".to_string())?;
+    Ok((packet::LinkType::Ethernet, frame))
+}
+
+// 將分類結果輸出為一行 JSON，方便接 jq 等工具
+fn write_json_line<W: Write>(out: &mut W, classified: &ClassifiedTraffic) -> io::Result<()> {
+    let record = serde_json::json!({
+        "timestamp": chrono::Local::now().to_rfc3339(),
+        "service": classified.application,
+        "src": classified.source_ip,
+        "dst": classified.destination_ip,
+        "src_port": classified.source_port,
+        "dst_port": classified.destination_port,
+        "protocol": classified.protocol,
+        "bytes": classified.bytes,
+        "category": classified.category,
+        "blocked": classified.blocked,
+    });
+    writeln!(out, "{}", record)
+}
+
+// 分類決定日誌中的方法名，與 --replay-decisions 的輸出一致
+fn method_name(method: ClassificationMethod) -> &'static str {
+    match method {
+        ClassificationMethod::PortHeuristic => "port",
+        ClassificationMethod::Sni => "sni",
+        ClassificationMethod::Dns => "dns",
+        ClassificationMethod::Dpi => "dpi",
+        ClassificationMethod::MaliciousIp => "malicious_ip",
+    }
+}
+
+// 把一個包的分類結果記入決定日誌；地址無法解析的包不記錄
+fn log_decision(log: &mut decisions::DecisionLog, classified: &ClassifiedTraffic) -> io::Result<()> {
+    let Some(key) = nftables::CacheKey::new(
+        &classified.source_ip,
+        &classified.destination_ip,
+        classified.source_port,
+        classified.destination_port,
+        &classified.protocol,
+    ) else {
+        return Ok(());
+    };
+    log.record(&decisions::Decision {
+        timestamp: chrono::Utc::now(),
+        src_ip: key.source_ip,
+        dst_ip: key.destination_ip,
+        protocol: key.protocol,
+        src_port: classified.source_port,
+        dst_port: classified.destination_port,
+        service: classified.application.clone(),
+        method: method_name(classified.method).to_string(),
+    })
+}
+
 // 信號處理
 fn setup_signal_handler(running: Arc<AtomicBool>) {
     ctrlc::set_handler(move || {
-        println!("\n收到停止信號,正在關閉...");
+        eprintln!("\n收到停止信號,正在關閉...");
         running.store(false, Ordering::SeqCst);
     }).expect("設置信號處理器失敗");
 }
 
+/// 檢查配置文件是否被修改的間隔
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// 配置文件修改後重新載入分類規則，分類緩存按 classifier_reload_flush 處理
+fn watch_config(path: PathBuf, classifier: Arc<std::sync::Mutex<NftablesClassifier>>, running: Arc<AtomicBool>) {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last = modified(&path);
+    
+    while running.load(Ordering::SeqCst) {
+        thread::sleep(CONFIG_POLL_INTERVAL);
+        let current = modified(&path);
+        if current == last {
+            continue;
+        }
+        last = current;
+        
+        match config::Config::from_file(&path) {
+            Ok(config) => {
+                let dropped = classifier.lock().unwrap().reload(&config);
+                println!("🔄 已重新載入配置 {}，丟棄 {} 條分類緩存", path.display(), dropped);
+            }
+            Err(e) => eprintln!("重新載入配置失敗，繼續使用原配置: {}", e),
+        }
+    }
+}
+
+// nftables 服務計數的來源：按間隔讀取規則集，或 nft_monitor 開啟時由 nft monitor 線程實時累計
+enum NftCounts {
+    Poll(Box<nft_rules::NftablesClassifier>),
+    Monitor(Arc<std::sync::Mutex<HashMap<String, u64>>>),
+}
+
+// 運行 nft monitor trace，把規則命中按服務累計到 bytes 中，停止後刪除 trace 規則
+fn monitor_nft(rules: nft_rules::NftablesClassifier, config: config::Config, bytes: Arc<std::sync::Mutex<HashMap<String, u64>>>, running: Arc<AtomicBool>) {
+    let result = rules.run_monitor(&config, &running, |update| {
+        *bytes.lock().unwrap().entry(update.service().to_string()).or_insert(0) += rules.extrapolate(update.bytes);
+    });
+    if let Err(e) = result {
+        eprintln!("nft monitor 失敗，不再更新 nftables 計數: {}", e);
+    }
+}
+
 // 統計報告函數
+// 按 report_interval 輸出報告，按 nft_poll_interval 讀取 nftables 計數；
+// nft 為 None 或讀取失敗後不再輪詢。interface_stats 時報告對比抓包和 nftables 計數
 fn report_stats(
     stats: Arc<std::sync::Mutex<TrafficStats>>, 
     nft_classifier: Arc<std::sync::Mutex<NftablesClassifier>>, 
-    interval: u64,
-    running: Arc<AtomicBool>
+    mut schedule: schedule::Schedule,
+    mut nft: Option<NftCounts>,
+    running: Arc<AtomicBool>,
+    format: ReportFormat,
+    interface_stats: bool
 ) {
+    let mut nft_bytes: Option<HashMap<String, u64>> = None;
     while running.load(Ordering::SeqCst) {
-        // 顯示統計信息
-        {
-            let stats_guard = stats.lock().unwrap();
-            stats_guard.display_summary();
-        }
-        
-        // 顯示分類器統計
-        {
-            let classifier_guard = nft_classifier.lock().unwrap();
-            let summary = classifier_guard.get_traffic_summary();
-            if !summary.is_empty() {
-                println!("=== 分類器統計 ===");
-                for (category, bytes) in summary {
-                    println!("{:?}: {} 字節", category, bytes);
+        for task in schedule.due(Instant::now()) {
+            match task {
+                schedule::Task::NftPoll => {
+                    let rules = match &nft {
+                        Some(NftCounts::Poll(rules)) => rules,
+                        Some(NftCounts::Monitor(bytes)) => {
+                            nft_bytes = Some(bytes.lock().unwrap().clone());
+                            continue;
+                        }
+                        None => continue,
+                    };
+                    match rules.get_service_bytes() {
+                        Ok(bytes) => nft_bytes = Some(bytes),
+                        Err(e) => {
+                            eprintln!("讀取 nftables 計數失敗，停止輪詢: {}", e);
+                            nft = None;
+                        }
+                    }
                 }
-                println!("==================\n");
+                schedule::Task::Report => display_report(&stats, &nft_classifier, nft_bytes.as_ref(), &format, interface_stats),
             }
         }
         
-        thread::sleep(Duration::from_secs(interval));
+        // 最多睡一秒，停止後及時退出
+        thread::sleep(schedule.until_next(Instant::now()).min(Duration::from_secs(1)));
     }
 }
 
+fn display_report(
+    stats: &std::sync::Mutex<TrafficStats>,
+    nft_classifier: &std::sync::Mutex<NftablesClassifier>,
+    nft_bytes: Option<&HashMap<String, u64>>,
+    format: &ReportFormat,
+    interface_stats: bool
+) {
+    let units = format.units;
+    
+    // 顯示統計信息
+    {
+        let stats_guard = stats.lock().unwrap();
+        stats_guard.display_summary(format);
+    }
+    
+    // 顯示分類器統計
+    {
+        let classifier_guard = nft_classifier.lock().unwrap();
+        let summary = classifier_guard.get_traffic_summary();
+        if !summary.is_empty() {
+            println!("=== 分類器統計 ===");
+            for (category, bytes) in summary {
+                println!("{}: {}", format.category(&category), units.format(bytes));
+            }
+            println!("--- 按應用 ---");
+            for (application, bytes) in classifier_guard.get_application_summary() {
+                println!("{}: {}", application, units.format(bytes));
+            }
+            let would_block = classifier_guard.would_block_bytes();
+            if would_block > 0 {
+                println!("⛔ 封鎖服務的流量（未實際丟棄）: {}", units.format(would_block));
+            }
+            let (hits, misses) = classifier_guard.cache_stats();
+            if hits + misses > 0 {
+                println!("緩存命中率: {:.1}% ({}/{})", hits as f64 * 100.0 / (hits + misses) as f64, hits, hits + misses);
+            }
+            println!("==================\n");
+        }
+    }
+    
+    // 最近一次輪詢到的 nftables 服務計數
+    let Some(nft_bytes) = nft_bytes else {
+        return;
+    };
+    if interface_stats {
+        let pcap = pcap_service_bytes(&nft_classifier.lock().unwrap());
+        println!("=== 抓包與 nftables 計數對比 ===");
+        print!("{}", nft_rules::format_counter_comparison(&nft_rules::compare_counters(&pcap, nft_bytes)));
+        println!("==================\n");
+    } else if !nft_bytes.is_empty() {
+        let mut services: Vec<_> = nft_bytes.iter().collect();
+        services.sort();
+        println!("=== nftables 計數 ===");
+        for (service, bytes) in services {
+            println!("{}: {}", service, units.format(*bytes));
+        }
+        println!("==================\n");
+    }
+}
+
+// 抓包看到的按應用字節數，應用名轉為小寫以對應 nftables 規則中的服務名；不統計包數
+fn pcap_service_bytes(classifier: &NftablesClassifier) -> HashMap<String, (u64, u64)> {
+    let mut services = HashMap::new();
+    for (application, bytes) in classifier.get_application_summary() {
+        services.entry(application.to_lowercase()).or_insert((0, 0)).0 += bytes;
+    }
+    services
+}
+
+// 分類結果的可選去處：惡意流量響應和分類決定日誌
+struct CaptureSinks {
+    responder: Option<MaliciousResponder>,
+    decision_log: Option<decisions::DecisionLog>,
+}
+
 // 模擬流量捕獲的函數
 fn capture_traffic(
     stats: Arc<std::sync::Mutex<TrafficStats>>, 
     classifier: Arc<std::sync::Mutex<NftablesClassifier>>,
-    running: Arc<AtomicBool>
+    running: Arc<AtomicBool>,
+    log_level: LogLevel,
+    json_lines: bool,
+    units: ByteUnits,
+    sinks: CaptureSinks
 ) {
+    let CaptureSinks { mut responder, mut decision_log } = sinks;
     let mut packet_count = 0;
+    let stdout = io::stdout();
     
     while running.load(Ordering::SeqCst) {
         packet_count += 1;
@@ -306,20 +1203,44 @@ fn capture_traffic(
         ];
         
         for (src_ip, dst_ip, src_port, dst_port, protocol, bytes) in sample_traffic {
-            let classified = {
+            let (classified, malicious) = {
                 let mut classifier_guard = classifier.lock().unwrap();
-                classifier_guard.classify_traffic(src_ip, dst_ip, src_port, dst_port, protocol, bytes)
+                let classified = classifier_guard.classify_traffic(src_ip, dst_ip, src_port, dst_port, protocol, bytes);
+                let malicious = responder.as_mut()
+                    .and_then(|responder| responder.detect(&classifier_guard, &classified, Instant::now()));
+                (classified, malicious)
             };
+            // 封鎖要調用 nft，釋放分類器的鎖之後再處理
+            if let (Some(responder), Some(ip)) = (responder.as_ref(), malicious) {
+                responder.respond(&classified, &ip);
+            }
             
             {
                 let mut stats_guard = stats.lock().unwrap();
                 stats_guard.update(&classified);
             }
             
-            if packet_count % 10 == 0 {
-                println!("處理包包 #{}: {}:{} -> {}:{} [{}] - {} 字節", 
+            if json_lines {
+                if let Err(e) = write_json_line(&mut stdout.lock(), &classified) {
+                    eprintln!("輸出 JSON 失敗: {}", e);
+                }
+            } else if log_level.should_log_packet(packet_count) {
+                println!("處理包包 #{}: {}:{} -> {}:{} [{}] - {}", 
                     packet_count, src_ip, src_port.unwrap_or(0), 
-                    dst_ip, dst_port.unwrap_or(0), protocol, bytes);
+                    dst_ip, dst_port.unwrap_or(0), protocol, units.format(bytes));
+            }
+            
+            if let Some(log) = decision_log.as_mut() {
+                if let Err(e) = log_decision(log, &classified) {
+                    eprintln!("寫入分類決定失敗: {}", e);
+                }
+            }
+        }
+        
+        // 每輪寫出一次緩衝的分類決定
+        if let Some(log) = decision_log.as_mut() {
+            if let Err(e) = log.flush() {
+                eprintln!("寫入分類決定失敗: {}", e);
             }
         }
         
@@ -328,11 +1249,125 @@ fn capture_traffic(
 }
 
 fn main() {
-    println!("🚀 TrafficMon 流量監控工具啟動中...");
+    let cli = CliArgs::parse(std::env::args().skip(1));
+    
+    if cli.check_config {
+        match check_config(cli.config_path.as_deref()) {
+            Ok(()) => {
+                println!("配置檢查通過");
+                std::process::exit(0);
+            }
+            Err(errors) => {
+                for error in &errors {
+                    eprintln!("配置錯誤: {}", error);
+                }
+                std::process::exit(1);
+            }
+        }
+    }
+    
+    if cli.flush {
+        match flush_nftables(cli.config_path.as_deref()) {
+            Ok(table) => {
+                println!("已刪除 nftables 表 {}", table);
+                std::process::exit(0);
+            }
+            Err(error) => {
+                eprintln!("清除 nftables 狀態失敗: {}", error);
+                std::process::exit(1);
+            }
+        }
+    }
+    
+    if let Some(path) = &cli.replay_decisions {
+        match replay_decisions(&mut io::stdout().lock(), path, &cli.replay_filter) {
+            Ok(count) => {
+                eprintln!("共 {} 條分類決定", count);
+                std::process::exit(0);
+            }
+            Err(error) => {
+                eprintln!("讀取分類決定日誌失敗: {}", error);
+                std::process::exit(1);
+            }
+        }
+    }
+    
+    if let Some((rule_path, sample)) = &cli.test_rule {
+        let config = match cli.config_path.as_deref() {
+            Some(path) => config::Config::from_file(path),
+            None => config::Config::load(),
+        };
+        // 配置讀不出來時用默認配置測試的結果沒有意義
+        let config = match config {
+            Ok(config) => config,
+            Err(error) => {
+                eprintln!("載入配置失敗: {}", error);
+                std::process::exit(2);
+            }
+        };
+        match test_rule(&config, rule_path, sample) {
+            Ok((name, true)) => {
+                println!("規則 {} 匹配樣本包", name);
+                std::process::exit(0);
+            }
+            Ok((name, false)) => {
+                println!("規則 {} 不匹配樣本包", name);
+                std::process::exit(1);
+            }
+            Err(error) => {
+                eprintln!("測試規則失敗: {}", error);
+                std::process::exit(2);
+            }
+        }
+    }
+    
+    let log_level = LogLevel::from_verbosity(cli.verbosity);
+    let json_lines = cli.json_lines;
+    
+    // JSON 模式下 stdout 只留給數據行
+    if !json_lines {
+        println!("🚀 TrafficMon 流量監控工具啟動中...");
+    }
+    
+    // 優先使用 --config 指定的文件，之後監視的也是這個文件；JSON 輸出始終是原始字節數
+    let config_path = cli.config_path.clone()
+        .or_else(|| config::Config::find_path().map(Path::to_path_buf));
+    let config = match config_path.as_deref() {
+        Some(path) => config::Config::from_file(path),
+        None => config::Config::load(),
+    };
+    let config = match config {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("載入配置失敗: {}", error);
+            std::process::exit(1);
+        }
+    };
+    let format = ReportFormat::from_config(&config);
+    let units = format.units;
     
     // 初始化統計數據
-    let stats = Arc::new(std::sync::Mutex::new(TrafficStats::new()));
-    let classifier = Arc::new(std::sync::Mutex::new(NftablesClassifier::new()));
+    let stats = Arc::new(std::sync::Mutex::new(TrafficStats::new(config.detect_local_networks())));
+    let classifier = NftablesClassifier::with_config(&config);
+    let responder = config.malicious_response.clone().and_then(|response| {
+        match nft_rules::NftablesClassifier::from_config(&config) {
+            Ok(blocker) => Some(MaliciousResponder::new(response, Box::new(blocker))),
+            Err(e) => {
+                eprintln!("無法啟用惡意流量響應: {}", e);
+                None
+            }
+        }
+    });
+    let decision_log = config.decision_log.as_ref().and_then(|log| {
+        match decisions::DecisionLog::open(&log.path, log.max_bytes) {
+            Ok(decision_log) => Some(decision_log),
+            Err(e) => {
+                eprintln!("打開分類決定日誌 {} 失敗: {}", log.path.display(), e);
+                None
+            }
+        }
+    });
+    let classifier = Arc::new(std::sync::Mutex::new(classifier));
     
     // 創建全局運行狀態
     let running = Arc::new(AtomicBool::new(true));
@@ -345,25 +1380,761 @@ fn main() {
     let classifier_capture = Arc::clone(&classifier);
     let running_capture = Arc::clone(&running);
     
+    // JSON 模式下 stdout 只留給數據行，不監視配置文件
+    let watch_handle = config_path.filter(|_| !json_lines).map(|path| {
+        let (classifier, running) = (Arc::clone(&classifier), Arc::clone(&running));
+        thread::spawn(move || watch_config(path, classifier, running))
+    });
+    
     let stats_report = Arc::clone(&stats);
     let classifier_report = Arc::clone(&classifier);
     let running_report = Arc::clone(&running);
+    let schedule = schedule::Schedule::from_config(&config, Instant::now());
+    let mut nft = nft_rules::NftablesClassifier::from_config(&config).ok().map(|rules| NftCounts::Poll(Box::new(rules)));
+    let monitor_handle = match nft_rules::NftablesClassifier::from_config(&config) {
+        Ok(rules) if config.nft_monitor => {
+            let bytes = Arc::new(std::sync::Mutex::new(HashMap::new()));
+            nft = Some(NftCounts::Monitor(Arc::clone(&bytes)));
+            let (config, running) = (config.clone(), Arc::clone(&running));
+            Some(thread::spawn(move || monitor_nft(rules, config, bytes, running)))
+        }
+        _ => None,
+    };
+    let interface_stats = cli.interface_stats;
+    if interface_stats && json_lines {
+        eprintln!("--interface-stats 在 JSON 模式下不輸出報告");
+    }
     
     // 啟動流量捕獲線程
     let capture_handle = thread::spawn(move || {
-        capture_traffic(stats_capture, classifier_capture, running_capture);
+        let sinks = CaptureSinks { responder, decision_log };
+        capture_traffic(stats_capture, classifier_capture, running_capture, log_level, json_lines, units, sinks);
     });
     
-    // 啟動統計報告線程
-    let report_handle = thread::spawn(move || {
-        report_stats(stats_report, classifier_report, 5, running_report);
-    });
+    // 啟動統計報告線程，與 JSON lines 模式互斥
+    let report_handle = if json_lines {
+        None
+    } else {
+        Some(thread::spawn(move || {
+            report_stats(stats_report, classifier_report, schedule, nft, running_report, format, interface_stats);
+        }))
+    };
     
-    println!("📊 流量監控運行中... 按 Ctrl+C 停止");
+    if !json_lines {
+        println!("📊 流量監控運行中... 按 Ctrl+C 停止");
+    }
     
     // 等待線程結束
     capture_handle.join().unwrap();
-    report_handle.join().unwrap();
+    if let Some(handle) = report_handle {
+        handle.join().unwrap();
+    }
+    if let Some(handle) = watch_handle {
+        handle.join().unwrap();
+    }
+    if let Some(handle) = monitor_handle {
+        handle.join().unwrap();
+    }
+    
+    if !json_lines {
+        println!("👋 TrafficMon 已正常關閉");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nftables::ClassificationMethod;
+    
+    #[test]
+    fn test_classification_method() {
+        let mut classifier = NftablesClassifier::new();
+        
+        let https = classifier.classify_traffic("192.168.1.100", "93.184.216.34", Some(54321), Some(443), "tcp", 1500);
+        assert_eq!(https.method, ClassificationMethod::PortHeuristic);
+        assert_eq!(https.category, TrafficCategory::Web);
+        
+        let unknown = classifier.classify_traffic("192.168.1.100", "93.184.216.34", Some(54321), Some(40000), "tcp", 100);
+        assert_eq!(unknown.method, ClassificationMethod::PortHeuristic);
+        
+        classifier.add_malicious_ip("203.0.113.66");
+        let malicious = classifier.classify_traffic("192.168.1.100", "203.0.113.66", Some(54322), Some(443), "tcp", 1500);
+        assert_eq!(malicious.method, ClassificationMethod::MaliciousIp);
+        assert_eq!(malicious.category, TrafficCategory::Malicious);
+    }
+    
+    #[test]
+    fn test_verbosity_log_level() {
+        let parse = |args: &[&str]| {
+            let cli = CliArgs::parse(args.iter().map(|a| a.to_string()));
+            LogLevel::from_verbosity(cli.verbosity)
+        };
+        
+        assert_eq!(parse(&[]), LogLevel::Quiet);
+        assert_eq!(parse(&["-v"]), LogLevel::Info);
+        assert_eq!(parse(&["-vv"]), LogLevel::Debug);
+        assert_eq!(parse(&["-v", "--verbose"]), LogLevel::Debug);
+        
+        assert!(!LogLevel::Quiet.should_log_packet(10));
+        assert!(LogLevel::Info.should_log_packet(10));
+        assert!(!LogLevel::Info.should_log_packet(11));
+        assert!(LogLevel::Debug.should_log_packet(11));
+    }
+    
+    #[test]
+    fn test_json_lines_output() {
+        let mut classifier = NftablesClassifier::new();
+        let mut out = Vec::new();
+        
+        let packets = [
+            ("192.168.1.100", "93.184.216.34", Some(54321), Some(80), "tcp", 1500),
+            ("192.168.1.100", "93.184.216.34", Some(54322), Some(443), "tcp", 2500),
+            ("192.168.1.100", "8.8.8.8", Some(54324), Some(53), "udp", 512),
+        ];
+        for (src, dst, sport, dport, proto, bytes) in packets {
+            let classified = classifier.classify_traffic(src, dst, sport, dport, proto, bytes);
+            write_json_line(&mut out, &classified).unwrap();
+        }
+        
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), packets.len());
+        
+        for line in &lines {
+            let value: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert!(value["timestamp"].is_string());
+        }
+        
+        let first: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(first["service"], "HTTP");
+        assert_eq!(first["dst_port"], 80);
+        assert_eq!(first["bytes"], 1500);
+        assert_eq!(first["category"], "Web");
+    }
+    
+    #[test]
+    fn test_decision_log_from_capture() {
+        let path = std::env::temp_dir().join(format!("trafficmon-main-decisions-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut classifier = NftablesClassifier::new();
+        let mut log = decisions::DecisionLog::open(&path, 1 << 20).unwrap();
+        
+        for dport in [443, 3306] {
+            let classified = classifier.classify_traffic("192.168.1.100", "93.184.216.34", Some(54321), Some(dport), "tcp", 1500);
+            log_decision(&mut log, &classified).unwrap();
+        }
+        // 地址無法解析的包不記錄
+        let classified = classifier.classify_traffic("not-an-ip", "93.184.216.34", Some(54321), Some(80), "tcp", 100);
+        log_decision(&mut log, &classified).unwrap();
+        log.flush().unwrap();
+        
+        let mut out = Vec::new();
+        assert_eq!(replay_decisions(&mut out, &path, &decisions::DecisionFilter::default()).unwrap(), 2);
+        let replayed = decisions::replay(&path, &decisions::DecisionFilter::default()).unwrap();
+        assert_eq!(replayed[0].service, "HTTPS");
+        assert_eq!((replayed[1].service.as_str(), replayed[1].method.as_str()), ("MySQL", "port"));
+        assert_eq!(replayed[1].protocol, 6);
+        
+        std::fs::remove_file(&path).unwrap();
+    }
+    
+    #[test]
+    fn test_cache_key_hash_eq() {
+        use nftables::CacheKey;
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+        
+        let hash = |key: &CacheKey| {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            hasher.finish()
+        };
+        
+        let a = CacheKey::new("192.168.1.100", "93.184.216.34", Some(54321), Some(443), "tcp").unwrap();
+        let b = CacheKey::new("192.168.1.100", "93.184.216.34", Some(54321), Some(443), "6").unwrap();
+        assert_eq!(a, b);
+        assert_eq!(hash(&a), hash(&b));
+        
+        // 方向、端口或協議不同都是不同的鍵
+        let reversed = CacheKey::new("93.184.216.34", "192.168.1.100", Some(443), Some(54321), "tcp").unwrap();
+        let udp = CacheKey::new("192.168.1.100", "93.184.216.34", Some(54321), Some(443), "udp").unwrap();
+        assert_ne!(a, reversed);
+        assert_ne!(a, udp);
+        
+        assert!(CacheKey::new("not-an-ip", "93.184.216.34", None, Some(443), "tcp").is_none());
+    }
+    
+    #[test]
+    fn test_cached_classification_identical() {
+        let mut classifier = NftablesClassifier::new();
+        
+        let first = classifier.classify_traffic("192.168.1.100", "192.168.1.200", Some(54323), Some(3306), "tcp", 1200);
+        let cached = classifier.classify_traffic("192.168.1.100", "192.168.1.200", Some(54323), Some(3306), "tcp", 9999);
+        
+        // 命中緩存時沿用首次的分類結果，字節數為本包的
+        assert_eq!(cached.application, first.application);
+        assert_eq!(cached.category, TrafficCategory::Database);
+        assert_eq!(cached.bytes, 9999);
+        assert_eq!(classifier.get_traffic_summary()[&TrafficCategory::Database], 1200 + 9999);
+    }
+    
+    #[test]
+    fn test_repeated_flow_accumulates() {
+        let mut classifier = NftablesClassifier::new();
+        for bytes in [1500, 1500, 400] {
+            let packet = classifier.classify_traffic("192.168.1.100", "93.184.216.34", Some(54321), Some(443), "tcp", bytes);
+            assert_eq!((packet.bytes, packet.packets), (bytes, 1));
+        }
+        
+        // 緩存中的流記錄累計值，不同的流各自累計
+        assert_eq!(classifier.cache_stats(), (2, 1));
+        assert_eq!(classifier.get_application_summary()["HTTPS"], 3400);
+        classifier.classify_traffic("192.168.1.100", "93.184.216.34", Some(54322), Some(443), "tcp", 100);
+        assert_eq!(classifier.get_application_summary()["HTTPS"], 3500);
+        
+        let mut flow = classifier.classify_traffic("192.168.1.100", "93.184.216.34", Some(54321), Some(443), "tcp", 100);
+        flow.merge(900, 2);
+        assert_eq!((flow.bytes, flow.packets), (1000, 3));
+    }
     
-    println!("👋 TrafficMon 已正常關閉");
-}
\ No newline at end of file
+    #[test]
+    fn test_cache_hit_rate() {
+        let mut classifier = NftablesClassifier::new();
+        let classify = |classifier: &mut NftablesClassifier, sport, dport| {
+            classifier.classify_traffic("192.168.1.100", "93.184.216.34", Some(sport), Some(dport), "tcp", 100);
+        };
+        
+        // 相同的流命中，不同的五元組未命中
+        for _ in 0..3 {
+            classify(&mut classifier, 54321, 443);
+        }
+        classify(&mut classifier, 54322, 443);
+        classify(&mut classifier, 54321, 80);
+        assert_eq!(classifier.cache_stats(), (2, 3));
+        
+        // 無法解析的地址不經過緩存
+        classifier.classify_traffic("not-an-ip", "93.184.216.34", Some(54321), Some(443), "tcp", 100);
+        assert_eq!(classifier.cache_stats(), (2, 3));
+        
+        classifier.clear_cache();
+        assert_eq!(classifier.cache_stats(), (0, 0));
+        classify(&mut classifier, 54321, 443);
+        assert_eq!(classifier.cache_stats(), (0, 1));
+    }
+    
+    #[test]
+    fn test_cache_entry_expires() {
+        use nftables::PacketMeta;
+        use std::time::Instant;
+        
+        let mut classifier = NftablesClassifier::new().with_cache_ttl(Duration::from_secs(60));
+        let packet = PacketMeta {
+            source_ip: "192.168.1.100",
+            destination_ip: "203.0.113.66",
+            source_port: Some(54322),
+            destination_port: Some(443),
+            protocol: "tcp",
+            bytes: 1500,
+        };
+        let start = Instant::now();
+        
+        assert_eq!(classifier.classify_at(&packet, start).category, TrafficCategory::Web);
+        
+        // 規則更新後，緩存有效期內仍返回舊分類
+        classifier.add_malicious_ip("203.0.113.66");
+        assert_eq!(classifier.classify_at(&packet, start + Duration::from_secs(59)).category, TrafficCategory::Web);
+        
+        // 過期後按新規則重新分類並替換緩存，流的累計值跟著新分類保留
+        let reclassified = classifier.classify_at(&packet, start + Duration::from_secs(60));
+        assert_eq!(reclassified.category, TrafficCategory::Malicious);
+        assert_eq!((reclassified.bytes, reclassified.packets), (1500, 1));
+        assert_eq!(classifier.get_traffic_summary().get(&TrafficCategory::Web), None);
+        assert_eq!(classifier.get_traffic_summary()[&TrafficCategory::Malicious], 3 * 1500);
+    }
+    
+    #[test]
+    fn test_application_summary() {
+        let mut classifier = NftablesClassifier::new();
+        
+        classifier.classify_traffic("192.168.1.100", "93.184.216.34", Some(54321), Some(80), "tcp", 1500);
+        classifier.classify_traffic("192.168.1.100", "93.184.216.34", Some(54322), Some(443), "tcp", 2500);
+        
+        let by_application = classifier.get_application_summary();
+        assert_eq!(by_application["HTTP"], 1500);
+        assert_eq!(by_application["HTTPS"], 2500);
+        
+        let by_category = classifier.get_traffic_summary();
+        assert_eq!(by_category[&TrafficCategory::Web], 4000);
+    }
+    
+    #[test]
+    fn test_category_round_trip() {
+        for category in TrafficCategory::ALL {
+            assert_eq!(category.to_string().parse::<TrafficCategory>(), Ok(category.clone()));
+            assert_eq!(category.to_string().to_uppercase().parse::<TrafficCategory>(), Ok(category));
+        }
+        
+        assert_eq!("Web".parse::<TrafficCategory>(), Ok(TrafficCategory::Web));
+        assert_eq!(
+            "p2p".parse::<TrafficCategory>(),
+            Err(nftables::ParseCategoryError("p2p".to_string()))
+        );
+    }
+    
+    #[test]
+    fn test_check_config() {
+        let dir = std::env::temp_dir().join(format!("trafficmon-check-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        
+        let valid = r#"
+            interface = "eth0"
+            report_interval = 60
+            log_unknown_traffic = false
+            filter = "tcp or udp"
+            time_rules = []
+            blocked_domains = []
+            pattern_rules = []
+            
+            [[services]]
+            name = "netflix"
+            ports = [443]
+            ip_ranges = ["108.175.32.0/20"]
+            blocked = false
+            
+            [[user_rules]]
+            mac_address = "aa:bb:cc:dd:ee:ff"
+            name = "kid"
+            blocked_services = ["netflix"]
+        "#;
+        std::fs::write(dir.join("valid.toml"), valid).unwrap();
+        std::fs::write(dir.join("invalid.toml"), valid
+            .replace("108.175.32.0/20", "108.175.32.0/40")
+            .replace("aa:bb:cc:dd:ee:ff", "aa:bb:cc")).unwrap();
+        
+        let cli = CliArgs::parse(["--check-config", "--config", "valid.toml"].iter().map(|a| a.to_string()));
+        assert!(cli.check_config);
+        assert!(!cli.flush);
+        assert!(CliArgs::parse(["--flush".to_string()]).flush);
+        let replay = CliArgs::parse(["--replay-decisions", "d.jsonl", "--service", "netflix", "--host", "10.0.0.1"].map(String::from));
+        assert_eq!(replay.replay_decisions, Some(PathBuf::from("d.jsonl")));
+        assert_eq!(replay.replay_filter.service.as_deref(), Some("netflix"));
+        assert_eq!(replay.replay_filter.addr, Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(cli.config_path, Some(PathBuf::from("valid.toml")));
+        
+        assert_eq!(check_config(Some(&dir.join("valid.toml"))), Ok(()));
+        
+        let errors = check_config(Some(&dir.join("invalid.toml"))).unwrap_err();
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        
+        assert!(check_config(Some(&dir.join("missing.toml"))).is_err());
+        
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn test_rule_preview() {
+        let dir = std::env::temp_dir().join(format!("trafficmon-test-rule-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rule_path = dir.join("rule.toml");
+        std::fs::write(&rule_path, "name = \"https_only\"\nprotocol = \"tcp\"\nports = [443]\naction = \"accept\"\n").unwrap();
+        
+        // 以太網頭 + IPv4 + TCP 50000 -> 443
+        let frame = |dport: &str| format!(
+            "000000000000 000000000000 0800 45000028 00004000 40060000 c0a80164 5db8d822 c350{} 00000001 00000001 5018ffff 00000000",
+            dport
+        );
+        let config = config::Config::default();
+        assert_eq!(test_rule(&config, &rule_path, &frame("01bb")), Ok(("https_only".to_string(), true)));
+        assert_eq!(test_rule(&config, &rule_path, &frame("0050")), Ok(("https_only".to_string(), false)));
+        assert!(test_rule(&config, &rule_path, "0800zz").is_err());
+        
+        let cli = CliArgs::parse(["--test-rule", "rule.toml", "ab:cd"].map(String::from));
+        assert_eq!(cli.test_rule, Some((PathBuf::from("rule.toml"), "ab:cd".to_string())));
+        
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn test_interface_stats_comparison() {
+        assert!(CliArgs::parse(["--interface-stats".to_string()]).interface_stats);
+        
+        let mut classifier = NftablesClassifier::new();
+        classifier.classify_traffic("192.168.1.100", "93.184.216.34", Some(54321), Some(443), "tcp", 1500);
+        classifier.classify_traffic("192.168.1.100", "8.8.8.8", Some(54324), Some(53), "udp", 500);
+        let pcap = pcap_service_bytes(&classifier);
+        assert_eq!(pcap["https"], (1500, 0));
+        
+        // nftables 多數出的字節是抓包漏掉的流量
+        let nft_bytes = HashMap::from([("https".to_string(), 2500), ("netflix".to_string(), 300)]);
+        let rows = nft_rules::compare_counters(&pcap, &nft_bytes);
+        let row = |service: &str| rows.iter().find(|row| row.service == service).unwrap().discrepancy();
+        assert_eq!((row("https"), row("dns"), row("netflix")), (-1000, 500, -300));
+        assert!(nft_rules::format_counter_comparison(&rows).lines().nth(1).unwrap().starts_with("https"));
+    }
+    
+    #[test]
+    fn test_category_style_override() {
+        let default = ReportFormat::default();
+        assert_eq!(default.category(&TrafficCategory::Streaming), "🎬 streaming");
+        
+        let config: config::Config = toml::from_str(r#"
+            interface = "eth0"
+            report_interval = 60
+            log_unknown_traffic = false
+            services = []
+            time_rules = []
+            user_rules = []
+            blocked_domains = []
+            pattern_rules = []
+            
+            [category_styles.streaming]
+            label = "影音串流"
+            color = "magenta"
+        "#).unwrap();
+        let format = ReportFormat::from_config(&config);
+        
+        assert_eq!(format.category(&TrafficCategory::Streaming), "\x1b[35m🎬 影音串流\x1b[0m");
+        // 未映射的分類保持默認
+        assert_eq!(format.category(&TrafficCategory::Web), "🌐 web");
+    }
+    
+    #[test]
+    fn test_direction_from_local_networks() {
+        let mut classifier = NftablesClassifier::new();
+        let mut stats = TrafficStats::new(config::Config::default().parsed_local_networks());
+        
+        // 遠程服務器回給本地高端口的包是接收
+        let reply = classifier.classify_traffic("93.184.216.34", "192.168.1.100", Some(443), Some(54321), "tcp", 1500);
+        stats.update(&reply);
+        assert_eq!((stats.bytes_received, stats.bytes_sent), (1500, 0));
+        
+        // 本地發往遠程 DNS 的查詢是發送
+        let query = classifier.classify_traffic("192.168.1.100", "8.8.8.8", Some(54324), Some(53), "udp", 80);
+        stats.update(&query);
+        assert_eq!((stats.bytes_received, stats.bytes_sent), (1500, 80));
+        
+        // 自定義網段
+        let mut stats = TrafficStats::new(vec![("100.64.0.0".parse().unwrap(), 10)]);
+        stats.update(&classifier.classify_traffic("8.8.8.8", "100.64.1.2", Some(53), Some(40000), "udp", 120));
+        stats.update(&reply);
+        assert_eq!((stats.bytes_received, stats.bytes_sent), (120, 1500));
+    }
+    
+    #[test]
+    fn test_direction_without_interface_address() {
+        let mut classifier = NftablesClassifier::new();
+        let config = config::Config {
+            local_networks: vec!["100.64.0.0/10".to_string()],
+            ..config::Config::default()
+        };
+        let address = |addr: &str, netmask: Option<&str>| pcap::Address {
+            addr: addr.parse().unwrap(),
+            netmask: netmask.map(|netmask| netmask.parse().unwrap()),
+            broadcast_addr: None,
+            dst_addr: None,
+        };
+        let reply = classifier.classify_traffic("8.8.8.8", "100.64.1.2", Some(53), Some(40000), "udp", 120);
+        let query = classifier.classify_traffic("100.64.1.2", "8.8.8.8", Some(40000), Some(53), "udp", 80);
+        
+        // 網卡只有 IPv6 鏈路本地地址：按配置的網段判斷方向
+        let networks = config.local_networks_for("eth0", &[address("fe80::1", None)]);
+        assert_eq!(networks, config.parsed_local_networks());
+        let mut stats = TrafficStats::new(networks);
+        stats.update(&reply);
+        stats.update(&query);
+        assert_eq!((stats.bytes_received, stats.bytes_sent), (120, 80));
+        
+        // 有地址時加入網卡所在的子網
+        let networks = config.local_networks_for("eth0", &[address("203.0.113.5", Some("255.255.255.0"))]);
+        let mut stats = TrafficStats::new(networks);
+        stats.update(&classifier.classify_traffic("8.8.8.8", "203.0.113.77", Some(53), Some(40000), "udp", 60));
+        stats.update(&reply);
+        assert_eq!((stats.bytes_received, stats.bytes_sent), (180, 0));
+    }
+    
+    #[test]
+    fn test_classify_packet_meta() {
+        use nftables::PacketMeta;
+        
+        let mut classifier = NftablesClassifier::new();
+        let packet = PacketMeta {
+            source_ip: "192.168.1.100",
+            destination_ip: "192.168.1.200",
+            source_port: Some(54323),
+            destination_port: Some(5432),
+            protocol: "tcp",
+            bytes: 800,
+        };
+        
+        let classified = classifier.classify(&packet);
+        assert_eq!(classified.application, "PostgreSQL");
+        assert_eq!(classified.category, TrafficCategory::Database);
+        assert_eq!((classified.source_port, classified.destination_port, classified.bytes), (Some(54323), Some(5432), 800));
+        
+        // 位置參數形式得到相同結果
+        let mut classifier = NftablesClassifier::new();
+        let positional = classifier.classify_traffic("192.168.1.100", "192.168.1.200", Some(54323), Some(5432), "tcp", 800);
+        assert_eq!(positional.application, classified.application);
+        assert_eq!(positional.destination_ip, classified.destination_ip);
+    }
+    
+    #[test]
+    fn test_email_applications() {
+        let mut classifier = NftablesClassifier::new();
+        for (port, application) in [
+            (25, "SMTP"),
+            (587, "SMTP-Submission"),
+            (465, "SMTPS"),
+            (143, "IMAP"),
+            (993, "IMAPS"),
+            (110, "POP3"),
+            (995, "POP3S"),
+        ] {
+            let classified = classifier.classify_traffic("192.168.1.100", "192.168.1.10", Some(50000), Some(port), "tcp", 100);
+            assert_eq!(classified.application, application);
+        }
+    }
+    
+    #[test]
+    fn test_remote_desktop_applications() {
+        let mut classifier = NftablesClassifier::new();
+        for (port, application) in [(3389, "RDP"), (5900, "VNC"), (5906, "VNC"), (5907, "Unknown")] {
+            let classified = classifier.classify_traffic("192.168.1.100", "192.168.1.10", Some(50000), Some(port), "tcp", 100);
+            assert_eq!(classified.application, application, "port {}", port);
+        }
+    }
+    
+    #[test]
+    fn test_sctp_and_dccp_applications() {
+        let mut classifier = NftablesClassifier::new();
+        let sctp = classifier.classify_traffic("192.168.1.100", "203.0.113.1", Some(3868), Some(3868), "sctp", 100);
+        assert_eq!((sctp.application.as_str(), sctp.destination_port), ("SCTP", Some(3868)));
+        let dccp = classifier.classify_traffic("192.168.1.100", "203.0.113.1", Some(5001), Some(443), "dccp", 100);
+        assert_eq!((dccp.application.as_str(), dccp.destination_port), ("DCCP", Some(443)));
+    }
+    
+    // 記錄封鎖調用，不執行 nft
+    struct RecordingBlocker(Arc<std::sync::Mutex<Vec<(String, u32)>>>);
+    
+    impl IpBlocker for RecordingBlocker {
+        fn block_ip_temporarily(&self, ip: &str, duration_seconds: u32) -> Result<(), String> {
+            self.0.lock().unwrap().push((ip.to_string(), duration_seconds));
+            Ok(())
+        }
+    }
+    
+    #[test]
+    fn test_malicious_flow_blocked_once() {
+        let mut classifier = NftablesClassifier::new();
+        classifier.add_malicious_ip("203.0.113.66");
+        
+        let blocked = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let config = config::MaliciousResponseConfig {
+            log: false,
+            alert: false,
+            block_seconds: Some(600),
+            debounce_secs: 60,
+        };
+        let mut responder = MaliciousResponder::new(config, Box::new(RecordingBlocker(Arc::clone(&blocked))));
+        let start = Instant::now();
+        
+        // 同一地址的重複檢測在去抖時間內只封鎖一次
+        for (offset, src_port) in [(0, 54321), (1, 54322), (30, 54323)] {
+            let classified = classifier.classify_traffic("192.168.1.100", "203.0.113.66", Some(src_port), Some(443), "tcp", 1500);
+            if let Some(ip) = responder.detect(&classifier, &classified, start + Duration::from_secs(offset)) {
+                responder.respond(&classified, &ip);
+            }
+        }
+        assert_eq!(*blocked.lock().unwrap(), vec![("203.0.113.66".to_string(), 600)]);
+        
+        // 正常流量不觸發
+        let normal = classifier.classify_traffic("192.168.1.100", "93.184.216.34", Some(54324), Some(443), "tcp", 1500);
+        assert_eq!(responder.detect(&classifier, &normal, start), None);
+        
+        // 去抖時間過後再次檢測會重新封鎖
+        let inbound = classifier.classify_traffic("203.0.113.66", "192.168.1.100", Some(443), Some(54321), "tcp", 1500);
+        let ip = responder.detect(&classifier, &inbound, start + Duration::from_secs(61)).unwrap();
+        responder.respond(&inbound, &ip);
+        assert_eq!(blocked.lock().unwrap().len(), 2);
+    }
+    
+    #[test]
+    fn test_classifier_with_config() {
+        let config: config::Config = toml::from_str(r#"
+            report_interval = 60
+            log_unknown_traffic = false
+            time_rules = []
+            user_rules = []
+            blocked_domains = []
+            pattern_rules = []
+            malicious_ips = ["203.0.113.66"]
+            
+            [[services]]
+            name = "minecraft"
+            ports = [25565]
+            ip_ranges = []
+            blocked = false
+            category = "gaming"
+            
+            [[services]]
+            name = "netflix"
+            ports = [443]
+            ip_ranges = ["198.38.96.0/19"]
+            blocked = false
+            category = "streaming"
+            
+            [[port_map]]
+            port = 1883
+            application = "MQTT"
+            
+            [[port_map]]
+            port = 3306
+            application = "MariaDB"
+            category = "database"
+        "#).unwrap();
+        let mut classifier = NftablesClassifier::with_config(&config);
+        let classify = |classifier: &mut NftablesClassifier, port, protocol| {
+            let classified = classifier.classify_traffic("192.168.1.100", "192.0.2.10", Some(50000), Some(port), protocol, 100);
+            (classified.application, classified.category)
+        };
+        
+        assert_eq!(classify(&mut classifier, 1883, "tcp"), ("MQTT".to_string(), TrafficCategory::Unknown));
+        assert_eq!(classify(&mut classifier, 3306, "tcp"), ("MariaDB".to_string(), TrafficCategory::Database));
+        assert_eq!(classify(&mut classifier, 25565, "udp"), ("minecraft".to_string(), TrafficCategory::Gaming));
+        // 限定地址範圍的服務不按端口映射，內置映射保持不變
+        assert_eq!(classify(&mut classifier, 443, "tcp"), ("HTTPS".to_string(), TrafficCategory::Web));
+        
+        let malicious = classifier.classify_traffic("203.0.113.66", "192.168.1.100", Some(443), Some(50000), "tcp", 100);
+        assert_eq!(malicious.category, TrafficCategory::Malicious);
+        
+        // new() 只有內置映射
+        let mut builtin = NftablesClassifier::new();
+        assert_eq!(classify(&mut builtin, 1883, "tcp").0, "Unknown");
+    }
+    
+    #[test]
+    fn test_reload_invalidates_cache() {
+        let mut config = config::Config::default();
+        let mut classifier = NftablesClassifier::with_config(&config);
+        for port in [443, 3306, 1883] {
+            classifier.classify_traffic("192.168.1.100", "192.0.2.10", Some(50000), Some(port), "tcp", 100);
+        }
+        
+        // 只有 1883 的分類因新的 port_map 改變
+        config.port_map = vec![config::PortMapping {
+            port: 1883,
+            protocol: "tcp".to_string(),
+            application: "MQTT".to_string(),
+            category: None,
+        }];
+        config.classifier_reload_flush = config::ReloadCacheFlush::Stale;
+        assert_eq!(classifier.reload(&config), 1);
+        let summary = classifier.get_application_summary();
+        assert_eq!(summary.get("HTTPS"), Some(&100));
+        assert_eq!(summary.get("MySQL"), Some(&100));
+        assert_eq!(summary.get("Unknown"), None);
+        let reclassified = classifier.classify_traffic("192.168.1.100", "192.0.2.10", Some(50000), Some(1883), "tcp", 100);
+        assert_eq!(reclassified.application, "MQTT");
+        
+        // 規則沒有變化時不丟棄
+        assert_eq!(classifier.reload(&config), 0);
+        
+        // 默認清空全部緩存
+        config.classifier_reload_flush = config::ReloadCacheFlush::All;
+        assert_eq!(classifier.reload(&config), 3);
+        assert!(classifier.get_application_summary().is_empty());
+    }
+    
+    #[test]
+    fn test_blocked_service_flagged() {
+        let mut config = config::Config::default();
+        config.services.push(config::ServiceConfig {
+            name: "BitTorrent".to_string(),
+            ports: vec![6881],
+            ip_ranges: vec![],
+            blocked: true,
+            bidirectional: true,
+            category: None,
+        });
+        let mut classifier = NftablesClassifier::with_config(&config);
+        
+        let torrent = classifier.classify_traffic("192.168.1.100", "198.51.100.7", Some(50000), Some(6881), "udp", 1000);
+        assert_eq!(torrent.application, "BitTorrent");
+        assert!(torrent.blocked);
+        // 命中緩存的包也計入
+        classifier.classify_traffic("192.168.1.100", "198.51.100.7", Some(50000), Some(6881), "udp", 400);
+        
+        // 其他流量照常
+        let web = classifier.classify_traffic("192.168.1.100", "93.184.216.34", Some(54321), Some(443), "tcp", 2500);
+        assert!(!web.blocked);
+        assert_eq!(classifier.would_block_bytes(), 1400);
+        
+        let mut out = Vec::new();
+        write_json_line(&mut out, &torrent).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(value["blocked"], true);
+        
+        // 只有地址範圍的服務按目標地址標記，與 nftables 的地址集合丟棄規則一致
+        config.services.push(config::ServiceConfig {
+            name: "cdn".to_string(),
+            ports: vec![],
+            ip_ranges: vec!["203.0.113.0/24".to_string()],
+            blocked: true,
+            bidirectional: false,
+            category: None,
+        });
+        let mut classifier = NftablesClassifier::with_config(&config);
+        assert!(classifier.classify_traffic("192.168.1.100", "203.0.113.9", Some(50000), Some(443), "tcp", 700).blocked);
+        assert!(!classifier.classify_traffic("192.168.1.100", "203.0.114.9", Some(50000), Some(443), "tcp", 300).blocked);
+        assert_eq!(classifier.would_block_bytes(), 700);
+    }
+    
+    #[test]
+    fn test_custom_category_accumulates() {
+        let config: config::Config = toml::from_str(r#"
+            report_interval = 60
+            log_unknown_traffic = false
+            time_rules = []
+            user_rules = []
+            blocked_domains = []
+            pattern_rules = []
+            custom_categories = ["Backup"]
+            
+            [[services]]
+            name = "restic"
+            ports = [8000]
+            ip_ranges = []
+            blocked = false
+            category = "backup"
+            
+            [[port_map]]
+            port = 873
+            application = "rsync"
+            category = "BACKUP"
+            
+            [[port_map]]
+            port = 1883
+            application = "MQTT"
+            category = "iot"
+        "#).unwrap();
+        assert!(config.validate().is_empty());
+        
+        let mut classifier = NftablesClassifier::with_config(&config);
+        let mut stats = TrafficStats::new(vec![]);
+        for (sport, dport, bytes) in [(50000, 8000, 1000), (50001, 873, 500), (50002, 1883, 70), (50003, 443, 30)] {
+            let classified = classifier.classify_traffic("192.168.1.100", "192.0.2.10", Some(sport), Some(dport), "tcp", bytes);
+            stats.update(&classified);
+        }
+        
+        let backup = TrafficCategory::Custom("Backup".to_string());
+        assert_eq!(backup.to_string(), "Backup");
+        assert_eq!(classifier.get_traffic_summary()[&backup], 1500);
+        assert_eq!(stats.classified_traffic[&backup], 1500);
+        // 未在 custom_categories 中定義的名稱不生效
+        assert_eq!(stats.classified_traffic[&TrafficCategory::Unknown], 70);
+        assert_eq!(stats.classified_traffic[&TrafficCategory::Web], 30);
+        
+        // 自定義分類使用配置中的樣式，沒有時用默認圖標
+        assert_eq!(ReportFormat::default().category(&backup), "🏷️ Backup");
+    }
+}
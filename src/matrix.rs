@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use crate::config::{cidr_contains, network_address, parse_cidr, ServiceConfig, TrafficMatrixConfig};
+
+/// 超出端點對上限後流量歸入的端點名
+const OVERFLOW: &str = "other";
+
+/// 源端點 → 目標端點的字節數矩陣；端點為服務名，或按前綴聚合後的主機地址
+#[derive(Debug)]
+pub struct TrafficMatrix {
+    config: TrafficMatrixConfig,
+    /// 服務的地址範圍，先匹配先得
+    services: Vec<((IpAddr, u8), String)>,
+    bytes: HashMap<(String, String), u64>,
+}
+
+impl TrafficMatrix {
+    pub fn new(config: TrafficMatrixConfig, services: &[ServiceConfig]) -> Self {
+        let services = services.iter()
+            .flat_map(|service| {
+                service.ip_ranges.iter()
+                    .filter_map(|range| parse_cidr(range).ok())
+                    .map(|network| (network, service.name.clone()))
+            })
+            .collect();
+        
+        Self { config, services, bytes: HashMap::new() }
+    }
+    
+    pub fn observe(&mut self, src: IpAddr, dst: IpAddr, bytes: u64) {
+        let mut key = (self.endpoint(src), self.endpoint(dst));
+        // 給溢出的端點對留一個位置，總數不超過 max_pairs
+        if !self.bytes.contains_key(&key) && self.bytes.len() + 1 >= self.config.max_pairs {
+            key = (OVERFLOW.to_string(), OVERFLOW.to_string());
+        }
+        *self.bytes.entry(key).or_insert(0) += bytes;
+    }
+    
+    /// 地址所屬的服務名；不屬於任何服務時為聚合後的前綴（完整前綴時為地址本身）
+    pub fn endpoint(&self, addr: IpAddr) -> String {
+        if let Some((_, service)) = self.services.iter().find(|(network, _)| cidr_contains(*network, addr)) {
+            return service.clone();
+        }
+        
+        let (prefix, max) = match addr {
+            IpAddr::V4(_) => (self.config.ipv4_prefix.min(32), 32),
+            IpAddr::V6(_) => (self.config.ipv6_prefix.min(128), 128),
+        };
+        if prefix == max {
+            return addr.to_string();
+        }
+        format!("{}/{}", network_address(addr, prefix), prefix)
+    }
+    
+    /// (源端點, 目標端點) → 字節數
+    pub fn matrix(&self) -> HashMap<(String, String), u64> {
+        self.bytes.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn database() -> ServiceConfig {
+        ServiceConfig {
+            name: "database".to_string(),
+            ports: vec![5432],
+            ip_ranges: vec!["192.168.10.5".to_string()],
+            blocked: false,
+            bidirectional: true,
+            category: None,
+        }
+    }
+    
+    fn key(src: &str, dst: &str) -> (String, String) {
+        (src.to_string(), dst.to_string())
+    }
+    
+    #[test]
+    fn test_matrix_with_prefix_aggregation() {
+        let config = TrafficMatrixConfig { ipv4_prefix: 24, ipv6_prefix: 64, max_pairs: 3 };
+        let mut matrix = TrafficMatrix::new(config, &[database()]);
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        
+        // 同一 /24 內的兩台主機訪問數據庫，合併為一個源端點
+        matrix.observe(ip("192.168.1.10"), ip("192.168.10.5"), 1000);
+        matrix.observe(ip("192.168.1.20"), ip("192.168.10.5"), 500);
+        matrix.observe(ip("192.168.10.5"), ip("192.168.1.10"), 4000);
+        // 超出端點對上限，連同溢出的一對不超過 3 對
+        matrix.observe(ip("2001:db8::1"), ip("2001:db8::2"), 100);
+        matrix.observe(ip("10.0.0.1"), ip("10.0.1.1"), 7);
+        
+        assert_eq!(matrix.matrix(), HashMap::from([
+            (key("192.168.1.0/24", "database"), 1500),
+            (key("database", "192.168.1.0/24"), 4000),
+            (key("other", "other"), 107),
+        ]));
+        assert_eq!(matrix.endpoint(ip("2001:db8::1")), "2001:db8::/64");
+        
+        // 不聚合時端點為主機地址
+        let config = TrafficMatrixConfig { ipv4_prefix: 32, ipv6_prefix: 128, max_pairs: 16 };
+        let matrix = TrafficMatrix::new(config, &[database()]);
+        assert_eq!(matrix.endpoint(ip("192.168.1.10")), "192.168.1.10");
+        
+        // 默認按 /24 聚合，與配置示例一致
+        let config: TrafficMatrixConfig = toml::from_str("").unwrap();
+        assert_eq!((config.ipv4_prefix, config.ipv6_prefix), (24, 64));
+    }
+}
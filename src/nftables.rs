@@ -1,92 +1,375 @@
 use std::process::{Command, Stdio};
-use std::io::Write;
-use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
 use anyhow::{Result, anyhow};
-use serde_json::Value;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::config::{cidr_contains, default_nft_max_rules, is_valid_nft_identifier, parse_cidr, ClassificationPriority, Config, DnsRule, GeoIpRule, RemoteHostConfig, ServiceConfig};
+use crate::packet::{PacketInfo, IPPROTO_TCP};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NftFamily {
+    Ip,
+    Ip6,
+    Inet,
+}
+
+impl NftFamily {
+    /// 地址匹配使用的關鍵字
+    pub fn addr_keyword(&self) -> &'static str {
+        match self {
+            NftFamily::Ip6 => "ip6",
+            NftFamily::Ip | NftFamily::Inet => "ip",
+        }
+    }
+    
+    /// 地址集合的元素類型
+    pub fn addr_type(&self) -> &'static str {
+        match self {
+            NftFamily::Ip6 => "ipv6_addr",
+            NftFamily::Ip | NftFamily::Inet => "ipv4_addr",
+        }
+    }
+}
+
+/// 外部命令的執行方式，便於測試時替換為預設輸出
+pub trait CommandRunner {
+    /// 執行命令並返回標準輸出；無法啟動或退出碼非零時返回錯誤
+    fn run(&self, program: &str, args: &[String]) -> Result<String>;
+}
+
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, program: &str, args: &[String]) -> Result<String> {
+        let output = Command::new(program).args(args).output()
+            .map_err(|e| anyhow!("Failed to run {}: {}", program, e))?;
+        
+        if !output.status.success() {
+            return Err(anyhow!(
+                "{} exited with {}: {}",
+                program, output.status, String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+impl fmt::Display for NftFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            NftFamily::Ip => "ip",
+            NftFamily::Ip6 => "ip6",
+            NftFamily::Inet => "inet",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for NftFamily {
+    type Err = anyhow::Error;
+    
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ip" => Ok(NftFamily::Ip),
+            "ip6" => Ok(NftFamily::Ip6),
+            "inet" => Ok(NftFamily::Inet),
+            other => Err(anyhow!("Unsupported nftables family: {} (expected ip, ip6 or inet)", other)),
+        }
+    }
+}
 
 pub struct NftablesClassifier {
+    family: NftFamily,
     table_name: String,
     chain_name: String,
     stats_chain: String,
+    output_chain: String,
+    dns_chain: String,
+    geoip_chain: String,
+    /// 一次初始化最多創建的規則和集合元素總數
+    max_rules: usize,
+    /// 地址範圍分派和端口分派的先後
+    priority: ClassificationPriority,
+    /// 計數規則的採樣分母：只計數 1/N 的包，讀取時乘回 N；1 表示全部計數
+    sample_rate: u32,
+    /// 按名稱添加的規則，可單獨停用和重新啟用
+    named_rules: Mutex<HashMap<String, NamedRule>>,
 }
 
+/// 按名稱跟蹤的規則：所在鏈、添加命令和 nft 返回的句柄（停用時為 None）
 #[derive(Debug, Clone)]
+struct NamedRule {
+    chain: String,
+    command: String,
+    handle: Option<u64>,
+}
+
+/// DNS 頭之後 QNAME 在傳輸層中的位置（UDP 頭 8 字節 + DNS 頭 12 字節），單位為位
+const DNS_QNAME_OFFSET_BITS: usize = 160;
+/// nftables 集合鍵的最大長度（字節）
+const NFT_SET_KEY_MAX_BYTES: usize = 64;
+/// 每條 add element 命令的元素數，國家地址列表可達數萬條
+const GEOIP_ELEMENT_BATCH: usize = 1024;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
 pub struct TrafficRule {
     pub name: String,
     pub protocol: String,
     pub ports: Vec<u16>,
     pub ip_ranges: Vec<String>,
     pub payload_patterns: Vec<String>,
+    /// 包長度範圍（字節），渲染為 `meta length min-max`
+    pub min_length: Option<u16>,
+    pub max_length: Option<u16>,
+    /// 本機進程的屬主 UID，僅在 output 鏈有效
+    pub uid: Option<u32>,
+    /// cgroup v2 路徑（如 "system.slice/sshd.service"），僅在 output 鏈有效
+    pub cgroup: Option<String>,
+    /// 必須置位的 TCP 標誌（如 "syn"），渲染為 `tcp flags syn`
+    pub tcp_flags: Vec<String>,
     pub action: String,
 }
 
+/// DSCP 是 6 位字段
+const DSCP_MAX: u8 = 63;
+/// DSCP 計數規則的註釋前綴，含 "traffic" 以便 `get_traffic_stats` 讀取，又不會被當作服務
+const DSCP_COMMENT_PREFIX: &str = "traffic dscp ";
+/// nft_monitor 打開 nftrace 的規則的註釋，不含 "traffic"，不會被當作計數規則
+const TRACE_COMMENT: &str = "nftrace";
+
+const TCP_FLAG_NAMES: [&str; 8] = ["fin", "syn", "rst", "psh", "ack", "urg", "ecn", "cwr"];
+
 impl NftablesClassifier {
     pub fn new(table_name: &str, chain_name: &str) -> Self {
+        Self::with_family(NftFamily::Inet, table_name, chain_name)
+    }
+    
+    pub fn with_family(family: NftFamily, table_name: &str, chain_name: &str) -> Self {
         Self {
+            family,
             table_name: table_name.to_string(),
             chain_name: chain_name.to_string(),
             stats_chain: "traffic_stats".to_string(),
+            output_chain: "local_output".to_string(),
+            dns_chain: "dns_filter".to_string(),
+            geoip_chain: "geoip_filter".to_string(),
+            max_rules: default_nft_max_rules(),
+            priority: ClassificationPriority::default(),
+            sample_rate: 1,
+            named_rules: Mutex::new(HashMap::new()),
         }
     }
-
+    
+    pub fn with_max_rules(mut self, max_rules: usize) -> Self {
+        self.max_rules = max_rules;
+        self
+    }
+    
+    pub fn with_priority(mut self, priority: ClassificationPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+    
+    /// 計數規則只對 `numgen random mod N == 0` 的包計數，0 按 1 處理
+    pub fn with_sample_rate(mut self, sample_rate: u32) -> Self {
+        self.sample_rate = sample_rate.max(1);
+        self
+    }
+    
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+    
+    /// 按採樣分母把讀到的計數還原為估計的總量
+    pub fn extrapolate(&self, count: u64) -> u64 {
+        count.saturating_mul(u64::from(self.sample_rate))
+    }
+    
+    /// 按配置中的 nft_family、nft_table、nft_chain、nft_max_rules、nft_sample_rate 和 classification_priority 創建
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Ok(Self::with_family(config.nft_family.parse()?, &config.nft_table, &config.nft_chain)
+            .with_max_rules(config.nft_max_rules)
+            .with_sample_rate(config.nft_sample_rate)
+            .with_priority(config.classification_priority))
+    }
+    
     pub fn initialize(&self) -> Result<()> {
+        self.initialize_with_config(&Config::default())
+    }
+    
+    /// 按配置創建服務計數、封鎖域名集合和 GeoIP 過濾；
+    /// 先生成全部命令，超出規則數上限時在改動任何規則之前報錯。
+    /// 配置了 block_grace_seconds 時封鎖規則在寬限期後才加入，期間可以 Ctrl+C 退出；
+    /// 配置了 preserve_counters 時重建前讀出現有計數，寫回同一鏈中註釋相同的新規則
+    pub fn initialize_with_config(&self, config: &Config) -> Result<()> {
+        let wait = |grace| {
+            thread::sleep(grace);
+            true
+        };
+        let previous = if config.preserve_counters {
+            self.read_counters_with(&SystemRunner)
+        } else {
+            HashMap::new()
+        };
+        self.initialize_preserving(config, &previous, wait, &|command| self.nft_cmd(command)).map(|_| ())
+    }
+    
+    /// 同 `initialize_with_config`，命令交給 `apply` 執行；計數規則生效後用寬限期調用 `wait`，
+    /// `wait` 返回 false（如收到停止信號）時不加入封鎖規則並返回 false
+    pub fn initialize_with_grace(
+        &self,
+        config: &Config,
+        wait: impl FnOnce(Duration) -> bool,
+        apply: &dyn Fn(&str) -> Result<()>,
+    ) -> Result<bool> {
+        self.initialize_preserving(config, &HashMap::new(), wait, apply)
+    }
+    
+    /// 同 `initialize_with_grace`，`previous` 中按 (鏈, 規則註釋) 記錄的 (包數, 字節數) 作為新計數的初始值
+    pub fn initialize_preserving(
+        &self,
+        config: &Config,
+        previous: &HashMap<(String, String), (u64, u64)>,
+        wait: impl FnOnce(Duration) -> bool,
+        apply: &dyn Fn(&str) -> Result<()>,
+    ) -> Result<bool> {
+        validate_service_names(&config.services)?;
+        let statistics = with_counter_values(self.statistics_chain_commands(&config.services), previous);
+        let domains = with_counter_values(self.dns_filter_commands(config)?, previous);
+        let geoip = with_counter_values(self.geoip_commands(&load_geoip_lists(config)?), previous);
+        self.check_rule_budget(statistics.iter().chain(&domains).chain(&geoip))?;
+        
+        // 表格不存在時刪除失敗，忽略
+        let _ = apply(&format!("delete table {} {}", self.family, self.table_name));
+        for command in self.base_structure_commands().iter().chain(&statistics) {
+            apply(command)?;
+        }
+        
+        if config.block_grace_seconds > 0 {
+            eprintln!(
+                "Counting rules active; blocking rules will be applied in {}s (press Ctrl+C to abort)",
+                config.block_grace_seconds
+            );
+            if !wait(Duration::from_secs(config.block_grace_seconds)) {
+                eprintln!("Stopped during the grace period; blocking rules were not applied");
+                return Ok(false);
+            }
+        }
+        
+        apply(&domains.join("\n"))?;
+        apply(&geoip.join("\n"))?;
+        Ok(true)
+    }
+    
+    /// 命令中的規則數和集合元素數之和超過 `max_rules` 時報錯
+    pub fn check_rule_budget<'a>(&self, commands: impl IntoIterator<Item = &'a String>) -> Result<()> {
+        let total: usize = commands.into_iter().map(|command| rule_objects(command)).sum();
+        if total > self.max_rules {
+            return Err(anyhow!(
+                "Refusing to create {} nftables rules and set elements (nft_max_rules = {})",
+                total, self.max_rules
+            ));
+        }
+        Ok(())
+    }
+    
+    pub fn initialize_with_services(&self, services: &[ServiceConfig]) -> Result<()> {
         self.cleanup()?;
         self.create_base_structure()?;
-        self.create_statistics_chain()?;
+        self.create_statistics_chain(services)?;
         Ok(())
     }
-
+    
     fn create_base_structure(&self) -> Result<()> {
-        let commands = vec![
+        for cmd in self.base_structure_commands() {
+            self.nft_cmd(&cmd)?;
+        }
+        
+        Ok(())
+    }
+    
+    fn base_structure_commands(&self) -> Vec<String> {
+        vec![
             // 創建主表格
-            format!("add table inet {}", self.table_name),
+            format!("add table {} {}", self.family, self.table_name),
             
             // 創建主過濾鏈
             format!(
-                "add chain inet {} {} {{ type filter hook forward priority 0; policy accept; }}",
-                self.table_name, self.chain_name
+                "add chain {} {} {} {{ type filter hook forward priority 0; policy accept; }}",
+                self.family, self.table_name, self.chain_name
+            ),
+            
+            // 本機發出的流量，用於按 UID/cgroup 匹配
+            format!(
+                "add chain {} {} {} {{ type filter hook output priority 0; policy accept; }}",
+                self.family, self.table_name, self.output_chain
             ),
             
             // 創建用於統計的鏈
             format!(
-                "add chain inet {} {}",
-                self.table_name, self.stats_chain
+                "add chain {} {} {}",
+                self.family, self.table_name, self.stats_chain
             ),
             
-            // 在主鏈中跳轉到統計鏈
+            // 按國家過濾地址，在域名過濾之前
+            format!(
+                "add chain {} {} {}",
+                self.family, self.table_name, self.geoip_chain
+            ),
             format!(
-                "add rule inet {} {} jump {}",
-                self.table_name, self.chain_name, self.stats_chain
+                "add rule {} {} {} jump {}",
+                self.family, self.table_name, self.chain_name, self.geoip_chain
             ),
             
-            // 創建各種集合
+            // 先過濾封鎖的域名和服務，被丟棄的包不計入統計
+            format!(
+                "add chain {} {} {}",
+                self.family, self.table_name, self.dns_chain
+            ),
             format!(
-                "add set inet {} netflix_ips {{ type ipv4_addr; flags interval; elements {{ {} }} }}",
-                self.table_name,
-                vec![
-                    "108.175.32.0/20",
-                    "198.38.96.0/19", 
-                    "198.45.48.0/20",
-                    "208.75.76.0/22",
-                    "208.75.80.0/20"
-                ].join(", ")
+                "add rule {} {} {} jump {}",
+                self.family, self.table_name, self.chain_name, self.dns_chain
             ),
             
+            // 在主鏈中跳轉到統計鏈
             format!(
-                "add set inet {} youtube_ips {{ type ipv4_addr; flags interval; elements {{ {} }} }}",
-                self.table_name,
-                vec![
-                    "173.194.0.0/16",
-                    "74.125.0.0/16",
-                    "216.58.0.0/16",
-                    "172.217.0.0/16"
-                ].join(", ")
+                "add rule {} {} {} jump {}",
+                self.family, self.table_name, self.chain_name, self.stats_chain
             ),
             
+            // 創建各種集合
+            self.addr_set_command("netflix_ips", &[
+                "108.175.32.0/20",
+                "198.38.96.0/19", 
+                "198.45.48.0/20",
+                "208.75.76.0/22",
+                "208.75.80.0/20"
+            ]),
+            
+            self.addr_set_command("youtube_ips", &[
+                "173.194.0.0/16",
+                "74.125.0.0/16",
+                "216.58.0.0/16",
+                "172.217.0.0/16"
+            ]),
+            
             format!(
-                "add set inet {} streaming_ports {{ type inet_service; elements {{ {} }} }}",
-                self.table_name,
-                vec![80, 443, 1935, 8080, 8000, 8008].iter()
+                "add set {} {} streaming_ports {{ type inet_service; elements {{ {} }} }}",
+                self.family, self.table_name,
+                [80, 443, 1935, 8080, 8000, 8008].iter()
                     .map(|p| p.to_string())
                     .collect::<Vec<_>>()
                     .join(", ")
@@ -94,68 +377,522 @@ impl NftablesClassifier {
             
             // 創建動態阻止集合
             format!(
-                "add set inet {} dynamic_block {{ type ipv4_addr; flags timeout; }}",
-                self.table_name
+                "add set {} {} dynamic_block {{ type {}; flags timeout; }}",
+                self.family, self.table_name, self.family.addr_type()
             ),
             
             // 創建用戶 MAC 地址集合
             format!(
-                "add set inet {} user_mac {{ type ether_addr; }}",
-                self.table_name
+                "add set {} {} user_mac {{ type ether_addr; }}",
+                self.family, self.table_name
             ),
-        ];
-
-        for cmd in commands {
-            self.nft_cmd(&cmd)?;
+        ]
+    }
+    
+    fn addr_set_command(&self, name: &str, ipv4_elements: &[&str]) -> String {
+        // 內建的地址範圍都是 IPv4，ip6 表格中只建立空集合
+        if self.family == NftFamily::Ip6 {
+            return format!(
+                "add set {} {} {} {{ type {}; flags interval; }}",
+                self.family, self.table_name, name, self.family.addr_type()
+            );
         }
-
+        
+        format!(
+            "add set {} {} {} {{ type {}; flags interval; elements {{ {} }} }}",
+            self.family, self.table_name, name, self.family.addr_type(), ipv4_elements.join(", ")
+        )
+    }
+    
+    fn create_statistics_chain(&self, services: &[ServiceConfig]) -> Result<()> {
+        validate_service_names(services)?;
+        let commands = self.statistics_chain_commands(services);
+        self.check_rule_budget(&commands)?;
+        
+        for rule in &commands {
+            self.nft_cmd(rule)?;
+        }
+        
         Ok(())
     }
-
-    fn create_statistics_chain(&self) -> Result<()> {
-        // 為 Netflix 流量創建計數器和規則
-        let netflix_rules = vec![
-            // 基於 IP 範圍的 Netflix 識別
-            format!(
-                "ip daddr @netflix_ips tcp dport @streaming_ports counter accept comment \"Netflix traffic\""
-            ),
-            format!(
-                "ip saddr @netflix_ips tcp sport @streaming_ports counter accept comment \"Netflix response\""
-            ),
+    
+    /// 用地址 vmap 把包直接分派到各服務的計數鏈，
+    /// 每個包只做一次查表，而不是逐條匹配每個服務的規則
+    fn statistics_chain_commands(&self, services: &[ServiceConfig]) -> Vec<String> {
+        let mut commands = Vec::new();
+        let mut request_elements = Vec::new();
+        let mut response_elements = Vec::new();
+        let mut port_dispatch = Vec::new();
+        
+        for service in services {
+            for (chain, rules) in self.service_chains(service) {
+                commands.push(format!("add chain {} {} {}", self.family, self.table_name, chain));
+                for rule in rules {
+                    commands.push(format!("add rule {} {} {} {}", self.family, self.table_name, chain, rule));
+                }
+            }
+            
+            let (request, response) = self.service_vmap_elements(service);
+            request_elements.extend(request);
+            response_elements.extend(response);
+            port_dispatch.extend(self.port_dispatch_command(service));
+        }
+        
+        // 計數規則 accept 後不再匹配，先分派的一方優先
+        if self.priority == ClassificationPriority::Port {
+            commands.append(&mut port_dispatch);
+        }
+        
+        for (map, direction, elements) in [
+            ("service_daddr_vmap", "daddr", request_elements),
+            ("service_saddr_vmap", "saddr", response_elements),
+        ] {
+            if elements.is_empty() {
+                continue;
+            }
             
-            // 基於 IP 範圍的 YouTube 識別
+            commands.push(format!(
+                "add map {} {} {} {{ type {} : verdict; flags interval; elements = {{ {} }} }}",
+                self.family, self.table_name, map, self.family.addr_type(), elements.join(", ")
+            ));
+            commands.push(format!(
+                "add rule {} {} {} {} {} vmap @{}",
+                self.family, self.table_name, self.stats_chain, self.family.addr_keyword(), direction, map
+            ));
+        }
+        
+        commands.append(&mut port_dispatch);
+        commands
+    }
+    
+    /// 服務的計數鏈 svc_<name> 及其規則，雙向服務還有回應方向的 svc_<name>_response。
+    /// 有地址範圍的服務按地址計數，不限端口：經 vmap 進入的包已經按地址分派，
+    /// 只匹配端口時其他端口的包會落回統計鏈，被後面的端口分派計到別的服務
+    fn service_chains(&self, service: &ServiceConfig) -> Vec<(String, Vec<String>)> {
+        let chain = format!("svc_{}", service.name);
+        let ranges = self.family_ranges(&service.ip_ranges);
+        let (request, response) = if ranges.is_empty() {
+            let ports = service_ports(service);
+            (format!("tcp dport {}", ports), format!("tcp sport {}", ports))
+        } else {
+            let addr = self.family.addr_keyword();
+            let ranges = ranges.join(", ");
+            (format!("{} daddr {{ {} }}", addr, ranges), format!("{} saddr {{ {} }}", addr, ranges))
+        };
+        
+        let mut chains = vec![(chain.clone(), self.counted_rule(&request, &format!("{} traffic", service.name)))];
+        if service.bidirectional {
+            chains.push((
+                format!("{}_response", chain),
+                self.counted_rule(&response, &format!("{} response", service.name)),
+            ));
+        }
+        chains
+    }
+    
+    /// 服務在 service_daddr_vmap 和 service_saddr_vmap 中的元素（"網段 : jump 鏈"）
+    fn service_vmap_elements(&self, service: &ServiceConfig) -> (Vec<String>, Vec<String>) {
+        let chain = format!("svc_{}", service.name);
+        let ranges = self.family_ranges(&service.ip_ranges);
+        
+        let request = ranges.iter().map(|range| format!("{} : jump {}", range, chain)).collect();
+        let response = if service.bidirectional {
+            ranges.iter().map(|range| format!("{} : jump {}_response", range, chain)).collect()
+        } else {
+            Vec::new()
+        };
+        (request, response)
+    }
+    
+    /// 沒有地址範圍的服務在統計鏈中按端口分派
+    fn port_dispatch_command(&self, service: &ServiceConfig) -> Option<String> {
+        (service.ip_ranges.is_empty() && !service.ports.is_empty()).then(|| format!(
+            "add rule {} {} {} tcp dport {} jump svc_{}",
+            self.family, self.table_name, self.stats_chain, service_ports(service), service.name
+        ))
+    }
+    
+    /// 計數並放行匹配的包；採樣時計數規則只命中 1/N 的包，
+    /// 其餘的包由緊跟的規則放行，不會落到後面的分派規則重復計數
+    fn counted_rule(&self, matcher: &str, comment: &str) -> Vec<String> {
+        if self.sample_rate <= 1 {
+            return vec![format!("{} counter accept comment \"{}\"", matcher, comment)];
+        }
+        
+        vec![
             format!(
-                "ip daddr @youtube_ips tcp dport @streaming_ports counter accept comment \"YouTube traffic\""
+                "{} numgen random mod {} == 0 counter accept comment \"{}\"",
+                matcher, self.sample_rate, comment
             ),
+            format!("{} accept", matcher),
+        ]
+    }
+    
+    /// 與表格地址族匹配的地址範圍（ip6 只取 IPv6，其餘只取 IPv4）
+    fn family_ranges<'a>(&self, ranges: &'a [String]) -> Vec<&'a str> {
+        ranges.iter()
+            .map(|r| r.as_str())
+            .filter(|r| r.contains(':') == (self.family == NftFamily::Ip6))
+            .collect()
+    }
+    
+    /// 根據服務配置生成計數規則，雙向服務同時匹配請求和回應方向
+    pub fn service_rule_commands(&self, service: &ServiceConfig) -> Vec<String> {
+        let ports = service_ports(service);
+        
+        self.service_counter_rules(
+            &format!("{}_ips", service.name),
+            &ports,
+            &service.name,
+            service.bidirectional,
+        )
+    }
+    
+    /// 服務地址集合的內容：集合不存在時創建，然後清空並重新填充
+    pub fn service_set_commands(&self, service: &ServiceConfig) -> Vec<String> {
+        let set = format!("{}_ips", service.name);
+        let mut commands = vec![
             format!(
-                "ip saddr @youtube_ips tcp sport @streaming_ports counter accept comment \"YouTube response\""
+                "add set {} {} {} {{ type {}; flags interval; }}",
+                self.family, self.table_name, set, self.family.addr_type()
             ),
+            format!("flush set {} {} {}", self.family, self.table_name, set),
         ];
-
-        for rule in netflix_rules {
-            let full_rule = format!(
-                "add rule inet {} {} {}",
-                self.table_name, self.stats_chain, rule
-            );
-            self.nft_cmd(&full_rule)?;
+        
+        let ranges = self.family_ranges(&service.ip_ranges);
+        if !ranges.is_empty() {
+            commands.push(format!(
+                "add element {} {} {} {{ {} }}",
+                self.family, self.table_name, set, ranges.join(", ")
+            ));
         }
-
+        
+        commands
+    }
+    
+    /// 運行時添加或更新服務，沿用 `statistics_chain_commands` 的佈局：清空並重建服務的計數鏈，
+    /// 按 `previous`（更新前的配置）刪除舊的 vmap 元素後加入新的，沒有地址範圍時按端口分派；
+    /// 統計鏈中該服務舊的平鋪計數規則和端口分派規則一併刪除。返回已執行的命令
+    pub fn upsert_service(
+        &self,
+        service: &ServiceConfig,
+        previous: Option<&ServiceConfig>,
+        runner: &dyn CommandRunner,
+    ) -> Result<Vec<String>> {
+        validate_service_names(std::slice::from_ref(service))?;
+        
+        let args = ["-a", "list", "chain", &self.family.to_string(), &self.table_name, &self.stats_chain].map(String::from);
+        let listing = runner.run("nft", &args)?;
+        
+        let chain = format!("svc_{}", service.name);
+        let mut handles = service_rule_handles(&listing, &service.name);
+        handles.extend(dispatch_rule_handles(&listing, &chain));
+        let mut commands: Vec<String> = handles.into_iter()
+            .map(|handle| format!(
+                "delete rule {} {} {} handle {}",
+                self.family, self.table_name, self.stats_chain, handle
+            ))
+            .collect();
+        
+        if let Some(previous) = previous {
+            let (request, response) = self.service_vmap_elements(previous);
+            for (map, elements) in [("service_daddr_vmap", request), ("service_saddr_vmap", response)] {
+                if elements.is_empty() {
+                    continue;
+                }
+                let keys: Vec<&str> = elements.iter().filter_map(|element| element.split(" : ").next()).collect();
+                commands.push(format!(
+                    "delete element {} {} {} {{ {} }}",
+                    self.family, self.table_name, map, keys.join(", ")
+                ));
+            }
+        }
+        
+        commands.extend(self.service_set_commands(service));
+        for (chain, rules) in self.service_chains(service) {
+            commands.push(format!("add chain {} {} {}", self.family, self.table_name, chain));
+            commands.push(format!("flush chain {} {} {}", self.family, self.table_name, chain));
+            for rule in rules {
+                commands.push(format!("add rule {} {} {} {}", self.family, self.table_name, chain, rule));
+            }
+        }
+        
+        let (request, response) = self.service_vmap_elements(service);
+        for (map, direction, elements) in [
+            ("service_daddr_vmap", "daddr", request),
+            ("service_saddr_vmap", "saddr", response),
+        ] {
+            if elements.is_empty() {
+                continue;
+            }
+            
+            commands.push(format!(
+                "add map {} {} {} {{ type {} : verdict; flags interval; }}",
+                self.family, self.table_name, map, self.family.addr_type()
+            ));
+            // 初始化時沒有服務用到這個 vmap 就沒有分派規則
+            if !listing.contains(&format!("vmap @{}", map)) {
+                commands.push(format!(
+                    "add rule {} {} {} {} {} vmap @{}",
+                    self.family, self.table_name, self.stats_chain, self.family.addr_keyword(), direction, map
+                ));
+            }
+            commands.push(format!(
+                "add element {} {} {} {{ {} }}",
+                self.family, self.table_name, map, elements.join(", ")
+            ));
+        }
+        commands.extend(self.port_dispatch_command(service));
+        
+        // nft 會把參數拼接成一條命令；舊的 vmap 元素可能已經不在（ENOENT），不算失敗
+        for command in &commands {
+            match runner.run("nft", std::slice::from_ref(command)) {
+                Err(e) if command.starts_with("delete element") && is_no_such_object(&e) => {}
+                result => {
+                    result?;
+                }
+            }
+        }
+        
+        Ok(commands)
+    }
+    
+    /// 校驗並添加配置中所有服務的規則
+    pub fn apply_services(&self, config: &Config) -> Result<()> {
+        validate_service_names(&config.services)?;
+        
+        for service in &config.services {
+            self.add_service_rules(service)?;
+        }
+        
         Ok(())
     }
-
-    pub fn add_traffic_rule(&self, rule: &TrafficRule) -> Result<()> {
-        let match_conditions = self.build_match_conditions(rule);
-        let full_rule = format!(
-            "add rule inet {} {} {} {} comment \"{}\"",
-            self.table_name, self.stats_chain, match_conditions, rule.action, rule.name
+    
+    /// 在 nft_trace_services 中各服務的計數鏈首打開 nftrace，供 `nft monitor trace` 讀取規則命中；
+    /// 未開啟 nft_monitor 時為空
+    pub fn trace_rule_commands(&self, config: &Config) -> Vec<String> {
+        if !config.nft_monitor {
+            return Vec::new();
+        }
+        
+        config.services.iter()
+            .filter(|service| config.nft_trace_services.contains(&service.name))
+            .flat_map(|service| self.service_chains(service))
+            .map(|(chain, _)| format!(
+                "insert rule {} {} {} meta nftrace set 1 comment \"{}\"",
+                self.family, self.table_name, chain, TRACE_COMMENT
+            ))
+            .collect()
+    }
+    
+    /// 刪除 `trace_rule_commands` 加入的規則
+    pub fn remove_trace_rules_with(&self, runner: &dyn CommandRunner) -> Result<()> {
+        let args = ["-a", "list", "table", &self.family.to_string(), &self.table_name].map(String::from);
+        let listing = runner.run("nft", &args)?;
+        
+        for (chain, handle) in trace_rule_handles(&listing) {
+            runner.run("nft", &[format!("delete rule {} {} {} handle {}", self.family, self.table_name, chain, handle)])?;
+        }
+        Ok(())
+    }
+    
+    /// 加入 trace 規則並運行 `nft monitor trace`，`running` 變為 false 後刪除 trace 規則
+    pub fn run_monitor(&self, config: &Config, running: &AtomicBool, on_update: impl FnMut(CounterUpdate) + Send) -> Result<()> {
+        for command in self.trace_rule_commands(config) {
+            if let Err(e) = self.nft_cmd(&command) {
+                let _ = self.remove_trace_rules_with(&SystemRunner);
+                return Err(e);
+            }
+        }
+        
+        NftMonitor::new().run(running, on_update);
+        self.remove_trace_rules_with(&SystemRunner)
+    }
+    
+    pub fn add_service_rules(&self, service: &ServiceConfig) -> Result<()> {
+        for rule in self.service_rule_commands(service) {
+            self.nft_cmd(&rule)?;
+        }
+        
+        Ok(())
+    }
+    
+    /// 為分類生成共享的命名 limit 及其下各服務的超限丟棄規則，
+    /// 規則插入到鏈首，確保在計數 accept 規則之前生效
+    pub fn category_limit_commands(&self, category: &str, mbps: u64, services: &[&ServiceConfig]) -> Vec<String> {
+        let limit_name = format!("{}_limit", category.to_lowercase());
+        // Mbps 轉換為 kbytes/second
+        let mut commands = vec![format!(
+            "add limit {} {} {} {{ rate over {} kbytes/second; }}",
+            self.family, self.table_name, limit_name, mbps * 125
+        )];
+        
+        let addr = self.family.addr_keyword();
+        for service in services {
+            let mut directions = vec!["daddr"];
+            if service.bidirectional {
+                directions.push("saddr");
+            }
+            
+            for direction in directions {
+                commands.push(format!(
+                    "insert rule {} {} {} {} {} @{}_ips limit name \"{}\" drop comment \"{} limit: {}\"",
+                    self.family, self.table_name, self.stats_chain, addr, direction,
+                    service.name, limit_name, category, service.name
+                ));
+            }
+        }
+        
+        commands
+    }
+    
+    pub fn apply_category_limits(&self, config: &Config) -> Result<()> {
+        for limit in &config.category_limits {
+            let services = config.services_in_category(&limit.category);
+            for cmd in self.category_limit_commands(&limit.category, limit.mbps, &services) {
+                self.nft_cmd(&cmd)?;
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// 給分類中各服務的包打上 mark，供 tc 的 fw 過濾器按分類整形；
+    /// 插入在統計鏈最前面，打標後繼續匹配計數規則
+    pub fn category_mark_commands(&self, category: &str, mark: u32, services: &[&ServiceConfig]) -> Vec<String> {
+        let addr = self.family.addr_keyword();
+        let mut commands = Vec::new();
+        
+        for service in services {
+            let mut directions = vec!["daddr"];
+            if service.bidirectional {
+                directions.push("saddr");
+            }
+            
+            for direction in directions {
+                commands.push(format!(
+                    "insert rule {} {} {} {} {} @{}_ips meta mark set {:#x} comment \"{} mark: {}\"",
+                    self.family, self.table_name, self.stats_chain, addr, direction,
+                    service.name, mark, category, service.name
+                ));
+            }
+        }
+        
+        commands
+    }
+    
+    pub fn apply_category_marks(&self, config: &Config) -> Result<()> {
+        for category_mark in &config.category_marks {
+            let services = config.services_in_category(&category_mark.category);
+            for cmd in self.category_mark_commands(&category_mark.category, category_mark.mark, &services) {
+                self.nft_cmd(&cmd)?;
+            }
+        }
+        
+        Ok(())
+    }
+    
+    fn service_counter_rules(&self, set: &str, ports: &str, label: &str, bidirectional: bool) -> Vec<String> {
+        let addr = self.family.addr_keyword();
+        
+        // 基於 IP 範圍的服務識別
+        let mut rules = self.counted_rule(
+            &format!("{} daddr @{} tcp dport {}", addr, set, ports),
+            &format!("{} traffic", label),
         );
         
-        self.nft_cmd(&full_rule)
+        if bidirectional {
+            rules.extend(self.counted_rule(
+                &format!("{} saddr @{} tcp sport {}", addr, set, ports),
+                &format!("{} response", label),
+            ));
+        }
+        
+        rules.into_iter()
+            .map(|rule| format!(
+                "add rule {} {} {} {}",
+                self.family, self.table_name, self.stats_chain, rule
+            ))
+            .collect()
     }
-
-    fn build_match_conditions(&self, rule: &TrafficRule) -> String {
+    
+    pub fn add_traffic_rule(&self, rule: &TrafficRule) -> Result<()> {
+        self.add_named_rule_with(rule, &SystemRunner).map(|_| ())
+    }
+    
+    /// 添加規則並記下 nft 返回的句柄，之後可按名稱停用和啟用；返回句柄
+    pub fn add_named_rule_with(&self, rule: &TrafficRule, runner: &dyn CommandRunner) -> Result<u64> {
+        let named = NamedRule {
+            chain: self.traffic_rule_chain(rule).to_string(),
+            command: self.traffic_rule_command(rule)?,
+            handle: None,
+        };
+        let handle = self.add_with_handle(&named.command, runner)?;
+        self.named_rules.lock().unwrap().insert(rule.name.clone(), NamedRule { handle: Some(handle), ..named });
+        Ok(handle)
+    }
+    
+    pub fn disable_rule(&self, name: &str) -> Result<()> {
+        self.disable_rule_with(name, &SystemRunner)
+    }
+    
+    /// 按句柄刪除規則但保留其定義；已停用時不做任何事
+    pub fn disable_rule_with(&self, name: &str, runner: &dyn CommandRunner) -> Result<()> {
+        let mut rules = self.named_rules.lock().unwrap();
+        let rule = rules.get_mut(name).ok_or_else(|| anyhow!("Unknown rule {:?}", name))?;
+        if let Some(handle) = rule.handle {
+            runner.run("nft", &[format!(
+                "delete rule {} {} {} handle {}",
+                self.family, self.table_name, rule.chain, handle
+            )])?;
+            rule.handle = None;
+        }
+        Ok(())
+    }
+    
+    pub fn enable_rule(&self, name: &str) -> Result<()> {
+        self.enable_rule_with(name, &SystemRunner)
+    }
+    
+    /// 重新添加已停用的規則（位於鏈尾）；已啟用時不做任何事
+    pub fn enable_rule_with(&self, name: &str, runner: &dyn CommandRunner) -> Result<()> {
+        let mut rules = self.named_rules.lock().unwrap();
+        let rule = rules.get_mut(name).ok_or_else(|| anyhow!("Unknown rule {:?}", name))?;
+        if rule.handle.is_none() {
+            rule.handle = Some(self.add_with_handle(&rule.command, runner)?);
+        }
+        Ok(())
+    }
+    
+    /// `nft --echo --handle` 會回顯添加的規則及其句柄
+    fn add_with_handle(&self, command: &str, runner: &dyn CommandRunner) -> Result<u64> {
+        let output = runner.run("nft", &["--echo".to_string(), "--handle".to_string(), command.to_string()])?;
+        output.lines()
+            .find_map(|line| line.rsplit_once("# handle ")?.1.trim().parse().ok())
+            .ok_or_else(|| anyhow!("nft did not report a handle for: {}", command))
+    }
+    
+    /// 套接字相關的匹配只能用在本機 output 鏈
+    fn traffic_rule_chain(&self, rule: &TrafficRule) -> &str {
+        if rule.uid.is_some() || rule.cgroup.is_some() {
+            &self.output_chain
+        } else {
+            &self.stats_chain
+        }
+    }
+    
+    fn traffic_rule_command(&self, rule: &TrafficRule) -> Result<String> {
+        let match_conditions = self.build_match_conditions(rule)?;
+        
+        Ok(format!(
+            "add rule {} {} {} {} {} comment \"{}\"",
+            self.family, self.table_name, self.traffic_rule_chain(rule), match_conditions, rule.action, rule.name
+        ))
+    }
+    
+    fn build_match_conditions(&self, rule: &TrafficRule) -> Result<String> {
         let mut conditions = Vec::new();
-
+        
         // 協議條件
         match rule.protocol.as_str() {
             "tcp" => conditions.push("tcp".to_string()),
@@ -163,7 +900,7 @@ impl NftablesClassifier {
             "any" => {}, // 任何協議
             _ => conditions.push(format!("meta l4proto {}", rule.protocol)),
         }
-
+        
         // 端口條件
         if !rule.ports.is_empty() {
             let ports_str = rule.ports.iter()
@@ -172,79 +909,351 @@ impl NftablesClassifier {
                 .join(", ");
             conditions.push(format!("tcp dport {{ {} }}", ports_str));
         }
-
+        
         // IP 範圍條件
         for ip_range in &rule.ip_ranges {
-            conditions.push(format!("ip daddr {}", ip_range));
+            conditions.push(format!("{} daddr {}", self.family.addr_keyword(), ip_range));
         }
-
+        
         // 負載模式匹配（簡單版本）
         for pattern in &rule.payload_patterns {
             // 注意：這需要 nftables 支持 payload 匹配
             conditions.push(format!("tcp payload ~ \"{}\"", pattern));
         }
-
-        conditions.join(" ")
+        
+        // 包長度範圍
+        match (rule.min_length, rule.max_length) {
+            (Some(min), Some(max)) if min > max => {
+                return Err(anyhow!("Invalid length range for rule {}: {} > {}", rule.name, min, max));
+            }
+            (Some(min), Some(max)) => conditions.push(format!("meta length {}-{}", min, max)),
+            (Some(min), None) => conditions.push(format!("meta length >= {}", min)),
+            (None, Some(max)) => conditions.push(format!("meta length <= {}", max)),
+            (None, None) => {}
+        }
+        
+        // 本機進程
+        if let Some(uid) = rule.uid {
+            conditions.push(format!("meta skuid {}", uid));
+        }
+        if let Some(cgroup) = &rule.cgroup {
+            let path = cgroup.trim_matches('/');
+            let level = path.split('/').filter(|c| !c.is_empty()).count();
+            conditions.push(format!("socket cgroupv2 level {} \"{}\"", level, path));
+        }
+        
+        // TCP 標誌：多個標誌時要求全部置位
+        if let Some(flag) = rule.tcp_flags.iter().find(|f| !TCP_FLAG_NAMES.contains(&f.as_str())) {
+            return Err(anyhow!("Unknown TCP flag {:?} in rule {}", flag, rule.name));
+        }
+        match rule.tcp_flags.as_slice() {
+            [] => {}
+            [flag] => conditions.push(format!("tcp flags {}", flag)),
+            flags => {
+                let mask = flags.join(" | ");
+                conditions.push(format!("tcp flags & ({}) == {}", mask, mask));
+            }
+        }
+        
+        Ok(conditions.join(" "))
     }
-
+    
+    /// 在用戶態按 `build_match_conditions` 生成的條件判斷規則是否匹配一個包，用於編寫規則時預覽；
+    /// 本機進程條件（uid、cgroup）無法由包判斷，報錯
+    pub fn rule_matches(&self, rule: &TrafficRule, packet: &PacketInfo) -> Result<bool> {
+        self.build_match_conditions(rule)?;
+        if rule.uid.is_some() || rule.cgroup.is_some() {
+            return Err(anyhow!("Rule {} matches local processes and cannot be tested against a packet", rule.name));
+        }
+        
+        let protocol = match rule.protocol.as_str() {
+            "any" => None,
+            name => Some(protocol_number(name)
+                .ok_or_else(|| anyhow!("Unknown protocol {:?} in rule {}", name, rule.name))?),
+        };
+        // 端口、負載和 TCP 標誌條件都以 tcp 開頭，隱含 TCP 協議
+        let tcp = packet.protocol == IPPROTO_TCP;
+        let flags = rule.tcp_flags.iter()
+            .filter_map(|flag| TCP_FLAG_NAMES.iter().position(|name| name == flag))
+            .fold(0u8, |mask, bit| mask | 1 << bit);
+        let length = |check: &dyn Fn(u16) -> bool| packet.ip_len.is_some_and(check);
+        
+        Ok(protocol.is_none_or(|protocol| protocol == packet.protocol)
+            && (rule.ports.is_empty() || tcp && packet.dst_port.is_some_and(|port| rule.ports.contains(&port)))
+            && rule.ip_ranges.iter().all(|range| {
+                // ip daddr 只匹配 IPv4 包，ip6 daddr 只匹配 IPv6 包
+                packet.dst_ip.is_ipv6() == (self.family == NftFamily::Ip6)
+                    && parse_cidr(range).is_ok_and(|network| cidr_contains(network, packet.dst_ip))
+            })
+            && (rule.payload_patterns.is_empty() || tcp && rule.payload_patterns.iter().all(|pattern| {
+                packet.payload.windows(pattern.len().max(1)).any(|window| window == pattern.as_bytes())
+            }))
+            && rule.min_length.is_none_or(|min| length(&|len| len >= min))
+            && rule.max_length.is_none_or(|max| length(&|len| len <= max))
+            && (flags == 0 || tcp && packet.tcp_flags.is_some_and(|set| set & flags == flags)))
+    }
+    
+    /// 按 DSCP 值計數，不改變包的去向；插在主鏈最前面，被統計鏈放行的包也會計入
+    pub fn add_dscp_count_rule(&self, dscp: u8) -> Result<()> {
+        self.nft_cmd(&self.dscp_count_rule_command(dscp)?)
+    }
+    
+    pub fn dscp_count_rule_command(&self, dscp: u8) -> Result<String> {
+        if dscp > DSCP_MAX {
+            return Err(anyhow!("Invalid DSCP value {} (must be 0-{})", dscp, DSCP_MAX));
+        }
+        
+        let sample = if self.sample_rate > 1 {
+            format!(" numgen random mod {} == 0", self.sample_rate)
+        } else {
+            String::new()
+        };
+        Ok(format!(
+            "insert rule {} {} {} {} dscp {}{} counter comment \"{}{}\"",
+            self.family, self.table_name, self.chain_name, self.family.addr_keyword(), dscp, sample, DSCP_COMMENT_PREFIX, dscp
+        ))
+    }
+    
     pub fn add_time_based_rule(&self, service: &str, start_time: &str, end_time: &str) -> Result<()> {
         let rule = format!(
-            "add rule inet {} {} meta hour >= \"{}\" meta hour < \"{}\" ip daddr @{}_ips drop comment \"Time block: {}\"",
-            self.table_name, self.stats_chain, start_time, end_time, service, service
+            "add rule {} {} {} meta hour >= \"{}\" meta hour < \"{}\" {} daddr @{}_ips drop comment \"Time block: {}\"",
+            self.family, self.table_name, self.stats_chain, start_time, end_time, self.family.addr_keyword(), service, service
         );
         self.nft_cmd(&rule)
     }
-
+    
     pub fn add_user_restriction(&self, mac_addr: &str, services: &[String]) -> Result<()> {
-        // 首先將 MAC 地址添加到集合
+        // 首先將 MAC 地址添加到集合，已存在時不算錯誤
         let add_mac = format!(
-            "add element inet {} user_mac {{ {} }}",
-            self.table_name, mac_addr
+            "add element {} {} user_mac {{ {} }}",
+            self.family, self.table_name, mac_addr
         );
-        self.nft_cmd(&add_mac)?;
-
+        self.add_element_with(&add_mac, &SystemRunner)?;
+        
         // 為每個服務創建阻止規則
         for service in services {
             let rule = format!(
-                "add rule inet {} {} ether saddr {} ip daddr @{}_ips drop comment \"User block: {} for {}\"",
-                self.table_name, self.stats_chain, mac_addr, service, service, mac_addr
+                "add rule {} {} {} ether saddr {} {} daddr @{}_ips drop comment \"User block: {} for {}\"",
+                self.family, self.table_name, self.stats_chain, mac_addr, self.family.addr_keyword(), service, service, mac_addr
             );
             self.nft_cmd(&rule)?;
         }
-
+        
         Ok(())
     }
-
+    
+    /// 把學到的地址加入服務的地址集合（`<服務>_ips`）；地址族與表不符時忽略
+    pub fn add_service_address(&self, service: &str, addr: IpAddr) -> Result<()> {
+        self.add_service_address_with(service, addr, &SystemRunner)
+    }
+    
+    pub fn add_service_address_with(&self, service: &str, addr: IpAddr, runner: &dyn CommandRunner) -> Result<()> {
+        if addr.is_ipv6() != (self.family == NftFamily::Ip6) {
+            return Ok(());
+        }
+        let command = format!("add element {} {} {}_ips {{ {} }}", self.family, self.table_name, service, addr);
+        self.add_element_with(&command, runner)
+    }
+    
     pub fn block_ip_temporarily(&self, ip: &str, duration_seconds: u32) -> Result<()> {
+        self.block_ip_temporarily_with(ip, duration_seconds, &SystemRunner)
+    }
+    
+    /// 重複封鎖同一 IP 視為成功，已有元素的超時不變
+    pub fn block_ip_temporarily_with(&self, ip: &str, duration_seconds: u32, runner: &dyn CommandRunner) -> Result<()> {
         let cmd = format!(
-            "add element inet {} dynamic_block {{ {} timeout {}s }}",
-            self.table_name, ip, duration_seconds
+            "add element {} {} dynamic_block {{ {} timeout {}s }}",
+            self.family, self.table_name, ip, duration_seconds
         );
-        self.nft_cmd(&cmd)
+        self.add_element_with(&cmd, runner)
     }
-
-    pub fn get_traffic_stats(&self) -> Result<HashMap<String, u64>> {
-        let output = Command::new("nft")
-            .args(&["list", "ruleset", "-a"])
-            .output()?;
-
-        if !output.status.success() {
-            return Err(anyhow!("Failed to get nftables rules"));
+    
+    pub fn unblock_ip(&self, ip: &str) -> Result<()> {
+        self.unblock_ip_with(ip, &SystemRunner)
+    }
+    
+    /// 在超時前解除封鎖；地址不在集合中（已超時或從未封鎖，nft 報 ENOENT）時視為成功
+    pub fn unblock_ip_with(&self, ip: &str, runner: &dyn CommandRunner) -> Result<()> {
+        let cmd = format!("delete element {} {} dynamic_block {{ {} }}", self.family, self.table_name, ip);
+        match runner.run("nft", &[cmd]) {
+            Err(e) if is_no_such_object(&e) => Ok(()),
+            result => result.map(|_| ()),
         }
-
-        let output_str = String::from_utf8_lossy(&output.stdout);
-        self.parse_counter_stats(&output_str)
     }
-
-    fn parse_counter_stats(&self, ruleset: &str) -> Result<HashMap<String, u64>> {
+    
+    /// dynamic_block 集合中的地址及剩餘的封鎖時間（秒）；沒有超時的元素為 None
+    pub fn list_blocked_ips(&self) -> Result<Vec<(String, Option<u32>)>> {
+        self.list_blocked_ips_with(&SystemRunner)
+    }
+    
+    pub fn list_blocked_ips_with(&self, runner: &dyn CommandRunner) -> Result<Vec<(String, Option<u32>)>> {
+        let args = ["list", "set", &self.family.to_string(), &self.table_name, "dynamic_block"].map(String::from);
+        let output = runner.run("nft", &args)
+            .map_err(|e| anyhow!("Failed to list dynamic_block: {}", e))?;
+        Ok(parse_set_elements(&output))
+    }
+    
+    /// 用配置的地址範圍和 `learned` 中的網段重建服務的地址集合（`<服務>_ips`），
+    /// 學習時加入、之後被淘汰或過期的地址不再留在集合中；地址族與表不符的網段忽略
+    pub fn rebuild_service_set(&self, service: &ServiceConfig, learned: &[(IpAddr, u8)]) -> Result<()> {
+        self.rebuild_service_set_with(service, learned, &SystemRunner)
+    }
+    
+    pub fn rebuild_service_set_with(&self, service: &ServiceConfig, learned: &[(IpAddr, u8)], runner: &dyn CommandRunner) -> Result<()> {
+        for command in self.service_set_commands(service) {
+            runner.run("nft", &[command])?;
+        }
+        for (addr, prefix) in learned.iter().filter(|(addr, _)| addr.is_ipv6() == (self.family == NftFamily::Ip6)) {
+            // 學到的網段可能落在配置的範圍內，重疊的元素（EEXIST）忽略
+            let command = format!("add element {} {} {}_ips {{ {}/{} }}", self.family, self.table_name, service.name, addr, prefix);
+            self.add_element_with(&command, runner)?;
+        }
+        Ok(())
+    }
+    
+    /// 執行 add element 命令；元素已存在（nft 報 EEXIST）時忽略錯誤
+    fn add_element_with(&self, command: &str, runner: &dyn CommandRunner) -> Result<()> {
+        match runner.run("nft", &[command.to_string()]) {
+            Err(e) if is_element_exists(&e) => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+    
+    pub fn get_traffic_stats(&self) -> Result<HashMap<String, u64>> {
+        let output = Command::new("nft")
+            .args(["list", "ruleset", "-a"])
+            .output()?;
+        
+        if !output.status.success() {
+            return Err(anyhow!("Failed to get nftables rules"));
+        }
+        
+        let output_str = String::from_utf8_lossy(&output.stdout);
+        Ok(self.parse_counter_stats(&output_str))
+    }
+    
+    /// 導出本程序管理的表（nft 格式），可保存後用 `nft -f` 重新加載
+    pub fn dump_ruleset(&self) -> Result<String> {
+        self.dump_ruleset_with(&SystemRunner)
+    }
+    
+    pub fn dump_ruleset_with(&self, runner: &dyn CommandRunner) -> Result<String> {
+        let args = ["list", "table", &self.family.to_string(), &self.table_name].map(String::from);
+        runner.run("nft", &args)
+            .map_err(|e| anyhow!("Failed to dump table {}: {}", self.table_name, e))
+    }
+    
+    /// 表中各條帶註釋計數規則的 (包數, 字節數)，按 (鏈, 註釋) 索引；表不存在時為空
+    pub fn read_counters_with(&self, runner: &dyn CommandRunner) -> HashMap<(String, String), (u64, u64)> {
+        let Ok(ruleset) = self.dump_ruleset_with(runner) else {
+            return HashMap::new();
+        };
+        
+        let mut counters = HashMap::new();
+        let mut chain = "";
+        for line in ruleset.lines() {
+            if let Some(name) = line.trim().strip_prefix("chain ").and_then(|rest| rest.strip_suffix(" {")) {
+                chain = name;
+            } else if let Some(caps) = counter_regex().captures(line) {
+                counters.insert(
+                    (chain.to_string(), caps[3].to_string()),
+                    (caps[1].parse().unwrap_or(0), caps[2].parse().unwrap_or(0)),
+                );
+            }
+        }
+        counters
+    }
+    
+    /// 各服務規則計數的字節數，用於和抓包統計對比
+    pub fn get_service_bytes(&self) -> Result<HashMap<String, u64>> {
+        self.get_service_bytes_with(&SystemRunner)
+    }
+    
+    /// 採樣時按 `sample_rate` 還原為估計的總字節數
+    pub fn get_service_bytes_with(&self, runner: &dyn CommandRunner) -> Result<HashMap<String, u64>> {
+        Ok(parse_service_bytes(&self.dump_ruleset_with(runner)?)
+            .into_iter()
+            .map(|(service, bytes)| (service, self.extrapolate(bytes)))
+            .collect())
+    }
+    
+    /// 用 `dump_ruleset` 導出的內容替換當前表，整個腳本一次提交，失敗時保持原狀
+    pub fn restore_ruleset(&self, text: &str) -> Result<()> {
+        self.nft_cmd(&self.restore_script(text)?)
+    }
+    
+    /// 恢復用的 nft 腳本：先確保表存在再刪除，然後重建導出的表
+    pub fn restore_script(&self, text: &str) -> Result<String> {
+        let mut tables = text.lines()
+            .map(str::trim)
+            .filter(|line| line.starts_with("table "))
+            .peekable();
+        if tables.peek().is_none() {
+            return Err(anyhow!("No table definition in ruleset"));
+        }
+        
+        for line in tables {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (family, name) = (fields.get(1).copied(), fields.get(2).copied());
+            if family != Some(&*self.family.to_string()) || name != Some(&*self.table_name) {
+                return Err(anyhow!(
+                    "Ruleset is for another table ({}), expected {} {}",
+                    line.trim_end_matches('{').trim(), self.family, self.table_name
+                ));
+            }
+        }
+        
+        Ok(format!(
+            "add table {family} {table}\ndelete table {family} {table}\n{}",
+            text,
+            family = self.family,
+            table = self.table_name
+        ))
+    }
+    
+    /// 自上次輪詢以來的計數增量
+    pub fn get_traffic_deltas(&self, deltas: &mut CounterDeltas) -> Result<HashMap<String, u64>> {
+        Ok(deltas.update(&self.get_traffic_stats()?))
+    }
+    
+    /// 通過 SSH 在遠程主機上執行 nft，解析返回的 JSON 計數
+    pub fn get_remote_traffic_stats(&self, remote: &RemoteHostConfig, runner: &dyn CommandRunner) -> Result<HashMap<String, u64>> {
+        let (program, ssh_args) = remote.ssh_command.split_first()
+            .ok_or_else(|| anyhow!("Empty ssh_command for {}", remote.host))?;
+        let mut args = ssh_args.to_vec();
+        args.push(remote.host.clone());
+        args.push(remote.nft_command.clone());
+        
+        let output = runner.run(program, &args)
+            .map_err(|e| anyhow!("Remote stats from {} failed: {}", remote.host, e))?;
+        parse_counter_stats_json(&output)
+            .map_err(|e| anyhow!("Invalid nft JSON from {}: {}", remote.host, e))
+    }
+    
+    /// 輪詢所有遠程主機；連接失敗的主機記錄日誌後跳過，不影響其他主機
+    pub fn collect_remote_stats(&self, remotes: &[RemoteHostConfig], runner: &dyn CommandRunner) -> HashMap<String, HashMap<String, u64>> {
+        let mut results = HashMap::new();
+        
+        for remote in remotes {
+            match self.get_remote_traffic_stats(remote, runner) {
+                Ok(stats) => {
+                    results.insert(remote.host.clone(), stats);
+                }
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        
+        results
+    }
+    
+    fn parse_counter_stats(&self, ruleset: &str) -> HashMap<String, u64> {
         let mut stats = HashMap::new();
-        let counter_re = regex::Regex::new(r"counter packets (\d+) bytes (\d+).*comment \"([^\"]+)\"")?;
-
+        
         for line in ruleset.lines() {
-            if let Some(caps) = counter_re.captures(line) {
+            if let Some(caps) = counter_regex().captures(line) {
                 if let (Some(packets), Some(service)) = (caps.get(1), caps.get(3)) {
                     let service_name = service.as_str().to_string();
-                    let packet_count: u64 = packets.as_str().parse().unwrap_or(0);
+                    let packet_count = self.extrapolate(packets.as_str().parse().unwrap_or(0));
                     
                     // 只統計我們感興趣的服務
                     if service_name.contains("traffic") {
@@ -253,30 +1262,168 @@ impl NftablesClassifier {
                 }
             }
         }
-
-        Ok(stats)
+        
+        stats
     }
-
+    
     pub fn create_payload_matching_rule(&self, name: &str, pattern: &str, action: &str) -> Result<()> {
         // 使用 nftables 的 payload 匹配來實現類似 L7-filter 的功能
         let rule = format!(
-            "add rule inet {} {} tcp dport @streaming_ports @th,64,128 \"{}\" {} comment \"Payload match: {}\"",
-            self.table_name, self.stats_chain, pattern, action, name
+            "add rule {} {} {} tcp dport @streaming_ports @th,64,128 \"{}\" {} comment \"Payload match: {}\"",
+            self.family, self.table_name, self.stats_chain, pattern, action, name
         );
         
         self.nft_cmd(&rule)
     }
-
-    pub fn create_dns_filtering_rule(&self, domain: &str, action: &str) -> Result<()> {
-        // 過濾 DNS 查詢（UDP 端口 53）
-        let rule = format!(
-            "add rule inet {} {} udp dport 53 @th,64,512 \"{}\" {} comment \"DNS filter: {}\"",
-            self.table_name, self.stats_chain, domain, action, domain
-        );
+    
+    /// 用配置中的域名和 DNS 規則重建 DNS 過濾，整個腳本一次提交
+    pub fn apply_dns_filters(&self, config: &Config) -> Result<()> {
+        let commands = self.dns_filter_commands(config)?;
+        self.check_rule_budget(&commands)?;
+        self.nft_cmd(&commands.join("\n"))
+    }
+    
+    /// `blocked_domains` 的封鎖規則之後接 blocked 服務和 `dns_rules` 的規則
+    pub fn dns_filter_commands(&self, config: &Config) -> Result<Vec<String>> {
+        let mut commands = self.blocked_domain_commands(&config.blocked_domains)?;
+        commands.extend(self.blocked_service_commands(&config.services));
+        for rule in &config.dns_rules {
+            commands.extend(self.dns_rule_commands(rule)?);
+        }
+        Ok(commands)
+    }
+    
+    /// blocked 服務按地址集合（`<服務>_ips`）丟棄發往它的包；集合不存在時創建並加入配置的地址範圍，
+    /// 已學到的地址保留。規則和域名封鎖在同一條鏈中，隨鏈一起清空重建
+    pub fn blocked_service_commands(&self, services: &[ServiceConfig]) -> Vec<String> {
+        let mut commands = Vec::new();
+        for service in services.iter().filter(|service| service.blocked) {
+            let set = format!("{}_ips", service.name);
+            commands.push(format!(
+                "add set {} {} {} {{ type {}; flags interval; }}",
+                self.family, self.table_name, set, self.family.addr_type()
+            ));
+            let ranges = self.family_ranges(&service.ip_ranges);
+            if !ranges.is_empty() {
+                commands.push(format!("add element {} {} {} {{ {} }}", self.family, self.table_name, set, ranges.join(", ")));
+            }
+            commands.push(format!(
+                "add rule {} {} {} {} daddr @{} counter drop comment \"blocked service {}\"",
+                self.family, self.table_name, self.dns_chain, self.family.addr_keyword(), set, service.name
+            ));
+        }
+        commands
+    }
+    
+    /// 查詢名按線上格式編碼後放入命名集合，以 QNAME 位置的原始負載查表；
+    /// 集合鍵長度固定，所以每種編碼長度（和大小寫掩碼）一個集合（blocked_domains_<字節數>）和一條規則。
+    /// 只精確匹配查詢名本身（不含子域名），不區分大小寫
+    pub fn blocked_domain_commands(&self, domains: &[String]) -> Result<Vec<String>> {
+        // 舊規則隨鏈一起清空；不再使用的集合不被引用，留在表中無影響
+        let mut commands = vec![format!("flush chain {} {} {}", self.family, self.table_name, self.dns_chain)];
+        for group in dns_name_groups(domains)? {
+            let set = format!("blocked_domains_{}", group.suffix);
+            
+            commands.push(format!("add set {} {} {} {{ typeof {}; }}", self.family, self.table_name, set, group.payload));
+            commands.push(format!("flush set {} {} {}", self.family, self.table_name, set));
+            commands.push(format!(
+                "add element {} {} {} {{ {} }}",
+                self.family, self.table_name, set, group.elements.join(", ")
+            ));
+            commands.push(format!(
+                "add rule {} {} {} udp dport 53 {} @{} counter drop comment \"blocked domains {}\"",
+                self.family, self.table_name, self.dns_chain, group.lookup, set, group.suffix
+            ));
+        }
         
-        self.nft_cmd(&rule)
+        Ok(commands)
     }
-
+    
+    /// 與 `blocked_domain_commands` 相同按編碼長度分集合（dns_<規則名>_<字節數>），
+    /// 查詢類型緊跟在 QNAME 之後，所以每條規則在該長度之後再匹配 16 位的 QTYPE。
+    /// 動作原樣寫入規則：accept 結束主鏈的匹配，放行的查詢不再進入統計鏈
+    pub fn dns_rule_commands(&self, rule: &DnsRule) -> Result<Vec<String>> {
+        let qtypes = rule.qtypes()
+            .map_err(|record_type| anyhow!("Unknown DNS record type {:?} in rule {}", record_type, rule.name))?;
+        
+        let mut commands = Vec::new();
+        for group in dns_name_groups(&rule.domains)? {
+            let set = format!("dns_{}_{}", rule.name, group.suffix);
+            let qtype_match = if qtypes.is_empty() {
+                String::new()
+            } else {
+                let codes: Vec<String> = qtypes.iter().map(|code| code.to_string()).collect();
+                format!(" @th,{},16 {{ {} }}", DNS_QNAME_OFFSET_BITS + group.length * 8, codes.join(", "))
+            };
+            
+            commands.push(format!("add set {} {} {} {{ typeof {}; }}", self.family, self.table_name, set, group.payload));
+            commands.push(format!("flush set {} {} {}", self.family, self.table_name, set));
+            commands.push(format!(
+                "add element {} {} {} {{ {} }}",
+                self.family, self.table_name, set, group.elements.join(", ")
+            ));
+            commands.push(format!(
+                "add rule {} {} {} udp dport 53 {} @{}{} counter {} comment \"dns rule: {} {}\"",
+                self.family, self.table_name, self.dns_chain, group.lookup, set, qtype_match,
+                rule.action, rule.name, group.suffix
+            ));
+        }
+        
+        Ok(commands)
+    }
+    
+    /// 從 `geoip_dir` 讀取各國地址列表並重建 GeoIP 過濾，整個腳本一次提交
+    pub fn apply_geoip_rules(&self, config: &Config) -> Result<()> {
+        let commands = self.geoip_commands(&load_geoip_lists(config)?);
+        self.check_rule_budget(&commands)?;
+        self.nft_cmd(&commands.join("\n"))
+    }
+    
+    /// 每個國家一個帶 interval 標誌的地址集合（geoip_<cc>），元素分批加入；
+    /// 源或目的地址命中集合時原樣執行規則的動作（accept 的包不再經過後面的過濾和統計鏈）。
+    /// ip 和 ip6 表只使用本地址族的範圍，inet 表的 IPv6 範圍放在單獨的 geoip_<cc>_v6 集合中
+    pub fn geoip_commands(&self, rules: &[(&GeoIpRule, Vec<String>)]) -> Vec<String> {
+        let mut commands = vec![format!("flush chain {} {} {}", self.family, self.table_name, self.geoip_chain)];
+        
+        for (rule, cidrs) in rules {
+            let country = rule.country.to_lowercase();
+            let set = format!("geoip_{}", country);
+            
+            let mut sets = vec![(set.clone(), self.family.addr_type(), self.family.addr_keyword(), self.family_ranges(cidrs))];
+            if self.family == NftFamily::Inet {
+                let ipv6: Vec<&str> = cidrs.iter().map(String::as_str).filter(|cidr| cidr.contains(':')).collect();
+                if !ipv6.is_empty() {
+                    sets.push((format!("{}_v6", set), NftFamily::Ip6.addr_type(), NftFamily::Ip6.addr_keyword(), ipv6));
+                }
+            }
+            
+            for (set, addr_type, addr, ranges) in sets {
+                // 國家列表中常有相鄰或重疊的網段，auto-merge 讓內核合併而不是報錯
+                commands.push(format!(
+                    "add set {} {} {} {{ type {}; flags interval; auto-merge; }}",
+                    self.family, self.table_name, set, addr_type
+                ));
+                commands.push(format!("flush set {} {} {}", self.family, self.table_name, set));
+                for batch in ranges.chunks(GEOIP_ELEMENT_BATCH) {
+                    commands.push(format!(
+                        "add element {} {} {} {{ {} }}",
+                        self.family, self.table_name, set, batch.join(", ")
+                    ));
+                }
+                
+                for direction in ["saddr", "daddr"] {
+                    commands.push(format!(
+                        "add rule {} {} {} {} {} @{} counter {} comment \"{} {}\"",
+                        self.family, self.table_name, self.geoip_chain, addr,
+                        direction, set, rule.action, set.replace('_', " "), direction
+                    ));
+                }
+            }
+        }
+        
+        commands
+    }
+    
     fn nft_cmd(&self, command: &str) -> Result<()> {
         let mut child = Command::new("nft")
             .arg("-f")
@@ -285,23 +1432,1712 @@ impl NftablesClassifier {
             .stdout(Stdio::null())
             .stderr(Stdio::piped())
             .spawn()?;
-
+        
         if let Some(mut stdin) = child.stdin.take() {
             stdin.write_all(command.as_bytes())?;
         }
-
+        
         let output = child.wait_with_output()?;
         if !output.status.success() {
             let error_msg = String::from_utf8_lossy(&output.stderr);
             return Err(anyhow!("nftables command failed: {}\nError: {}", command, error_msg));
         }
-
+        
         Ok(())
     }
-
+    
     pub fn cleanup(&self) -> Result<()> {
+        self.cleanup_with(&SystemRunner)
+    }
+    
+    pub fn cleanup_with(&self, runner: &dyn CommandRunner) -> Result<()> {
         // 刪除表格（會自動刪除所有相關規則和集合）
-        let _ = self.nft_cmd(&format!("delete table inet {}", self.table_name));
+        let _ = runner.run("nft", &[format!("delete table {} {}", self.family, self.table_name)]);
         Ok(())
     }
-}
\ No newline at end of file
+    
+    /// 刪除本程序創建的全部 nftables 狀態，並確認表已不存在
+    pub fn flush(&self) -> Result<()> {
+        self.flush_with(&SystemRunner)
+    }
+    
+    pub fn flush_with(&self, runner: &dyn CommandRunner) -> Result<()> {
+        self.cleanup_with(runner)?;
+        
+        match runner.run("nft", &[format!("list table {} {}", self.family, self.table_name)]) {
+            Ok(_) => Err(anyhow!("Table {} {} still exists after flush", self.family, self.table_name)),
+            Err(e) if is_no_such_object(&e) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// 每條 geoip 規則及其國家的地址列表
+fn load_geoip_lists(config: &Config) -> Result<Vec<(&GeoIpRule, Vec<String>)>> {
+    config.geoip_rules.iter()
+        .map(|rule| {
+            let path = Path::new(&config.geoip_dir).join(format!("{}.zone", rule.country.to_lowercase()));
+            let cidrs = load_country_list(&path)
+                .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+            Ok((rule, cidrs))
+        })
+        .collect()
+}
+
+/// 讀取國家地址列表（如 ipdeny 的 zone 文件）：每行一個 CIDR，忽略空行和 # 註釋
+pub fn load_country_list(path: &Path) -> Result<Vec<String>> {
+    let text = fs::read_to_string(path)?;
+    let mut cidrs = Vec::new();
+    
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        parse_cidr(line).map_err(|e| anyhow!("line {}: {}", lineno + 1, e))?;
+        cidrs.push(line.to_string());
+    }
+    
+    Ok(cidrs)
+}
+
+/// 一條命令創建的規則數或集合元素數
+fn rule_objects(command: &str) -> usize {
+    if command.starts_with("add rule ") || command.starts_with("insert rule ") {
+        return 1;
+    }
+    
+    let elements = if command.starts_with("add element ") {
+        command.split_once('{').and_then(|(_, rest)| rest.rsplit_once('}')).map(|(inner, _)| inner)
+    } else {
+        command.split_once("elements = {")
+            .or_else(|| command.split_once("elements {"))
+            .and_then(|(_, rest)| rest.split_once('}'))
+            .map(|(inner, _)| inner)
+    };
+    elements.map_or(0, |inner| inner.split(',').filter(|e| !e.trim().is_empty()).count())
+}
+
+/// 給 (鏈, 註釋) 出現在 `previous` 中的計數規則加上初始值（`counter packets N bytes M`），
+/// 其餘命令不變；同一鏈中計數規則的註釋各不相同，每條規則只寫回自己的值
+fn with_counter_values(commands: Vec<String>, previous: &HashMap<(String, String), (u64, u64)>) -> Vec<String> {
+    if previous.is_empty() {
+        return commands;
+    }
+    
+    commands.into_iter()
+        .map(|command| {
+            // add rule <地址族> <表> <鏈> ...
+            let chain = command.strip_prefix("add rule ")
+                .and_then(|rest| rest.split_whitespace().nth(2));
+            let comment = command.rsplit_once("comment \"")
+                .and_then(|(_, rest)| rest.split_once('"'))
+                .map(|(comment, _)| comment);
+            let key = chain.zip(comment).map(|(chain, comment)| (chain.to_string(), comment.to_string()));
+            match key.and_then(|key| previous.get(&key)) {
+                Some((packets, bytes)) if command.contains(" counter ") => {
+                    command.replacen(" counter ", &format!(" counter packets {} bytes {} ", packets, bytes), 1)
+                }
+                _ => command,
+            }
+        })
+        .collect()
+}
+
+/// 從 `get_traffic_stats` 的結果中取出各 DSCP 值的包數
+pub fn dscp_counters(stats: &HashMap<String, u64>) -> BTreeMap<u8, u64> {
+    stats.iter()
+        .filter_map(|(comment, packets)| {
+            let dscp = comment.strip_prefix(DSCP_COMMENT_PREFIX)?.parse().ok()?;
+            Some((dscp, *packets))
+        })
+        .collect()
+}
+
+/// nft 規則中的協議名或協議號
+fn protocol_number(name: &str) -> Option<u8> {
+    match name {
+        "tcp" => Some(IPPROTO_TCP),
+        "udp" => Some(17),
+        "icmp" => Some(1),
+        "dccp" => Some(33),
+        "icmpv6" => Some(58),
+        "sctp" => Some(132),
+        other => other.parse().ok(),
+    }
+}
+
+/// nft 對已存在的元素報 "File exists"（EEXIST）
+fn is_element_exists(error: &anyhow::Error) -> bool {
+    error.to_string().contains("File exists")
+}
+
+fn is_no_such_object(error: &anyhow::Error) -> bool {
+    error.to_string().contains("No such file or directory")
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    format!("0x{}", bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+}
+
+/// 服務的端口集合表達式，未配置端口時使用內建的串流端口集合
+fn service_ports(service: &ServiceConfig) -> String {
+    if service.ports.is_empty() {
+        return "@streaming_ports".to_string();
+    }
+    
+    format!(
+        "{{ {} }}",
+        service.ports.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", ")
+    )
+}
+
+/// 檢查服務名能否生成合法的 `{name}_ips` 集合名，且集合名互不衝突
+/// （不區分大小寫，避免只有大小寫不同的名字混淆）
+pub fn validate_service_names(services: &[ServiceConfig]) -> Result<()> {
+    let mut seen: HashMap<String, &str> = HashMap::new();
+    
+    for service in services {
+        let set_name = format!("{}_ips", service.name);
+        if !is_valid_nft_identifier(&set_name) {
+            return Err(anyhow!(
+                "Invalid service name {:?}: set name {} is not a valid nftables identifier",
+                service.name, set_name
+            ));
+        }
+        
+        if let Some(other) = seen.insert(set_name.to_lowercase(), &service.name) {
+            return Err(anyhow!(
+                "Services {:?} and {:?} map to the same nftables set {}",
+                other, service.name, set_name
+            ));
+        }
+    }
+    
+    Ok(())
+}
+
+/// `nft -a list chain` 輸出中屬於該服務的計數規則（註釋為 "<服務> traffic/response"）的 handle
+fn service_rule_handles(listing: &str, service: &str) -> Vec<u64> {
+    let comments = [format!("comment \"{} traffic\"", service), format!("comment \"{} response\"", service)];
+    
+    listing.lines()
+        .filter(|line| comments.iter().any(|c| line.contains(c.as_str())))
+        .filter_map(|line| line.rsplit_once("# handle ")?.1.trim().parse().ok())
+        .collect()
+}
+
+/// `nft -a list table` 輸出中 trace 規則所在的鏈和句柄
+fn trace_rule_handles(listing: &str) -> Vec<(String, u64)> {
+    let comment = format!("comment \"{}\"", TRACE_COMMENT);
+    let mut chain = None;
+    let mut handles = Vec::new();
+    
+    for line in listing.lines().map(str::trim) {
+        if let Some(name) = line.strip_prefix("chain ") {
+            chain = name.split_whitespace().next();
+        } else if line.contains(comment.as_str()) {
+            let handle = line.rsplit_once("# handle ").and_then(|(_, handle)| handle.trim().parse().ok());
+            if let (Some(chain), Some(handle)) = (chain, handle) {
+                handles.push((chain.to_string(), handle));
+            }
+        }
+    }
+    handles
+}
+
+/// 統計鏈中跳轉到 `chain` 的端口分派規則的句柄
+fn dispatch_rule_handles(listing: &str, chain: &str) -> Vec<u64> {
+    let target = format!("jump {} #", chain);
+    
+    listing.lines()
+        .filter(|line| line.contains(target.as_str()))
+        .filter_map(|line| line.rsplit_once("# handle ")?.1.trim().parse().ok())
+        .collect()
+}
+
+/// 解析 `nft -j list ruleset` 的輸出，和文本格式一樣只取帶 "traffic" 註釋的規則的包計數
+fn parse_counter_stats_json(json: &str) -> Result<HashMap<String, u64>> {
+    let value: serde_json::Value = serde_json::from_str(json)?;
+    let items = value["nftables"].as_array()
+        .ok_or_else(|| anyhow!("Missing \"nftables\" array"))?;
+    
+    let mut stats = HashMap::new();
+    for rule in items.iter().filter_map(|item| item.get("rule")) {
+        let Some(comment) = rule["comment"].as_str().filter(|c| c.contains("traffic")) else {
+            continue;
+        };
+        let packets = rule["expr"].as_array()
+            .and_then(|exprs| exprs.iter().find_map(|e| e["counter"]["packets"].as_u64()));
+        
+        if let Some(packets) = packets {
+            stats.insert(comment.to_string(), packets);
+        }
+    }
+    
+    Ok(stats)
+}
+
+/// `nft list set` 輸出中的元素及 `expires` 給出的剩餘時間（秒，不足一秒按一秒計）；
+/// 元素列表可以跨多行
+fn parse_set_elements(output: &str) -> Vec<(String, Option<u32>)> {
+    let Some(start) = output.find("elements = {") else {
+        return Vec::new();
+    };
+    let rest = &output[start + "elements = {".len()..];
+    let body = &rest[..rest.find('}').unwrap_or(rest.len())];
+    
+    body.split(',')
+        .filter_map(|element| {
+            let mut tokens = element.split_whitespace();
+            let addr = tokens.next()?.to_string();
+            let mut remaining = None;
+            while let Some(token) = tokens.next() {
+                if token == "expires" {
+                    remaining = tokens.next().and_then(parse_nft_duration);
+                }
+            }
+            Some((addr, remaining))
+        })
+        .collect()
+}
+
+/// nft 的時長寫法，如 "4m58s760ms"、"1d2h"
+fn parse_nft_duration(text: &str) -> Option<u32> {
+    let mut millis: u64 = 0;
+    let mut rest = text;
+    
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let value: u64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let unit_len = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let scale = match &rest[..unit_len] {
+            "d" => 86_400_000,
+            "h" => 3_600_000,
+            "m" => 60_000,
+            "s" => 1000,
+            "ms" => 1,
+            _ => return None,
+        };
+        millis = millis.checked_add(value.checked_mul(scale)?)?;
+        rest = &rest[unit_len..];
+    }
+    
+    u32::try_from(millis.div_ceil(1000)).ok()
+}
+
+/// 編碼長度和大小寫掩碼相同的一組查詢名，共用一個集合和一條規則
+struct DnsNameGroup {
+    /// 集合名和註釋的後綴：編碼長度，同長度的其他掩碼再加序號（13、13_1）
+    suffix: String,
+    length: usize,
+    /// 集合的鍵：QNAME 位置的原始負載
+    payload: String,
+    /// 規則中查表的表達式，有字母時帶大小寫掩碼
+    lookup: String,
+    elements: Vec<String>,
+}
+
+/// 查詢名可能大小寫混合（如 DNS 0x20 隨機化）：字母所在字節或上 0x20 轉成小寫後再查集合，
+/// 字母位置不同的域名需要不同的掩碼，按（長度, 掩碼）分組
+fn dns_name_groups(domains: &[String]) -> Result<Vec<DnsNameGroup>> {
+    let mut groups: BTreeMap<(usize, Vec<u8>), Vec<String>> = BTreeMap::new();
+    for domain in domains {
+        let encoded = encode_dns_name(domain)?;
+        let mask: Vec<u8> = encoded.iter().map(|b| if b.is_ascii_alphabetic() { 0x20 } else { 0 }).collect();
+        groups.entry((encoded.len(), mask)).or_default().push(hex_bytes(&encoded));
+    }
+    
+    let mut previous_length = None;
+    let mut index = 0;
+    Ok(groups.into_iter()
+        .map(|((length, mask), mut elements)| {
+            elements.sort();
+            elements.dedup();
+            index = if previous_length == Some(length) { index + 1 } else { 0 };
+            previous_length = Some(length);
+            let suffix = match index {
+                0 => length.to_string(),
+                index => format!("{}_{}", length, index),
+            };
+            let payload = format!("@th,{},{}", DNS_QNAME_OFFSET_BITS, length * 8);
+            let lookup = if mask.iter().any(|b| *b != 0) {
+                format!("{} | {}", payload, hex_bytes(&mask))
+            } else {
+                payload.clone()
+            };
+            DnsNameGroup { suffix, length, payload, lookup, elements }
+        })
+        .collect())
+}
+
+/// 域名的 DNS 線上格式：每個標籤前加長度，以 0 結尾
+fn encode_dns_name(domain: &str) -> Result<Vec<u8>> {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    let mut encoded = Vec::with_capacity(domain.len() + 2);
+    
+    for label in domain.split('.') {
+        if label.is_empty() || label.len() > 63 || !label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+            return Err(anyhow!("Invalid domain name: {:?}", domain));
+        }
+        encoded.push(label.len() as u8);
+        encoded.extend_from_slice(label.as_bytes());
+    }
+    encoded.push(0);
+    
+    if encoded.len() > NFT_SET_KEY_MAX_BYTES {
+        return Err(anyhow!("Domain name too long for an nftables set key: {:?}", domain));
+    }
+    Ok(encoded)
+}
+
+/// 帶註釋的計數規則：包數、字節數、註釋；只編譯一次，頻繁輪詢時不重複編譯
+fn counter_regex() -> &'static Regex {
+    static COUNTER_RE: OnceLock<Regex> = OnceLock::new();
+    COUNTER_RE.get_or_init(|| {
+        Regex::new(r#"counter packets (\d+) bytes (\d+).*comment "([^"]+)""#).expect("valid regex")
+    })
+}
+
+/// 註釋為 "<服務> traffic" / "<服務> response" 的計數規則：字節數、服務名
+fn service_bytes_regex() -> &'static Regex {
+    static SERVICE_BYTES_RE: OnceLock<Regex> = OnceLock::new();
+    SERVICE_BYTES_RE.get_or_init(|| {
+        Regex::new(r#"counter packets \d+ bytes (\d+).*comment "(.+) (?:traffic|response)""#).expect("valid regex")
+    })
+}
+
+/// 按規則註釋 "<服務> traffic" / "<服務> response" 匯總各服務的字節數
+fn parse_service_bytes(ruleset: &str) -> HashMap<String, u64> {
+    let mut bytes = HashMap::new();
+    
+    for caps in ruleset.lines().filter_map(|line| service_bytes_regex().captures(line)) {
+        let value: u64 = caps[1].parse().unwrap_or(0);
+        *bytes.entry(caps[2].to_string()).or_insert(0) += value;
+    }
+    
+    bytes
+}
+
+/// 同一服務抓包看到的字節數和 nftables 計數的字節數
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CounterComparison {
+    pub service: String,
+    pub pcap_bytes: u64,
+    pub nft_bytes: u64,
+}
+
+impl CounterComparison {
+    /// 抓包減去 nftables，負值說明抓包少看到了流量（丟包或卸載）
+    pub fn discrepancy(&self) -> i64 {
+        self.pcap_bytes as i64 - self.nft_bytes as i64
+    }
+    
+    /// 差值佔兩者中較大值的比例
+    pub fn discrepancy_ratio(&self) -> f64 {
+        let larger = self.pcap_bytes.max(self.nft_bytes);
+        if larger == 0 {
+            return 0.0;
+        }
+        self.discrepancy().unsigned_abs() as f64 / larger as f64
+    }
+}
+
+/// 對比兩邊的按服務統計（抓包為 字節數, 包數），只出現在一邊的服務另一邊記為 0；
+/// 按差值絕對值從大到小排列
+pub fn compare_counters(pcap: &HashMap<String, (u64, u64)>, nft_bytes: &HashMap<String, u64>) -> Vec<CounterComparison> {
+    let mut services: Vec<&String> = pcap.keys().chain(nft_bytes.keys()).collect();
+    services.sort();
+    services.dedup();
+    
+    let mut rows: Vec<CounterComparison> = services.into_iter()
+        .map(|service| CounterComparison {
+            service: service.clone(),
+            pcap_bytes: pcap.get(service).map_or(0, |(bytes, _)| *bytes),
+            nft_bytes: nft_bytes.get(service).copied().unwrap_or(0),
+        })
+        .collect();
+    rows.sort_by_key(|row| std::cmp::Reverse(row.discrepancy().unsigned_abs()));
+    rows
+}
+
+/// 對比報告的文本，每個服務一行
+pub fn format_counter_comparison(rows: &[CounterComparison]) -> String {
+    let mut report = format!("{:<20} {:>14} {:>14} {:>14} {:>8}\n", "service", "pcap", "nft", "diff", "diff%");
+    for row in rows {
+        report.push_str(&format!(
+            "{:<20} {:>14} {:>14} {:>14} {:>7.1}%\n",
+            row.service, row.pcap_bytes, row.nft_bytes, row.discrepancy(), row.discrepancy_ratio() * 100.0
+        ));
+    }
+    report
+}
+
+/// 將 nftables 的絕對計數轉為增量，規則集重建導致計數歸零時不會得到負值
+#[derive(Debug, Default)]
+pub struct CounterDeltas {
+    last: HashMap<String, u64>,
+}
+
+impl CounterDeltas {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    pub fn delta(&mut self, name: &str, value: u64) -> u64 {
+        let previous = self.last.insert(name.to_string(), value).unwrap_or(0);
+        
+        if value >= previous {
+            value - previous
+        } else {
+            // 計數器倒退說明已被重置，新值即為重置後的增量
+            value
+        }
+    }
+    
+    pub fn update(&mut self, counters: &HashMap<String, u64>) -> HashMap<String, u64> {
+        counters.iter()
+            .map(|(name, value)| (name.clone(), self.delta(name, *value)))
+            .collect()
+    }
+}
+
+/// 一次規則命中對應的計數增量
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CounterUpdate {
+    pub name: String,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl CounterUpdate {
+    /// 規則註釋 "<服務> traffic" / "<服務> response" 中的服務名
+    pub fn service(&self) -> &str {
+        self.name.strip_suffix(" traffic")
+            .or_else(|| self.name.strip_suffix(" response"))
+            .unwrap_or(&self.name)
+    }
+}
+
+/// 解析 `nft monitor trace` 的輸出：packet 行給出包長度，
+/// 之後同一 trace id 下帶 "traffic" 註釋的 rule 行即一次計數命中
+#[derive(Debug, Default)]
+pub struct TraceParser {
+    lengths: HashMap<String, u64>,
+}
+
+impl TraceParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    pub fn parse_line(&mut self, line: &str) -> Option<CounterUpdate> {
+        let (id, rest) = line.strip_prefix("trace id ")?.split_once(' ')?;
+        
+        if let Some((_, packet)) = rest.split_once(" packet: ") {
+            // 沒有收到結束行的 trace 不應無限累積
+            if self.lengths.len() >= 4096 {
+                self.lengths.clear();
+            }
+            let length = packet.split_whitespace()
+                .skip_while(|word| *word != "length")
+                .nth(1)
+                .and_then(|value| value.parse().ok());
+            if let Some(length) = length {
+                self.lengths.insert(id.to_string(), length);
+            }
+            return None;
+        }
+        
+        if let Some((_, rule)) = rest.split_once(" rule ") {
+            let (_, comment) = rule.split_once("comment \"")?;
+            let name = comment.split('"').next()?;
+            if !name.contains("traffic") {
+                return None;
+            }
+            return Some(CounterUpdate {
+                name: name.to_string(),
+                packets: 1,
+                bytes: self.lengths.get(id).copied().unwrap_or(0),
+            });
+        }
+        
+        // 鏈的默認策略決定了包的最終去向，該 trace 結束
+        if rest.contains(" policy ") {
+            self.lengths.remove(id);
+        }
+        None
+    }
+}
+
+/// 運行 `nft monitor trace` 並把規則命中實時轉為計數更新，代替定期輪詢；
+/// 子進程退出時自動重啟
+pub struct NftMonitor {
+    command: Vec<String>,
+    restart_delay: Duration,
+}
+
+impl NftMonitor {
+    pub fn new() -> Self {
+        Self {
+            command: ["nft", "monitor", "trace"].iter().map(|s| s.to_string()).collect(),
+            restart_delay: Duration::from_secs(1),
+        }
+    }
+    
+    pub fn run(&self, running: &AtomicBool, mut on_update: impl FnMut(CounterUpdate) + Send) {
+        while running.load(Ordering::SeqCst) {
+            match Command::new(&self.command[0]).args(&self.command[1..]).stdout(Stdio::piped()).spawn() {
+                Ok(mut child) => {
+                    let stdout = child.stdout.take();
+                    thread::scope(|scope| {
+                        let reader = scope.spawn(|| {
+                            let mut parser = TraceParser::new();
+                            let Some(stdout) = stdout else {
+                                return;
+                            };
+                            for line in BufReader::new(stdout).lines() {
+                                let Ok(line) = line else {
+                                    break;
+                                };
+                                if let Some(update) = parser.parse_line(&line) {
+                                    on_update(update);
+                                }
+                            }
+                        });
+                        
+                        // 沒有輸出時讀取會一直阻塞，停止後殺掉子進程讓它返回
+                        while running.load(Ordering::SeqCst) && !reader.is_finished() {
+                            thread::sleep(Duration::from_millis(100));
+                        }
+                        if !reader.is_finished() {
+                            let _ = child.kill();
+                        }
+                    });
+                    
+                    match child.wait() {
+                        Ok(status) if running.load(Ordering::SeqCst) => {
+                            eprintln!("nft monitor exited with {}, restarting", status);
+                        }
+                        Ok(_) => {}
+                        Err(e) => eprintln!("Failed to wait for nft monitor: {}", e),
+                    }
+                }
+                Err(e) => eprintln!("Failed to start nft monitor: {}", e),
+            }
+            
+            thread::sleep(self.restart_delay);
+        }
+    }
+}
+
+impl Default for NftMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::parse_ethernet;
+    
+    #[test]
+    fn test_family_rendering() {
+        let family: NftFamily = "ip".parse().unwrap();
+        let classifier = NftablesClassifier::with_family(family, "trafficmon", "forward");
+        
+        let base = classifier.base_structure_commands();
+        assert_eq!(base[0], "add table ip trafficmon");
+        assert!(base.iter().all(|cmd| cmd.starts_with("add ") && cmd.contains(" ip trafficmon")));
+        
+        let services = Config::default().services;
+        let stats = classifier.statistics_chain_commands(&services);
+        assert!(stats.contains(&"add rule ip trafficmon traffic_stats ip daddr vmap @service_daddr_vmap".to_string()));
+        
+        let ip6 = NftablesClassifier::with_family(NftFamily::Ip6, "trafficmon", "forward");
+        let mut v6_services = services.clone();
+        v6_services[0].ip_ranges.push("2a00:86c0::/32".to_string());
+        let ip6_stats = ip6.statistics_chain_commands(&v6_services);
+        assert!(ip6_stats.iter().any(|cmd| cmd.ends_with(" ip6 daddr vmap @service_daddr_vmap")));
+        assert!(ip6_stats.iter().any(|cmd| cmd.contains("type ipv6_addr : verdict") && cmd.contains("2a00:86c0::/32 : jump svc_netflix")));
+        assert!(ip6.base_structure_commands().iter().any(|cmd| cmd.contains("type ipv6_addr")));
+        
+        assert!("bridge".parse::<NftFamily>().is_err());
+    }
+    
+    #[test]
+    fn test_names_from_config() {
+        let config = Config {
+            nft_family: "ip".to_string(),
+            nft_table: "tm_lab".to_string(),
+            nft_chain: "fwd".to_string(),
+            ..Config::default()
+        };
+        assert!(config.validate().is_empty());
+        let classifier = NftablesClassifier::from_config(&config).unwrap();
+        
+        let base = classifier.base_structure_commands();
+        assert_eq!(base[0], "add table ip tm_lab");
+        assert!(base.contains(&"add chain ip tm_lab fwd { type filter hook forward priority 0; policy accept; }".to_string()));
+        assert!(base.contains(&"add rule ip tm_lab fwd jump traffic_stats".to_string()));
+        assert!(classifier.statistics_chain_commands(&config.services).iter().all(|cmd| cmd.contains(" ip tm_lab ")));
+        
+        // 默認值與原來寫死的名稱相同
+        let default = NftablesClassifier::from_config(&Config::default()).unwrap();
+        assert_eq!(default.base_structure_commands()[0], "add table inet trafficmon");
+        
+        let config = Config { nft_table: "bad name".to_string(), nft_chain: "1st".to_string(), ..Config::default() };
+        let errors = config.validate();
+        assert!(errors.iter().any(|e| e.starts_with("nft_table:")));
+        assert!(errors.iter().any(|e| e.starts_with("nft_chain:")));
+    }
+    
+    #[test]
+    fn test_geoip_country_rules() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        assert!(classifier.base_structure_commands().contains(&"add rule inet trafficmon forward jump geoip_filter".to_string()));
+        
+        let path = std::env::temp_dir().join(format!("trafficmon-geoip-{}.zone", std::process::id()));
+        fs::write(&path, "# KP\n175.45.176.0/22\n\n210.52.109.0/24\n2a0a:4a80::/29\n").unwrap();
+        let cidrs = load_country_list(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(cidrs.len(), 3);
+        
+        let rule = GeoIpRule { country: "KP".to_string(), action: "drop".to_string() };
+        let commands = classifier.geoip_commands(&[(&rule, cidrs.clone())]);
+        assert_eq!(commands, vec![
+            "flush chain inet trafficmon geoip_filter",
+            "add set inet trafficmon geoip_kp { type ipv4_addr; flags interval; auto-merge; }",
+            "flush set inet trafficmon geoip_kp",
+            "add element inet trafficmon geoip_kp { 175.45.176.0/22, 210.52.109.0/24 }",
+            "add rule inet trafficmon geoip_filter ip saddr @geoip_kp counter drop comment \"geoip kp saddr\"",
+            "add rule inet trafficmon geoip_filter ip daddr @geoip_kp counter drop comment \"geoip kp daddr\"",
+            "add set inet trafficmon geoip_kp_v6 { type ipv6_addr; flags interval; auto-merge; }",
+            "flush set inet trafficmon geoip_kp_v6",
+            "add element inet trafficmon geoip_kp_v6 { 2a0a:4a80::/29 }",
+            "add rule inet trafficmon geoip_filter ip6 saddr @geoip_kp_v6 counter drop comment \"geoip kp v6 saddr\"",
+            "add rule inet trafficmon geoip_filter ip6 daddr @geoip_kp_v6 counter drop comment \"geoip kp v6 daddr\"",
+        ]);
+        // ip 表只用 IPv4 範圍
+        let ip_only = NftablesClassifier::with_family(NftFamily::Ip, "trafficmon", "forward");
+        assert!(!ip_only.geoip_commands(&[(&rule, cidrs.clone())]).iter().any(|cmd| cmd.contains("_v6") || cmd.contains("2a0a:")));
+        
+        // 大列表分批加入；動作原樣寫入
+        let large: Vec<String> = (0..2500u32).map(|i| format!("10.{}.{}.0/24", i / 256, i % 256)).collect();
+        let rule = GeoIpRule { country: "us".to_string(), action: "accept".to_string() };
+        let commands = classifier.geoip_commands(&[(&rule, large)]);
+        assert_eq!(commands.iter().filter(|cmd| cmd.starts_with("add element inet trafficmon geoip_us")).count(), 3);
+        assert!(commands.last().unwrap().ends_with("@geoip_us counter accept comment \"geoip us daddr\""));
+        
+        fs::write(&path, "not-a-cidr\n").unwrap();
+        assert!(load_country_list(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+    
+    #[test]
+    fn test_bidirectional_service_rules() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let mut service = ServiceConfig {
+            name: "disney".to_string(),
+            ports: vec![443],
+            ip_ranges: vec!["10.1.0.0/16".to_string()],
+            blocked: false,
+            bidirectional: true,
+            category: None,
+        };
+        
+        assert_eq!(classifier.service_rule_commands(&service), vec![
+            "add rule inet trafficmon traffic_stats ip daddr @disney_ips tcp dport { 443 } counter accept comment \"disney traffic\"",
+            "add rule inet trafficmon traffic_stats ip saddr @disney_ips tcp sport { 443 } counter accept comment \"disney response\"",
+        ]);
+        
+        service.bidirectional = false;
+        assert_eq!(classifier.service_rule_commands(&service).len(), 1);
+    }
+    
+    #[test]
+    fn test_counter_reset_delta() {
+        let mut deltas = CounterDeltas::new();
+        
+        let observed: Vec<u64> = [100, 250, 40, 90, 90]
+            .iter()
+            .map(|value| deltas.delta("Netflix traffic", *value))
+            .collect();
+        
+        // 250 -> 40 為重置，增量取 40 而不是負數
+        assert_eq!(observed, vec![100, 150, 40, 50, 0]);
+    }
+    
+    #[test]
+    fn test_category_limit_rules() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let config = Config::default();
+        
+        let services = config.services_in_category("Streaming");
+        let commands = classifier.category_limit_commands("Streaming", 20, &services);
+        
+        assert_eq!(commands[0], "add limit inet trafficmon streaming_limit { rate over 2500 kbytes/second; }");
+        for service in ["netflix", "youtube"] {
+            assert!(commands.contains(&format!(
+                "insert rule inet trafficmon traffic_stats ip daddr @{}_ips limit name \"streaming_limit\" drop comment \"Streaming limit: {}\"",
+                service, service
+            )));
+        }
+        assert_eq!(commands.len(), 1 + 4);
+    }
+    
+    #[test]
+    fn test_category_mark_rules() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let mut config = Config::default();
+        config.services[1].category = Some("bulk".to_string());
+        config.services[1].bidirectional = false;
+        
+        let streaming = classifier.category_mark_commands("streaming", 0x10, &config.services_in_category("streaming"));
+        assert_eq!(streaming, vec![
+            "insert rule inet trafficmon traffic_stats ip daddr @netflix_ips meta mark set 0x10 comment \"streaming mark: netflix\"",
+            "insert rule inet trafficmon traffic_stats ip saddr @netflix_ips meta mark set 0x10 comment \"streaming mark: netflix\"",
+        ]);
+        
+        let bulk = classifier.category_mark_commands("bulk", 32, &config.services_in_category("bulk"));
+        assert_eq!(bulk, vec![
+            "insert rule inet trafficmon traffic_stats ip daddr @youtube_ips meta mark set 0x20 comment \"bulk mark: youtube\"",
+        ]);
+    }
+    
+    #[test]
+    fn test_length_range_match() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let mut rule = TrafficRule {
+            name: "keepalive_flood".to_string(),
+            protocol: "udp".to_string(),
+            ports: vec![],
+            ip_ranges: vec![],
+            payload_patterns: vec![],
+            min_length: Some(0),
+            max_length: Some(64),
+            uid: None,
+            cgroup: None,
+            tcp_flags: vec![],
+            action: "drop".to_string(),
+        };
+        
+        assert_eq!(classifier.build_match_conditions(&rule).unwrap(), "udp meta length 0-64");
+        
+        rule.min_length = None;
+        assert_eq!(classifier.build_match_conditions(&rule).unwrap(), "udp meta length <= 64");
+        
+        rule.min_length = Some(128);
+        assert!(classifier.build_match_conditions(&rule).is_err());
+    }
+    
+    #[test]
+    fn test_rule_matches_packet() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let frame = |dport: u16, payload: &[u8]| {
+            let mut frame = vec![0u8; 12];
+            frame.extend_from_slice(&[0x08, 0x00, 0x45, 0x00]);
+            frame.extend_from_slice(&((40 + payload.len()) as u16).to_be_bytes());
+            frame.extend_from_slice(&[0, 0, 0x40, 0, 0x40, 6, 0, 0, 192, 168, 1, 100, 93, 184, 216, 34]);
+            frame.extend_from_slice(&50000u16.to_be_bytes());
+            frame.extend_from_slice(&dport.to_be_bytes());
+            frame.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 1, 0x50, 0x18, 0xff, 0xff, 0, 0, 0, 0]);
+            frame.extend_from_slice(payload);
+            frame
+        };
+        let (https, http) = (frame(443, b"\x16\x03\x01"), frame(80, b"GET / HTTP/1.1"));
+        let (https, http) = (parse_ethernet(&https).unwrap(), parse_ethernet(&http).unwrap());
+        let mut rule = TrafficRule {
+            name: "https".to_string(),
+            protocol: "tcp".to_string(),
+            ports: vec![443],
+            action: "accept".to_string(),
+            ..TrafficRule::default()
+        };
+        
+        assert!(classifier.rule_matches(&rule, &https).unwrap());
+        assert!(!classifier.rule_matches(&rule, &http).unwrap());
+        
+        // 其餘條件同時滿足才匹配
+        rule.ip_ranges = vec!["93.184.216.0/24".to_string()];
+        rule.tcp_flags = vec!["psh".to_string(), "ack".to_string()];
+        rule.max_length = Some(43);
+        assert!(classifier.rule_matches(&rule, &https).unwrap());
+        rule.tcp_flags.push("syn".to_string());
+        assert!(!classifier.rule_matches(&rule, &https).unwrap());
+        
+        rule.ports.clear();
+        rule.tcp_flags.clear();
+        rule.max_length = None;
+        rule.payload_patterns = vec!["HTTP/1.1".to_string()];
+        assert!(!classifier.rule_matches(&rule, &https).unwrap());
+        assert!(classifier.rule_matches(&rule, &http).unwrap());
+        
+        rule.uid = Some(1000);
+        assert!(classifier.rule_matches(&rule, &http).is_err());
+    }
+    
+    #[test]
+    fn test_local_process_match() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let mut rule = TrafficRule {
+            name: "backup_agent".to_string(),
+            protocol: "tcp".to_string(),
+            ports: vec![],
+            ip_ranges: vec![],
+            payload_patterns: vec![],
+            min_length: None,
+            max_length: None,
+            uid: Some(1000),
+            cgroup: None,
+            tcp_flags: vec![],
+            action: "counter".to_string(),
+        };
+        
+        assert_eq!(
+            classifier.traffic_rule_command(&rule).unwrap(),
+            "add rule inet trafficmon local_output tcp meta skuid 1000 counter comment \"backup_agent\""
+        );
+        
+        rule.uid = None;
+        rule.cgroup = Some("/system.slice/restic.service".to_string());
+        assert_eq!(
+            classifier.build_match_conditions(&rule).unwrap(),
+            "tcp socket cgroupv2 level 2 \"system.slice/restic.service\""
+        );
+        
+        // 沒有本機匹配時仍寫入統計鏈
+        rule.cgroup = None;
+        assert!(classifier.traffic_rule_command(&rule).unwrap().starts_with("add rule inet trafficmon traffic_stats "));
+    }
+    
+    #[test]
+    fn test_tcp_flags_match() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let mut rule = TrafficRule {
+            name: "syn_rate".to_string(),
+            protocol: "tcp".to_string(),
+            ports: vec![443],
+            ip_ranges: vec![],
+            payload_patterns: vec![],
+            min_length: None,
+            max_length: None,
+            uid: None,
+            cgroup: None,
+            tcp_flags: vec!["syn".to_string()],
+            action: "counter".to_string(),
+        };
+        
+        assert_eq!(classifier.build_match_conditions(&rule).unwrap(), "tcp tcp dport { 443 } tcp flags syn");
+        
+        rule.ports.clear();
+        rule.tcp_flags = vec!["syn".to_string(), "ack".to_string()];
+        assert_eq!(classifier.build_match_conditions(&rule).unwrap(), "tcp tcp flags & (syn | ack) == syn | ack");
+        
+        rule.tcp_flags = vec!["push".to_string()];
+        assert!(classifier.build_match_conditions(&rule).is_err());
+    }
+    
+    #[test]
+    fn test_trace_monitor_lines() {
+        let lines = [
+            "trace id 9a3b5f1c inet trafficmon forward packet: iif \"eth0\" oif \"eth1\" ip saddr 192.168.1.100 ip daddr 198.38.96.10 ip ttl 63 ip length 1400 tcp sport 54321 tcp dport 443",
+            "trace id 9a3b5f1c inet trafficmon forward rule jump traffic_stats (verdict jump traffic_stats)",
+            "trace id 9a3b5f1c inet trafficmon traffic_stats rule ip daddr vmap @service_daddr_vmap (verdict jump svc_netflix)",
+            "trace id 9a3b5f1c inet trafficmon svc_netflix rule tcp dport { 80, 443, 1935 } counter packets 7 bytes 9800 accept comment \"netflix traffic\" (verdict accept)",
+            "trace id 9a3b5f1c inet trafficmon forward policy accept",
+            "trace id 1c2d3e4f inet trafficmon svc_netflix rule tcp dport { 80, 443, 1935 } counter packets 8 bytes 9860 accept comment \"netflix traffic\" (verdict accept)",
+        ];
+        
+        let mut parser = TraceParser::new();
+        let updates: Vec<CounterUpdate> = lines.iter().filter_map(|line| parser.parse_line(line)).collect();
+        
+        assert_eq!(updates, vec![
+            CounterUpdate { name: "netflix traffic".to_string(), packets: 1, bytes: 1400 },
+            // 沒有看到 packet 行時只計包數
+            CounterUpdate { name: "netflix traffic".to_string(), packets: 1, bytes: 0 },
+        ]);
+        assert_eq!(updates[0].service(), "netflix");
+        assert!(parser.lengths.is_empty());
+    }
+    
+    #[test]
+    fn test_monitor_stops_while_silent() {
+        let monitor = NftMonitor {
+            command: ["sleep", "30"].iter().map(|s| s.to_string()).collect(),
+            restart_delay: Duration::from_millis(10),
+        };
+        let running = AtomicBool::new(true);
+        let started = std::time::Instant::now();
+        
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(200));
+                running.store(false, Ordering::SeqCst);
+            });
+            monitor.run(&running, |_| {});
+        });
+        
+        // 子進程沒有輸出時也要在停止後被殺掉
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+    
+    #[test]
+    fn test_trace_rules_limited_to_traced_services() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let service = |name: &str, bidirectional| ServiceConfig {
+            name: name.to_string(),
+            ports: vec![443],
+            ip_ranges: vec![],
+            blocked: false,
+            bidirectional,
+            category: None,
+        };
+        let mut config = Config {
+            services: vec![service("netflix", true), service("youtube", false)],
+            nft_trace_services: vec!["netflix".to_string()],
+            ..Config::default()
+        };
+        assert!(classifier.trace_rule_commands(&config).is_empty());
+        
+        config.nft_monitor = true;
+        assert_eq!(classifier.trace_rule_commands(&config), [
+            "insert rule inet trafficmon svc_netflix meta nftrace set 1 comment \"nftrace\"",
+            "insert rule inet trafficmon svc_netflix_response meta nftrace set 1 comment \"nftrace\"",
+        ]);
+        
+        // 監視結束時按句柄刪除
+        let runner = RecordingRunner {
+            output: [
+                "table inet trafficmon { # handle 1",
+                "\tchain svc_netflix { # handle 7",
+                "\t\tmeta nftrace set 1 comment \"nftrace\" # handle 21",
+                "\t\ttcp dport { 443 } counter packets 0 bytes 0 accept comment \"netflix traffic\" # handle 22",
+                "\t}",
+                "\tchain svc_netflix_response { # handle 8",
+                "\t\tmeta nftrace set 1 comment \"nftrace\" # handle 23",
+                "\t}",
+                "}",
+            ].join("\n"),
+            calls: Default::default(),
+        };
+        classifier.remove_trace_rules_with(&runner).unwrap();
+        let commands: Vec<String> = runner.calls.borrow().iter().skip(1).map(|(_, args)| args.join(" ")).collect();
+        assert_eq!(commands, [
+            "delete rule inet trafficmon svc_netflix handle 21",
+            "delete rule inet trafficmon svc_netflix_response handle 23",
+        ]);
+    }
+    
+    struct CannedRunner(Result<String, String>);
+    
+    impl CommandRunner for CannedRunner {
+        fn run(&self, program: &str, args: &[String]) -> Result<String> {
+            assert_eq!(program, "ssh");
+            assert_eq!(args[args.len() - 2..], ["root@192.168.1.1".to_string(), "nft -j list ruleset".to_string()]);
+            self.0.clone().map_err(|e| anyhow!(e))
+        }
+    }
+    
+    /// 記錄收到的命令並返回固定輸出
+    struct RecordingRunner {
+        output: String,
+        calls: std::cell::RefCell<Vec<(String, Vec<String>)>>,
+    }
+    
+    impl CommandRunner for RecordingRunner {
+        fn run(&self, program: &str, args: &[String]) -> Result<String> {
+            self.calls.borrow_mut().push((program.to_string(), args.to_vec()));
+            Ok(self.output.clone())
+        }
+    }
+    
+    /// 按調用順序返回預設結果
+    struct ScriptedRunner(std::cell::RefCell<Vec<Result<String, String>>>);
+    
+    impl CommandRunner for ScriptedRunner {
+        fn run(&self, program: &str, _args: &[String]) -> Result<String> {
+            assert_eq!(program, "nft");
+            self.0.borrow_mut().remove(0).map_err(|e| anyhow!(e))
+        }
+    }
+    
+    #[test]
+    fn test_duplicate_element_add() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let runner = RecordingRunner { output: String::new(), calls: Default::default() };
+        classifier.block_ip_temporarily_with("203.0.113.7", 300, &runner).unwrap();
+        assert_eq!(*runner.calls.borrow(), vec![(
+            "nft".to_string(),
+            vec!["add element inet trafficmon dynamic_block { 203.0.113.7 timeout 300s }".to_string()],
+        )]);
+        
+        let runner = ScriptedRunner(std::cell::RefCell::new(vec![
+            Ok(String::new()),
+            Err("nft exited with exit status: 1: Error: Could not process rule: File exists".to_string()),
+            Err("nft exited with exit status: 1: Error: Could not process rule: No such file or directory".to_string()),
+        ]));
+        classifier.block_ip_temporarily_with("203.0.113.7", 300, &runner).unwrap();
+        // 重複添加視為成功
+        classifier.block_ip_temporarily_with("203.0.113.7", 300, &runner).unwrap();
+        // 其他錯誤照常返回
+        assert!(classifier.block_ip_temporarily_with("203.0.113.7", 300, &runner).is_err());
+    }
+    
+    #[test]
+    fn test_rebuild_service_set() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let service = ServiceConfig {
+            ip_ranges: vec!["198.51.100.0/24".to_string()],
+            ..Config::default().services[0].clone()
+        };
+        let runner = RecordingRunner { output: String::new(), calls: Default::default() };
+        let learned = ["203.0.113.0/25", "2001:db8::1/128"].map(|cidr| crate::config::parse_cidr(cidr).unwrap());
+        classifier.rebuild_service_set_with(&service, &learned, &runner).unwrap();
+        
+        // 集合清空後只剩配置的範圍和仍在學習表中的網段，IPv6 不進 inet 表的 IPv4 集合
+        let set = format!("{}_ips", service.name);
+        let commands: Vec<String> = runner.calls.take().into_iter().flat_map(|(_, args)| args).collect();
+        assert_eq!(commands, vec![
+            format!("add set inet trafficmon {} {{ type ipv4_addr; flags interval; }}", set),
+            format!("flush set inet trafficmon {}", set),
+            format!("add element inet trafficmon {} {{ 198.51.100.0/24 }}", set),
+            format!("add element inet trafficmon {} {{ 203.0.113.0/25 }}", set),
+        ]);
+    }
+    
+    /// 模擬一張表：刪除後再列出時報告不存在；`stuck` 時刪除不生效
+    struct TableRunner {
+        exists: std::cell::Cell<bool>,
+        stuck: bool,
+        calls: std::cell::RefCell<Vec<String>>,
+    }
+    
+    impl CommandRunner for TableRunner {
+        fn run(&self, program: &str, args: &[String]) -> Result<String> {
+            assert_eq!(program, "nft");
+            self.calls.borrow_mut().push(args.join(" "));
+            if !self.exists.get() {
+                return Err(anyhow!("nft exited with exit status: 1: Error: No such file or directory"));
+            }
+            if args[0].starts_with("delete table") && !self.stuck {
+                self.exists.set(false);
+            }
+            Ok(String::new())
+        }
+    }
+    
+    #[test]
+    fn test_unblock_ip() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let runner = RecordingRunner { output: String::new(), calls: Default::default() };
+        classifier.unblock_ip_with("203.0.113.7", &runner).unwrap();
+        assert_eq!(*runner.calls.borrow(), vec![(
+            "nft".to_string(),
+            vec!["delete element inet trafficmon dynamic_block { 203.0.113.7 }".to_string()],
+        )]);
+        
+        let runner = ScriptedRunner(std::cell::RefCell::new(vec![
+            Err("nft exited with exit status: 1: Error: Could not process rule: No such file or directory".to_string()),
+            Err("nft exited with exit status: 1: Error: Could not process rule: Operation not permitted".to_string()),
+        ]));
+        classifier.unblock_ip_with("203.0.113.7", &runner).unwrap();
+        assert!(classifier.unblock_ip_with("203.0.113.7", &runner).is_err());
+    }
+    
+    #[test]
+    fn test_list_blocked_ips() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let output = "table inet trafficmon {
+\tset dynamic_block {
+\t\ttype ipv4_addr
+\t\tflags timeout
+\t\telements = { 203.0.113.7 timeout 5m expires 4m58s760ms, 198.51.100.2 timeout 1d expires 23h59m1s,
+\t\t\t     192.0.2.1 }
+\t}
+}
+";
+        let runner = RecordingRunner { output: output.to_string(), calls: Default::default() };
+        
+        assert_eq!(classifier.list_blocked_ips_with(&runner).unwrap(), vec![
+            ("203.0.113.7".to_string(), Some(299)),
+            ("198.51.100.2".to_string(), Some(86341)),
+            ("192.0.2.1".to_string(), None),
+        ]);
+        assert_eq!(runner.calls.borrow()[0].1, ["list", "set", "inet", "trafficmon", "dynamic_block"].map(String::from));
+        
+        // 空集合沒有 elements 行
+        let empty = RecordingRunner { output: "table inet trafficmon {\n\tset dynamic_block {\n\t}\n}\n".to_string(), calls: Default::default() };
+        assert!(classifier.list_blocked_ips_with(&empty).unwrap().is_empty());
+    }
+    
+    #[test]
+    fn test_flush_removes_table() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let runner = TableRunner { exists: true.into(), stuck: false, calls: Default::default() };
+        classifier.flush_with(&runner).unwrap();
+        assert_eq!(*runner.calls.borrow(), vec![
+            "delete table inet trafficmon",
+            "list table inet trafficmon",
+        ]);
+        
+        // 表本來就不存在也算成功
+        classifier.flush_with(&runner).unwrap();
+        
+        let runner = TableRunner { exists: true.into(), stuck: true, calls: Default::default() };
+        let error = classifier.flush_with(&runner).unwrap_err();
+        assert_eq!(error.to_string(), "Table inet trafficmon still exists after flush");
+    }
+    
+    #[test]
+    fn test_disable_and_enable_named_rule() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let rule = TrafficRule {
+            name: "block_ssh".to_string(),
+            protocol: "tcp".to_string(),
+            ports: vec![22],
+            ip_ranges: vec![],
+            payload_patterns: vec![],
+            min_length: None,
+            max_length: None,
+            uid: None,
+            cgroup: None,
+            tcp_flags: vec![],
+            action: "drop".to_string(),
+        };
+        let added = "add rule inet trafficmon traffic_stats tcp dport { 22 } drop comment \"block_ssh\" # handle 42\n";
+        let runner = RecordingRunner { output: added.to_string(), calls: Default::default() };
+        
+        assert_eq!(classifier.add_named_rule_with(&rule, &runner).unwrap(), 42);
+        let add_args = runner.calls.borrow()[0].1.clone();
+        assert_eq!(add_args[..2], ["--echo".to_string(), "--handle".to_string()]);
+        
+        classifier.disable_rule_with("block_ssh", &runner).unwrap();
+        // 已停用時不重複刪除
+        classifier.disable_rule_with("block_ssh", &runner).unwrap();
+        classifier.enable_rule_with("block_ssh", &runner).unwrap();
+        classifier.enable_rule_with("block_ssh", &runner).unwrap();
+        
+        let calls = runner.calls.borrow();
+        assert_eq!(calls.len(), 3);
+        assert_eq!(calls[1], ("nft".to_string(), vec!["delete rule inet trafficmon traffic_stats handle 42".to_string()]));
+        assert_eq!(calls[2].1, add_args);
+        
+        assert!(classifier.disable_rule_with("missing", &runner).is_err());
+    }
+    
+    #[test]
+    fn test_dump_ruleset() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let table = "table inet trafficmon {\n\tchain traffic_stats {\n\t}\n}\n";
+        let runner = RecordingRunner { output: table.to_string(), calls: Default::default() };
+        
+        assert_eq!(classifier.dump_ruleset_with(&runner).unwrap(), table);
+        assert_eq!(*runner.calls.borrow(), vec![(
+            "nft".to_string(),
+            vec!["list".to_string(), "table".to_string(), "inet".to_string(), "trafficmon".to_string()],
+        )]);
+    }
+    
+    #[test]
+    fn test_blocked_service_drop() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let mut config = Config::default();
+        config.services[0].blocked = true;
+        config.services[0].ip_ranges = vec!["198.38.96.0/19".to_string(), "2a00:86c0::/32".to_string()];
+        config.services.push(ServiceConfig {
+            name: "ssh".to_string(),
+            ports: vec![22],
+            ip_ranges: vec![],
+            blocked: true,
+            bidirectional: false,
+            category: None,
+        });
+        
+        // 只有地址範圍的服務同樣按地址集合丟棄；沒有地址範圍時集合只含學到的地址
+        assert_eq!(classifier.blocked_service_commands(&config.services), vec![
+            "add set inet trafficmon netflix_ips { type ipv4_addr; flags interval; }",
+            "add element inet trafficmon netflix_ips { 198.38.96.0/19 }",
+            "add rule inet trafficmon dns_filter ip daddr @netflix_ips counter drop comment \"blocked service netflix\"",
+            "add set inet trafficmon ssh_ips { type ipv4_addr; flags interval; }",
+            "add rule inet trafficmon dns_filter ip daddr @ssh_ips counter drop comment \"blocked service ssh\"",
+        ]);
+        
+        let commands = classifier.dns_filter_commands(&config).unwrap();
+        assert_eq!(commands[0], "flush chain inet trafficmon dns_filter");
+        assert!(commands.iter().any(|c| c.ends_with("counter drop comment \"blocked service netflix\"")));
+        assert!(classifier.blocked_service_commands(&Config::default().services).is_empty());
+    }
+    
+    #[test]
+    fn test_blocked_domain_set() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let domains = ["netflix.com", "Nflxvideo.net.", "example.org"].map(String::from);
+        
+        assert_eq!(classifier.blocked_domain_commands(&domains).unwrap(), vec![
+            "flush chain inet trafficmon dns_filter",
+            "add set inet trafficmon blocked_domains_13 { typeof @th,160,104; }",
+            "flush set inet trafficmon blocked_domains_13",
+            "add element inet trafficmon blocked_domains_13 { 0x076578616d706c65036f726700, 0x076e6574666c697803636f6d00 }",
+            "add rule inet trafficmon dns_filter udp dport 53 @th,160,104 | 0x00202020202020200020202000 @blocked_domains_13 counter drop comment \"blocked domains 13\"",
+            "add set inet trafficmon blocked_domains_15 { typeof @th,160,120; }",
+            "flush set inet trafficmon blocked_domains_15",
+            "add element inet trafficmon blocked_domains_15 { 0x096e666c78766964656f036e657400 }",
+            "add rule inet trafficmon dns_filter udp dport 53 @th,160,120 | 0x002020202020202020200020202000 @blocked_domains_15 counter drop comment \"blocked domains 15\"",
+        ]);
+        
+        // 大小寫混合的查詢（DNS 0x20）或上掩碼後與集合中的小寫元素相同；字母位置不同的同長度域名另用一個集合
+        let commands = classifier.blocked_domain_commands(&["youtube.com".to_string(), "1234567.com".to_string()]).unwrap();
+        assert!(commands.contains(&"add element inet trafficmon blocked_domains_13_1 { 0x07796f757475626503636f6d00 }".to_string()));
+        let query: Vec<u8> = b"\x07YouTube\x03cOm\x00".to_vec();
+        let mask: Vec<u8> = query.iter().map(|b| if b.is_ascii_alphabetic() { 0x20 } else { 0 }).collect();
+        let folded: Vec<u8> = query.iter().zip(&mask).map(|(b, m)| b | m).collect();
+        assert_eq!(hex_bytes(&folded), "0x07796f757475626503636f6d00");
+        assert!(commands.iter().any(|command| command.contains(&format!("@th,160,104 | {} @blocked_domains_13_1", hex_bytes(&mask)))));
+        
+        // 沒有封鎖的域名時只清空過濾鏈
+        assert_eq!(classifier.blocked_domain_commands(&[]).unwrap().len(), 1);
+        assert!(classifier.base_structure_commands().contains(&"add rule inet trafficmon forward jump dns_filter".to_string()));
+        
+        assert!(classifier.blocked_domain_commands(&["bad..name".to_string()]).is_err());
+        assert!(classifier.blocked_domain_commands(&[format!("{}.com", "a".repeat(60))]).is_err());
+    }
+    
+    #[test]
+    fn test_dns_rule_record_types() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let rule = DnsRule {
+            name: "no_txt".to_string(),
+            domains: vec!["example.org".to_string()],
+            record_types: vec!["txt".to_string()],
+            action: "drop".to_string(),
+        };
+        
+        // QNAME 為 13 字節，QTYPE 位於 160 + 104 位；字母所在字節或上 0x20 後比較，不區分大小寫
+        assert_eq!(classifier.dns_rule_commands(&rule).unwrap(), vec![
+            "add set inet trafficmon dns_no_txt_13 { typeof @th,160,104; }",
+            "flush set inet trafficmon dns_no_txt_13",
+            "add element inet trafficmon dns_no_txt_13 { 0x076578616d706c65036f726700 }",
+            "add rule inet trafficmon dns_filter udp dport 53 @th,160,104 | 0x00202020202020200020202000 @dns_no_txt_13 @th,264,16 { 16 } counter drop comment \"dns rule: no_txt 13\"",
+        ]);
+        
+        // 不指定類型時匹配所有查詢；動作原樣寫入
+        let any = DnsRule { record_types: vec![], action: "accept".to_string(), ..rule.clone() };
+        assert!(classifier.dns_rule_commands(&any).unwrap()[3].contains("@dns_no_txt_13 counter accept"));
+        
+        // 大寫的配置按小寫匹配；長度相同但字母位置不同的域名分到不同的集合
+        let mixed = DnsRule { domains: vec!["EXAMPLE.org".to_string(), "example.123".to_string()], ..rule.clone() };
+        let commands = classifier.dns_rule_commands(&mixed).unwrap();
+        assert!(commands.contains(&"add element inet trafficmon dns_no_txt_13 { 0x076578616d706c650331323300 }".to_string()));
+        assert!(commands.iter().any(|cmd| cmd.contains("| 0x00202020202020200000000000 @dns_no_txt_13 ")));
+        assert!(commands.contains(&"add element inet trafficmon dns_no_txt_13_1 { 0x076578616d706c65036f726700 }".to_string()));
+        assert!(commands.iter().any(|cmd| cmd.contains("| 0x00202020202020200020202000 @dns_no_txt_13_1 ")));
+        
+        let unknown = DnsRule { record_types: vec!["BOGUS".to_string()], ..rule };
+        assert!(classifier.dns_rule_commands(&unknown).is_err());
+    }
+    
+    #[test]
+    fn test_rule_budget_guard() {
+        let domains: Vec<String> = (0..40).map(|i| format!("host{:02}.example.com", i)).collect();
+        let classifier = NftablesClassifier::new("trafficmon", "forward").with_max_rules(40);
+        
+        // 40 個域名只有一種編碼長度：一條規則加 40 個元素
+        let commands = classifier.blocked_domain_commands(&domains).unwrap();
+        assert_eq!(commands.iter().map(|c| rule_objects(c)).sum::<usize>(), 41);
+        let err = classifier.check_rule_budget(&commands).unwrap_err();
+        assert!(err.to_string().contains("41 nftables rules and set elements (nft_max_rules = 40)"));
+        assert!(classifier.check_rule_budget(&commands[..3]).is_ok());
+        
+        let services = Config::default().services;
+        let commands = classifier.statistics_chain_commands(&services);
+        assert!(classifier.check_rule_budget(&commands).is_ok());
+        assert!(NftablesClassifier::new("trafficmon", "forward").with_max_rules(2).check_rule_budget(&commands).is_err());
+        
+        let config: Config = toml::from_str(
+            "report_interval = 60\nlog_unknown_traffic = false\nservices = []\ntime_rules = []\n\
+             user_rules = []\nblocked_domains = []\npattern_rules = []\nnft_max_rules = 500\n"
+        ).unwrap();
+        assert_eq!(NftablesClassifier::from_config(&config).unwrap().max_rules, 500);
+        assert_eq!(NftablesClassifier::from_config(&Config::default()).unwrap().max_rules, 100_000);
+    }
+    
+    #[test]
+    fn test_ip_range_and_port_dispatch_order() {
+        let mut services = Config::default().services;
+        services.truncate(1);
+        services.push(ServiceConfig {
+            name: "ssh".to_string(),
+            ports: vec![22],
+            ip_ranges: vec![],
+            ..services[0].clone()
+        });
+        let position = |priority, pattern: &str| {
+            let classifier = NftablesClassifier::new("trafficmon", "forward").with_priority(priority);
+            classifier.statistics_chain_commands(&services).iter().position(|c| c.contains(pattern)).unwrap()
+        };
+        let port_rule = "add rule inet trafficmon traffic_stats tcp dport { 22 } jump svc_ssh";
+        let vmap_rule = "traffic_stats ip daddr vmap @service_daddr_vmap";
+        
+        // Netflix 地址上的 22 端口：先分派的一方優先
+        assert!(position(ClassificationPriority::IpRange, vmap_rule) < position(ClassificationPriority::IpRange, port_rule));
+        assert!(position(ClassificationPriority::Port, port_rule) < position(ClassificationPriority::Port, vmap_rule));
+        
+        // 經 vmap 進入 svc_netflix 的 22 端口包在鏈中按地址計數，不會落回端口分派
+        let commands = NftablesClassifier::new("trafficmon", "forward").statistics_chain_commands(&services);
+        let netflix: Vec<_> = commands.iter().filter(|c| c.starts_with("add rule inet trafficmon svc_netflix ")).collect();
+        assert_eq!(netflix.len(), 1);
+        assert!(netflix[0].contains("ip daddr { 108.175.32.0/20,") && !netflix[0].contains("dport"));
+        
+        let config = Config { classification_priority: ClassificationPriority::Port, ..Config::default() };
+        assert_eq!(NftablesClassifier::from_config(&config).unwrap().priority, ClassificationPriority::Port);
+    }
+    
+    #[test]
+    fn test_block_grace_period() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let config = Config {
+            blocked_domains: vec!["example.org".to_string()],
+            block_grace_seconds: 30,
+            ..Config::default()
+        };
+        let applied = std::cell::RefCell::new(Vec::new());
+        let apply = |command: &str| -> Result<()> {
+            applied.borrow_mut().push(command.to_string());
+            Ok(())
+        };
+        let is_blocking = |command: &String| command.contains("dns_filter udp dport 53");
+        
+        // 寬限期內只有計數規則，之後才加入封鎖規則
+        let wait = |grace: Duration| {
+            assert_eq!(grace, Duration::from_secs(30));
+            assert!(applied.borrow().iter().any(|c| c.contains("jump svc_netflix")));
+            assert!(!applied.borrow().iter().any(is_blocking));
+            applied.borrow_mut().push("<grace>".to_string());
+            true
+        };
+        assert!(classifier.initialize_with_grace(&config, wait, &apply).unwrap());
+        let commands = applied.take();
+        let grace = commands.iter().position(|c| c == "<grace>").unwrap();
+        assert!(commands.iter().position(is_blocking).unwrap() > grace);
+        
+        // 寬限期內停止時不加入封鎖規則
+        assert!(!classifier.initialize_with_grace(&config, |_| false, &apply).unwrap());
+        assert!(!applied.take().iter().any(is_blocking));
+        
+        // 不設寬限期時不等待
+        let config = Config { block_grace_seconds: 0, ..config };
+        assert!(classifier.initialize_with_grace(&config, |_| panic!("no grace period"), &apply).unwrap());
+        assert!(applied.take().iter().any(is_blocking));
+    }
+    
+    #[test]
+    fn test_preserve_counters_on_reinit() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let config = Config {
+            blocked_domains: vec!["example.org".to_string()],
+            preserve_counters: true,
+            ..Config::default()
+        };
+        let runner = RecordingRunner {
+            output: [
+                "table inet trafficmon {",
+                "\tchain svc_netflix {",
+                "\t\ttcp dport { 80, 443, 1935 } counter packets 120 bytes 98000 accept comment \"netflix traffic\" # handle 12",
+                "\t}",
+                "\tchain dns_filter {",
+                "\t\tudp dport 53 @th,160,104 | 0x00202020202020200020202000 @blocked_domains_13 counter packets 3 bytes 210 drop comment \"blocked domains 13\" # handle 20",
+                "\t}",
+                "}",
+            ].join("\n"),
+            calls: Default::default(),
+        };
+        let previous = classifier.read_counters_with(&runner);
+        assert_eq!(previous[&("svc_netflix".to_string(), "netflix traffic".to_string())], (120, 98000));
+        
+        let applied = std::cell::RefCell::new(Vec::new());
+        let apply = |command: &str| -> Result<()> {
+            applied.borrow_mut().push(command.to_string());
+            Ok(())
+        };
+        assert!(classifier.initialize_preserving(&config, &previous, |_| true, &apply).unwrap());
+        let script = applied.take().join("\n");
+        assert!(script.contains("counter packets 120 bytes 98000 accept comment \"netflix traffic\""));
+        assert!(script.contains("counter packets 3 bytes 210 drop comment \"blocked domains 13\""));
+        // 沒有舊計數的規則從零開始
+        assert!(script.contains(" counter accept comment \"youtube traffic\""));
+        
+        // 表不存在時沒有可恢復的計數
+        assert!(classifier.read_counters_with(&ScriptedRunner(vec![Err("No such file or directory".to_string())].into())).is_empty());
+    }
+    
+    #[test]
+    fn test_preserve_counters_shared_comment() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        // 同一註釋出現在兩條鏈中，各自只恢復自己的計數
+        let runner = RecordingRunner {
+            output: [
+                "table inet trafficmon {",
+                "\tchain svc_netflix {",
+                "\t\ttcp dport { 80, 443, 1935 } counter packets 120 bytes 98000 accept comment \"netflix traffic\" # handle 12",
+                "\t}",
+                "\tchain traffic_stats {",
+                "\t\tip daddr @netflix_ips tcp dport { 80, 443, 1935 } counter packets 5 bytes 700 accept comment \"netflix traffic\" # handle 30",
+                "\t}",
+                "}",
+            ].join("\n"),
+            calls: Default::default(),
+        };
+        let previous = classifier.read_counters_with(&runner);
+        assert_eq!(previous.len(), 2);
+        
+        let commands = with_counter_values(vec![
+            "add rule inet trafficmon svc_netflix tcp dport { 80, 443, 1935 } counter accept comment \"netflix traffic\"".to_string(),
+            "add rule inet trafficmon traffic_stats ip daddr @netflix_ips tcp dport { 80, 443, 1935 } counter accept comment \"netflix traffic\"".to_string(),
+        ], &previous);
+        assert!(commands[0].contains("counter packets 120 bytes 98000 accept"));
+        assert!(commands[1].contains("counter packets 5 bytes 700 accept"));
+        
+        // 按長度、方向分開的規則註釋互不相同，不會把一條的計數寫到另一條
+        let domains = ["example.org", "nflxvideo.net"].map(String::from);
+        let rule = GeoIpRule { country: "KP".to_string(), action: "drop".to_string() };
+        let mut comments: Vec<String> = classifier.blocked_domain_commands(&domains).unwrap().into_iter()
+            .chain(classifier.geoip_commands(&[(&rule, vec!["175.45.176.0/22".to_string(), "2a0a:4a80::/29".to_string()])]))
+            .filter_map(|command| command.split_once("comment ").map(|(_, comment)| comment.to_string()))
+            .collect();
+        let count = comments.len();
+        comments.sort();
+        comments.dedup();
+        assert_eq!((count, comments.len()), (6, 6));
+    }
+    
+    #[test]
+    fn test_dscp_count_rule() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        assert_eq!(
+            classifier.dscp_count_rule_command(46).unwrap(),
+            "insert rule inet trafficmon forward ip dscp 46 counter comment \"traffic dscp 46\""
+        );
+        assert!(classifier.dscp_count_rule_command(64).is_err());
+        
+        let sampled = NftablesClassifier::with_family(NftFamily::Ip6, "trafficmon", "forward").with_sample_rate(10);
+        assert_eq!(
+            sampled.dscp_count_rule_command(0).unwrap(),
+            "insert rule ip6 trafficmon forward ip6 dscp 0 numgen random mod 10 == 0 counter comment \"traffic dscp 0\""
+        );
+        
+        let ruleset = "\t\tip dscp 46 counter packets 30 bytes 6000 comment \"traffic dscp 46\" # handle 3\n\
+                       \t\tip dscp 10 counter packets 4 bytes 800 comment \"traffic dscp 10\" # handle 4\n\
+                       \t\ttcp dport { 443 } counter packets 12 bytes 3400 accept comment \"https traffic\" # handle 9\n";
+        let stats = classifier.parse_counter_stats(ruleset);
+        assert_eq!(dscp_counters(&stats), BTreeMap::from([(10, 4), (46, 30)]));
+        // 不被當作服務計入字節數對比
+        assert_eq!(parse_service_bytes(ruleset), HashMap::from([("https".to_string(), 3400)]));
+    }
+    
+    #[test]
+    fn test_counter_regex_compiled_once() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let ruleset = "\t\tip daddr @netflix_ips counter packets 12 bytes 3400 comment \"netflix traffic\" # handle 7\n\
+                       \t\tcounter packets 5 bytes 600 comment \"trace\" # handle 8\n";
+        
+        for _ in 0..3 {
+            let stats = classifier.parse_counter_stats(ruleset);
+            assert_eq!(stats, HashMap::from([("netflix traffic".to_string(), 12)]));
+        }
+        assert!(std::ptr::eq(counter_regex(), counter_regex()));
+        assert!(std::ptr::eq(service_bytes_regex(), service_bytes_regex()));
+    }
+    
+    #[test]
+    fn test_compare_counters() {
+        let pcap = HashMap::from([
+            ("netflix".to_string(), (9_000, 10)),
+            ("dns".to_string(), (500, 5)),
+            ("mdns".to_string(), (300, 3)),
+        ]);
+        let ruleset = "\t\tip daddr @netflix_ips counter packets 8 bytes 6000 accept comment \"netflix traffic\"
+\t\tip saddr @netflix_ips counter packets 4 bytes 4000 accept comment \"netflix response\"
+\t\tudp dport { 53 } counter packets 5 bytes 500 accept comment \"dns traffic\"
+\t\ttcp dport { 22 } counter packets 2 bytes 120 accept comment \"ssh traffic\"";
+        let runner = RecordingRunner { output: ruleset.to_string(), calls: Default::default() };
+        let nft = NftablesClassifier::new("trafficmon", "forward").get_service_bytes_with(&runner).unwrap();
+        assert_eq!(nft["netflix"], 10_000);
+        
+        let rows = compare_counters(&pcap, &nft);
+        let summary: Vec<(&str, i64)> = rows.iter().map(|r| (r.service.as_str(), r.discrepancy())).collect();
+        assert_eq!(summary, vec![("netflix", -1000), ("mdns", 300), ("ssh", -120), ("dns", 0)]);
+        assert!((rows[0].discrepancy_ratio() - 0.1).abs() < 1e-9);
+        assert_eq!(rows[1].discrepancy_ratio(), 1.0);
+        assert_eq!(rows[3].discrepancy_ratio(), 0.0);
+        
+        let report = format_counter_comparison(&rows);
+        assert_eq!(report.lines().count(), 5);
+        assert!(report.lines().nth(1).unwrap().ends_with("-1000    10.0%"));
+    }
+    
+    #[test]
+    fn test_restore_ruleset() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let dump = "table inet trafficmon {\n\tchain traffic_stats {\n\t\tcounter comment \"netflix traffic\"\n\t}\n}\n";
+        
+        assert_eq!(
+            classifier.restore_script(dump).unwrap(),
+            format!("add table inet trafficmon\ndelete table inet trafficmon\n{}", dump)
+        );
+        
+        // 其他表或其他協議族的導出不能恢復到本表
+        let err = classifier.restore_script(&dump.replace("trafficmon", "filter")).unwrap_err();
+        assert!(err.to_string().contains("table inet filter"));
+        assert!(classifier.restore_script(&dump.replace("inet", "ip")).is_err());
+        assert!(classifier.restore_script("").is_err());
+    }
+    
+    #[test]
+    fn test_remote_stats_over_ssh() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let remote: RemoteHostConfig = toml::from_str("host = \"root@192.168.1.1\"").unwrap();
+        
+        let output = r#"{"nftables": [
+            {"metainfo": {"json_schema_version": 1}},
+            {"rule": {"family": "inet", "table": "trafficmon", "chain": "traffic_stats", "handle": 7,
+                "expr": [{"match": {}}, {"counter": {"packets": 42, "bytes": 6300}}], "comment": "netflix_traffic"}},
+            {"rule": {"family": "inet", "table": "trafficmon", "chain": "traffic_stats", "handle": 8,
+                "expr": [{"counter": {"packets": 5, "bytes": 300}}], "comment": "other rule"}}
+        ]}"#;
+        let stats = classifier.get_remote_traffic_stats(&remote, &CannedRunner(Ok(output.to_string()))).unwrap();
+        assert_eq!(stats, HashMap::from([("netflix_traffic".to_string(), 42)]));
+        
+        // SSH 失敗時返回帶主機名的錯誤，匯總時跳過該主機
+        let failing = CannedRunner(Err("ssh exited with exit status: 255: Connection refused".to_string()));
+        let err = classifier.get_remote_traffic_stats(&remote, &failing).unwrap_err();
+        assert!(err.to_string().contains("root@192.168.1.1"));
+        assert!(classifier.collect_remote_stats(&[remote], &failing).is_empty());
+    }
+    
+    #[test]
+    fn test_service_name_validation() {
+        let service = |name: &str| ServiceConfig {
+            name: name.to_string(),
+            ports: vec![443],
+            ip_ranges: vec![],
+            blocked: false,
+            bidirectional: true,
+            category: None,
+        };
+        
+        assert!(validate_service_names(&Config::default().services).is_ok());
+        assert!(validate_service_names(&[service("disney-plus"), service("hbo_max")]).is_ok());
+        
+        let err = validate_service_names(&[service("prime video")]).unwrap_err();
+        assert!(err.to_string().contains("not a valid nftables identifier"));
+        assert!(validate_service_names(&[service("9gag")]).is_err());
+        
+        let err = validate_service_names(&[service("netflix"), service("Netflix")]).unwrap_err();
+        assert!(err.to_string().contains("same nftables set"));
+    }
+    
+    #[test]
+    fn test_statistics_vmap() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let mut services = Config::default().services;
+        services[1].bidirectional = false;
+        
+        let commands = classifier.statistics_chain_commands(&services);
+        
+        assert!(commands.contains(&"add chain inet trafficmon svc_netflix".to_string()));
+        // 按地址計數，不限端口
+        let ranges = services[0].ip_ranges.join(", ");
+        assert!(commands.contains(&format!("add rule inet trafficmon svc_netflix ip daddr {{ {} }} counter accept comment \"netflix traffic\"", ranges)));
+        assert!(commands.contains(&format!("add rule inet trafficmon svc_netflix_response ip saddr {{ {} }} counter accept comment \"netflix response\"", ranges)));
+        assert!(!commands.iter().any(|cmd| cmd.contains("svc_youtube_response")));
+        
+        let request_map = commands.iter().find(|cmd| cmd.starts_with("add map inet trafficmon service_daddr_vmap")).unwrap();
+        assert!(request_map.contains("type ipv4_addr : verdict; flags interval;"));
+        for element in ["108.175.32.0/20 : jump svc_netflix", "198.38.96.0/19 : jump svc_netflix", "74.125.0.0/16 : jump svc_youtube"] {
+            assert!(request_map.contains(element), "{}", element);
+        }
+        
+        let response_map = commands.iter().find(|cmd| cmd.starts_with("add map inet trafficmon service_saddr_vmap")).unwrap();
+        assert!(response_map.contains("108.175.32.0/20 : jump svc_netflix_response"));
+        assert!(!response_map.contains("svc_youtube"));
+        
+        // 統計鏈只剩兩條查表規則
+        let stats_rules: Vec<_> = commands.iter().filter(|cmd| cmd.contains(" traffic_stats ")).collect();
+        assert_eq!(stats_rules, vec![
+            "add rule inet trafficmon traffic_stats ip daddr vmap @service_daddr_vmap",
+            "add rule inet trafficmon traffic_stats ip saddr vmap @service_saddr_vmap",
+        ]);
+    }
+    
+    #[test]
+    fn test_ip_ranges_from_file() {
+        let dir = std::env::temp_dir().join(format!("trafficmon-ranges-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("cdn.txt"), "# published ranges\n23.246.0.0/18\n\n37.77.184.0/21\n").unwrap();
+        fs::write(dir.join("trafficmon.conf"), r#"
+            interface = "eth0"
+            report_interval = 30
+            log_unknown_traffic = false
+            time_rules = []
+            user_rules = []
+            blocked_domains = []
+            pattern_rules = []
+            
+            [[services]]
+            name = "netflix"
+            ports = [443]
+            ip_ranges = ["198.38.96.0/19", "@file:cdn.txt"]
+            blocked = false
+        "#).unwrap();
+        
+        let config = Config::from_file(&dir.join("trafficmon.conf")).unwrap();
+        assert_eq!(config.services[0].ip_ranges, vec!["198.38.96.0/19", "23.246.0.0/18", "37.77.184.0/21"]);
+        assert!(config.validate().is_empty());
+        
+        let classifier = NftablesClassifier::new("trafficmon", "forward");
+        let commands = classifier.statistics_chain_commands(&config.services);
+        let request_map = commands.iter().find(|cmd| cmd.starts_with("add map inet trafficmon service_daddr_vmap")).unwrap();
+        for element in ["198.38.96.0/19 : jump svc_netflix", "23.246.0.0/18 : jump svc_netflix", "37.77.184.0/21 : jump svc_netflix"] {
+            assert!(request_map.contains(element), "{}", element);
+        }
+        
+        // 文件中的無效 CIDR 使載入失敗，重新載入時讀取文件的新內容
+        fs::write(dir.join("cdn.txt"), "23.246.0.0/33\n").unwrap();
+        let error = Config::from_file(&dir.join("trafficmon.conf")).unwrap_err().to_string();
+        assert!(error.contains("services.netflix") && error.contains("line 1"), "{}", error);
+        fs::write(dir.join("cdn.txt"), "45.57.0.0/17\n").unwrap();
+        let reloaded = Config::from_file(&dir.join("trafficmon.conf")).unwrap();
+        assert_eq!(reloaded.services[0].ip_ranges, vec!["198.38.96.0/19", "45.57.0.0/17"]);
+        
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn test_sampled_counters() {
+        let classifier = NftablesClassifier::new("trafficmon", "forward").with_sample_rate(100);
+        let service = Config::default().services.into_iter().find(|s| s.name == "netflix").unwrap();
+        
+        // 未被採樣的包由緊跟的規則放行
+        assert_eq!(classifier.service_rule_commands(&service), vec![
+            "add rule inet trafficmon traffic_stats ip daddr @netflix_ips tcp dport { 80, 443, 1935 } numgen random mod 100 == 0 counter accept comment \"netflix traffic\"",
+            "add rule inet trafficmon traffic_stats ip daddr @netflix_ips tcp dport { 80, 443, 1935 } accept",
+            "add rule inet trafficmon traffic_stats ip saddr @netflix_ips tcp sport { 80, 443, 1935 } numgen random mod 100 == 0 counter accept comment \"netflix response\"",
+            "add rule inet trafficmon traffic_stats ip saddr @netflix_ips tcp sport { 80, 443, 1935 } accept",
+        ]);
+        
+        let ruleset = "\t\tip daddr @netflix_ips tcp dport { 80, 443, 1935 } numgen random mod 100 == 0 counter packets 3 bytes 4500 accept comment \"netflix traffic\"
+\t\tip saddr @netflix_ips tcp sport { 80, 443, 1935 } numgen random mod 100 == 0 counter packets 2 bytes 1500 accept comment \"netflix response\"";
+        let runner = RecordingRunner { output: ruleset.to_string(), calls: Default::default() };
+        assert_eq!(classifier.get_service_bytes_with(&runner).unwrap()["netflix"], 600_000);
+        assert_eq!(classifier.parse_counter_stats(ruleset)["netflix traffic"], 300);
+        
+        // 0 按不採樣處理，計數不變
+        let unsampled = NftablesClassifier::new("trafficmon", "forward").with_sample_rate(0);
+        assert_eq!(unsampled.sample_rate(), 1);
+        assert_eq!(unsampled.extrapolate(4500), 4500);
+        assert_eq!(classifier.extrapolate(u64::MAX), u64::MAX);
+    }
+}
@@ -0,0 +1,379 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::flow::FlowKey;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_IPV6: u16 = 0x86DD;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const ETHERTYPE_PPPOE_SESSION: u16 = 0x8864;
+pub const ETHERTYPE_VLAN: u16 = 0x8100;
+pub const ETHERTYPE_QINQ: u16 = 0x88A8;
+
+const PPP_IPV4: u16 = 0x0021;
+const PPP_IPV6: u16 = 0x0057;
+
+pub const IPPROTO_IPIP: u8 = 4;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+pub const IPPROTO_DCCP: u8 = 33;
+pub const IPPROTO_IPV6: u8 = 41;
+pub const IPPROTO_GRE: u8 = 47;
+pub const IPPROTO_SCTP: u8 = 132;
+
+/// 最多解開的隧道層數，更深的按最內層已解開的隧道包處理
+const MAX_TUNNEL_DEPTH: usize = 4;
+
+// IPv6 擴展頭 (RFC 8200)
+const IPV6_HOP_BY_HOP: u8 = 0;
+const IPV6_ROUTING: u8 = 43;
+pub const IPV6_FRAGMENT: u8 = 44;
+const IPV6_AUTH: u8 = 51;
+const IPV6_DEST_OPTIONS: u8 = 60;
+
+/// 最多跳過的擴展頭數，防止畸形或循環的頭鏈
+const MAX_IPV6_EXTENSIONS: usize = 8;
+
+// GRE 頭中的可選字段標誌 (RFC 2784/2890)
+const GRE_CHECKSUM: u16 = 0x8000;
+const GRE_ROUTING: u16 = 0x4000;
+const GRE_KEY: u16 = 0x2000;
+const GRE_SEQUENCE: u16 = 0x1000;
+const GRE_VERSION: u16 = 0x0007;
+
+/// 包外層的隧道封裝
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tunnel {
+    Gre,
+    /// IPv4/IPv6 直接封裝在 IP 中（協議號 4 或 41）
+    IpInIp,
+}
+
+/// 從鏈路層幀中解析出的網絡層和傳輸層信息
+#[derive(Debug, Clone)]
+pub struct PacketInfo<'a> {
+    pub src_ip: IpAddr,
+    pub dst_ip: IpAddr,
+    pub protocol: u8,
+    pub src_port: Option<u16>,
+    pub dst_port: Option<u16>,
+    pub tcp_flags: Option<u8>,
+    pub tcp_seq: Option<u32>,
+    /// IPv4 頭中的標識字段，隧道包為內層的
+    pub ip_id: Option<u16>,
+    /// IPv4 TTL 或 IPv6 hop limit，隧道包為內層的
+    pub ttl: Option<u8>,
+    /// IP 包總長度（IPv6 含 40 字節固定頭），隧道包為內層的
+    pub ip_len: Option<u16>,
+    /// 802.1Q 標籤中的 VLAN ID（QinQ 時取外層），未打標籤為 None
+    pub vlan: Option<u16>,
+    /// 從 GRE/IP-in-IP 隧道中解出時為最外層的隧道類型，地址和端口均為內層的
+    pub tunnel: Option<Tunnel>,
+    /// 傳輸層負載（TCP/UDP 頭之後的數據）
+    pub payload: &'a [u8],
+}
+
+impl PacketInfo<'_> {
+    pub fn flow_key(&self) -> Option<FlowKey> {
+        Some(FlowKey {
+            src_ip: self.src_ip,
+            dst_ip: self.dst_ip,
+            src_port: self.src_port?,
+            dst_port: self.dst_port?,
+            protocol: self.protocol,
+        })
+    }
+}
+
+pub const ARP_REQUEST: u16 = 1;
+pub const ARP_REPLY: u16 = 2;
+
+/// 以太網上 IPv4 的 ARP 包 (RFC 826)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpPacket {
+    /// 1 為請求，2 為回應
+    pub operation: u16,
+    pub sender_mac: [u8; 6],
+    pub sender_ip: Ipv4Addr,
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    /// 發送方宣告自己的地址（發送方和目標 IP 相同），常見於地址變更，也是 ARP 欺騙的手段
+    pub fn is_gratuitous(&self) -> bool {
+        self.sender_ip == self.target_ip
+    }
+}
+
+/// 抓包句柄的鏈路層類型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LinkType {
+    #[default]
+    Ethernet,
+    /// Linux cooked capture v1（在 `any` 接口上抓包時使用）
+    LinuxSll,
+    /// Linux cooked capture v2，較新的 libpcap 在 `any` 上使用，頭部帶接口索引
+    LinuxSll2,
+}
+
+impl LinkType {
+    /// 由 pcap 的 DLT 值轉換，不支持的類型返回 None
+    pub fn from_dlt(dlt: i32) -> Option<Self> {
+        match dlt {
+            1 => Some(LinkType::Ethernet),
+            113 => Some(LinkType::LinuxSll),
+            276 => Some(LinkType::LinuxSll2),
+            _ => None,
+        }
+    }
+}
+
+/// 按鏈路層類型解析一幀
+pub fn parse_frame(link: LinkType, frame: &[u8]) -> Option<PacketInfo<'_>> {
+    match link {
+        LinkType::Ethernet => parse_ethernet(frame),
+        // SLL 頭 16 字節，協議號在最後 2 字節
+        LinkType::LinuxSll if frame.len() >= 16 => {
+            parse_l3(u16::from_be_bytes([frame[14], frame[15]]), &frame[16..])
+        }
+        // SLL2 頭 20 字節，協議號在最前面
+        LinkType::LinuxSll2 if frame.len() >= 20 => {
+            parse_l3(u16::from_be_bytes([frame[0], frame[1]]), &frame[20..])
+        }
+        _ => None,
+    }
+}
+
+/// 解析以太網幀
+pub fn parse_ethernet(frame: &[u8]) -> Option<PacketInfo<'_>> {
+    let (ethertype, data, vlan) = ethernet_payload(frame)?;
+    let mut info = parse_l3(ethertype, data)?;
+    info.vlan = vlan;
+    Some(info)
+}
+
+/// 以太網幀的 EtherType、負載和 VLAN ID
+fn ethernet_payload(frame: &[u8]) -> Option<(u16, &[u8], Option<u16>)> {
+    if frame.len() < 14 {
+        return None;
+    }
+    
+    let mut ethertype = u16::from_be_bytes([frame[12], frame[13]]);
+    let mut data = &frame[14..];
+    let mut vlan = None;
+    
+    // 剝離 802.1Q/802.1ad 標籤：2 字節 TCI（低 12 位為 VLAN ID）+ 2 字節內層 EtherType
+    while matches!(ethertype, ETHERTYPE_VLAN | ETHERTYPE_QINQ) {
+        if data.len() < 4 {
+            return None;
+        }
+        vlan.get_or_insert(u16::from_be_bytes([data[0], data[1]]) & 0x0fff);
+        ethertype = u16::from_be_bytes([data[2], data[3]]);
+        data = &data[4..];
+    }
+    
+    Some((ethertype, data, vlan))
+}
+
+/// 按鏈路層類型解析 ARP 幀，只接受以太網硬件地址和 IPv4 協議地址
+pub fn parse_arp(link: LinkType, frame: &[u8]) -> Option<ArpPacket> {
+    let (ethertype, data) = match link {
+        LinkType::Ethernet => ethernet_payload(frame).map(|(ethertype, data, _)| (ethertype, data))?,
+        LinkType::LinuxSll if frame.len() >= 16 => (u16::from_be_bytes([frame[14], frame[15]]), &frame[16..]),
+        LinkType::LinuxSll2 if frame.len() >= 20 => (u16::from_be_bytes([frame[0], frame[1]]), &frame[20..]),
+        _ => return None,
+    };
+    // 硬件類型 1（以太網）、協議類型 0x0800、地址長度 6 和 4，之後是操作碼和兩組地址
+    if ethertype != ETHERTYPE_ARP || data.len() < 28 || data[..6] != [0x00, 0x01, 0x08, 0x00, 6, 4] {
+        return None;
+    }
+    
+    let ipv4 = |at: usize| Ipv4Addr::new(data[at], data[at + 1], data[at + 2], data[at + 3]);
+    Some(ArpPacket {
+        operation: u16::from_be_bytes([data[6], data[7]]),
+        sender_mac: data[8..14].try_into().ok()?,
+        sender_ip: ipv4(14),
+        target_ip: ipv4(24),
+    })
+}
+
+/// 按 EtherType 解析網絡層，PPPoE 會先剝離 PPPoE/PPP 頭
+fn parse_l3(ethertype: u16, data: &[u8]) -> Option<PacketInfo<'_>> {
+    match ethertype {
+        ETHERTYPE_IPV4 => parse_ipv4(data, 0),
+        ETHERTYPE_IPV6 => parse_ipv6(data, 0),
+        ETHERTYPE_PPPOE_SESSION => {
+            // 6 字節 PPPoE 頭 + 2 字節 PPP 協議號
+            if data.len() < 8 {
+                return None;
+            }
+            
+            match u16::from_be_bytes([data[6], data[7]]) {
+                PPP_IPV4 => parse_ipv4(&data[8..], 0),
+                PPP_IPV6 => parse_ipv6(&data[8..], 0),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// `depth` 為已解開的隧道層數
+fn parse_ipv4(data: &[u8], depth: usize) -> Option<PacketInfo<'_>> {
+    if data.len() < 20 || data[0] >> 4 != 4 {
+        return None;
+    }
+    
+    let header_len = ((data[0] & 0x0f) as usize) * 4;
+    let total_len = (u16::from_be_bytes([data[2], data[3]]) as usize).min(data.len());
+    if header_len < 20 || total_len < header_len {
+        return None;
+    }
+    
+    let src_ip = IpAddr::V4(Ipv4Addr::new(data[12], data[13], data[14], data[15]));
+    let dst_ip = IpAddr::V4(Ipv4Addr::new(data[16], data[17], data[18], data[19]));
+    let mut info = parse_ip_payload(src_ip, dst_ip, data[9], &data[header_len..total_len], depth);
+    if info.tunnel.is_none() {
+        info.ip_id = Some(u16::from_be_bytes([data[4], data[5]]));
+        info.ttl = Some(data[8]);
+        info.ip_len = Some(u16::from_be_bytes([data[2], data[3]]));
+    }
+    Some(info)
+}
+
+fn parse_ipv6(data: &[u8], depth: usize) -> Option<PacketInfo<'_>> {
+    if data.len() < 40 || data[0] >> 4 != 6 {
+        return None;
+    }
+    
+    let payload_len = u16::from_be_bytes([data[4], data[5]]) as usize;
+    let end = (40 + payload_len).min(data.len());
+    
+    let src: [u8; 16] = data[8..24].try_into().ok()?;
+    let dst: [u8; 16] = data[24..40].try_into().ok()?;
+    let payload = &data[40..end];
+    let (protocol, offset) = walk_ipv6_extensions(data[6], payload)?;
+    let mut info = parse_ip_payload(
+        IpAddr::V6(Ipv6Addr::from(src)),
+        IpAddr::V6(Ipv6Addr::from(dst)),
+        protocol,
+        &payload[offset..],
+        depth,
+    );
+    if info.tunnel.is_none() {
+        info.ttl = Some(data[7]);
+        info.ip_len = Some((payload_len as u16).saturating_add(40));
+    }
+    Some(info)
+}
+
+/// 跳過 IPv6 擴展頭，返回上層協議號及其在 `payload` 中的偏移。
+/// 非首個分片沒有上層頭，返回 IPV6_FRAGMENT；頭鏈截斷或超過層數上限時返回 None
+pub fn walk_ipv6_extensions(next_header: u8, payload: &[u8]) -> Option<(u8, usize)> {
+    let mut protocol = next_header;
+    let mut offset = 0;
+    
+    for _ in 0..MAX_IPV6_EXTENSIONS {
+        let header = payload.get(offset..offset + 8);
+        let len = match protocol {
+            IPV6_HOP_BY_HOP | IPV6_ROUTING | IPV6_DEST_OPTIONS => (header?[1] as usize + 1) * 8,
+            IPV6_AUTH => (header?[1] as usize + 2) * 4,
+            IPV6_FRAGMENT => {
+                let header = header?;
+                if u16::from_be_bytes([header[2], header[3]]) >> 3 != 0 {
+                    return Some((IPV6_FRAGMENT, offset));
+                }
+                8
+            }
+            _ => return Some((protocol, offset)),
+        };
+        
+        if offset + len > payload.len() {
+            return None;
+        }
+        protocol = payload[offset];
+        offset += len;
+    }
+    
+    None
+}
+
+/// 隧道包先解封裝並按內層流處理；超過層數上限或內層無法解析時按外層處理
+fn parse_ip_payload(src_ip: IpAddr, dst_ip: IpAddr, protocol: u8, data: &[u8], depth: usize) -> PacketInfo<'_> {
+    if depth < MAX_TUNNEL_DEPTH {
+        if let Some(inner) = parse_tunnel(protocol, data, depth + 1) {
+            return inner;
+        }
+    }
+    parse_transport(src_ip, dst_ip, protocol, data)
+}
+
+fn parse_tunnel(protocol: u8, data: &[u8], depth: usize) -> Option<PacketInfo<'_>> {
+    let (tunnel, inner) = match protocol {
+        IPPROTO_IPIP => (Tunnel::IpInIp, parse_ipv4(data, depth)),
+        IPPROTO_IPV6 => (Tunnel::IpInIp, parse_ipv6(data, depth)),
+        IPPROTO_GRE => (Tunnel::Gre, parse_gre(data, depth)),
+        _ => return None,
+    };
+    
+    let mut inner = inner?;
+    inner.tunnel = Some(tunnel);
+    Some(inner)
+}
+
+/// 4 字節基本頭 + 按標誌出現的校驗和、密鑰、序列號字段，只處理版本 0 且承載 IP 的 GRE
+fn parse_gre(data: &[u8], depth: usize) -> Option<PacketInfo<'_>> {
+    if data.len() < 4 {
+        return None;
+    }
+    
+    let flags = u16::from_be_bytes([data[0], data[1]]);
+    // 版本 1 為 PPTP 的增強 GRE；路由字段已廢棄且長度可變
+    if flags & (GRE_VERSION | GRE_ROUTING) != 0 {
+        return None;
+    }
+    let optional = [GRE_CHECKSUM, GRE_KEY, GRE_SEQUENCE].iter().filter(|flag| flags & **flag != 0).count();
+    let inner = data.get(4 + 4 * optional..)?;
+    
+    match u16::from_be_bytes([data[2], data[3]]) {
+        ETHERTYPE_IPV4 => parse_ipv4(inner, depth),
+        ETHERTYPE_IPV6 => parse_ipv6(inner, depth),
+        _ => None,
+    }
+}
+
+fn parse_transport(src_ip: IpAddr, dst_ip: IpAddr, protocol: u8, data: &[u8]) -> PacketInfo<'_> {
+    let mut info = PacketInfo {
+        src_ip,
+        dst_ip,
+        protocol,
+        src_port: None,
+        dst_port: None,
+        tcp_flags: None,
+        tcp_seq: None,
+        ip_id: None,
+        ttl: None,
+        ip_len: None,
+        vlan: None,
+        tunnel: None,
+        payload: &[],
+    };
+    
+    let header_len = match protocol {
+        IPPROTO_TCP if data.len() >= 20 => {
+            info.tcp_flags = Some(data[13]);
+            info.tcp_seq = Some(u32::from_be_bytes([data[4], data[5], data[6], data[7]]));
+            ((data[12] >> 4) as usize) * 4
+        }
+        IPPROTO_UDP if data.len() >= 8 => 8,
+        // SCTP 公共頭固定 12 字節，之後是數據塊
+        IPPROTO_SCTP if data.len() >= 12 => 12,
+        // DCCP 頭長度（4 字節為單位）在第 5 字節
+        IPPROTO_DCCP if data.len() >= 12 => (data[4] as usize * 4).max(12),
+        _ => return info,
+    };
+    
+    info.src_port = Some(u16::from_be_bytes([data[0], data[1]]));
+    info.dst_port = Some(u16::from_be_bytes([data[2], data[3]]));
+    info.payload = data.get(header_len..).unwrap_or(&[]);
+    info
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::fs;
+
+use crate::flow::FlowKey;
+
+/// 最多緩存進程名的套接字數，超出時清空重建
+const MAX_CACHED_SOCKETS: usize = 4096;
+
+/// 本機套接字到所屬進程的映射
+pub trait SocketOwners: Send {
+    /// 擁有以該五元組標識的本地套接字的進程 ID
+    fn pid(&self, key: &FlowKey) -> Option<u32>;
+    
+    /// 進程的可執行文件名，默認讀取 /proc/<pid>/comm
+    fn process_name(&self, pid: u32) -> Option<String> {
+        let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+        Some(comm.trim_end().to_string()).filter(|name| !name.is_empty())
+    }
+}
+
+/// 包所屬的進程名；入站包按反方向的五元組查找
+pub fn owning_process(owners: &dyn SocketOwners, key: &FlowKey) -> Option<String> {
+    let pid = owners.pid(key).or_else(|| owners.pid(&key.reversed()))?;
+    owners.process_name(pid)
+}
+
+/// 按套接字緩存進程名：同一套接字的後續包只查映射中的 PID，PID 不變時不再讀取 /proc
+pub struct ProcessResolver {
+    owners: Box<dyn SocketOwners>,
+    /// 套接字（映射中命中的方向）→ (PID, 進程名)
+    names: HashMap<FlowKey, (u32, String)>,
+}
+
+impl ProcessResolver {
+    pub fn new(owners: Box<dyn SocketOwners>) -> Self {
+        Self { owners, names: HashMap::new() }
+    }
+    
+    /// 同 `owning_process`；套接字已不在映射中時丟棄它的緩存
+    pub fn resolve(&mut self, key: &FlowKey) -> Option<String> {
+        let lookup = |key: FlowKey| self.owners.pid(&key).map(|pid| (key, pid));
+        let Some((socket, pid)) = lookup(*key).or_else(|| lookup(key.reversed())) else {
+            self.names.remove(key);
+            self.names.remove(&key.reversed());
+            return None;
+        };
+        
+        if let Some((cached_pid, name)) = self.names.get(&socket) {
+            if *cached_pid == pid {
+                return Some(name.clone());
+            }
+        }
+        
+        let name = self.owners.process_name(pid)?;
+        if self.names.len() >= MAX_CACHED_SOCKETS {
+            self.names.clear();
+        }
+        self.names.insert(socket, (pid, name.clone()));
+        Some(name)
+    }
+}
+
+#[cfg(feature = "ebpf")]
+pub use bpf::EbpfSocketOwners;
+
+#[cfg(feature = "ebpf")]
+mod bpf {
+    use std::net::IpAddr;
+    
+    use aya::maps::{HashMap, Map, MapData};
+    
+    use super::SocketOwners;
+    use crate::flow::FlowKey;
+    
+    /// 映射的鍵，與內核側程序的定義一致；地址一律按 IPv6 存放（IPv4 用映射地址），端口為主機字節序
+    #[repr(C)]
+    #[derive(Debug, Clone, Copy)]
+    struct SocketKey {
+        src_ip: [u8; 16],
+        dst_ip: [u8; 16],
+        src_port: u16,
+        dst_port: u16,
+        protocol: u8,
+        _pad: [u8; 3],
+    }
+    
+    // 純數據結構，沒有指針和未初始化的填充
+    unsafe impl aya::Pod for SocketKey {}
+    
+    impl From<&FlowKey> for SocketKey {
+        fn from(key: &FlowKey) -> Self {
+            let octets = |ip: IpAddr| match ip {
+                IpAddr::V4(addr) => addr.to_ipv6_mapped().octets(),
+                IpAddr::V6(addr) => addr.octets(),
+            };
+            Self {
+                src_ip: octets(key.src_ip),
+                dst_ip: octets(key.dst_ip),
+                src_port: key.src_port,
+                dst_port: key.dst_port,
+                protocol: key.protocol,
+                _pad: [0; 3],
+            }
+        }
+    }
+    
+    /// 讀取外部 eBPF 程序固定到 bpffs 的五元組 → PID 映射；
+    /// 程序本身（掛在 connect/accept 等路徑上）由部署方加載
+    pub struct EbpfSocketOwners {
+        map: HashMap<MapData, SocketKey, u32>,
+    }
+    
+    impl EbpfSocketOwners {
+        pub fn open(path: &str) -> Result<Self, String> {
+            let data = MapData::from_pin(path)
+                .map_err(|e| format!("Failed to open pinned eBPF map {}: {}", path, e))?;
+            let map = HashMap::try_from(Map::HashMap(data))
+                .map_err(|e| format!("Unexpected eBPF map at {}: {}", path, e))?;
+            Ok(Self { map })
+        }
+    }
+    
+    impl SocketOwners for EbpfSocketOwners {
+        fn pid(&self, key: &FlowKey) -> Option<u32> {
+            self.map.get(&SocketKey::from(key), 0).ok()
+        }
+    }
+}
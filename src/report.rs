@@ -0,0 +1,389 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveTime};
+
+use crate::config::{ByteUnits, Config, EmailReportConfig};
+use crate::stats::TrafficStats;
+
+/// 摘要最多覆蓋的時長，實際不超過統計的保留期
+const SUMMARY_WINDOW: Duration = Duration::from_secs(24 * 3600);
+/// 發送失敗後多久重試
+const RETRY_MINUTES: i64 = 10;
+/// SMTP 連接和每次讀寫的超時
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+/// 沒有配置分類的服務歸入
+const UNCATEGORIZED: &str = "uncategorized";
+/// 流量矩陣中超出上限的端點，不算主機
+const MATRIX_OVERFLOW: &str = "other";
+
+/// 一次流量摘要：熱門服務、各分類字節數和流量最多的主機
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub generated_at: DateTime<FixedOffset>,
+    /// 服務和分類統計實際覆蓋的時長
+    pub window: Duration,
+    pub total_bytes: u64,
+    pub top_services: Vec<(String, u64)>,
+    pub categories: BTreeMap<String, u64>,
+    /// 各抓包接口啟動以來的字節數；沒有接口統計時為空
+    pub interfaces: BTreeMap<String, u64>,
+    /// 按啟動以來收發字節數排列的主機；未開啟 traffic_matrix 時為空
+    pub top_talkers: Vec<(String, u64)>,
+    pub units: ByteUnits,
+}
+
+impl Summary {
+    /// 由最近 24 小時（不超過統計保留期）的統計和啟動以來的流量矩陣生成，服務和主機各取前 `top_n` 個
+    pub fn collect(
+        stats: &TrafficStats,
+        config: &Config,
+        matrix: &HashMap<(String, String), u64>,
+        top_n: usize,
+        now: DateTime<FixedOffset>,
+    ) -> Self {
+        let window = SUMMARY_WINDOW.min(stats.retention_period());
+        let services = stats.top_services_window(usize::MAX, window);
+        
+        let mut categories = BTreeMap::new();
+        for (service, bytes) in &services {
+            let category = config.services.iter()
+                .find(|s| &s.name == service)
+                .and_then(|s| s.category.clone())
+                .unwrap_or_else(|| UNCATEGORIZED.to_string());
+            *categories.entry(category).or_insert(0) += bytes;
+        }
+        
+        // 矩陣端點可能是服務名，只把主機（地址或前綴）計為 talker
+        let mut hosts: HashMap<&String, u64> = HashMap::new();
+        for ((src, dst), bytes) in matrix {
+            for endpoint in [src, dst] {
+                if endpoint != MATRIX_OVERFLOW && !config.services.iter().any(|s| &s.name == endpoint) {
+                    *hosts.entry(endpoint).or_insert(0) += bytes;
+                }
+            }
+        }
+        let mut top_talkers: Vec<(String, u64)> = hosts.into_iter()
+            .map(|(host, bytes)| (host.clone(), bytes))
+            .collect();
+        top_talkers.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top_talkers.truncate(top_n);
+        
+        let interfaces = stats.export_interface_stats().into_iter()
+            .map(|(interface, services)| (interface, services.iter().map(|(_, (bytes, _))| bytes).sum()))
+            .collect();
+        
+        Self {
+            generated_at: now,
+            window,
+            total_bytes: services.iter().map(|(_, bytes)| bytes).sum(),
+            top_services: services.into_iter().take(top_n).collect(),
+            categories,
+            interfaces,
+            top_talkers,
+            units: config.byte_units,
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Traffic summary for the {} before {}",
+            format_window(self.window),
+            self.generated_at.format("%Y-%m-%d %H:%M %:z")
+        )?;
+        writeln!(f, "Total: {}", self.units.format(self.total_bytes))?;
+        
+        writeln!(f, "\nTop services:")?;
+        for (service, bytes) in &self.top_services {
+            writeln!(f, "  {:<24} {:>12}", service, self.units.format(*bytes))?;
+        }
+        
+        writeln!(f, "\nBy category:")?;
+        for (category, bytes) in &self.categories {
+            writeln!(f, "  {:<24} {:>12}", category, self.units.format(*bytes))?;
+        }
+        
+        if !self.interfaces.is_empty() {
+            writeln!(f, "\nBy interface (since start):")?;
+            for (interface, bytes) in &self.interfaces {
+                writeln!(f, "  {:<24} {:>12}", interface, self.units.format(*bytes))?;
+            }
+        }
+        
+        writeln!(f, "\nTop talkers (since start):")?;
+        if self.top_talkers.is_empty() {
+            writeln!(f, "  (enable traffic_matrix to list hosts)")?;
+        }
+        for (host, bytes) in &self.top_talkers {
+            writeln!(f, "  {:<24} {:>12}", host, self.units.format(*bytes))?;
+        }
+        Ok(())
+    }
+}
+
+/// 摘要標題中的時長：整小時按小時，否則按分鐘
+fn format_window(window: Duration) -> String {
+    let minutes = window.as_secs() / 60;
+    let (count, unit) = if minutes % 60 == 0 { (minutes / 60, "hour") } else { (minutes, "minute") };
+    format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" })
+}
+
+/// 投遞一封已編排好的郵件；失敗時返回原因
+pub trait MailTransport: Send {
+    fn send(&mut self, from: &str, to: &[String], message: &str) -> Result<(), String>;
+}
+
+/// 明文 SMTP 投遞，不做 STARTTLS 和認證
+pub struct SmtpTransport {
+    host: String,
+    port: u16,
+}
+
+impl SmtpTransport {
+    pub fn new(config: &EmailReportConfig) -> Self {
+        Self { host: config.smtp_host.clone(), port: config.smtp_port }
+    }
+    
+    fn deliver(&self, from: &str, to: &[String], message: &str) -> io::Result<()> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port))?;
+        stream.set_read_timeout(Some(SMTP_TIMEOUT))?;
+        stream.set_write_timeout(Some(SMTP_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        
+        expect_reply(&mut reader, &[220])?;
+        smtp_command(&mut writer, &mut reader, "EHLO trafficmon", &[250])?;
+        smtp_command(&mut writer, &mut reader, &format!("MAIL FROM:<{}>", from), &[250])?;
+        for recipient in to {
+            smtp_command(&mut writer, &mut reader, &format!("RCPT TO:<{}>", recipient), &[250, 251])?;
+        }
+        smtp_command(&mut writer, &mut reader, "DATA", &[354])?;
+        
+        // 以 "." 開頭的行加一個 "."，避免被當作結束標記
+        for line in message.lines() {
+            let stuffed = if line.starts_with('.') { "." } else { "" };
+            write!(writer, "{}{}\r\n", stuffed, line)?;
+        }
+        smtp_command(&mut writer, &mut reader, ".", &[250])?;
+        smtp_command(&mut writer, &mut reader, "QUIT", &[221])
+    }
+}
+
+impl MailTransport for SmtpTransport {
+    fn send(&mut self, from: &str, to: &[String], message: &str) -> Result<(), String> {
+        self.deliver(from, to, message)
+            .map_err(|e| format!("SMTP {}:{}: {}", self.host, self.port, e))
+    }
+}
+
+fn smtp_command(writer: &mut impl Write, reader: &mut impl BufRead, command: &str, expected: &[u16]) -> io::Result<()> {
+    write!(writer, "{}\r\n", command)?;
+    writer.flush()?;
+    expect_reply(reader, expected)
+}
+
+/// 讀取一個（可能多行的）回應，狀態碼不在 `expected` 中時報錯
+fn expect_reply(reader: &mut impl BufRead, expected: &[u16]) -> io::Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+        }
+        
+        let code = line.get(..3).and_then(|code| code.parse::<u16>().ok());
+        let Some(code) = code else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("malformed reply {:?}", line.trim_end())));
+        };
+        // "250-" 表示後面還有行
+        if line.as_bytes().get(3) == Some(&b'-') {
+            continue;
+        }
+        if !expected.contains(&code) {
+            return Err(io::Error::other(format!("unexpected reply {:?}", line.trim_end())));
+        }
+        return Ok(());
+    }
+}
+
+/// 加上郵件頭，正文按 UTF-8 純文本發送
+pub fn format_message(config: &EmailReportConfig, body: &str, date: DateTime<FixedOffset>) -> String {
+    let mut message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+        config.from, config.to.join(", "), config.subject, date.to_rfc2822()
+    );
+    for line in body.lines() {
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message
+}
+
+/// 每天到了 send_at 發送一次摘要；失敗時只記日誌，隔一段時間重試
+pub struct SummaryMailer {
+    config: EmailReportConfig,
+    send_at: NaiveTime,
+    transport: Box<dyn MailTransport>,
+    /// 最近一次發送成功的日期
+    last_sent: Option<NaiveDate>,
+    /// 發送失敗後下一次重試的時間
+    retry_at: Option<DateTime<FixedOffset>>,
+}
+
+impl SummaryMailer {
+    /// 啟動時已過當天的發送時間則從第二天開始，不會一啟動就發送
+    pub fn new(config: &EmailReportConfig, transport: Box<dyn MailTransport>, now: DateTime<FixedOffset>) -> Self {
+        let send_at = NaiveTime::parse_from_str(&config.send_at, "%H:%M").unwrap_or(NaiveTime::MIN);
+        Self {
+            config: config.clone(),
+            send_at,
+            transport,
+            last_sent: (now.time() >= send_at).then(|| now.date_naive()),
+            retry_at: None,
+        }
+    }
+    
+    pub fn is_due(&self, now: DateTime<FixedOffset>) -> bool {
+        now.time() >= self.send_at
+            && self.last_sent != Some(now.date_naive())
+            && self.retry_at.is_none_or(|at| now >= at)
+    }
+    
+    /// 發送成功返回 true
+    pub fn send(&mut self, summary: &Summary, now: DateTime<FixedOffset>) -> bool {
+        let message = format_message(&self.config, &summary.to_string(), now);
+        match self.transport.send(&self.config.from, &self.config.to, &message) {
+            Ok(()) => {
+                self.last_sent = Some(now.date_naive());
+                self.retry_at = None;
+                true
+            }
+            Err(e) => {
+                eprintln!("Failed to send summary email, retrying in {} minutes: {}", RETRY_MINUTES, e);
+                self.retry_at = Some(now + chrono::Duration::minutes(RETRY_MINUTES));
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use chrono::TimeZone;
+    
+    use crate::config::ServiceConfig;
+    
+    /// 每次投遞的 (發件人, 收件人, 郵件)
+    type Sent = Arc<Mutex<Vec<(String, Vec<String>, String)>>>;
+    
+    /// 記錄投遞的郵件；`up` 為 false 時模擬 SMTP 服務器不可用
+    #[derive(Clone, Default)]
+    struct MockTransport {
+        up: Arc<Mutex<bool>>,
+        sent: Sent,
+    }
+    
+    impl MailTransport for MockTransport {
+        fn send(&mut self, from: &str, to: &[String], message: &str) -> Result<(), String> {
+            if !*self.up.lock().unwrap() {
+                return Err("connection refused".to_string());
+            }
+            self.sent.lock().unwrap().push((from.to_string(), to.to_vec(), message.to_string()));
+            Ok(())
+        }
+    }
+    
+    fn service(name: &str, category: Option<&str>) -> ServiceConfig {
+        ServiceConfig {
+            name: name.to_string(),
+            ports: vec![443],
+            ip_ranges: vec![],
+            blocked: false,
+            bidirectional: true,
+            category: category.map(str::to_string),
+        }
+    }
+    
+    #[test]
+    fn test_daily_summary_email() {
+        let config = Config {
+            services: vec![service("netflix", Some("Streaming")), service("youtube", Some("Streaming"))],
+            ..Config::default()
+        };
+        let email = EmailReportConfig {
+            smtp_host: "127.0.0.1".to_string(),
+            smtp_port: 25,
+            from: "trafficmon@router.lan".to_string(),
+            to: vec!["admin@example.com".to_string()],
+            send_at: "08:00".to_string(),
+            subject: "trafficmon daily summary".to_string(),
+            top_n: 2,
+        };
+        
+        let stats = TrafficStats::new();
+        stats.add_traffic("netflix", 3 * 1024 * 1024, 2000);
+        stats.add_traffic("youtube", 1024 * 1024, 800);
+        stats.add_traffic("dns", 2048, 20);
+        let matrix = HashMap::from([
+            (("192.168.1.10".to_string(), "netflix".to_string()), 3000),
+            (("youtube".to_string(), "192.168.1.20".to_string()), 5000),
+            (("192.168.1.10".to_string(), "192.168.1.20".to_string()), 500),
+            (("other".to_string(), "other".to_string()), 9000),
+        ]);
+        
+        let offset = FixedOffset::east_opt(8 * 3600).unwrap();
+        let at = |hour, minute| offset.with_ymd_and_hms(2024, 3, 1, hour, minute, 0).unwrap();
+        let summary = Summary::collect(&stats, &config, &matrix, email.top_n, at(8, 0));
+        assert_eq!(summary.to_string(), "\
+Traffic summary for the 1 hour before 2024-03-01 08:00 +08:00
+Total: 4.00 MiB
+
+Top services:
+  netflix                      3.00 MiB
+  youtube                      1.00 MiB
+
+By category:
+  Streaming                    4.00 MiB
+  uncategorized                2.00 KiB
+
+Top talkers (since start):
+  192.168.1.20                 5.37 KiB
+  192.168.1.10                 3.42 KiB
+");
+        // 標題按摘要實際覆蓋的時長，而不是固定的 24 小時
+        assert_eq!(format_window(SUMMARY_WINDOW), "24 hours");
+        assert_eq!(format_window(Duration::from_secs(90 * 60)), "90 minutes");
+        
+        let transport = MockTransport::default();
+        let mut mailer = SummaryMailer::new(&email, Box::new(transport.clone()), at(7, 0));
+        assert!(!mailer.is_due(at(7, 59)));
+        assert!(mailer.is_due(at(8, 0)));
+        
+        // 發送失敗不會 panic，十分鐘後重試
+        assert!(!mailer.send(&summary, at(8, 0)));
+        assert!(!mailer.is_due(at(8, 5)));
+        assert!(mailer.is_due(at(8, 10)));
+        
+        *transport.up.lock().unwrap() = true;
+        assert!(mailer.send(&summary, at(8, 10)));
+        assert!(!mailer.is_due(at(23, 0)));
+        
+        let sent = transport.sent.lock().unwrap();
+        let (from, to, message) = &sent[0];
+        assert_eq!(from, "trafficmon@router.lan");
+        assert_eq!(to, &vec!["admin@example.com".to_string()]);
+        assert!(message.starts_with("From: trafficmon@router.lan\r\nTo: admin@example.com\r\nSubject: trafficmon daily summary\r\n"));
+        assert!(message.contains("\r\n\r\nTraffic summary for the 1 hour before 2024-03-01 08:00 +08:00\r\n"));
+        
+        // 啟動時已過發送時間的，當天不再發送
+        let mailer = SummaryMailer::new(&email, Box::new(MockTransport::default()), at(9, 0));
+        assert!(!mailer.is_due(at(9, 1)));
+    }
+}
@@ -0,0 +1,67 @@
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// 追加寫入的文件；寫入後會超過 `max_bytes` 時把文件改名為 `<path>.1`（覆蓋上一份）
+/// 再重新開始，佔用的空間不超過兩個文件
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    file: File,
+    written: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_bytes: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let written = file.metadata()?.len();
+        Ok(Self { path: path.to_path_buf(), max_bytes, file, written })
+    }
+    
+    /// `data` 整塊寫入同一個文件，不會拆到輪轉前後兩個文件中
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.written > 0 && self.written + data.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(data)?;
+        self.written += data.len() as u64;
+        Ok(())
+    }
+    
+    /// 一次寫入多行：放得下的完整行一起寫入，其餘的從輪轉後的新文件開始，一行不會拆到兩個文件中
+    pub fn write_lines(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let room = self.max_bytes.saturating_sub(self.written) as usize;
+            let fits = data[..room.min(data.len())].iter().rposition(|b| *b == b'\n').map(|i| i + 1);
+            let len = match fits {
+                Some(len) => len,
+                None if self.written > 0 => {
+                    self.rotate()?;
+                    continue;
+                }
+                // 空文件也放不下時照樣寫入一行
+                None => data.iter().position(|b| *b == b'\n').map_or(data.len(), |i| i + 1),
+            };
+            self.file.write_all(&data[..len])?;
+            self.written += len as u64;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+    
+    fn rotate(&mut self) -> io::Result<()> {
+        fs::rename(&self.path, rotated_path(&self.path))?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+/// 輪轉出的上一份文件
+pub fn rotated_path(path: &Path) -> PathBuf {
+    let mut rotated = OsString::from(path.as_os_str());
+    rotated.push(".1");
+    PathBuf::from(rotated)
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::IpAddr;
+
+use crate::config::PayloadSampleConfig;
+
+/// 一個包的負載樣本
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayloadSample {
+    /// 原始負載長度
+    pub len: usize,
+    /// 負載的前若干字節，脫敏時為空
+    pub bytes: Vec<u8>,
+}
+
+impl PayloadSample {
+    /// 十六進制表示，脫敏時只給出長度
+    pub fn to_hex(&self) -> String {
+        if self.bytes.is_empty() && self.len > 0 {
+            return format!("[redacted {} bytes]", self.len);
+        }
+        
+        let mut hex = String::with_capacity(self.bytes.len() * 2);
+        for byte in &self.bytes {
+            let _ = write!(hex, "{:02x}", byte);
+        }
+        hex
+    }
+}
+
+/// 隱去的地址或端口在日誌中的寫法
+const REDACTED: &str = "[redacted]";
+
+/// 日誌中地址和端口的寫法；開啟脫敏（payload_samples.redact）時一律隱去
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Redactor {
+    enabled: bool,
+}
+
+impl Redactor {
+    pub fn new(enabled: bool) -> Self {
+        Self { enabled }
+    }
+    
+    pub fn addr(&self, addr: IpAddr) -> String {
+        if self.enabled {
+            return REDACTED.to_string();
+        }
+        addr.to_string()
+    }
+    
+    /// 地址:端口，沒有端口時記為 0
+    pub fn endpoint(&self, addr: IpAddr, port: Option<u16>) -> String {
+        if self.enabled {
+            return REDACTED.to_string();
+        }
+        format!("{}:{}", addr, port.unwrap_or(0))
+    }
+    
+    /// 隱去錯誤信息等文本中出現的地址（如失敗的 nft 命令）
+    pub fn scrub(&self, text: &str, addr: IpAddr) -> String {
+        if self.enabled {
+            return text.replace(&addr.to_string(), REDACTED);
+        }
+        text.to_string()
+    }
+}
+
+/// 記錄每個新服務前幾個包的負載，用於排查誤分類
+#[derive(Debug)]
+pub struct PayloadSampler {
+    config: PayloadSampleConfig,
+    samples: HashMap<String, Vec<PayloadSample>>,
+}
+
+impl PayloadSampler {
+    pub fn new(config: PayloadSampleConfig) -> Self {
+        Self { config, samples: HashMap::new() }
+    }
+    
+    /// 服務的樣本未滿時記錄負載並返回新樣本；空負載不記錄
+    pub fn observe(&mut self, service: &str, payload: &[u8]) -> Option<&PayloadSample> {
+        if payload.is_empty() || self.config.packets == 0 {
+            return None;
+        }
+        if !self.samples.contains_key(service) && self.samples.len() >= self.config.max_services {
+            return None;
+        }
+        
+        let samples = self.samples.entry(service.to_string()).or_default();
+        if samples.len() >= self.config.packets {
+            return None;
+        }
+        
+        let bytes = if self.config.redact {
+            Vec::new()
+        } else {
+            payload[..payload.len().min(self.config.bytes)].to_vec()
+        };
+        samples.push(PayloadSample { len: payload.len(), bytes });
+        samples.last()
+    }
+    
+    pub fn samples(&self) -> &HashMap<String, Vec<PayloadSample>> {
+        &self.samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn sampler(redact: bool) -> PayloadSampler {
+        PayloadSampler::new(PayloadSampleConfig { bytes: 4, packets: 2, max_services: 2, redact })
+    }
+    
+    #[test]
+    fn test_sample_limits() {
+        let mut sampler = sampler(false);
+        
+        assert_eq!(sampler.observe("https", b"\x16\x03\x01\x02\x00").unwrap().to_hex(), "16030102");
+        assert!(sampler.observe("https", b"").is_none());
+        assert!(sampler.observe("https", b"ab").is_some());
+        // 每個服務最多 2 個包
+        assert!(sampler.observe("https", b"cd").is_none());
+        
+        assert!(sampler.observe("dns", b"\x12\x34").is_some());
+        // 最多 2 個服務
+        assert!(sampler.observe("other", b"xyz").is_none());
+        
+        let samples = sampler.samples();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples["https"].iter().map(PayloadSample::to_hex).collect::<Vec<_>>(), vec!["16030102", "6162"]);
+        assert_eq!(samples["https"][0].len, 5);
+    }
+    
+    #[test]
+    fn test_redacted_samples() {
+        let mut sampler = sampler(true);
+        
+        let sample = sampler.observe("http", b"GET / HTTP/1.1\r\n").unwrap();
+        assert!(sample.bytes.is_empty());
+        assert_eq!(sample.to_hex(), "[redacted 16 bytes]");
+    }
+    
+    #[test]
+    fn test_redactor() {
+        let addr: IpAddr = "192.0.2.7".parse().unwrap();
+        let error = "nftables command failed: add element inet trafficmon dynamic_block { 192.0.2.7 timeout 60s }";
+        
+        let plain = Redactor::new(false);
+        assert_eq!((plain.addr(addr), plain.endpoint(addr, Some(443))), ("192.0.2.7".to_string(), "192.0.2.7:443".to_string()));
+        assert_eq!(plain.scrub(error, addr), error);
+        
+        let redacted = Redactor::new(true);
+        assert_eq!((redacted.addr(addr), redacted.endpoint(addr, None)), ("[redacted]".to_string(), "[redacted]".to_string()));
+        assert!(!redacted.scrub(error, addr).contains("192.0.2.7"));
+    }
+}
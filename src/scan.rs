@@ -0,0 +1,97 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::config::ScanDetectionConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanState {
+    Normal,
+    /// 本窗口內首次超過閾值
+    Detected,
+    /// 已在本窗口內被標記
+    Scanning,
+}
+
+#[derive(Debug, Default)]
+struct SourceActivity {
+    ports: HashSet<u16>,
+    hosts: HashSet<IpAddr>,
+    flagged: bool,
+}
+
+/// 按來源 IP 統計窗口內接觸的不同目標端口和主機數量
+#[derive(Debug)]
+pub struct ScanDetector {
+    window: Duration,
+    port_threshold: usize,
+    host_threshold: usize,
+    window_start: Option<Instant>,
+    sources: HashMap<IpAddr, SourceActivity>,
+}
+
+impl ScanDetector {
+    pub fn new(config: &ScanDetectionConfig) -> Self {
+        Self {
+            window: Duration::from_secs(config.window_secs),
+            port_threshold: config.port_threshold,
+            host_threshold: config.host_threshold,
+            window_start: None,
+            sources: HashMap::new(),
+        }
+    }
+    
+    pub fn observe(&mut self, source: IpAddr, destination: IpAddr, dport: u16, now: Instant) -> ScanState {
+        // 窗口結束後重置所有計數
+        let start = *self.window_start.get_or_insert(now);
+        if now.duration_since(start) >= self.window {
+            self.sources.clear();
+            self.window_start = Some(now);
+        }
+        
+        let activity = self.sources.entry(source).or_default();
+        activity.ports.insert(dport);
+        activity.hosts.insert(destination);
+        
+        if activity.flagged {
+            return ScanState::Scanning;
+        }
+        
+        if activity.ports.len() > self.port_threshold || activity.hosts.len() > self.host_threshold {
+            activity.flagged = true;
+            return ScanState::Detected;
+        }
+        
+        ScanState::Normal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    
+    #[test]
+    fn test_port_scan_detected_once() {
+        let config = ScanDetectionConfig {
+            window_secs: 60,
+            port_threshold: 20,
+            host_threshold: 20,
+            block_seconds: None,
+        };
+        let mut detector = ScanDetector::new(&config);
+        let scanner = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 66));
+        let target = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1));
+        let now = Instant::now();
+        
+        let detections = (1..=100u16)
+            .map(|port| detector.observe(scanner, target, port, now))
+            .filter(|state| *state == ScanState::Detected)
+            .count();
+        assert_eq!(detections, 1);
+        
+        // 新窗口重新計數
+        let later = now + Duration::from_secs(61);
+        assert_eq!(detector.observe(scanner, target, 1, later), ScanState::Normal);
+    }
+}
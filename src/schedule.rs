@@ -0,0 +1,209 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use chrono::{DateTime, Datelike, FixedOffset, Local, NaiveTime, Offset, Utc, Weekday};
+use serde::Serialize;
+
+use crate::config::{BusinessHoursConfig, Config};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Task {
+    /// 輸出控制台報告
+    Report,
+    /// 讀取 nftables 計數
+    NftPoll,
+}
+
+/// 報告和 nft 計數輪詢各按自己的間隔觸發，互不影響
+#[derive(Debug)]
+pub struct Schedule {
+    report_interval: Duration,
+    poll_interval: Duration,
+    next_report: Instant,
+    next_poll: Instant,
+}
+
+impl Schedule {
+    /// 兩個任務都在 `start` 之後一個間隔首次觸發
+    pub fn new(report_interval: Duration, poll_interval: Duration, start: Instant) -> Self {
+        Self {
+            report_interval,
+            poll_interval,
+            next_report: start + report_interval,
+            next_poll: start + poll_interval,
+        }
+    }
+    
+    pub fn from_config(config: &Config, start: Instant) -> Self {
+        Self::new(
+            Duration::from_secs(config.report_interval),
+            Duration::from_secs(config.poll_interval()),
+            start,
+        )
+    }
+    
+    /// 自適應報告調整間隔後，從下一次報告起生效
+    pub fn set_report_interval(&mut self, interval: Duration) {
+        self.report_interval = interval;
+    }
+    
+    /// 到期的任務（輪詢在前），並把它們推後一個間隔；
+    /// 落後超過一個間隔時不補觸發，從 `now` 重新計時
+    pub fn due(&mut self, now: Instant) -> Vec<Task> {
+        let mut tasks = Vec::new();
+        
+        if advance(&mut self.next_poll, self.poll_interval, now) {
+            tasks.push(Task::NftPoll);
+        }
+        if advance(&mut self.next_report, self.report_interval, now) {
+            tasks.push(Task::Report);
+        }
+        
+        tasks
+    }
+    
+    /// 距下一個任務到期的時間
+    pub fn until_next(&self, now: Instant) -> Duration {
+        self.next_poll.min(self.next_report).saturating_duration_since(now)
+    }
+}
+
+/// 流量發生在工作時間內還是外
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeBucket {
+    BusinessHours,
+    OffHours,
+}
+
+/// 按配置的時段、工作日和時區判斷時間點屬於哪個桶
+#[derive(Debug, Clone)]
+pub struct BusinessHours {
+    start: NaiveTime,
+    end: NaiveTime,
+    weekdays: Vec<Weekday>,
+    offset: Option<FixedOffset>,
+}
+
+impl BusinessHours {
+    /// 時間、工作日或時區無法解析時返回 None，具體錯誤由 `Config::validate` 報告
+    pub fn from_config(config: &BusinessHoursConfig) -> Option<Self> {
+        let time = |time: &str| NaiveTime::parse_from_str(time, "%H:%M").ok();
+        Some(Self {
+            start: time(&config.start_time)?,
+            end: time(&config.end_time)?,
+            weekdays: config.weekdays.iter().map(|day| day.parse().ok()).collect::<Option<_>>()?,
+            offset: config.utc_offset.as_ref().map(|offset| offset.parse()).transpose().ok()?,
+        })
+    }
+    
+    pub fn bucket(&self, at: SystemTime) -> TimeBucket {
+        let utc = DateTime::<Utc>::from(at);
+        let offset = self.offset.unwrap_or_else(|| utc.with_timezone(&Local).offset().fix());
+        let local = utc.with_timezone(&offset);
+        let (day, time) = (local.weekday(), local.time());
+        let workday = |day: Weekday| self.weekdays.contains(&day);
+        
+        // 跨午夜的時段（如 22:00–06:00）午夜後的部分屬於前一天
+        let inside = if self.start <= self.end {
+            workday(day) && self.start <= time && time < self.end
+        } else {
+            (workday(day) && time >= self.start) || (workday(day.pred()) && time < self.end)
+        };
+        
+        if inside {
+            TimeBucket::BusinessHours
+        } else {
+            TimeBucket::OffHours
+        }
+    }
+}
+
+fn advance(next: &mut Instant, interval: Duration, now: Instant) -> bool {
+    if now < *next {
+        return false;
+    }
+    
+    *next += interval;
+    if *next <= now {
+        *next = now + interval;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_intervals_independent() {
+        let start = Instant::now();
+        let mut schedule = Schedule::new(Duration::from_secs(10), Duration::from_secs(3), start);
+        
+        let mut polls = Vec::new();
+        let mut reports = Vec::new();
+        for second in 0..=21 {
+            for task in schedule.due(start + Duration::from_secs(second)) {
+                match task {
+                    Task::NftPoll => polls.push(second),
+                    Task::Report => reports.push(second),
+                }
+            }
+        }
+        assert_eq!(polls, vec![3, 6, 9, 12, 15, 18, 21]);
+        assert_eq!(reports, vec![10, 20]);
+        assert_eq!(schedule.until_next(start + Duration::from_secs(21)), Duration::from_secs(3));
+        
+        // 輪詢比報告慢時同樣各自觸發
+        let mut schedule = Schedule::new(Duration::from_secs(5), Duration::from_secs(60), start);
+        assert_eq!(schedule.due(start + Duration::from_secs(5)), vec![Task::Report]);
+        assert_eq!(schedule.due(start + Duration::from_secs(60)), vec![Task::NftPoll, Task::Report]);
+        // 長時間阻塞後不會連續補觸發
+        assert!(schedule.due(start + Duration::from_secs(61)).is_empty());
+    }
+    
+    #[test]
+    fn test_business_hours_buckets() {
+        let hours = BusinessHours::from_config(&BusinessHoursConfig {
+            start_time: "09:00".to_string(),
+            end_time: "18:00".to_string(),
+            weekdays: vec!["mon".to_string(), "tue".to_string(), "wed".to_string(), "thu".to_string(), "fri".to_string()],
+            utc_offset: Some("+08:00".to_string()),
+        }).unwrap();
+        // 2024-01-01 是星期一；時間按 UTC+8
+        let at = |day: u64, hour: u64, minute: u64| {
+            let local = 1_704_067_200 + day * 86_400 + hour * 3600 + minute * 60;
+            SystemTime::UNIX_EPOCH + Duration::from_secs(local - 8 * 3600)
+        };
+        
+        assert_eq!(hours.bucket(at(0, 9, 0)), TimeBucket::BusinessHours);
+        assert_eq!(hours.bucket(at(0, 17, 59)), TimeBucket::BusinessHours);
+        assert_eq!(hours.bucket(at(0, 8, 59)), TimeBucket::OffHours);
+        assert_eq!(hours.bucket(at(0, 18, 0)), TimeBucket::OffHours);
+        // 週末全天在工作時間外
+        assert_eq!(hours.bucket(at(5, 10, 0)), TimeBucket::OffHours);
+        
+        // 夜班跨過午夜，星期五晚上開始的班延續到星期六早上
+        let night = BusinessHours {
+            start: NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(6, 0, 0).unwrap(),
+            ..hours
+        };
+        assert_eq!(night.bucket(at(4, 23, 0)), TimeBucket::BusinessHours);
+        assert_eq!(night.bucket(at(5, 5, 0)), TimeBucket::BusinessHours);
+        assert_eq!(night.bucket(at(5, 23, 0)), TimeBucket::OffHours);
+        assert_eq!(night.bucket(at(0, 5, 0)), TimeBucket::OffHours);
+        assert_eq!(night.bucket(at(0, 12, 0)), TimeBucket::OffHours);
+    }
+    
+    #[test]
+    fn test_poll_interval_defaults_to_report_interval() {
+        let mut config = Config { report_interval: 30, ..Config::default() };
+        assert_eq!(config.poll_interval(), 30);
+        
+        config.nft_poll_interval = Some(5);
+        let start = Instant::now();
+        let mut schedule = Schedule::from_config(&config, start);
+        assert_eq!(schedule.due(start + Duration::from_secs(5)), vec![Task::NftPoll]);
+        assert_eq!(schedule.until_next(start + Duration::from_secs(5)), Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,115 @@
+use std::fmt;
+
+use crate::packet::LinkType;
+
+/// 單個合成包的分類結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestCase {
+    pub name: &'static str,
+    pub expected: &'static str,
+    pub actual: String,
+}
+
+impl SelfTestCase {
+    pub fn passed(&self) -> bool {
+        self.actual == self.expected
+    }
+}
+
+/// 按抓包鏈路層類型封裝的合成包走一遍分類路徑的結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestReport {
+    pub link: LinkType,
+    pub cases: Vec<SelfTestCase>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.cases.iter().all(SelfTestCase::passed)
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Self-test ({:?}):", self.link)?;
+        for case in &self.cases {
+            if case.passed() {
+                writeln!(f, "  PASS {}: {}", case.name, case.actual)?;
+            } else {
+                writeln!(f, "  FAIL {}: expected {}, got {}", case.name, case.expected, case.actual)?;
+            }
+        }
+        let passed = self.cases.iter().filter(|c| c.passed()).count();
+        write!(f, "{}/{} passed", passed, self.cases.len())
+    }
+}
+
+/// 自檢用的 (名稱, 期望服務, 幀)，幀按 `link` 加上鏈路層頭
+pub fn probe_frames(link: LinkType) -> Vec<(&'static str, &'static str, Vec<u8>)> {
+    let http = ipv4_packet(6, 50000, 80, b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n");
+    // TLS 記錄頭：handshake，版本 3.1
+    let https = ipv4_packet(6, 50001, 443, &[0x16, 0x03, 0x01, 0x00, 0x00]);
+    let dns = ipv4_packet(17, 50002, 53, &dns_query("example.com"));
+    
+    vec![
+        ("HTTP", "http", link_frame(link, &http)),
+        ("HTTPS", "https", link_frame(link, &https)),
+        ("DNS", "dns", link_frame(link, &dns)),
+    ]
+}
+
+/// 192.168.1.100 → 93.184.216.34 的 IPv4 包，`protocol` 為 6（TCP）或 17（UDP）
+fn ipv4_packet(protocol: u8, sport: u16, dport: u16, payload: &[u8]) -> Vec<u8> {
+    let l4_len = if protocol == 6 { 20 } else { 8 };
+    let mut packet = vec![0x45, 0x00];
+    packet.extend_from_slice(&((20 + l4_len + payload.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(&[0x00, 0x00, 0x40, 0x00, 0x40, protocol, 0x00, 0x00]);
+    packet.extend_from_slice(&[192, 168, 1, 100, 93, 184, 216, 34]);
+    packet.extend_from_slice(&sport.to_be_bytes());
+    packet.extend_from_slice(&dport.to_be_bytes());
+    if protocol == 6 {
+        packet.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0x50, 0x18, 0xff, 0xff, 0, 0, 0, 0]);
+    } else {
+        packet.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&[0x00, 0x00]);
+    }
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// 單個 A 記錄查詢
+fn dns_query(name: &str) -> Vec<u8> {
+    let mut query = vec![0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    for label in name.split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x01]);
+    query
+}
+
+/// 加上以太網、SLL 或 SLL2 頭，協議號為 IPv4
+fn link_frame(link: LinkType, packet: &[u8]) -> Vec<u8> {
+    let mut frame = match link {
+        LinkType::Ethernet => {
+            let mut header = vec![0u8; 12];
+            header.extend_from_slice(&[0x08, 0x00]);
+            header
+        }
+        // 包類型、ARPHRD、地址長度、8 字節地址、協議號
+        LinkType::LinuxSll => {
+            let mut header = vec![0x00, 0x04, 0x00, 0x01, 0x00, 0x06];
+            header.extend_from_slice(&[0x02, 0x42, 0xac, 0x11, 0x00, 0x02, 0x00, 0x00]);
+            header.extend_from_slice(&[0x08, 0x00]);
+            header
+        }
+        // 協議號、保留、接口索引、ARPHRD、包類型、地址長度、8 字節地址
+        LinkType::LinuxSll2 => {
+            let mut header = vec![0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x01, 0x04, 0x06];
+            header.extend_from_slice(&[0x02, 0x42, 0xac, 0x11, 0x00, 0x02, 0x00, 0x00]);
+            header
+        }
+    };
+    frame.extend_from_slice(packet);
+    frame
+}
@@ -0,0 +1,157 @@
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::json;
+
+use crate::stats::TrafficStats;
+use crate::store::Bucket;
+
+/// 檢查停止標誌的間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// 客戶端不讀數據時最多等待多久，超時視為斷開
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// 在 Unix 域套接字上向所有已連接的客戶端推送每次輪轉的統計，
+/// 每個快照一行 JSON：`{"timestamp": .., "services": {服務: TrafficData}}`
+#[derive(Debug)]
+pub struct StatsSocket {
+    path: PathBuf,
+    clients: Arc<Mutex<Vec<UnixStream>>>,
+    stop: Arc<AtomicBool>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl StatsSocket {
+    /// 綁定 `path`（替換上次留下的套接字文件）並開始推送 `stats` 的輪轉
+    pub fn start(path: &Path, stats: &TrafficStats) -> io::Result<Self> {
+        if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let rotations = stats.subscribe();
+        let private = stats.private_services().clone();
+        
+        let accept = {
+            let clients = Arc::clone(&clients);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            let configured = stream.set_nonblocking(false)
+                                .and_then(|_| stream.set_write_timeout(Some(WRITE_TIMEOUT)));
+                            match configured {
+                                Ok(()) => clients.lock().unwrap().push(stream),
+                                Err(e) => eprintln!("Failed to configure stats socket client: {}", e),
+                            }
+                        }
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                        Err(e) => {
+                            eprintln!("Stats socket accept failed: {}", e);
+                            thread::sleep(POLL_INTERVAL);
+                        }
+                    }
+                }
+            })
+        };
+        
+        let forward = {
+            let clients = Arc::clone(&clients);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    match rotations.recv_timeout(POLL_INTERVAL) {
+                        Ok((timestamp, mut bucket)) => {
+                            bucket.retain(|service, _| !private.contains(service));
+                            let line = snapshot_line(timestamp, &bucket);
+                            // 寫入失敗（斷開或超時）的客戶端直接丟棄
+                            clients.lock().unwrap().retain_mut(|client| client.write_all(line.as_bytes()).is_ok());
+                        }
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+            })
+        };
+        
+        Ok(Self { path: path.to_path_buf(), clients, stop, handles: vec![accept, forward] })
+    }
+    
+    /// 當前連接的客戶端數
+    pub fn client_count(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+}
+
+impl Drop for StatsSocket {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn snapshot_line(timestamp: SystemTime, bucket: &Bucket) -> String {
+    let timestamp = DateTime::<Utc>::from(timestamp).to_rfc3339_opts(SecondsFormat::Millis, true);
+    format!("{}\n", json!({ "timestamp": timestamp, "services": bucket }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::time::Instant;
+    
+    #[test]
+    fn test_snapshot_over_unix_socket() {
+        let path = std::env::temp_dir().join(format!("trafficmon-stats-{}.sock", std::process::id()));
+        let stats = TrafficStats::new();
+        let server = StatsSocket::start(&path, &stats).unwrap();
+        
+        let client = UnixStream::connect(&path).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.client_count() == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(server.client_count(), 1);
+        
+        stats.add_traffic("netflix", 1536, 2);
+        stats.flush();
+        
+        let mut line = String::new();
+        BufReader::new(&client).read_line(&mut line).unwrap();
+        let snapshot: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(snapshot["services"]["netflix"]["bytes"], 1536);
+        assert_eq!(snapshot["services"]["netflix"]["packets"], 2);
+        assert!(DateTime::parse_from_rfc3339(snapshot["timestamp"].as_str().unwrap()).is_ok());
+        
+        // 斷開的客戶端在下一次推送時移除
+        drop(client);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while server.client_count() > 0 && Instant::now() < deadline {
+            stats.add_traffic("dns", 100, 1);
+            stats.flush();
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(server.client_count(), 0);
+        
+        drop(server);
+        assert!(!path.exists());
+    }
+}
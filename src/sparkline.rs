@@ -0,0 +1,76 @@
+use std::iter;
+use std::time::{Duration, SystemTime};
+
+/// 由低到高的八級字符
+const LEVELS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// 數據點不足時左側補的空白
+const PADDING: char = ' ';
+
+/// 把最近 `cells` 個值畫成一行追加到 `out`，最大值對應最高一級；
+/// 不足 `cells` 個點時左側補空白，寬度始終為 `cells`
+pub fn render_into(out: &mut String, values: &[f64], cells: usize) {
+    let recent = &values[values.len().saturating_sub(cells)..];
+    let max = recent.iter().copied().fold(0.0, f64::max);
+    
+    out.extend(iter::repeat_n(PADDING, cells - recent.len()));
+    for value in recent {
+        let level = if max > 0.0 {
+            (value.max(0.0) / max * (LEVELS.len() - 1) as f64).round() as usize
+        } else {
+            0
+        };
+        out.push(LEVELS[level.min(LEVELS.len() - 1)]);
+    }
+}
+
+pub fn render(values: &[f64], cells: usize) -> String {
+    let mut out = String::with_capacity(cells * LEVELS[0].len_utf8());
+    render_into(&mut out, values, cells);
+    out
+}
+
+/// 由 `TrafficStats::timeseries` 的點計算各周期的字節速率（字節/秒）；
+/// 第一個點或時間沒有前進時按 `interval` 計算
+pub fn byte_rates(points: &[(SystemTime, u64)], interval: Duration) -> Vec<f64> {
+    let mut previous: Option<SystemTime> = None;
+    points.iter()
+        .map(|(timestamp, bytes)| {
+            let elapsed = previous
+                .and_then(|previous| timestamp.duration_since(previous).ok())
+                .filter(|elapsed| !elapsed.is_zero())
+                .unwrap_or(interval);
+            previous = Some(*timestamp);
+            *bytes as f64 / elapsed.as_secs_f64().max(1.0)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_sparkline_cells() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let points: Vec<_> = [0u64, 600, 1200, 6000, 3000].iter().enumerate()
+            .map(|(i, bytes)| (start + Duration::from_secs(60 * i as u64), *bytes))
+            .collect();
+        let rates = byte_rates(&points, Duration::from_secs(60));
+        assert_eq!(rates, vec![0.0, 10.0, 20.0, 100.0, 50.0]);
+        
+        assert_eq!(render(&rates, 5), "▁▂▂█▅");
+        // 只畫最近的點
+        assert_eq!(render(&rates, 3).chars().count(), 3);
+        // 點數不足時補足寬度
+        let line = render(&rates[..2], 8);
+        assert_eq!(line.chars().count(), 8);
+        assert_eq!(line, "      ▁█");
+        assert_eq!(render(&[], 4), "    ");
+        
+        // 追加到已有緩衝區，不另外分配
+        let mut out = String::from("netflix ");
+        render_into(&mut out, &rates, 5);
+        assert_eq!(out, "netflix ▁▂▂█▅");
+    }
+}
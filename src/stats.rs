@@ -1,44 +1,418 @@
-use std::collections::HashMap;
-use std::sync::Mutex;
-use std::time::{SystemTime, Duration};
-use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Instant, SystemTime, Duration};
+use chrono::{DateTime, FixedOffset, Local, NaiveTime, Offset, SecondsFormat, TimeZone, Utc};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{json, Value};
 
-#[derive(Debug, Serialize, Clone)]
+use crate::config::Config;
+use crate::sparkline;
+use crate::store::{entry_size, Bucket, MemoryStore, StatsStore};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct TrafficData {
     pub bytes: u64,
     pub packets: u64,
+    #[serde(serialize_with = "serialize_rfc3339", deserialize_with = "deserialize_rfc3339")]
     pub first_seen: SystemTime,
+    #[serde(serialize_with = "serialize_rfc3339", deserialize_with = "deserialize_rfc3339")]
     pub last_seen: SystemTime,
 }
 
+/// 以 UTC 的 RFC 3339 字符串（毫秒精度）輸出時間，代替 serde 默認的 {secs_since_epoch, nanos_since_epoch}
+fn serialize_rfc3339<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&DateTime::<Utc>::from(*time).to_rfc3339_opts(SecondsFormat::Millis, true))
+}
+
+fn deserialize_rfc3339<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    DateTime::parse_from_rfc3339(&text)
+        .map(SystemTime::from)
+        .map_err(D::Error::custom)
+}
+
+impl TrafficData {
+    /// 從首次到最後一次看到的時長；時鐘回撥時為 0
+    pub fn duration(&self) -> Duration {
+        self.last_seen.duration_since(self.first_seen).unwrap_or_default()
+    }
+    
+    /// 距最後一次看到的時長；時鐘回撥時為 0
+    pub fn age(&self, now: SystemTime) -> Duration {
+        now.duration_since(self.last_seen).unwrap_or_default()
+    }
+    
+    /// 超過 `max_idle` 沒有新流量
+    pub fn is_stale(&self, now: SystemTime, max_idle: Duration) -> bool {
+        self.age(now) >= max_idle
+    }
+}
+
+/// 一個自然日（按午夜對齊）內各服務的字節數
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DailyTotal {
+    pub day_start: DateTime<FixedOffset>,
+    pub bytes: HashMap<String, u64>,
+    /// 當天尚未結束
+    pub partial: bool,
+}
+
+/// 寫入分片數；add_traffic 按線程選擇分片，多個分類線程不爭用同一把鎖
+const WRITE_SHARDS: usize = 16;
+/// textfile 導出線程檢查停止標誌的最長間隔
+const TEXTFILE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// 單個 VLAN 內各服務的 (字節數, 包數)
+pub type VlanStats = HashMap<String, (u64, u64)>;
+/// 單個抓包接口上各服務的 (字節數, 包數)
+pub type InterfaceStats = HashMap<String, (u64, u64)>;
+/// 按接口名排序的各接口服務統計，每個接口內按服務名排序
+pub type SortedInterfaceStats = Vec<(String, Vec<(String, (u64, u64))>)>;
+
 #[derive(Debug)]
 pub struct TrafficStats {
     data: Mutex<StatsData>,
+    /// 尚未合併到當前桶的新流量，讀取統計前合併
+    shards: Vec<Mutex<Bucket>>,
     retention_period: Duration,
+    /// 詳細統計最多合併的最近桶數，None 表示不限
+    max_buckets: Option<usize>,
+    /// 當前桶和內存中歷史桶的估算內存上限（字節），超出時淘汰最久沒有流量的服務
+    memory_budget: Option<usize>,
+    /// 抓包隊列已滿而丟棄的包數
+    queue_drops: AtomicU64,
+    /// 超出時間預算而改按端口分類的包數
+    classification_timeouts: AtomicU64,
+    /// 同一源地址 TTL 大幅變化（可能是偽造的源地址）的次數，以及正在跟蹤 TTL 的源地址數
+    ttl_anomalies: AtomicU64,
+    ttl_sources: AtomicU64,
+    /// 按 VLAN（未打標籤為 0）和服務累計的字節數和包數
+    vlan_data: Mutex<HashMap<u16, VlanStats>>,
+    /// 按抓包接口和服務累計的字節數和包數，與 shards 一樣按線程分片，讀取時合併
+    interface_data: Vec<Mutex<HashMap<String, InterfaceStats>>>,
+    /// 長連接服務（如 VoIP），first_seen 跨輪轉保留，時長按整個會話計算
+    sticky_services: HashSet<String>,
+    /// false 時 IPv6 流量記在帶 "6" 後綴的服務下（如 netflix6）
+    merge_address_families: bool,
+    /// 照常統計但不出現在任何導出（Prometheus、Grafana、JSON、MessagePack）中的服務
+    private_services: HashSet<String>,
+    /// 導出時只保留字節數最多的服務數，其餘合併為 "__other__"
+    export_top: Option<usize>,
+    /// 導出中合併的 "__other__" 序列，以及各接口內合併的序列
+    export_other: Mutex<OtherSeries>,
+    interface_other: Mutex<HashMap<String, OtherSeries>>,
+    /// 最多跟蹤的不同服務數，達到後新出現的服務記入 "overflow"
+    max_services: Option<usize>,
+    /// 已計入 max_services 的服務，只在設置了上限時記錄；清理過期數據時移除不再有統計的服務。
+    /// 每個包都要查詢，已跟蹤的服務只取讀鎖
+    known_services: RwLock<HashSet<String>>,
+    /// 速率窗口使用的單調時鐘基準；牆上時間只用於顯示，NTP 調整不影響速率
+    monotonic_origin: Instant,
+    /// 最早和最近一次流量距 monotonic_origin 的納秒數，沒有流量時 first 為 u64::MAX
+    first_activity: AtomicU64,
+    last_activity: AtomicU64,
 }
 
 #[derive(Debug)]
 struct StatsData {
-    current: HashMap<String, TrafficData>,
-    history: Vec<(SystemTime, HashMap<String, TrafficData>)>,
+    current: Bucket,
+    /// 已輪轉的歷史桶
+    store: Box<dyn StatsStore>,
+    /// 最近一次輪轉的時間；系統時鐘回撥時統計時間停在這裡，不會倒退
+    latest: Option<SystemTime>,
+    /// 每次輪轉時收到 (輪轉時間, 桶) 的訂閱者
+    subscribers: Vec<Sender<(SystemTime, Bucket)>>,
+    /// 常駐服務最早一次出現的時間
+    sticky_first_seen: HashMap<String, SystemTime>,
+    /// 關閉時不輪轉，當前桶一直累計，存儲中沒有歷史桶
+    history_enabled: bool,
+}
+
+impl StatsData {
+    /// 不早於最近一次輪轉的統計時間，時鐘回撥視為沒有經過時間
+    fn clock(&self, now: SystemTime) -> SystemTime {
+        self.latest.map_or(now, |latest| latest.max(now))
+    }
+    
+    /// 截至 now 的所有歷史桶
+    fn history(&self, now: SystemTime) -> Vec<(SystemTime, Bucket)> {
+        self.store.range(SystemTime::UNIX_EPOCH, now)
+    }
+    
+    /// 截至 now 的最近 `limit` 個歷史桶，不限時返回全部
+    fn recent_history(&self, now: SystemTime, limit: Option<usize>) -> Vec<(SystemTime, Bucket)> {
+        match limit {
+            Some(limit) => self.store.recent(now, limit),
+            None => self.history(now),
+        }
+    }
+    
+    /// 把當前桶寫入存儲，返回實際使用的統計時間
+    fn rotate(&mut self, now: SystemTime) -> SystemTime {
+        let now = self.clock(now);
+        if self.history_enabled && !self.current.is_empty() {
+            let current = std::mem::take(&mut self.current);
+            // 接收端已斷開的訂閱者直接移除
+            self.subscribers.retain(|tx| tx.send((now, current.clone())).is_ok());
+            self.store.put(now, current);
+            self.latest = Some(now);
+        }
+        now
+    }
+    
+    fn estimated_bytes(&self) -> usize {
+        self.current.keys().map(|service| entry_size(service)).sum::<usize>() + self.store.estimated_bytes()
+    }
+    
+    fn evict_to(&mut self, max_bytes: usize) -> usize {
+        let mut size = self.estimated_bytes();
+        if size <= max_bytes {
+            return 0;
+        }
+        
+        let mut last_seen: HashMap<String, SystemTime> = HashMap::new();
+        let history = self.history(self.clock(SystemTime::now()));
+        let buckets = std::iter::once(&self.current).chain(history.iter().map(|(_, bucket)| bucket));
+        for (service, traffic_data) in buckets.flat_map(|bucket| bucket.iter()) {
+            let seen = last_seen.entry(service.clone()).or_insert(traffic_data.last_seen);
+            *seen = (*seen).max(traffic_data.last_seen);
+        }
+        
+        // 最久沒有流量的在前，時間相同時按名稱
+        let mut order: Vec<(SystemTime, String)> = last_seen.into_iter().map(|(service, seen)| (seen, service)).collect();
+        order.sort();
+        
+        let mut evicted = 0;
+        for (_, service) in order {
+            if size <= max_bytes {
+                break;
+            }
+            let mut freed = self.store.remove_service(&service);
+            if self.current.remove(&service).is_some() {
+                freed += entry_size(&service);
+            }
+            self.sticky_first_seen.remove(&service);
+            size = size.saturating_sub(freed);
+            evicted += 1;
+        }
+        evicted
+    }
+    
+    /// 當前桶與全部歷史桶中某個服務的累計
+    fn service_totals(&self, service: &str) -> Option<TrafficData> {
+        let mut result = None;
+        
+        // 檢查當前數據
+        if let Some(current) = self.current.get(service) {
+            result = Some(current.clone());
+        }
+        
+        // 合併歷史數據
+        for (_, historical) in self.store.get(service) {
+            if let Some(ref mut res) = result {
+                res.bytes += historical.bytes;
+                res.packets += historical.packets;
+                if historical.first_seen < res.first_seen {
+                    res.first_seen = historical.first_seen;
+                }
+                if historical.last_seen > res.last_seen {
+                    res.last_seen = historical.last_seen;
+                }
+            } else {
+                result = Some(historical);
+            }
+        }
+        
+        result
+    }
 }
 
 impl TrafficStats {
     pub fn new() -> Self {
+        Self::with_store(Box::new(MemoryStore::new()))
+    }
+    
+    /// 使用指定的存儲後端保存歷史桶
+    pub fn with_store(store: Box<dyn StatsStore>) -> Self {
         Self {
             data: Mutex::new(StatsData {
                 current: HashMap::new(),
-                history: Vec::new(),
+                store,
+                latest: None,
+                subscribers: Vec::new(),
+                sticky_first_seen: HashMap::new(),
+                history_enabled: true,
             }),
+            shards: (0..WRITE_SHARDS).map(|_| Mutex::new(Bucket::new())).collect(),
             retention_period: Duration::from_secs(3600), // 保留1小時歷史數據
+            max_buckets: None,
+            memory_budget: None,
+            queue_drops: AtomicU64::new(0),
+            classification_timeouts: AtomicU64::new(0),
+            ttl_anomalies: AtomicU64::new(0),
+            ttl_sources: AtomicU64::new(0),
+            vlan_data: Mutex::new(HashMap::new()),
+            interface_data: (0..WRITE_SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            sticky_services: HashSet::new(),
+            merge_address_families: true,
+            private_services: HashSet::new(),
+            export_top: None,
+            export_other: Mutex::new(OtherSeries::default()),
+            interface_other: Mutex::new(HashMap::new()),
+            max_services: None,
+            known_services: RwLock::new(HashSet::new()),
+            monotonic_origin: Instant::now(),
+            first_activity: AtomicU64::new(u64::MAX),
+            last_activity: AtomicU64::new(0),
         }
     }
     
+    /// 按配置中的統計選項構建，使用內存存儲
+    pub fn from_config(config: &Config) -> Self {
+        Self::new()
+            .with_max_buckets(config.max_history_buckets)
+            .with_sticky_services(&config.sticky_services)
+            .with_history(config.history_enabled)
+            .with_memory_budget(config.stats_memory_budget)
+            .with_merged_address_families(config.merge_address_families)
+            .with_private_services(&config.private_services)
+            .with_export_top(config.export_top_services)
+            .with_max_services(config.max_services)
+    }
+    
+    /// 限制 `get_detailed_stats` 合併的歷史桶數，與保留時長無關
+    pub fn with_max_buckets(mut self, max_buckets: Option<usize>) -> Self {
+        self.max_buckets = max_buckets;
+        self
+    }
+    
+    /// 每次輪轉清理過期數據後按 `prune_to` 把估算內存壓到此上限以內
+    pub fn with_memory_budget(mut self, max_bytes: Option<usize>) -> Self {
+        self.memory_budget = max_bytes;
+        self
+    }
+    
+    /// 關閉歷史時只保留累計的當前統計，`get_stats` 直接返回它，適合內存很小的設備；
+    /// 不再輪轉，訂閱者收不到快照，依賴歷史桶的查詢（如 `timeseries`）為空
+    pub fn with_history(self, enabled: bool) -> Self {
+        self.data.lock().unwrap().history_enabled = enabled;
+        self
+    }
+    
+    /// 這些服務輪轉後仍沿用最早的 first_seen，字節數照常按桶累計
+    pub fn with_sticky_services(mut self, services: &[String]) -> Self {
+        self.sticky_services = services.iter().cloned().collect();
+        self
+    }
+    
+    /// 是否把同一服務的 IPv4 和 IPv6 流量合併統計，默認合併
+    pub fn with_merged_address_families(mut self, merge: bool) -> Self {
+        self.merge_address_families = merge;
+        self
+    }
+    
+    /// 這些服務只在內部統計，導出時略去
+    pub fn with_private_services(mut self, services: &[String]) -> Self {
+        self.private_services = services.iter().cloned().collect();
+        self
+    }
+    
+    /// 導出時只保留字節數最多的 `top` 個服務，其餘合併為 "__other__"，
+    /// 各接口內同樣只保留 `top` 個服務；None 時全部導出
+    pub fn with_export_top(mut self, top: Option<usize>) -> Self {
+        self.export_top = top;
+        self
+    }
+    
+    /// 最多跟蹤 `max` 個不同服務，之後新出現的服務記入 "overflow"，已有的照常累計；None 時不限
+    pub fn with_max_services(mut self, max: Option<usize>) -> Self {
+        self.max_services = max;
+        self
+    }
+    
+    /// 不導出的服務
+    pub fn private_services(&self) -> &HashSet<String> {
+        &self.private_services
+    }
+    
+    pub fn is_private(&self, service: &str) -> bool {
+        self.private_services.contains(service)
+    }
+    
+    pub fn add_queue_drops(&self, count: u64) {
+        self.queue_drops.fetch_add(count, Ordering::Relaxed);
+    }
+    
+    pub fn queue_drops(&self) -> u64 {
+        self.queue_drops.load(Ordering::Relaxed)
+    }
+    
+    pub fn add_classification_timeouts(&self, count: u64) {
+        self.classification_timeouts.fetch_add(count, Ordering::Relaxed);
+    }
+    
+    pub fn classification_timeouts(&self) -> u64 {
+        self.classification_timeouts.load(Ordering::Relaxed)
+    }
+    
+    pub fn add_ttl_anomalies(&self, count: u64) {
+        self.ttl_anomalies.fetch_add(count, Ordering::Relaxed);
+    }
+    
+    pub fn ttl_anomalies(&self) -> u64 {
+        self.ttl_anomalies.load(Ordering::Relaxed)
+    }
+    
+    pub fn set_ttl_sources(&self, count: u64) {
+        self.ttl_sources.store(count, Ordering::Relaxed);
+    }
+    
+    pub fn ttl_sources(&self) -> u64 {
+        self.ttl_sources.load(Ordering::Relaxed)
+    }
+    
     pub fn add_traffic(&self, service: &str, bytes: u64, packets: u64) {
-        let mut data = self.data.lock().unwrap();
-        let now = SystemTime::now();
+        self.add_traffic_at(service, bytes, packets, SystemTime::now());
+    }
+    
+    /// 按目標地址的協議族計入；不合併時 IPv6 流量記在 `<service>6` 下
+    pub fn add_family_traffic(&self, service: &str, addr: IpAddr, bytes: u64, packets: u64) {
+        self.add_family_traffic_at(service, addr, bytes, packets, SystemTime::now());
+    }
+    
+    pub fn add_family_traffic_at(&self, service: &str, addr: IpAddr, bytes: u64, packets: u64, now: SystemTime) {
+        if addr.is_ipv6() && !self.merge_address_families {
+            self.add_traffic_at(&format!("{}6", service), bytes, packets, now);
+        } else {
+            self.add_traffic_at(service, bytes, packets, now);
+        }
+    }
+    
+    /// `now` 為包的時間（如抓包時間戳），記入 first_seen/last_seen
+    pub fn add_traffic_at(&self, service: &str, bytes: u64, packets: u64, now: SystemTime) {
+        self.add_traffic_with_clocks(service, bytes, packets, now, Instant::now());
+    }
+    
+    /// `now` 只記錄在 first_seen/last_seen 中用於顯示，速率窗口按 `at` 計算
+    fn add_traffic_with_clocks(&self, service: &str, bytes: u64, packets: u64, now: SystemTime, at: Instant) {
+        let nanos = at.saturating_duration_since(self.monotonic_origin).as_nanos() as u64;
+        self.first_activity.fetch_min(nanos, Ordering::Relaxed);
+        self.last_activity.fetch_max(nanos, Ordering::Relaxed);
         
-        let traffic_data = data.current.entry(service.to_string()).or_insert_with(|| TrafficData {
+        let service = self.tracked_service(service);
+        let mut shard = self.shards[shard_index()].lock().unwrap();
+        
+        let traffic_data = shard.entry(service.to_string()).or_insert_with(|| TrafficData {
             bytes: 0,
             packets: 0,
             first_seen: now,
@@ -47,42 +421,103 @@ impl TrafficStats {
         
         traffic_data.bytes += bytes;
         traffic_data.packets += packets;
-        traffic_data.last_seen = now;
+        // 多個分類線程處理的包不一定按時間戳順序到達
+        traffic_data.first_seen = traffic_data.first_seen.min(now);
+        traffic_data.last_seen = traffic_data.last_seen.max(now);
     }
     
-    pub fn get_stats(&self) -> HashMap<String, (u64, u64)> {
+    /// 未達到 max_services 或已經跟蹤的服務原樣返回，否則返回 "overflow"
+    fn tracked_service<'a>(&self, service: &'a str) -> &'a str {
+        let Some(max) = self.max_services else {
+            return service;
+        };
+        if service == OVERFLOW_SERVICE {
+            return service;
+        }
+        if self.known_services.read().unwrap().contains(service) {
+            return service;
+        }
+        // 取寫鎖期間其他線程可能已經加入了同一服務
+        let mut known = self.known_services.write().unwrap();
+        if known.contains(service) {
+            service
+        } else if known.len() < max {
+            known.insert(service.to_string());
+            service
+        } else {
+            OVERFLOW_SERVICE
+        }
+    }
+    
+    /// 鎖住統計數據，並把各分片中的新流量合併到當前桶
+    fn lock_data(&self) -> MutexGuard<'_, StatsData> {
         let mut data = self.data.lock().unwrap();
+        
+        for shard in &self.shards {
+            for (service, pending) in std::mem::take(&mut *shard.lock().unwrap()) {
+                merge_traffic(&mut data.current, service, pending);
+            }
+        }
+        
+        // 常駐服務的新桶沿用最早的 first_seen
+        if !self.sticky_services.is_empty() {
+            let StatsData { current, sticky_first_seen, .. } = &mut *data;
+            for (service, traffic_data) in current.iter_mut().filter(|(service, _)| self.sticky_services.contains(*service)) {
+                let first_seen = sticky_first_seen.entry(service.clone()).or_insert(traffic_data.first_seen);
+                *first_seen = (*first_seen).min(traffic_data.first_seen);
+                traffic_data.first_seen = *first_seen;
+            }
+        }
+        
+        data
+    }
+    
+    /// 把其他實例的統計快照（如 `from_msgpack` 的結果）合併到當前桶
+    pub fn merge(&self, snapshot: HashMap<String, TrafficData>) {
+        let mut data = self.lock_data();
+        for (service, traffic_data) in snapshot {
+            merge_traffic(&mut data.current, service, traffic_data);
+        }
+    }
+    
+    pub fn get_stats(&self) -> HashMap<String, (u64, u64)> {
+        let mut data = self.lock_data();
+        if !data.history_enabled {
+            return data.current.iter()
+                .map(|(service, traffic_data)| (service.clone(), (traffic_data.bytes, traffic_data.packets)))
+                .collect();
+        }
         let now = SystemTime::now();
         
         // 保存當前統計到歷史記錄
-        if !data.current.is_empty() {
-            data.history.push((now, data.current.clone()));
-            data.current.clear();
-        }
+        let now = data.rotate(now);
         
         // 清理過期數據
-        self.clean_old_data(&mut data);
+        self.clean_old_data(&mut data, now);
         
         // 合併歷史數據並返回簡化格式
-        self.merge_history(&data.history)
+        self.merge_history(&data.history(now))
     }
     
     pub fn get_detailed_stats(&self) -> HashMap<String, TrafficData> {
-        let mut data = self.data.lock().unwrap();
-        let now = SystemTime::now();
+        self.get_detailed_stats_at(SystemTime::now())
+    }
+    
+    fn get_detailed_stats_at(&self, now: SystemTime) -> HashMap<String, TrafficData> {
+        let mut data = self.lock_data();
+        if !data.history_enabled {
+            return data.current.clone();
+        }
         
         // 保存當前統計到歷史記錄
-        if !data.current.is_empty() {
-            data.history.push((now, data.current.clone()));
-            data.current.clear();
-        }
+        let now = data.rotate(now);
         
         // 清理過期數據
-        self.clean_old_data(&mut data);
+        self.clean_old_data(&mut data, now);
         
-        // 合併歷史數據
+        // 合併最近的歷史數據
         let mut merged = HashMap::new();
-        for (_, stats) in &data.history {
+        for (_, stats) in &data.recent_history(now, self.max_buckets) {
             for (service, traffic_data) in stats {
                 let entry = merged.entry(service.clone()).or_insert_with(|| TrafficData {
                     bytes: 0,
@@ -107,16 +542,36 @@ impl TrafficStats {
         merged
     }
     
-    fn clean_old_data(&self, data: &mut StatsData) {
-        let now = SystemTime::now();
-        data.history.retain(|(timestamp, _)| {
-            now.duration_since(*timestamp)
-                .map(|dur| dur < self.retention_period)
-                .unwrap_or(false)
-        });
+    fn clean_old_data(&self, data: &mut StatsData, now: SystemTime) {
+        if let Some(cutoff) = now.checked_sub(self.retention_period) {
+            data.store.prune(cutoff);
+        }
+        if let Some(max_bytes) = self.memory_budget {
+            data.evict_to(max_bytes);
+        }
+        
+        // 過期或被淘汰的服務不再佔用 max_services 的名額
+        if self.max_services.is_some() {
+            let history = data.history(now);
+            let active: HashSet<&String> = data.current.keys()
+                .chain(history.iter().flat_map(|(_, bucket)| bucket.keys()))
+                .collect();
+            self.known_services.write().unwrap().retain(|service| active.contains(service));
+        }
+    }
+    
+    /// 淘汰最久沒有流量的服務（當前桶和歷史桶中的全部數據），直到估算內存不超過 `max_bytes`；
+    /// 返回淘汰的服務數。只計算內存中的數據，持久化存儲中的記錄不受影響
+    pub fn prune_to(&self, max_bytes: usize) -> usize {
+        self.lock_data().evict_to(max_bytes)
+    }
+    
+    /// 當前桶和內存中歷史桶的估算字節數
+    pub fn estimated_bytes(&self) -> usize {
+        self.lock_data().estimated_bytes()
     }
     
-    fn merge_history(&self, history: &[(SystemTime, HashMap<String, TrafficData>)]) -> HashMap<String, (u64, u64)> {
+    fn merge_history(&self, history: &[(SystemTime, Bucket)]) -> HashMap<String, (u64, u64)> {
         let mut merged = HashMap::new();
         
         for (_, stats) in history {
@@ -130,89 +585,1536 @@ impl TrafficStats {
         merged
     }
     
+    /// 記錄某個 VLAN 上的流量，與 `add_traffic` 分開累計
+    pub fn add_vlan_traffic(&self, vlan: u16, service: &str, bytes: u64, packets: u64) {
+        let service = self.tracked_service(service);
+        let mut vlan_data = self.vlan_data.lock().unwrap();
+        let entry = vlan_data.entry(vlan).or_default().entry(service.to_string()).or_insert((0, 0));
+        entry.0 += bytes;
+        entry.1 += packets;
+    }
+    
+    /// 按 VLAN ID 分開的服務統計
+    pub fn get_vlan_stats(&self) -> HashMap<u16, VlanStats> {
+        self.vlan_data.lock().unwrap().clone()
+    }
+    
+    /// 記錄某個抓包接口上的流量，與 `add_traffic` 分開累計
+    pub fn add_interface_traffic(&self, interface: &str, service: &str, bytes: u64, packets: u64) {
+        let service = self.tracked_service(service);
+        let mut interface_data = self.interface_data[shard_index()].lock().unwrap();
+        let entry = interface_data.entry(interface.to_string()).or_default().entry(service.to_string()).or_insert((0, 0));
+        entry.0 += bytes;
+        entry.1 += packets;
+    }
+    
+    /// 按抓包接口分開的服務統計
+    pub fn get_interface_stats(&self) -> HashMap<String, InterfaceStats> {
+        let mut merged: HashMap<String, InterfaceStats> = HashMap::new();
+        for shard in &self.interface_data {
+            for (interface, services) in shard.lock().unwrap().iter() {
+                let merged = merged.entry(interface.clone()).or_default();
+                for (service, (bytes, packets)) in services {
+                    let entry = merged.entry(service.clone()).or_insert((0, 0));
+                    entry.0 += bytes;
+                    entry.1 += packets;
+                }
+            }
+        }
+        merged
+    }
+    
+    /// 導出用的接口統計：略去私有服務，按接口和服務名排序；
+    /// 設置了 `export_top` 時每個接口內其餘服務合併為 "__other__"
+    pub fn export_interface_stats(&self) -> SortedInterfaceStats {
+        let mut interface_other = self.interface_other.lock().unwrap();
+        let mut interfaces: Vec<_> = self.get_interface_stats().into_iter()
+            .map(|(interface, services)| {
+                let mut services: Vec<_> = services.into_iter()
+                    .filter(|(service, _)| !self.is_private(service))
+                    .collect();
+                if let Some(top) = self.export_top {
+                    services.sort_by(|a, b| b.1.0.cmp(&a.1.0).then_with(|| a.0.cmp(&b.0)));
+                    let rest = services.split_off(top.min(services.len()));
+                    let other = interface_other.entry(interface.clone()).or_default();
+                    if let Some(totals) = other.update(services.iter().map(|(service, _)| service.clone()).collect(), &rest) {
+                        services.push((OTHER_SERVICE.to_string(), totals));
+                    }
+                }
+                services.sort();
+                (interface, services)
+            })
+            .collect();
+        interfaces.sort();
+        interfaces
+    }
+    
+    /// 訂閱之後的每次輪轉，丟棄接收端即取消訂閱
+    pub fn subscribe(&self) -> Receiver<(SystemTime, Bucket)> {
+        let (tx, rx) = mpsc::channel();
+        self.data.lock().unwrap().subscribers.push(tx);
+        rx
+    }
+    
+    /// 立即把當前桶寫入存儲，不等下一次輪轉
+    pub fn flush(&self) {
+        self.lock_data().rotate(SystemTime::now());
+    }
+    
     pub fn reset_stats(&self) {
-        let mut data = self.data.lock().unwrap();
+        let mut data = self.lock_data();
         data.current.clear();
-        data.history.clear();
+        data.store.clear();
+        data.sticky_first_seen.clear();
+        self.known_services.write().unwrap().clear();
+        self.vlan_data.lock().unwrap().clear();
+        for shard in &self.interface_data {
+            shard.lock().unwrap().clear();
+        }
+        self.first_activity.store(u64::MAX, Ordering::Relaxed);
+        self.last_activity.store(0, Ordering::Relaxed);
     }
     
     pub fn get_service_stats(&self, service: &str) -> Option<TrafficData> {
-        let data = self.data.lock().unwrap();
-        let mut result = None;
+        self.lock_data().service_totals(service)
+    }
+    
+    /// 一次加鎖取得多個服務的累計統計，沒有數據的服務不出現在結果中
+    pub fn get_services_stats(&self, names: &[&str]) -> HashMap<String, TrafficData> {
+        let data = self.lock_data();
+        names.iter()
+            .filter_map(|name| Some((name.to_string(), data.service_totals(name)?)))
+            .collect()
+    }
+    
+    /// 所有服務合計的平均字節速率（字節/秒），不會輪轉當前數據；
+    /// 時長按單調時鐘計算，最長為保留時長，系統時鐘跳變不影響結果
+    pub fn byte_rate(&self) -> f64 {
+        let Some(elapsed) = self.activity_span() else {
+            return 0.0;
+        };
         
-        // 檢查當前數據
-        if let Some(current) = data.current.get(service) {
-            result = Some(current.clone());
+        let data = self.lock_data();
+        let history = data.history(SystemTime::now());
+        let buckets = std::iter::once(&data.current).chain(history.iter().map(|(_, stats)| stats));
+        let total_bytes: u64 = buckets.flat_map(|stats| stats.values()).map(|traffic_data| traffic_data.bytes).sum();
+        
+        total_bytes as f64 / elapsed.min(self.retention_period).as_secs_f64().max(1.0)
+    }
+    
+    /// 從最早到最近一次流量的單調時長，沒有流量時為 None
+    pub fn activity_span(&self) -> Option<Duration> {
+        let first = self.first_activity.load(Ordering::Relaxed);
+        let last = self.last_activity.load(Ordering::Relaxed);
+        (first != u64::MAX).then(|| Duration::from_nanos(last.saturating_sub(first)))
+    }
+    
+    /// 各服務的平均包速率（包/秒），不會輪轉當前數據
+    pub fn get_packet_rates(&self) -> HashMap<String, f64> {
+        let data = self.lock_data();
+        let mut totals: HashMap<&String, TrafficData> = HashMap::new();
+        
+        let history = data.history(SystemTime::now());
+        let buckets = std::iter::once(&data.current).chain(history.iter().map(|(_, stats)| stats));
+        for (service, traffic_data) in buckets.flat_map(|stats| stats.iter()) {
+            let entry = totals.entry(service).or_insert_with(|| TrafficData { packets: 0, ..traffic_data.clone() });
+            entry.packets += traffic_data.packets;
+            entry.first_seen = entry.first_seen.min(traffic_data.first_seen);
+            entry.last_seen = entry.last_seen.max(traffic_data.last_seen);
         }
         
-        // 合併歷史數據
-        for (_, stats) in &data.history {
-            if let Some(historical) = stats.get(service) {
-                if let Some(ref mut res) = result {
-                    res.bytes += historical.bytes;
-                    res.packets += historical.packets;
-                    if historical.first_seen < res.first_seen {
-                        res.first_seen = historical.first_seen;
-                    }
-                    if historical.last_seen > res.last_seen {
-                        res.last_seen = historical.last_seen;
-                    }
-                } else {
-                    result = Some(historical.clone());
-                }
+        totals.into_iter()
+            .map(|(service, total)| {
+                (service.clone(), total.packets as f64 / total.duration().as_secs_f64().max(1.0))
+            })
+            .collect()
+    }
+    
+    /// 歷史數據的保留時長，更早的數據已被清除
+    pub fn retention_period(&self) -> Duration {
+        self.retention_period
+    }
+    
+    /// 保留期內字節數最多的前 n 個服務，不會輪轉當前數據
+    pub fn top_services(&self, n: usize) -> Vec<(String, u64)> {
+        self.top_services_window(n, self.retention_period)
+    }
+    
+    /// 最近 `window` 內字節數最多的前 n 個服務，字節數相同時按名稱排序
+    pub fn top_services_window(&self, n: usize, window: Duration) -> Vec<(String, u64)> {
+        self.top_services_window_at(n, window, SystemTime::now())
+    }
+    
+    fn top_services_window_at(&self, n: usize, window: Duration, now: SystemTime) -> Vec<(String, u64)> {
+        let mut top: Vec<(String, u64)> = self.window_bytes_at(window, now).into_iter().collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(n);
+        top
+    }
+    
+    /// 最近 `window` 內各服務的字節數，不會輪轉當前數據
+    pub fn window_bytes(&self, window: Duration) -> HashMap<String, u64> {
+        self.window_bytes_at(window, SystemTime::now())
+    }
+    
+    fn window_bytes_at(&self, window: Duration, now: SystemTime) -> HashMap<String, u64> {
+        let data = self.lock_data();
+        let now = data.clock(now);
+        let since = now.checked_sub(window).unwrap_or(SystemTime::UNIX_EPOCH);
+        
+        // 歷史桶按輪轉時間計，當前數據記在 now，每個桶只計一次
+        let history = data.store.range(since, now);
+        let buckets = history.iter()
+            .map(|(_, stats)| stats)
+            .chain(std::iter::once(&data.current));
+        
+        let mut totals: HashMap<String, u64> = HashMap::new();
+        for stats in buckets {
+            for (service, traffic_data) in stats {
+                *totals.entry(service.clone()).or_insert(0) += traffic_data.bytes;
             }
         }
-        
-        result
+        totals
     }
-}
-
-impl Default for TrafficStats {
-    fn default() -> Self {
-        Self::new()
+    
+    /// 最近 24 小時內按本地午夜分日的各服務字節數，按日期升序
+    pub fn daily_totals(&self) -> Vec<DailyTotal> {
+        self.daily_totals_at(SystemTime::now(), Local::now().offset().fix())
     }
-}
-
-impl Clone for TrafficData {
-    fn clone(&self) -> Self {
-        Self {
-            bytes: self.bytes,
-            packets: self.packets,
-            first_seen: self.first_seen,
-            last_seen: self.last_seen,
+    
+    fn daily_totals_at(&self, now: SystemTime, offset: FixedOffset) -> Vec<DailyTotal> {
+        let data = self.lock_data();
+        let now = data.clock(now);
+        let since = now.checked_sub(Duration::from_secs(24 * 3600)).unwrap_or(SystemTime::UNIX_EPOCH);
+        
+        // 當前未輪轉的數據記在 now，不和歷史重複
+        let history = data.store.range(since, now);
+        let buckets = history.iter()
+            .map(|(timestamp, stats)| (*timestamp, stats))
+            .chain(std::iter::once((now, &data.current)));
+        
+        let mut days: Vec<DailyTotal> = Vec::new();
+        for (timestamp, stats) in buckets {
+            let day_start = midnight(timestamp, offset);
+            let index = match days.iter().position(|d| d.day_start == day_start) {
+                Some(index) => index,
+                None => {
+                    days.push(DailyTotal { day_start, bytes: HashMap::new(), partial: false });
+                    days.len() - 1
+                }
+            };
+            
+            for (service, traffic_data) in stats {
+                *days[index].bytes.entry(service.clone()).or_insert(0) += traffic_data.bytes;
+            }
+        }
+        
+        days.retain(|d| !d.bytes.is_empty());
+        days.sort_by_key(|d| d.day_start);
+        
+        let today = midnight(now, offset);
+        for day in &mut days {
+            day.partial = day.day_start == today;
         }
+        days
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
     
-    #[test]
-    fn test_traffic_stats() {
-        let stats = TrafficStats::new();
+    /// 單個服務按輪轉桶排列的 (時間, 字節數) 序列，當前未輪轉的數據記為現在
+    pub fn timeseries(&self, service: &str) -> Vec<(SystemTime, u64)> {
+        let data = self.lock_data();
+        let mut points: Vec<_> = data.store.get(service).into_iter()
+            .map(|(timestamp, t)| (timestamp, t.bytes))
+            .collect();
         
-        // 添加一些數據
-        stats.add_traffic("netflix", 1024, 10);
-        stats.add_traffic("youtube", 2048, 20);
-        stats.add_traffic("netflix", 512, 5);
+        if let Some(current) = data.current.get(service) {
+            points.push((data.clock(SystemTime::now()), current.bytes));
+        }
         
-        // 檢查統計
-        let result = stats.get_stats();
-        assert_eq!(result.get("netflix").unwrap().0, 1536); // 1024 + 512
-        assert_eq!(result.get("netflix").unwrap().1, 15);   // 10 + 5
-        assert_eq!(result.get("youtube").unwrap().0, 2048);
-        assert_eq!(result.get("youtube").unwrap().1, 20);
+        points
     }
     
-    #[test]
-    fn test_reset_stats() {
-        let stats = TrafficStats::new();
+    /// 單個服務最近 `cells` 個周期字節速率的 sparkline，`interval` 為輪轉間隔
+    pub fn sparkline(&self, service: &str, cells: usize, interval: Duration) -> String {
+        sparkline::render(&sparkline::byte_rates(&self.timeseries(service), interval), cells)
+    }
+    
+    /// Grafana SimpleJSON `/search`：返回可查詢的服務列表
+    pub fn grafana_search(&self) -> Value {
+        let data = self.lock_data();
+        let history = data.history(SystemTime::now());
+        let mut services: Vec<&String> = data.current.keys()
+            .chain(history.iter().flat_map(|(_, stats)| stats.keys()))
+            .filter(|service| !self.is_private(service))
+            .collect();
+        services.sort();
+        services.dedup();
         
-        stats.add_traffic("netflix", 1024, 10);
-        stats.reset_stats();
+        json!(services)
+    }
+    
+    /// Grafana SimpleJSON `/query`：按請求中的 targets 和 range 返回時間序列
+    pub fn grafana_query(&self, request: &Value) -> Value {
+        let parse_time = |key: &str| {
+            request["range"][key].as_str()
+                .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+                .map(SystemTime::from)
+        };
+        let from = parse_time("from");
+        let to = parse_time("to");
         
-        let result = stats.get_stats();
-        assert!(result.is_empty());
+        let targets = request["targets"].as_array().cloned().unwrap_or_default();
+        let series: Vec<Value> = targets.iter()
+            .filter_map(|target| target["target"].as_str())
+            .filter(|service| !self.is_private(service))
+            .map(|service| {
+                let datapoints: Vec<Value> = self.timeseries(service).into_iter()
+                    .filter(|(timestamp, _)| from.is_none_or(|f| *timestamp >= f) && to.is_none_or(|t| *timestamp <= t))
+                    .map(|(timestamp, bytes)| {
+                        let millis = timestamp.duration_since(SystemTime::UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .as_millis() as u64;
+                        json!([bytes, millis])
+                    })
+                    .collect();
+                
+                json!({ "target": service, "datapoints": datapoints })
+            })
+            .collect();
+        
+        Value::Array(series)
     }
-}
\ No newline at end of file
+    
+    /// 根據 Accept 頭選擇輸出格式，返回 (Content-Type, 內容)
+    pub fn export_metrics(&self, accept: Option<&str>) -> (&'static str, String) {
+        let wants_openmetrics = accept
+            .map(|a| a.contains("application/openmetrics-text"))
+            .unwrap_or(false);
+        
+        if wants_openmetrics {
+            (OPENMETRICS_CONTENT_TYPE, self.export_openmetrics())
+        } else {
+            (PROMETHEUS_CONTENT_TYPE, self.export_prometheus())
+        }
+    }
+    
+    pub fn export_prometheus(&self) -> String {
+        let (stats, rates) = self.export_stats_and_rates();
+        let mut out = String::new();
+        
+        out.push_str("# HELP trafficmon_bytes_total Bytes seen per service.\n");
+        out.push_str("# TYPE trafficmon_bytes_total counter\n");
+        for (service, data) in &stats {
+            out.push_str(&format!("trafficmon_bytes_total{{service=\"{}\"}} {}\n", escape_label_value(service), data.bytes));
+        }
+        
+        out.push_str("# HELP trafficmon_packets_total Packets seen per service.\n");
+        out.push_str("# TYPE trafficmon_packets_total counter\n");
+        for (service, data) in &stats {
+            out.push_str(&format!("trafficmon_packets_total{{service=\"{}\"}} {}\n", escape_label_value(service), data.packets));
+        }
+        
+        out.push_str("# HELP trafficmon_packet_rate Average packets per second per service.\n");
+        out.push_str("# TYPE trafficmon_packet_rate gauge\n");
+        for (service, _) in &stats {
+            out.push_str(&format!("trafficmon_packet_rate{{service=\"{}\"}} {}\n", escape_label_value(service), rates.get(service).copied().unwrap_or(0.0)));
+        }
+        
+        let interfaces = self.export_interface_stats();
+        if !interfaces.is_empty() {
+            out.push_str("# HELP trafficmon_interface_bytes_total Bytes seen per capture interface and service.\n");
+            out.push_str("# TYPE trafficmon_interface_bytes_total counter\n");
+            out.push_str(&interface_series("trafficmon_interface_bytes_total", &interfaces, |(bytes, _)| bytes));
+            out.push_str("# HELP trafficmon_interface_packets_total Packets seen per capture interface and service.\n");
+            out.push_str("# TYPE trafficmon_interface_packets_total counter\n");
+            out.push_str(&interface_series("trafficmon_interface_packets_total", &interfaces, |(_, packets)| packets));
+        }
+        
+        out.push_str("# HELP trafficmon_queue_dropped_total Packets dropped because the capture queue was full.\n");
+        out.push_str("# TYPE trafficmon_queue_dropped_total counter\n");
+        out.push_str(&format!("trafficmon_queue_dropped_total {}\n", self.queue_drops()));
+        
+        out.push_str("# HELP trafficmon_classification_timeouts_total Packets classified by port because payload parsing exceeded the time budget.\n");
+        out.push_str("# TYPE trafficmon_classification_timeouts_total counter\n");
+        out.push_str(&format!("trafficmon_classification_timeouts_total {}\n", self.classification_timeouts()));
+        
+        out.push_str("# HELP trafficmon_ttl_anomalies_total Packets whose TTL differed sharply from the previous packet from the same source.\n");
+        out.push_str("# TYPE trafficmon_ttl_anomalies_total counter\n");
+        out.push_str(&format!("trafficmon_ttl_anomalies_total {}\n", self.ttl_anomalies()));
+        
+        out.push_str("# HELP trafficmon_ttl_sources Source addresses whose TTL is being tracked.\n");
+        out.push_str("# TYPE trafficmon_ttl_sources gauge\n");
+        out.push_str(&format!("trafficmon_ttl_sources {}\n", self.ttl_sources()));
+        
+        out
+    }
+    
+    /// 供 node_exporter 的 textfile 收集器讀取：先寫同目錄下的 `<path>.tmp` 再改名，
+    /// 收集器不會讀到寫了一半的文件（臨時文件不以 .prom 結尾，也不會被收集）
+    pub fn export_prometheus_textfile(&self, path: &Path) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        
+        let mut file = File::create(&tmp)?;
+        file.write_all(self.export_prometheus().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+    
+    pub fn export_openmetrics(&self) -> String {
+        let (stats, rates) = self.export_stats_and_rates();
+        let mut out = String::new();
+        
+        // OpenMetrics 中 counter 的 family 名稱不帶 _total，樣本名稱必須帶 _total
+        out.push_str("# TYPE trafficmon_bytes counter\n");
+        out.push_str("# UNIT trafficmon_bytes bytes\n");
+        out.push_str("# HELP trafficmon_bytes Bytes seen per service.\n");
+        for (service, data) in &stats {
+            out.push_str(&format!("trafficmon_bytes_total{{service=\"{}\"}} {}\n", escape_label_value(service), data.bytes));
+        }
+        
+        out.push_str("# TYPE trafficmon_packets counter\n");
+        out.push_str("# HELP trafficmon_packets Packets seen per service.\n");
+        for (service, data) in &stats {
+            out.push_str(&format!("trafficmon_packets_total{{service=\"{}\"}} {}\n", escape_label_value(service), data.packets));
+        }
+        
+        out.push_str("# TYPE trafficmon_packet_rate gauge\n");
+        out.push_str("# HELP trafficmon_packet_rate Average packets per second per service.\n");
+        for (service, _) in &stats {
+            out.push_str(&format!("trafficmon_packet_rate{{service=\"{}\"}} {}\n", escape_label_value(service), rates.get(service).copied().unwrap_or(0.0)));
+        }
+        
+        let interfaces = self.export_interface_stats();
+        if !interfaces.is_empty() {
+            out.push_str("# TYPE trafficmon_interface_bytes counter\n");
+            out.push_str("# UNIT trafficmon_interface_bytes bytes\n");
+            out.push_str("# HELP trafficmon_interface_bytes Bytes seen per capture interface and service.\n");
+            out.push_str(&interface_series("trafficmon_interface_bytes_total", &interfaces, |(bytes, _)| bytes));
+            out.push_str("# TYPE trafficmon_interface_packets counter\n");
+            out.push_str("# HELP trafficmon_interface_packets Packets seen per capture interface and service.\n");
+            out.push_str(&interface_series("trafficmon_interface_packets_total", &interfaces, |(_, packets)| packets));
+        }
+        
+        out.push_str("# TYPE trafficmon_queue_dropped counter\n");
+        out.push_str("# HELP trafficmon_queue_dropped Packets dropped because the capture queue was full.\n");
+        out.push_str(&format!("trafficmon_queue_dropped_total {}\n", self.queue_drops()));
+        
+        out.push_str("# TYPE trafficmon_classification_timeouts counter\n");
+        out.push_str("# HELP trafficmon_classification_timeouts Packets classified by port because payload parsing exceeded the time budget.\n");
+        out.push_str(&format!("trafficmon_classification_timeouts_total {}\n", self.classification_timeouts()));
+        
+        out.push_str("# TYPE trafficmon_ttl_anomalies counter\n");
+        out.push_str("# HELP trafficmon_ttl_anomalies Packets whose TTL differed sharply from the previous packet from the same source.\n");
+        out.push_str(&format!("trafficmon_ttl_anomalies_total {}\n", self.ttl_anomalies()));
+        
+        out.push_str("# TYPE trafficmon_ttl_sources gauge\n");
+        out.push_str("# HELP trafficmon_ttl_sources Source addresses whose TTL is being tracked.\n");
+        out.push_str(&format!("trafficmon_ttl_sources {}\n", self.ttl_sources()));
+        
+        out.push_str("# EOF\n");
+        out
+    }
+    
+    /// 比較兩份序列化的統計快照（`get_stats` 或 `get_detailed_stats` 的 JSON），
+    /// 返回各服務從 a 到 b 的字節/包數變化；只在一方出現的服務按 0 計算
+    pub fn diff(a_json: &str, b_json: &str) -> Result<HashMap<String, (i64, i64)>, serde_json::Error> {
+        let a = snapshot_totals(&serde_json::from_str(a_json)?);
+        let b = snapshot_totals(&serde_json::from_str(b_json)?);
+        
+        let mut result = HashMap::new();
+        for service in a.keys().chain(b.keys()) {
+            let (a_bytes, a_packets) = a.get(service).copied().unwrap_or((0, 0));
+            let (b_bytes, b_packets) = b.get(service).copied().unwrap_or((0, 0));
+            result.insert(service.clone(), (b_bytes - a_bytes, b_packets - a_packets));
+        }
+        
+        Ok(result)
+    }
+    
+    /// 以 MessagePack 編碼詳細統計，內容與 `get_detailed_stats` 的 JSON 相同但體積更小，
+    /// 字段按名稱編碼，兩端版本不同時也能解碼
+    pub fn to_msgpack(&self) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+        let mut stats = self.get_detailed_stats();
+        stats.retain(|service, _| !self.is_private(service));
+        rmp_serde::to_vec_named(&stats)
+    }
+    
+    /// 解碼 `to_msgpack` 的輸出，可交給 `merge`
+    pub fn from_msgpack(bytes: &[u8]) -> Result<HashMap<String, TrafficData>, rmp_serde::decode::Error> {
+        rmp_serde::from_slice(bytes)
+    }
+    
+    /// 導出用的詳細統計，按服務名排序，不含私有服務；
+    /// 設置了 `export_top` 時其餘服務合併為 "__other__"
+    pub fn export_stats(&self) -> Vec<(String, TrafficData)> {
+        self.export_stats_and_rates().0
+    }
+    
+    /// 導出的統計和對應的包速率，"__other__" 的速率為被合併服務的速率之和
+    fn export_stats_and_rates(&self) -> (Vec<(String, TrafficData)>, HashMap<String, f64>) {
+        let mut stats: Vec<_> = self.get_detailed_stats().into_iter()
+            .filter(|(service, _)| !self.is_private(service))
+            .collect();
+        let mut rates = self.get_packet_rates();
+        
+        if let Some(top) = self.export_top {
+            stats.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then_with(|| a.0.cmp(&b.0)));
+            let rest = stats.split_off(top.min(stats.len()));
+            let mut other = self.export_other.lock().unwrap();
+            let totals: Vec<_> = rest.iter()
+                .map(|(service, data)| (service.clone(), (data.bytes, data.packets)))
+                .collect();
+            let named = stats.iter().map(|(service, _)| service.clone()).collect();
+            if let Some((bytes, packets)) = other.update(named, &totals) {
+                for (_, data) in &rest {
+                    other.first_seen = Some(other.first_seen.map_or(data.first_seen, |t| t.min(data.first_seen)));
+                    other.last_seen = Some(other.last_seen.map_or(data.last_seen, |t| t.max(data.last_seen)));
+                }
+                let now = SystemTime::now();
+                stats.push((OTHER_SERVICE.to_string(), TrafficData {
+                    bytes,
+                    packets,
+                    first_seen: other.first_seen.unwrap_or(now),
+                    last_seen: other.last_seen.unwrap_or(now),
+                }));
+                let other_rate = rest.iter().map(|(service, _)| rates.remove(service).unwrap_or(0.0)).sum();
+                rates.insert(OTHER_SERVICE.to_string(), other_rate);
+            }
+        }
+        
+        stats.sort_by(|a, b| a.0.cmp(&b.0));
+        (stats, rates)
+    }
+}
+
+/// `export_top` 之外的服務在導出中合併成的服務名，不會與分類出的 "other" 重名
+pub const OTHER_SERVICE: &str = "__other__";
+
+/// 導出中合併的服務序列。只增不減：服務進入前 N 名時把它已計入的量留在這裡，
+/// 之前單獨導出過的服務併入時只計併入之後的增量
+#[derive(Debug, Default)]
+struct OtherSeries {
+    /// 上次單獨導出的服務
+    named: HashSet<String>,
+    /// 當前合併的服務
+    members: HashMap<String, OtherMember>,
+    /// 已離開的服務計入過的字節數和包數
+    carried: (u64, u64),
+    first_seen: Option<SystemTime>,
+    last_seen: Option<SystemTime>,
+}
+
+#[derive(Debug)]
+struct OtherMember {
+    /// 併入時的字節數和包數，從未單獨導出過時為 0
+    base: (u64, u64),
+    /// 已計入合併序列的字節數和包數
+    counted: (u64, u64),
+}
+
+impl OtherSeries {
+    /// 按本次單獨導出的 `named` 和合併的 `rest` 更新，返回合併序列的字節數和包數；
+    /// 從未合併過任何服務時返回 None
+    fn update(&mut self, named: HashSet<String>, rest: &[(String, (u64, u64))]) -> Option<(u64, u64)> {
+        let merged: HashSet<&str> = rest.iter().map(|(service, _)| service.as_str()).collect();
+        let carried = &mut self.carried;
+        self.members.retain(|service, member| {
+            let keep = merged.contains(service.as_str());
+            if !keep {
+                carried.0 += member.counted.0;
+                carried.1 += member.counted.1;
+            }
+            keep
+        });
+        
+        for (service, (bytes, packets)) in rest {
+            let member = self.members.entry(service.clone()).or_insert_with(|| {
+                let base = if self.named.contains(service) { (*bytes, *packets) } else { (0, 0) };
+                OtherMember { base, counted: (0, 0) }
+            });
+            // 歷史桶過期時服務的總量會變小，已計入的量不退回
+            member.counted.0 = member.counted.0.max(bytes.saturating_sub(member.base.0));
+            member.counted.1 = member.counted.1.max(packets.saturating_sub(member.base.1));
+        }
+        self.named = named;
+        
+        if self.members.is_empty() && self.carried == (0, 0) {
+            return None;
+        }
+        Some(self.members.values().fold(self.carried, |(bytes, packets), member| (bytes + member.counted.0, packets + member.counted.1)))
+    }
+}
+/// 超出 `max_services` 後新出現的服務計入的服務名
+pub const OVERFLOW_SERVICE: &str = "overflow";
+
+/// 每個接口和服務一行 `name{interface="..",service=".."} value`
+fn interface_series(name: &str, interfaces: &SortedInterfaceStats, value: impl Fn((u64, u64)) -> u64) -> String {
+    let mut out = String::new();
+    for (interface, services) in interfaces {
+        for (service, totals) in services {
+            out.push_str(&format!(
+                "{}{{interface=\"{}\",service=\"{}\"}} {}\n",
+                name, escape_label_value(interface), escape_label_value(service), value(*totals)
+            ));
+        }
+    }
+    out
+}
+
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+pub const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+// 支持 {"svc": [bytes, packets]} 和 {"svc": {"bytes": .., "packets": ..}} 兩種格式
+fn snapshot_totals(snapshot: &Value) -> HashMap<String, (i64, i64)> {
+    let Some(services) = snapshot.as_object() else {
+        return HashMap::new();
+    };
+    
+    // 長度不足的數組條目跳過，不讓手工編輯過的快照導致崩潰
+    services.iter()
+        .filter_map(|(service, value)| {
+            let (bytes, packets) = match value {
+                Value::Array(pair) => (pair.first()?, pair.get(1)?),
+                other => (&other["bytes"], &other["packets"]),
+            };
+            Some((service.clone(), (bytes.as_i64().unwrap_or(0), packets.as_i64().unwrap_or(0))))
+        })
+        .collect()
+}
+
+/// 累加到桶中同一服務的數據，時間範圍取並集
+fn merge_traffic(bucket: &mut Bucket, service: String, traffic_data: TrafficData) {
+    match bucket.get_mut(&service) {
+        Some(current) => {
+            current.bytes += traffic_data.bytes;
+            current.packets += traffic_data.packets;
+            current.first_seen = current.first_seen.min(traffic_data.first_seen);
+            current.last_seen = current.last_seen.max(traffic_data.last_seen);
+        }
+        None => {
+            bucket.insert(service, traffic_data);
+        }
+    }
+}
+
+/// 時間點所在日的午夜
+fn midnight(time: SystemTime, offset: FixedOffset) -> DateTime<FixedOffset> {
+    let local = DateTime::<Utc>::from(time).with_timezone(&offset);
+    let start = local.date_naive().and_time(NaiveTime::MIN);
+    offset.from_local_datetime(&start).single().unwrap_or(local)
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// 當前線程使用的寫入分片
+fn shard_index() -> usize {
+    let mut hasher = DefaultHasher::new();
+    thread::current().id().hash(&mut hasher);
+    hasher.finish() as usize % WRITE_SHARDS
+}
+
+/// 後台定時把緩衝的統計寫入存儲，崩潰時最多丟失一個間隔的數據；
+/// 停止（或 drop）時立即再寫一次
+#[derive(Debug)]
+pub struct FlushTimer {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl FlushTimer {
+    pub fn start(stats: Arc<TrafficStats>, interval: Duration) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&stop);
+        
+        let handle = thread::spawn(move || {
+            let mut next = Instant::now() + interval;
+            while !flag.load(Ordering::SeqCst) {
+                let now = Instant::now();
+                if now >= next {
+                    stats.flush();
+                    next = now + interval;
+                }
+                // stop 時會喚醒，不必等到間隔結束
+                thread::park_timeout(next.saturating_duration_since(now));
+            }
+            stats.flush();
+        });
+        
+        Self { stop, handle: Some(handle) }
+    }
+    
+    /// 停止定時器，返回前完成最後一次寫入
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+    
+    fn shutdown(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            self.stop.store(true, Ordering::SeqCst);
+            handle.thread().unpark();
+            if handle.join().is_err() {
+                eprintln!("Stats flush thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for FlushTimer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// 啟動時及每隔 `interval` 把 Prometheus 格式的統計寫入 textfile 收集器目錄下的文件，
+/// 不依賴其他讀取方觸發輪轉
+#[derive(Debug)]
+pub struct PrometheusTextfile {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl PrometheusTextfile {
+    pub fn start(stats: Arc<TrafficStats>, path: PathBuf, interval: Duration) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&stop);
+        
+        let handle = thread::spawn(move || {
+            let mut next = Instant::now();
+            while !flag.load(Ordering::SeqCst) {
+                let now = Instant::now();
+                if now >= next {
+                    next = now + interval;
+                    if let Err(e) = stats.export_prometheus_textfile(&path) {
+                        eprintln!("Failed to write {}: {}", path.display(), e);
+                    }
+                }
+                thread::park_timeout(TEXTFILE_POLL_INTERVAL.min(next.saturating_duration_since(now)));
+            }
+        });
+        
+        Self { stop, handle: Some(handle) }
+    }
+}
+
+impl Drop for PrometheusTextfile {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Default for TrafficStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_traffic_stats() {
+        let stats = TrafficStats::new();
+        
+        // 添加一些數據
+        stats.add_traffic("netflix", 1024, 10);
+        stats.add_traffic("youtube", 2048, 20);
+        stats.add_traffic("netflix", 512, 5);
+        
+        // 檢查統計
+        let result = stats.get_stats();
+        assert_eq!(result.get("netflix").unwrap().0, 1536); // 1024 + 512
+        assert_eq!(result.get("netflix").unwrap().1, 15);   // 10 + 5
+        assert_eq!(result.get("youtube").unwrap().0, 2048);
+        assert_eq!(result.get("youtube").unwrap().1, 20);
+    }
+    
+    #[test]
+    fn test_rfc3339_timestamps() {
+        let data = TrafficData {
+            bytes: 1024,
+            packets: 1,
+            first_seen: SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+            last_seen: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_060),
+        };
+        
+        let json = serde_json::to_value(&data).unwrap();
+        assert_eq!(json["first_seen"], "2023-11-14T22:13:20.123Z");
+        let last_seen = DateTime::parse_from_rfc3339(json["last_seen"].as_str().unwrap()).unwrap();
+        assert_eq!(SystemTime::from(last_seen), data.last_seen);
+        
+        // 詳細統計導出同樣使用 RFC 3339
+        let stats = TrafficStats::new();
+        stats.add_traffic("netflix", 1024, 1);
+        let exported = serde_json::to_value(stats.get_detailed_stats()).unwrap();
+        assert!(DateTime::parse_from_rfc3339(exported["netflix"]["first_seen"].as_str().unwrap()).is_ok());
+    }
+    
+    #[test]
+    fn test_reset_stats() {
+        let stats = TrafficStats::new();
+        
+        stats.add_traffic("netflix", 1024, 10);
+        stats.reset_stats();
+        
+        let result = stats.get_stats();
+        assert!(result.is_empty());
+    }
+    
+    #[test]
+    fn test_openmetrics_export() {
+        let stats = TrafficStats::new();
+        stats.add_traffic("netflix", 1024, 10);
+        
+        let (content_type, body) = stats.export_metrics(Some("application/openmetrics-text; version=1.0.0"));
+        assert_eq!(content_type, OPENMETRICS_CONTENT_TYPE);
+        assert!(body.ends_with("# EOF\n"));
+        assert!(body.contains("# TYPE trafficmon_bytes counter\n"));
+        assert!(body.contains("# TYPE trafficmon_packets counter\n"));
+        assert!(body.contains("# UNIT trafficmon_bytes bytes\n"));
+        assert!(body.contains("trafficmon_bytes_total{service=\"netflix\"} 1024\n"));
+        assert!(body.contains("trafficmon_queue_dropped_total 0\n"));
+        
+        // counter 的樣本行都必須以 _total 結尾的名稱開頭
+        let counters: Vec<&str> = body.lines()
+            .filter_map(|l| l.strip_prefix("# TYPE "))
+            .filter_map(|l| l.strip_suffix(" counter"))
+            .collect();
+        for line in body.lines().filter(|l| !l.starts_with('#')) {
+            let name = line.split(['{', ' ']).next().unwrap();
+            if counters.iter().any(|family| name.starts_with(family)) {
+                assert!(name.ends_with("_total"), "{}", line);
+            }
+        }
+        assert!(body.contains("# TYPE trafficmon_packet_rate gauge\n"));
+        
+        let (content_type, body) = stats.export_metrics(None);
+        assert_eq!(content_type, PROMETHEUS_CONTENT_TYPE);
+        assert!(!body.contains("# EOF"));
+    }
+    
+    #[test]
+    fn test_prometheus_textfile() {
+        use std::os::unix::fs::MetadataExt;
+        
+        let dir = std::env::temp_dir().join(format!("trafficmon-textfile-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("trafficmon.prom");
+        fs::write(&path, "stale").unwrap();
+        let before = fs::metadata(&path).unwrap().ino();
+        
+        let stats = Arc::new(TrafficStats::new());
+        stats.add_traffic("netflix", 1024, 10);
+        // 啟動時立即寫入，不等輪轉
+        let exporter = PrometheusTextfile::start(Arc::clone(&stats), path.clone(), Duration::from_secs(3600));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while fs::read_to_string(&path).unwrap() == "stale" && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        drop(exporter);
+        
+        // 改名替換而不是原地改寫，也不留下臨時文件
+        assert_ne!(fs::metadata(&path).unwrap().ino(), before);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        
+        // 每行都是註釋或 `名稱{標籤} 數值`，且樣本屬於聲明過類型的指標
+        let body = fs::read_to_string(&path).unwrap();
+        let families: Vec<&str> = body.lines()
+            .filter_map(|l| l.strip_prefix("# TYPE "))
+            .filter_map(|l| l.split(' ').next())
+            .collect();
+        for line in body.lines().filter(|l| !l.starts_with('#')) {
+            let (series, value) = line.rsplit_once(' ').unwrap();
+            assert!(value.parse::<f64>().is_ok(), "{}", line);
+            let name = series.split('{').next().unwrap();
+            assert!(families.contains(&name), "{}", line);
+            assert!(name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'), "{}", line);
+        }
+        assert!(body.contains("trafficmon_bytes_total{service=\"netflix\"} 1024\n"));
+        
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    
+    #[test]
+    fn test_grafana_simplejson() {
+        let stats = TrafficStats::new();
+        stats.add_traffic("youtube", 2048, 20);
+        stats.add_traffic("netflix", 1024, 10);
+        
+        assert_eq!(stats.grafana_search(), json!(["netflix", "youtube"]));
+        
+        let response = stats.grafana_query(&json!({
+            "range": { "from": "2000-01-01T00:00:00Z", "to": "2100-01-01T00:00:00Z" },
+            "targets": [{ "target": "netflix", "type": "timeserie" }]
+        }));
+        
+        let series = response.as_array().unwrap();
+        assert_eq!(series.len(), 1);
+        assert_eq!(series[0]["target"], "netflix");
+        let datapoints = series[0]["datapoints"].as_array().unwrap();
+        assert_eq!(datapoints.len(), 1);
+        assert_eq!(datapoints[0][0], 1024);
+        assert!(datapoints[0][1].as_u64().unwrap() > 0);
+    }
+    
+    #[test]
+    fn test_snapshot_diff() {
+        let before = TrafficStats::new();
+        before.add_traffic("netflix", 1000, 10);
+        before.add_traffic("youtube", 500, 5);
+        let a = serde_json::to_string(&before.get_detailed_stats()).unwrap();
+        
+        let after = TrafficStats::new();
+        after.add_traffic("netflix", 400, 4);
+        after.add_traffic("dns", 300, 3);
+        let b = serde_json::to_string(&after.get_stats()).unwrap();
+        
+        let diff = TrafficStats::diff(&a, &b).unwrap();
+        assert_eq!(diff["netflix"], (-600, -6));
+        assert_eq!(diff["youtube"], (-500, -5));
+        assert_eq!(diff["dns"], (300, 3));
+        assert_eq!(diff.len(), 3);
+        
+        assert!(TrafficStats::diff("not json", &b).is_err());
+        
+        let diff = TrafficStats::diff(r#"{"netflix": [100], "dns": []}"#, r#"{"dns": [10, 1]}"#).unwrap();
+        assert_eq!(diff, HashMap::from([("dns".to_string(), (10, 1))]));
+    }
+    
+    #[test]
+    fn test_msgpack_round_trip() {
+        let stats = TrafficStats::new();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        for (i, service) in ["netflix", "youtube", "dns", "https", "ssh"].iter().enumerate() {
+            stats.add_traffic_at(service, 1_000_000 * (i as u64 + 1), 700 + i as u64, start);
+            stats.add_traffic_at(service, 1500, 1, start + Duration::from_millis(61_250));
+        }
+        
+        let packed = stats.to_msgpack().unwrap();
+        let json = serde_json::to_string(&stats.get_detailed_stats()).unwrap();
+        assert!(packed.len() < json.len(), "{} >= {}", packed.len(), json.len());
+        
+        let from_msgpack = TrafficStats::from_msgpack(&packed).unwrap();
+        let from_json: HashMap<String, TrafficData> = serde_json::from_str(&json).unwrap();
+        assert_eq!(from_msgpack, from_json);
+        assert_eq!(from_msgpack["youtube"].bytes, 2_001_500);
+        assert_eq!(from_msgpack["youtube"].last_seen, start + Duration::from_millis(61_250));
+        
+        // 中心節點合併多個實例的快照
+        let central = TrafficStats::new();
+        central.merge(from_msgpack.clone());
+        central.merge(from_msgpack);
+        assert_eq!(central.get_stats()["dns"], (2 * 3_001_500, 2 * 703));
+        
+        assert!(TrafficStats::from_msgpack(b"not msgpack").is_err());
+    }
+    
+    #[test]
+    fn test_packet_rates() {
+        let stats = TrafficStats::new();
+        let start = SystemTime::now();
+        
+        // 10 秒內 dns 共 500 包，syn 洪水 10 秒內 20000 個小包
+        stats.add_traffic_at("dns", 30_000, 200, start);
+        stats.add_traffic_at("dns", 45_000, 300, start + Duration::from_secs(10));
+        stats.add_traffic_at("syn_flood", 800_000, 10_000, start);
+        stats.add_traffic_at("syn_flood", 800_000, 10_000, start + Duration::from_secs(10));
+        
+        let rates = stats.get_packet_rates();
+        assert!((rates["dns"] - 50.0).abs() < 1e-9);
+        assert!((rates["syn_flood"] - 2000.0).abs() < 1e-9);
+        
+        assert!(stats.export_prometheus().contains("trafficmon_packet_rate{service=\"dns\"} 50\n"));
+    }
+    
+    #[test]
+    fn test_byte_rate_ignores_wall_clock_jump() {
+        let stats = TrafficStats::new();
+        let wall = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let start = Instant::now();
+        
+        // 單調時鐘過了 10 秒，期間 NTP 把系統時鐘撥快了 1 天
+        stats.add_traffic_with_clocks("netflix", 50_000, 50, wall, start);
+        stats.add_traffic_with_clocks("netflix", 50_000, 50, wall + Duration::from_secs(86_400), start + Duration::from_secs(10));
+        assert_eq!(stats.activity_span(), Some(Duration::from_secs(10)));
+        assert!((stats.byte_rate() - 10_000.0).abs() < 1e-9);
+        
+        // 撥慢時同樣不受影響，first_seen 仍按牆上時間顯示
+        let earlier = wall - Duration::from_secs(3600);
+        stats.add_traffic_with_clocks("netflix", 100_000, 100, earlier, start + Duration::from_secs(20));
+        assert!((stats.byte_rate() - 10_000.0).abs() < 1e-9);
+        assert_eq!(stats.get_service_stats("netflix").unwrap().first_seen, earlier);
+        
+        stats.reset_stats();
+        assert_eq!(stats.activity_span(), None);
+        assert_eq!(stats.byte_rate(), 0.0);
+    }
+    
+    #[test]
+    fn test_daily_totals_across_midnight() {
+        let stats = TrafficStats::new();
+        let utc = FixedOffset::east_opt(0).unwrap();
+        let midnight: SystemTime = utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap().into();
+        let at = |h: u64, m: u64| midnight + Duration::from_secs(h * 3600 + m * 60);
+        let bucket = |service: &str, bytes: u64| {
+            let now = SystemTime::now();
+            HashMap::from([(service.to_string(), TrafficData { bytes, packets: 1, first_seen: now, last_seen: now })])
+        };
+        
+        {
+            let mut data = stats.data.lock().unwrap();
+            // 前一天 22:00 和 23:30，以及當天 00:30
+            data.store.put(at(0, 0) - Duration::from_secs(2 * 3600), bucket("netflix", 100));
+            data.store.put(at(0, 0) - Duration::from_secs(30 * 60), bucket("netflix", 200));
+            data.store.put(at(0, 30), bucket("netflix", 400));
+            // 超過 24 小時的不計入
+            data.store.put(at(0, 0) - Duration::from_secs(30 * 3600), bucket("netflix", 9999));
+            data.current = bucket("youtube", 50);
+        }
+        
+        let days = stats.daily_totals_at(at(1, 0), utc);
+        assert_eq!(days.len(), 2);
+        
+        assert_eq!(days[0].day_start.to_rfc3339(), "2024-02-29T00:00:00+00:00");
+        assert_eq!(days[0].bytes["netflix"], 300);
+        assert!(!days[0].partial);
+        
+        assert_eq!(days[1].day_start.to_rfc3339(), "2024-03-01T00:00:00+00:00");
+        assert_eq!(days[1].bytes["netflix"], 400);
+        assert_eq!(days[1].bytes["youtube"], 50);
+        assert!(days[1].partial);
+    }
+    
+    #[test]
+    fn test_top_services_window() {
+        let stats = TrafficStats::new();
+        let now = SystemTime::now();
+        let bucket = |entries: &[(&str, u64)]| {
+            entries.iter()
+                .map(|(service, bytes)| (service.to_string(), TrafficData { bytes: *bytes, packets: 1, first_seen: now, last_seen: now }))
+                .collect::<HashMap<_, _>>()
+        };
+        
+        {
+            let mut data = stats.data.lock().unwrap();
+            // 10 分鐘前 netflix 很大，最近兩個桶 youtube 領先
+            data.store.put(now - Duration::from_secs(600), bucket(&[("netflix", 10_000), ("youtube", 100)]));
+            data.store.put(now - Duration::from_secs(50), bucket(&[("netflix", 200), ("youtube", 500)]));
+            data.store.put(now - Duration::from_secs(20), bucket(&[("youtube", 300), ("dns", 200)]));
+            data.current = bucket(&[("dns", 100), ("netflix", 100)]);
+        }
+        
+        let top = stats.top_services_window_at(2, Duration::from_secs(60), now);
+        assert_eq!(top, vec![("youtube".to_string(), 800), ("dns".to_string(), 300)]);
+        
+        let top = stats.top_services_window_at(3, Duration::from_secs(30), now);
+        assert_eq!(top, vec![("dns".to_string(), 300), ("youtube".to_string(), 300), ("netflix".to_string(), 100)]);
+        
+        // 整個保留期內 netflix 居首，且當前數據只計一次
+        let top = stats.top_services_window_at(1, Duration::from_secs(3600), now);
+        assert_eq!(top, vec![("netflix".to_string(), 10_300)]);
+        
+        // 查詢不會輪轉當前數據
+        assert_eq!(stats.data.lock().unwrap().history(now).len(), 3);
+    }
+    
+    #[test]
+    fn test_detailed_stats_bucket_limit() {
+        let now = SystemTime::now();
+        let fill = |stats: &TrafficStats| {
+            let mut data = stats.data.lock().unwrap();
+            for (age, bytes) in [(300, 1000), (200, 100), (100, 10)] {
+                let at = now - Duration::from_secs(age);
+                data.store.put(at, HashMap::from([("netflix".to_string(), TrafficData { bytes, packets: 1, first_seen: at, last_seen: at })]));
+            }
+            drop(data);
+            stats.add_traffic_at("netflix", 1, 1, now);
+        };
+        
+        // 當前數據輪轉後成為最新的桶，只再合併一個歷史桶
+        let config = Config { max_history_buckets: Some(2), ..Config::default() };
+        let stats = TrafficStats::from_config(&config);
+        fill(&stats);
+        let detailed = stats.get_detailed_stats_at(now);
+        assert_eq!(detailed["netflix"].bytes, 11);
+        assert_eq!(detailed["netflix"].packets, 2);
+        assert_eq!(detailed["netflix"].first_seen, now - Duration::from_secs(100));
+        
+        // 不限時合併保留期內的全部桶
+        let stats = TrafficStats::new();
+        fill(&stats);
+        assert_eq!(stats.get_detailed_stats_at(now)["netflix"].bytes, 1111);
+    }
+    
+    #[test]
+    fn test_private_services_not_exported() {
+        let stats = TrafficStats::from_config(&Config { private_services: vec!["intranet".to_string()], ..Config::default() });
+        stats.add_traffic("intranet", 4096, 4);
+        stats.add_traffic("netflix", 1024, 1);
+        
+        // 內部統計照常
+        assert_eq!(stats.get_stats()["intranet"], (4096, 4));
+        assert_eq!(stats.top_services(5)[0], ("intranet".to_string(), 4096));
+        
+        for body in [stats.export_prometheus(), stats.export_openmetrics()] {
+            assert!(body.contains(r#"service="netflix""#));
+            assert!(!body.contains("intranet"));
+        }
+        assert_eq!(stats.grafana_search(), json!(["netflix"]));
+        let query = json!({ "targets": [{ "target": "intranet" }, { "target": "netflix" }] });
+        assert_eq!(stats.grafana_query(&query).as_array().unwrap().len(), 1);
+        
+        let exported = TrafficStats::from_msgpack(&stats.to_msgpack().unwrap()).unwrap();
+        assert!(exported.contains_key("netflix"));
+        assert!(!exported.contains_key("intranet"));
+    }
+    
+    #[test]
+    fn test_export_top_services() {
+        let stats = TrafficStats::from_config(&Config { export_top_services: Some(5), ..Config::default() });
+        for i in 1..=10u64 {
+            stats.add_traffic(&format!("svc{:02}", i), i * 100, i);
+        }
+        
+        let exported = stats.export_stats();
+        assert_eq!(exported.len(), 6);
+        let other = &exported.iter().find(|(service, _)| service == OTHER_SERVICE).unwrap().1;
+        assert_eq!((other.bytes, other.packets), (1500, 15));
+        assert!(exported.iter().any(|(service, data)| service == "svc10" && data.bytes == 1000));
+        assert!(!exported.iter().any(|(service, _)| service == "svc05"));
+        
+        for body in [stats.export_prometheus(), stats.export_openmetrics()] {
+            let series: Vec<&str> = body.lines().filter(|line| line.starts_with("trafficmon_bytes_total{")).collect();
+            assert_eq!(series.len(), 6);
+            assert!(series.contains(&r#"trafficmon_bytes_total{service="__other__"} 1500"#));
+            assert_eq!(body.lines().filter(|line| line.starts_with("trafficmon_packet_rate{")).count(), 6);
+        }
+        
+        // 內部統計不受影響
+        assert_eq!(stats.get_stats().len(), 10);
+        
+        // svc05 進入前 5 名、svc06 掉出：svc05 計入過的 500 留在合併序列中，
+        // svc06 只計掉出之後的增量，合併序列不回退
+        stats.add_traffic("svc05", 1500, 1);
+        let other = |stats: &TrafficStats| {
+            let exported = stats.export_stats();
+            let other = &exported.iter().find(|(service, _)| service == OTHER_SERVICE).unwrap().1;
+            (other.bytes, other.packets)
+        };
+        assert_eq!(other(&stats), (1500, 15));
+        assert!(stats.export_stats().iter().any(|(service, data)| service == "svc05" && data.bytes == 2000));
+        stats.add_traffic("svc06", 50, 1);
+        assert_eq!(other(&stats), (1550, 16));
+        
+        // 分類出的 "other" 服務照常單獨導出
+        stats.add_traffic("other", 5000, 5);
+        let exported = stats.export_stats();
+        assert!(exported.iter().any(|(service, data)| service == "other" && data.bytes == 5000));
+    }
+    
+    #[test]
+    fn test_export_top_interface_services() {
+        let stats = TrafficStats::from_config(&Config { export_top_services: Some(2), ..Config::default() });
+        for i in 1..=4u64 {
+            stats.add_interface_traffic("eth0", &format!("svc{}", i), i * 100, i);
+        }
+        stats.add_interface_traffic("wlan0", "svc1", 10, 1);
+        
+        assert_eq!(stats.export_interface_stats(), vec![
+            ("eth0".to_string(), vec![
+                (OTHER_SERVICE.to_string(), (300, 3)),
+                ("svc3".to_string(), (300, 3)),
+                ("svc4".to_string(), (400, 4)),
+            ]),
+            ("wlan0".to_string(), vec![("svc1".to_string(), (10, 1))]),
+        ]);
+        
+        // svc1 進入前 2 名後，eth0 的合併序列保留它之前計入的量
+        stats.add_interface_traffic("eth0", "svc1", 1000, 1);
+        let eth0 = &stats.export_interface_stats()[0].1;
+        assert!(eth0.contains(&(OTHER_SERVICE.to_string(), (300, 3))));
+        assert!(eth0.contains(&("svc1".to_string(), (1100, 2))));
+    }
+    
+    #[test]
+    fn test_interface_labels() {
+        let stats = TrafficStats::new().with_private_services(&["intranet".to_string()]);
+        stats.add_interface_traffic("eth0", "netflix", 1000, 2);
+        stats.add_interface_traffic("wlan0", "netflix", 300, 1);
+        stats.add_interface_traffic("wlan0", "intranet", 50, 1);
+        
+        let body = stats.export_prometheus();
+        let series: Vec<&str> = body.lines().filter(|line| line.starts_with("trafficmon_interface_bytes_total{")).collect();
+        assert_eq!(series, [
+            r#"trafficmon_interface_bytes_total{interface="eth0",service="netflix"} 1000"#,
+            r#"trafficmon_interface_bytes_total{interface="wlan0",service="netflix"} 300"#,
+        ]);
+        assert!(body.contains(r#"trafficmon_interface_packets_total{interface="wlan0",service="netflix"} 1"#));
+        assert!(stats.export_openmetrics().contains(r#"trafficmon_interface_bytes_total{interface="eth0",service="netflix"} 1000"#));
+        
+        // 沒有接口統計時不輸出這些序列
+        assert!(!TrafficStats::new().export_prometheus().contains("trafficmon_interface_"));
+        
+        // 多個線程寫入不同分片，讀取時合併
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| stats.add_interface_traffic("eth0", "netflix", 100, 1));
+            }
+        });
+        assert_eq!(stats.get_interface_stats()["eth0"]["netflix"], (1400, 6));
+    }
+    
+    #[test]
+    fn test_max_services_overflow() {
+        let stats = TrafficStats::from_config(&Config { max_services: Some(3), ..Config::default() });
+        for port in [8001, 8002, 8003, 8004, 8005] {
+            stats.add_traffic(&format!("other:{}", port), 100, 1);
+        }
+        // 已跟蹤的服務照常累計
+        stats.add_traffic("other:8001", 50, 1);
+        stats.add_traffic("other:8006", 10, 1);
+        
+        let result = stats.get_stats();
+        assert_eq!(result.len(), 4);
+        assert_eq!(result["other:8001"], (150, 2));
+        assert_eq!(result["other:8003"], (100, 1));
+        assert_eq!(result[OVERFLOW_SERVICE], (210, 3));
+        assert!(!result.contains_key("other:8004"));
+        
+        // VLAN 和接口統計同樣受上限約束
+        stats.add_vlan_traffic(10, "other:8007", 10, 1);
+        stats.add_interface_traffic("eth0", "other:8001", 10, 1);
+        assert_eq!(stats.get_vlan_stats()[&10].keys().collect::<Vec<_>>(), [OVERFLOW_SERVICE]);
+        assert_eq!(stats.get_interface_stats()["eth0"].keys().collect::<Vec<_>>(), ["other:8001"]);
+        
+        // 保留期過後舊服務不再佔用名額
+        let later = SystemTime::now() + Duration::from_secs(7200);
+        assert!(stats.get_detailed_stats_at(later).is_empty());
+        stats.add_traffic("youtube", 100, 1);
+        assert_eq!(stats.get_detailed_stats_at(later).keys().collect::<Vec<_>>(), ["youtube"]);
+        
+        // 重置後重新計數
+        stats.reset_stats();
+        stats.add_traffic("netflix", 100, 1);
+        assert_eq!(stats.get_stats().keys().collect::<Vec<_>>(), ["netflix"]);
+        
+        // 多個線程同時加入新服務也不超出上限
+        stats.reset_stats();
+        thread::scope(|scope| {
+            for t in 0..4 {
+                let stats = &stats;
+                scope.spawn(move || {
+                    for port in 0..50 {
+                        stats.add_traffic(&format!("other:{}", 9000 + t * 50 + port), 1, 1);
+                    }
+                });
+            }
+        });
+        let result = stats.get_stats();
+        assert_eq!(result.len(), 4);
+        assert_eq!(result.values().map(|(bytes, _)| bytes).sum::<u64>(), 200);
+    }
+    
+    #[test]
+    fn test_address_family_merge() {
+        let v4: IpAddr = "198.38.96.1".parse().unwrap();
+        let v6: IpAddr = "2a00:86c0:1::1".parse().unwrap();
+        
+        let merged = TrafficStats::new();
+        let split = TrafficStats::from_config(&Config { merge_address_families: false, ..Config::default() });
+        for stats in [&merged, &split] {
+            stats.add_family_traffic("netflix", v4, 1000, 2);
+            stats.add_family_traffic("netflix", v6, 500, 1);
+            stats.add_family_traffic("dns", v4, 100, 1);
+        }
+        
+        let result = merged.get_stats();
+        assert_eq!(result["netflix"], (1500, 3));
+        assert_eq!(result.len(), 2);
+        
+        let result = split.get_stats();
+        assert_eq!(result["netflix"], (1000, 2));
+        assert_eq!(result["netflix6"], (500, 1));
+        assert_eq!(result["dns"], (100, 1));
+        assert_eq!(result.len(), 3);
+    }
+    
+    #[test]
+    fn test_sticky_service_keeps_first_seen() {
+        let config = Config {
+            max_history_buckets: Some(1),
+            sticky_services: vec!["voip".to_string()],
+            ..Config::default()
+        };
+        let stats = TrafficStats::from_config(&config);
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        
+        for minute in 0..3 {
+            let at = start + Duration::from_secs(60 * minute);
+            stats.add_traffic_at("voip", 1000, 10, at);
+            stats.add_traffic_at("dns", 100, 1, at);
+            stats.get_detailed_stats_at(at);
+        }
+        
+        // 只合併最近一個桶：普通服務的 first_seen 是本周期，常駐服務是整個會話的開始
+        let now = start + Duration::from_secs(180);
+        stats.add_traffic_at("voip", 1000, 10, now);
+        stats.add_traffic_at("dns", 100, 1, now);
+        let detailed = stats.get_detailed_stats_at(now);
+        assert_eq!(detailed["voip"].first_seen, start);
+        assert_eq!(detailed["voip"].duration(), Duration::from_secs(180));
+        assert_eq!(detailed["voip"].bytes, 1000);
+        assert_eq!(detailed["dns"].first_seen, now);
+        
+        // 保留期過後仍然記得會話開始時間
+        let later = start + Duration::from_secs(7200);
+        stats.add_traffic_at("voip", 1000, 10, later);
+        assert_eq!(stats.get_detailed_stats_at(later)["voip"].first_seen, start);
+        
+        stats.reset_stats();
+        stats.add_traffic_at("voip", 1000, 10, later);
+        assert_eq!(stats.get_detailed_stats_at(later)["voip"].first_seen, later);
+    }
+    
+    #[test]
+    fn test_clock_moving_backwards_keeps_history() {
+        let stats = TrafficStats::new();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        stats.add_traffic_at("netflix", 1000, 1, now);
+        assert_eq!(stats.get_detailed_stats_at(now)["netflix"].bytes, 1000);
+        
+        // 時鐘回撥 2 小時：歷史不被清理，也不會被查詢範圍排除
+        let earlier = now - Duration::from_secs(7200);
+        stats.add_traffic_at("netflix", 500, 1, earlier);
+        let detailed = stats.get_detailed_stats_at(earlier);
+        assert_eq!(detailed["netflix"].bytes, 1500);
+        assert_eq!(detailed["netflix"].packets, 2);
+        
+        // 回撥期間輪轉的桶記在最近一次輪轉時間，不早於已有的桶
+        let history = stats.data.lock().unwrap().history(now);
+        assert_eq!(history.iter().map(|(t, _)| *t).collect::<Vec<_>>(), vec![now, now]);
+        assert_eq!(stats.top_services_window_at(1, Duration::from_secs(60), earlier), vec![("netflix".to_string(), 1500)]);
+        
+        // 時鐘恢復後照常按保留期清理
+        assert!(stats.get_detailed_stats_at(now + Duration::from_secs(7200)).is_empty());
+    }
+    
+    #[test]
+    fn test_prune_to_memory_budget() {
+        let stats = TrafficStats::new();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let name = |i: u64| format!("service{:03}", i);
+        
+        // 前一半輪轉到歷史桶，後一半留在當前桶，越往後越新
+        for i in 0..200 {
+            stats.add_traffic_at(&name(i), 1000, 1, start + Duration::from_secs(i));
+            if i == 99 {
+                stats.get_detailed_stats_at(start + Duration::from_secs(i));
+            }
+        }
+        let per_service = entry_size(&name(0));
+        assert_eq!(stats.estimated_bytes(), 200 * per_service);
+        
+        let budget = 50 * per_service;
+        assert_eq!(stats.prune_to(budget), 150);
+        assert!(stats.estimated_bytes() <= budget);
+        
+        let remaining = stats.get_detailed_stats();
+        assert_eq!(remaining.len(), 50);
+        assert!((150..200).all(|i| remaining.contains_key(&name(i))));
+        assert_eq!(stats.prune_to(budget), 0);
+        
+        // 配置了上限時每次輪轉自動淘汰
+        let stats = TrafficStats::from_config(&Config { stats_memory_budget: Some(10 * per_service), ..Config::default() });
+        for i in 0..100 {
+            stats.add_traffic_at(&name(i), 1000, 1, start + Duration::from_secs(i));
+        }
+        let detailed = stats.get_detailed_stats_at(start + Duration::from_secs(100));
+        assert_eq!(detailed.len(), 10);
+        assert!(detailed.contains_key(&name(99)));
+    }
+    
+    #[test]
+    fn test_current_only_mode() {
+        let stats = TrafficStats::from_config(&Config { history_enabled: false, ..Config::default() });
+        stats.add_traffic("netflix", 1024, 10);
+        assert_eq!(stats.get_stats()["netflix"], (1024, 10));
+        stats.flush();
+        stats.add_traffic("netflix", 512, 5);
+        stats.add_traffic("youtube", 2048, 20);
+        
+        let result = stats.get_stats();
+        assert_eq!(result["netflix"], (1536, 15));
+        assert_eq!(result["youtube"], (2048, 20));
+        assert_eq!(stats.get_detailed_stats()["netflix"].bytes, 1536);
+        
+        let data = stats.lock_data();
+        assert!(data.history(SystemTime::now() + Duration::from_secs(60)).is_empty());
+        assert_eq!(data.current["netflix"].bytes, 1536);
+    }
+    
+    #[test]
+    fn test_get_services_stats() {
+        let stats = TrafficStats::new();
+        stats.add_traffic("netflix", 1024, 10);
+        stats.flush();
+        stats.add_traffic("netflix", 512, 5);
+        stats.add_traffic("youtube", 2048, 20);
+        
+        let result = stats.get_services_stats(&["netflix", "youtube", "hulu", "netflix"]);
+        assert_eq!(result.len(), 2);
+        assert_eq!((result["netflix"].bytes, result["netflix"].packets), (1536, 15));
+        assert_eq!(result["youtube"], stats.get_service_stats("youtube").unwrap());
+        assert!(!result.contains_key("hulu"));
+        
+        assert!(stats.get_services_stats(&[]).is_empty());
+    }
+    
+    #[test]
+    fn test_traffic_data_duration_and_age() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let data = TrafficData {
+            bytes: 100,
+            packets: 1,
+            first_seen: start,
+            last_seen: start + Duration::from_secs(30),
+        };
+        
+        assert_eq!(data.duration(), Duration::from_secs(30));
+        assert_eq!(data.age(start + Duration::from_secs(90)), Duration::from_secs(60));
+        assert!(data.is_stale(start + Duration::from_secs(90), Duration::from_secs(60)));
+        assert!(!data.is_stale(start + Duration::from_secs(90), Duration::from_secs(61)));
+        
+        // 時鐘回撥：now 早於 last_seen，或 last_seen 早於 first_seen
+        assert_eq!(data.age(start), Duration::ZERO);
+        let backwards = TrafficData { first_seen: data.last_seen, last_seen: data.first_seen, ..data.clone() };
+        assert_eq!(backwards.duration(), Duration::ZERO);
+    }
+    
+    /// 另一個按 BTreeMap 實現的存儲，驗證 TrafficStats 只通過 trait 訪問歷史
+    #[derive(Debug, Default)]
+    struct BTreeStore(std::collections::BTreeMap<SystemTime, Bucket>);
+    
+    impl StatsStore for BTreeStore {
+        fn put(&mut self, timestamp: SystemTime, bucket: Bucket) {
+            self.0.insert(timestamp, bucket);
+        }
+        
+        fn get(&self, service: &str) -> Vec<(SystemTime, TrafficData)> {
+            self.0.iter()
+                .filter_map(|(t, bucket)| bucket.get(service).map(|d| (*t, d.clone())))
+                .collect()
+        }
+        
+        fn range(&self, since: SystemTime, until: SystemTime) -> Vec<(SystemTime, Bucket)> {
+            self.0.range(since..=until).map(|(t, bucket)| (*t, bucket.clone())).collect()
+        }
+        
+        fn prune(&mut self, before: SystemTime) {
+            self.0 = self.0.split_off(&before);
+        }
+        
+        fn clear(&mut self) {
+            self.0.clear();
+        }
+    }
+    
+    fn check_store_backed_stats(stats: TrafficStats) {
+        stats.add_traffic("netflix", 1024, 10);
+        stats.add_traffic("youtube", 2048, 20);
+        assert_eq!(stats.get_stats()["netflix"], (1024, 10));
+        
+        // 第二輪數據輪轉到新的桶，兩輪合併
+        stats.add_traffic("netflix", 512, 5);
+        let result = stats.get_stats();
+        assert_eq!(result["netflix"], (1536, 15));
+        assert_eq!(result["youtube"], (2048, 20));
+        
+        let netflix = stats.get_service_stats("netflix").unwrap();
+        assert_eq!((netflix.bytes, netflix.packets), (1536, 15));
+        assert_eq!(stats.timeseries("netflix").len(), 2);
+        assert_eq!(stats.top_services(1), vec![("youtube".to_string(), 2048)]);
+        assert_eq!(stats.grafana_search(), json!(["netflix", "youtube"]));
+        
+        stats.reset_stats();
+        assert!(stats.get_stats().is_empty());
+        assert!(stats.get_service_stats("netflix").is_none());
+    }
+    
+    /// 只記錄寫入的桶
+    #[derive(Debug)]
+    struct RecordingStore(Arc<Mutex<Vec<Bucket>>>);
+    
+    impl StatsStore for RecordingStore {
+        fn put(&mut self, _timestamp: SystemTime, bucket: Bucket) {
+            self.0.lock().unwrap().push(bucket);
+        }
+        
+        fn get(&self, _service: &str) -> Vec<(SystemTime, TrafficData)> {
+            Vec::new()
+        }
+        
+        fn range(&self, _since: SystemTime, _until: SystemTime) -> Vec<(SystemTime, Bucket)> {
+            Vec::new()
+        }
+        
+        fn prune(&mut self, _before: SystemTime) {}
+        
+        fn clear(&mut self) {}
+    }
+    
+    #[test]
+    fn test_flush_timer() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let stats = Arc::new(TrafficStats::with_store(Box::new(RecordingStore(Arc::clone(&written)))));
+        let timer = FlushTimer::start(Arc::clone(&stats), Duration::from_millis(20));
+        
+        // 定時器到期後寫入，無需讀取統計觸發輪轉
+        stats.add_traffic("netflix", 1024, 1);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while written.lock().unwrap().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(written.lock().unwrap()[0]["netflix"].bytes, 1024);
+        
+        // 沒有新數據時不寫空桶；停止時立即寫入剩餘數據
+        let timer_flushes = written.lock().unwrap().len();
+        assert_eq!(timer_flushes, 1);
+        stats.add_traffic("youtube", 2048, 2);
+        let started = Instant::now();
+        timer.stop();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(written.lock().unwrap().last().unwrap()["youtube"].packets, 2);
+        
+        // 間隔很長時也由停止觸發寫入
+        let stats = Arc::new(TrafficStats::with_store(Box::new(RecordingStore(Arc::clone(&written)))));
+        let timer = FlushTimer::start(Arc::clone(&stats), Duration::from_secs(3600));
+        stats.add_traffic("dns", 80, 1);
+        drop(timer);
+        assert!(written.lock().unwrap().last().unwrap().contains_key("dns"));
+    }
+    
+    #[test]
+    fn test_stats_store_backends() {
+        check_store_backed_stats(TrafficStats::new());
+        check_store_backed_stats(TrafficStats::with_store(Box::new(MemoryStore::new())));
+        check_store_backed_stats(TrafficStats::with_store(Box::<BTreeStore>::default()));
+        
+        // 過期的桶由存儲後端清理
+        let mut store = MemoryStore::new();
+        let now = SystemTime::now();
+        store.put(now - Duration::from_secs(7200), Bucket::new());
+        store.put(now - Duration::from_secs(60), Bucket::new());
+        store.prune(now - Duration::from_secs(3600));
+        assert_eq!(store.range(SystemTime::UNIX_EPOCH, now).len(), 1);
+    }
+}
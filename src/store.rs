@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::SystemTime;
+
+use crate::stats::TrafficData;
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStatsStore;
+
+/// 一個輪轉周期內各服務的統計
+pub type Bucket = HashMap<String, TrafficData>;
+
+/// 桶中一個服務條目佔用內存的粗略估算（鍵和值，不含哈希表本身的開銷）
+pub fn entry_size(service: &str) -> usize {
+    std::mem::size_of::<String>() + service.len() + std::mem::size_of::<TrafficData>()
+}
+
+/// 已輪轉統計桶的存儲後端，每個桶以輪轉時間為鍵
+pub trait StatsStore: fmt::Debug + Send {
+    /// 保存在 `timestamp` 輪轉出的桶
+    fn put(&mut self, timestamp: SystemTime, bucket: Bucket);
+
+    /// 單個服務在各個桶中的數據，按時間升序
+    fn get(&self, service: &str) -> Vec<(SystemTime, TrafficData)>;
+
+    /// `since..=until` 範圍內的桶，按時間升序
+    fn range(&self, since: SystemTime, until: SystemTime) -> Vec<(SystemTime, Bucket)>;
+
+    /// 截至 `until` 的最近 `limit` 個桶，按時間升序
+    fn recent(&self, until: SystemTime, limit: usize) -> Vec<(SystemTime, Bucket)> {
+        let mut buckets = self.range(SystemTime::UNIX_EPOCH, until);
+        let skip = buckets.len().saturating_sub(limit);
+        buckets.drain(..skip);
+        buckets
+    }
+
+    /// 刪除早於 `before` 的桶
+    fn prune(&mut self, before: SystemTime);
+
+    /// 刪除所有桶
+    fn clear(&mut self);
+
+    /// 歷史桶在內存中佔用的估算字節數；不在內存中保存桶的後端為 0
+    fn estimated_bytes(&self) -> usize {
+        0
+    }
+
+    /// 從所有桶中刪除某個服務，返回釋放的估算內存；默認只影響內存中的數據，不刪除持久化的記錄
+    fn remove_service(&mut self, _service: &str) -> usize {
+        0
+    }
+}
+
+/// 默認的內存存儲，重啟後數據丟失
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    buckets: Vec<(SystemTime, Bucket)>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StatsStore for MemoryStore {
+    fn put(&mut self, timestamp: SystemTime, bucket: Bucket) {
+        // 輪轉時間通常遞增，亂序時插入到正確位置
+        let index = self.buckets.partition_point(|(t, _)| *t <= timestamp);
+        self.buckets.insert(index, (timestamp, bucket));
+    }
+
+    fn get(&self, service: &str) -> Vec<(SystemTime, TrafficData)> {
+        self.buckets.iter()
+            .filter_map(|(timestamp, bucket)| bucket.get(service).map(|t| (*timestamp, t.clone())))
+            .collect()
+    }
+
+    fn range(&self, since: SystemTime, until: SystemTime) -> Vec<(SystemTime, Bucket)> {
+        self.buckets.iter()
+            .filter(|(timestamp, _)| *timestamp >= since && *timestamp <= until)
+            .cloned()
+            .collect()
+    }
+
+    fn prune(&mut self, before: SystemTime) {
+        self.buckets.retain(|(timestamp, _)| *timestamp >= before);
+    }
+
+    fn clear(&mut self) {
+        self.buckets.clear();
+    }
+
+    fn estimated_bytes(&self) -> usize {
+        self.buckets.iter()
+            .flat_map(|(_, bucket)| bucket.keys())
+            .map(|service| entry_size(service))
+            .sum()
+    }
+
+    fn remove_service(&mut self, service: &str) -> usize {
+        let removed = self.buckets.iter_mut()
+            .filter_map(|(_, bucket)| bucket.remove(service))
+            .count();
+        self.buckets.retain(|(_, bucket)| !bucket.is_empty());
+        removed * entry_size(service)
+    }
+}
@@ -0,0 +1,237 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use rusqlite::{params, Connection};
+
+use super::{Bucket, StatsStore};
+use crate::stats::TrafficData;
+
+/// 按順序執行的建表/升級語句，版本號記在 `PRAGMA user_version`
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE stats (
+        bucket_us INTEGER NOT NULL,
+        service TEXT NOT NULL,
+        bytes INTEGER NOT NULL,
+        packets INTEGER NOT NULL,
+        first_seen_us INTEGER NOT NULL,
+        last_seen_us INTEGER NOT NULL,
+        PRIMARY KEY (bucket_us, service)
+    );
+    CREATE INDEX stats_service ON stats (service, bucket_us);",
+];
+
+/// 每個服務每個輪轉周期一行，重啟後歷史數據仍在
+#[derive(Debug)]
+pub struct SqliteStatsStore {
+    conn: Connection,
+}
+
+impl SqliteStatsStore {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        Self::with_connection(Connection::open(path)?)
+    }
+    
+    pub fn open_in_memory() -> rusqlite::Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+    
+    fn with_connection(conn: Connection) -> rusqlite::Result<Self> {
+        let store = Self { conn };
+        store.migrate()?;
+        Ok(store)
+    }
+    
+    fn migrate(&self) -> rusqlite::Result<()> {
+        let version: usize = self.conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            self.conn.execute_batch(&format!(
+                "BEGIN; {} PRAGMA user_version = {}; COMMIT;",
+                migration, index + 1
+            ))?;
+        }
+        
+        Ok(())
+    }
+    
+    fn query(&self, sql: &str, params: impl rusqlite::Params) -> rusqlite::Result<Vec<(SystemTime, String, TrafficData)>> {
+        let mut stmt = self.conn.prepare_cached(sql)?;
+        let rows = stmt.query_map(params, |row| {
+            Ok((
+                from_micros(row.get(0)?),
+                row.get::<_, String>(1)?,
+                TrafficData {
+                    bytes: row.get::<_, i64>(2)? as u64,
+                    packets: row.get::<_, i64>(3)? as u64,
+                    first_seen: from_micros(row.get(4)?),
+                    last_seen: from_micros(row.get(5)?),
+                },
+            ))
+        })?;
+        rows.collect()
+    }
+}
+
+impl StatsStore for SqliteStatsStore {
+    fn put(&mut self, timestamp: SystemTime, bucket: Bucket) {
+        let result = (|| {
+            let tx = self.conn.transaction()?;
+            {
+                // 同一微秒內輪轉兩次時合併到同一行
+                let mut stmt = tx.prepare_cached(
+                    "INSERT INTO stats (bucket_us, service, bytes, packets, first_seen_us, last_seen_us)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                     ON CONFLICT (bucket_us, service) DO UPDATE SET
+                        bytes = bytes + excluded.bytes,
+                        packets = packets + excluded.packets,
+                        first_seen_us = MIN(first_seen_us, excluded.first_seen_us),
+                        last_seen_us = MAX(last_seen_us, excluded.last_seen_us)",
+                )?;
+                for (service, data) in &bucket {
+                    stmt.execute(params![
+                        to_micros(timestamp),
+                        service,
+                        data.bytes as i64,
+                        data.packets as i64,
+                        to_micros(data.first_seen),
+                        to_micros(data.last_seen),
+                    ])?;
+                }
+            }
+            tx.commit()
+        })();
+        
+        if let Err(e) = result {
+            eprintln!("Failed to write stats to SQLite: {}", e);
+        }
+    }
+    
+    fn get(&self, service: &str) -> Vec<(SystemTime, TrafficData)> {
+        let sql = "SELECT bucket_us, service, bytes, packets, first_seen_us, last_seen_us
+                   FROM stats WHERE service = ?1 ORDER BY bucket_us";
+        match self.query(sql, params![service]) {
+            Ok(rows) => rows.into_iter().map(|(timestamp, _, data)| (timestamp, data)).collect(),
+            Err(e) => {
+                eprintln!("Failed to read stats from SQLite: {}", e);
+                Vec::new()
+            }
+        }
+    }
+    
+    fn range(&self, since: SystemTime, until: SystemTime) -> Vec<(SystemTime, Bucket)> {
+        let sql = "SELECT bucket_us, service, bytes, packets, first_seen_us, last_seen_us
+                   FROM stats WHERE bucket_us BETWEEN ?1 AND ?2 ORDER BY bucket_us";
+        group_buckets(self.query(sql, params![to_micros(since), to_micros(until)]))
+    }
+    
+    fn recent(&self, until: SystemTime, limit: usize) -> Vec<(SystemTime, Bucket)> {
+        // 只讀最近的桶，不掃描整個表
+        let sql = "SELECT bucket_us, service, bytes, packets, first_seen_us, last_seen_us
+                   FROM stats WHERE bucket_us IN (
+                       SELECT DISTINCT bucket_us FROM stats WHERE bucket_us <= ?1
+                       ORDER BY bucket_us DESC LIMIT ?2
+                   ) ORDER BY bucket_us";
+        group_buckets(self.query(sql, params![to_micros(until), limit as i64]))
+    }
+    
+    fn prune(&mut self, before: SystemTime) {
+        if let Err(e) = self.conn.execute("DELETE FROM stats WHERE bucket_us < ?1", params![to_micros(before)]) {
+            eprintln!("Failed to prune SQLite stats: {}", e);
+        }
+    }
+    
+    fn clear(&mut self) {
+        if let Err(e) = self.conn.execute("DELETE FROM stats", []) {
+            eprintln!("Failed to clear SQLite stats: {}", e);
+        }
+    }
+}
+
+/// 把按時間排序的行組成桶，相鄰的同一時間行屬於同一個桶
+fn group_buckets(rows: rusqlite::Result<Vec<(SystemTime, String, TrafficData)>>) -> Vec<(SystemTime, Bucket)> {
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(e) => {
+            eprintln!("Failed to read stats from SQLite: {}", e);
+            return Vec::new();
+        }
+    };
+    
+    let mut buckets: Vec<(SystemTime, Bucket)> = Vec::new();
+    for (timestamp, service, data) in rows {
+        match buckets.last_mut() {
+            Some((last, bucket)) if *last == timestamp => {
+                bucket.insert(service, data);
+            }
+            _ => buckets.push((timestamp, Bucket::from([(service, data)]))),
+        }
+    }
+    buckets
+}
+
+fn to_micros(time: SystemTime) -> i64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_micros() as i64
+}
+
+fn from_micros(micros: i64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_micros(micros.max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn bucket(entries: &[(&str, u64)], at: SystemTime) -> Bucket {
+        entries.iter()
+            .map(|(service, bytes)| (service.to_string(), TrafficData { bytes: *bytes, packets: 1, first_seen: at, last_seen: at }))
+            .collect()
+    }
+    
+    #[test]
+    fn test_write_and_range_query() {
+        let mut store = SqliteStatsStore::open_in_memory().unwrap();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let at = |minutes: u64| start + Duration::from_secs(minutes * 60);
+        
+        store.put(at(0), bucket(&[("netflix", 100), ("dns", 10)], at(0)));
+        store.put(at(1), bucket(&[("netflix", 200)], at(1)));
+        store.put(at(2), bucket(&[("youtube", 300)], at(2)));
+        
+        let range = store.range(at(1), at(2));
+        assert_eq!(range.len(), 2);
+        assert_eq!(range[0].0, at(1));
+        assert_eq!(range[0].1["netflix"].bytes, 200);
+        assert_eq!(range[1].1["youtube"].bytes, 300);
+        
+        let netflix = store.get("netflix");
+        assert_eq!(netflix.iter().map(|(t, d)| (*t, d.bytes)).collect::<Vec<_>>(), vec![(at(0), 100), (at(1), 200)]);
+        
+        let recent = store.recent(at(2), 2);
+        assert_eq!(recent.iter().map(|(t, _)| *t).collect::<Vec<_>>(), vec![at(1), at(2)]);
+        assert_eq!(recent[0].1["netflix"].bytes, 200);
+        
+        store.prune(at(1));
+        assert_eq!(store.range(at(0), at(2)).len(), 2);
+        assert!(store.get("dns").is_empty());
+    }
+    
+    #[test]
+    fn test_rows_survive_reopen() {
+        let path = std::env::temp_dir().join(format!("trafficmon-stats-{}.db", std::process::id()));
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        
+        {
+            let mut store = SqliteStatsStore::open(&path).unwrap();
+            store.put(now, bucket(&[("netflix", 1536)], now));
+        }
+        
+        // 重新打開時不會重複執行遷移
+        let store = SqliteStatsStore::open(&path).unwrap();
+        let version: usize = store.conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        assert_eq!(store.get("netflix")[0].1.bytes, 1536);
+        
+        drop(store);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
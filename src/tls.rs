@@ -0,0 +1,239 @@
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const EXTENSION_ALPN: u16 = 0x0010;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+/// 最多解析的擴展個數，正常 ClientHello 不超過二三十個
+const MAX_EXTENSIONS: usize = 64;
+
+/// ClientHello 中與分類相關的擴展
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientHello {
+    /// SNI 主機名（小寫）
+    pub server_name: Option<String>,
+    /// 客戶端按偏好順序提供的 ALPN 協議（h2、http/1.1 等）
+    pub alpn: Vec<String>,
+}
+
+impl ClientHello {
+    /// 客戶端首選的應用層協議；實際協商結果在 ServerHello 中，這裡只作提示
+    pub fn protocol_hint(&self) -> Option<&str> {
+        self.alpn.first().map(String::as_str)
+    }
+}
+
+/// 從 TLS ClientHello 中取出 SNI 主機名，不是 ClientHello 或沒有 SNI 時返回 None
+pub fn parse_sni(payload: &[u8]) -> Option<String> {
+    parse_sni_until(payload, &|| false)
+}
+
+/// 同 `parse_sni`，每個擴展前調用 `stop`，返回 true 時放棄解析
+pub fn parse_sni_until(payload: &[u8], stop: &dyn Fn() -> bool) -> Option<String> {
+    parse_client_hello_until(payload, stop)?.server_name
+}
+
+/// 解析 TLS ClientHello 的 SNI 和 ALPN 擴展，不是 ClientHello 時返回 None
+pub fn parse_client_hello(payload: &[u8]) -> Option<ClientHello> {
+    parse_client_hello_until(payload, &|| false)
+}
+
+/// 同 `parse_client_hello`，每個擴展前調用 `stop`，返回 true 時放棄解析並返回 None；
+/// 只看前 MAX_EXTENSIONS 個擴展
+pub fn parse_client_hello_until(payload: &[u8], stop: &dyn Fn() -> bool) -> Option<ClientHello> {
+    if *payload.first()? != CONTENT_TYPE_HANDSHAKE || *payload.get(5)? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+
+    let mut reader = Reader { data: payload, pos: 9 };
+
+    // 版本 + 隨機數
+    reader.skip(2 + 32)?;
+    // 會話 ID、密碼套件、壓縮方法
+    let session_len = reader.u8()? as usize;
+    reader.skip(session_len)?;
+    let suites_len = reader.u16()? as usize;
+    reader.skip(suites_len)?;
+    let compression_len = reader.u8()? as usize;
+    reader.skip(compression_len)?;
+
+    let mut hello = ClientHello::default();
+    // 沒有擴展的 ClientHello
+    let Some(extensions_len) = reader.u16() else {
+        return Some(hello);
+    };
+    let extensions_end = reader.pos + extensions_len as usize;
+
+    let mut parsed = 0;
+    while reader.pos + 4 <= extensions_end && parsed < MAX_EXTENSIONS {
+        if stop() {
+            return None;
+        }
+        parsed += 1;
+        let ext_type = reader.u16()?;
+        let ext_len = reader.u16()? as usize;
+        let mut ext = Reader { data: reader.bytes(ext_len)?, pos: 0 };
+
+        match ext_type {
+            EXTENSION_SERVER_NAME => {
+                // server_name_list 長度，後接 (類型, 長度, 名稱)
+                ext.skip(2)?;
+                if ext.u8()? == NAME_TYPE_HOST_NAME {
+                    let name_len = ext.u16()? as usize;
+                    hello.server_name = std::str::from_utf8(ext.bytes(name_len)?).ok()
+                        .map(|s| s.to_ascii_lowercase());
+                }
+            }
+            EXTENSION_ALPN => {
+                // protocol_name_list 長度，後接若干 (長度, 名稱)
+                let list_len = ext.u16()? as usize;
+                let mut list = Reader { data: ext.bytes(list_len)?, pos: 0 };
+                while let Some(len) = list.u8() {
+                    let name = list.bytes(len as usize)?;
+                    hello.alpn.push(String::from_utf8_lossy(name).into_owned());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Some(hello)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+/// 構造只帶 SNI 擴展的最小 ClientHello
+#[cfg(test)]
+pub(crate) fn test_client_hello(host: &str) -> Vec<u8> {
+    test_client_hello_with_alpn(host, &[])
+}
+
+/// 構造帶 SNI 和 ALPN 擴展的最小 ClientHello，`alpn` 為空時不帶 ALPN 擴展
+#[cfg(test)]
+pub(crate) fn test_client_hello_with_alpn(host: &str, alpn: &[&str]) -> Vec<u8> {
+    test_client_hello_padded(host, alpn, 0)
+}
+
+/// 同 `test_client_hello_with_alpn`，SNI 前另加 `padding` 個空擴展
+#[cfg(test)]
+pub(crate) fn test_client_hello_padded(host: &str, alpn: &[&str], padding: usize) -> Vec<u8> {
+    let name = host.as_bytes();
+
+    let mut sni = Vec::new();
+    sni.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+    sni.push(NAME_TYPE_HOST_NAME);
+    sni.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    sni.extend_from_slice(name);
+
+    let mut extensions = Vec::new();
+    for _ in 0..padding {
+        // padding 擴展（類型 21），長度 0
+        extensions.extend_from_slice(&[0x00, 0x15, 0x00, 0x00]);
+    }
+    extensions.extend_from_slice(&EXTENSION_SERVER_NAME.to_be_bytes());
+    extensions.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+    extensions.extend_from_slice(&sni);
+    
+    if !alpn.is_empty() {
+        let mut protocols = Vec::new();
+        for protocol in alpn {
+            protocols.push(protocol.len() as u8);
+            protocols.extend_from_slice(protocol.as_bytes());
+        }
+        extensions.extend_from_slice(&EXTENSION_ALPN.to_be_bytes());
+        extensions.extend_from_slice(&((protocols.len() + 2) as u16).to_be_bytes());
+        extensions.extend_from_slice(&(protocols.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&protocols);
+    }
+    
+    let mut body = vec![0x03, 0x03];
+    body.extend_from_slice(&[0x11; 32]);
+    // 空會話 ID、一個密碼套件、一個壓縮方法
+    body.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+    body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    body.extend_from_slice(&extensions);
+
+    let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+    handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    handshake.extend_from_slice(&body);
+
+    let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend_from_slice(&handshake);
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sni() {
+        assert_eq!(parse_sni(&test_client_hello("www.Netflix.com")), Some("www.netflix.com".to_string()));
+
+        // 截斷的記錄和非握手數據
+        let hello = test_client_hello("www.netflix.com");
+        assert_eq!(parse_sni(&hello[..hello.len() - 4]), None);
+        assert_eq!(parse_sni(b"\x17\x03\x03\x00\x10"), None);
+    }
+    
+    #[test]
+    fn test_parse_alpn() {
+        let hello = parse_client_hello(&test_client_hello_with_alpn("www.youtube.com", &["h2", "http/1.1"])).unwrap();
+        assert_eq!(hello.server_name.as_deref(), Some("www.youtube.com"));
+        assert_eq!(hello.alpn, vec!["h2", "http/1.1"]);
+        assert_eq!(hello.protocol_hint(), Some("h2"));
+        
+        // 沒有 ALPN 擴展
+        let hello = parse_client_hello(&test_client_hello("www.youtube.com")).unwrap();
+        assert_eq!(hello.protocol_hint(), None);
+    }
+    
+    #[test]
+    fn test_parse_stops_early() {
+        let hello = test_client_hello_with_alpn("www.youtube.com", &["h2"]);
+        assert_eq!(parse_client_hello_until(&hello, &|| true), None);
+        
+        // 第二個擴展前放棄
+        let checks = std::cell::Cell::new(0);
+        let stop = || {
+            checks.set(checks.get() + 1);
+            checks.get() > 1
+        };
+        assert_eq!(parse_sni_until(&hello, &stop), None);
+        assert_eq!(checks.get(), 2);
+        
+        // 只解析前 MAX_EXTENSIONS 個擴展
+        let checks = std::cell::Cell::new(0);
+        let count = || {
+            checks.set(checks.get() + 1);
+            false
+        };
+        let padded = test_client_hello_padded("www.youtube.com", &[], 1000);
+        assert_eq!(parse_client_hello_until(&padded, &count), Some(ClientHello::default()));
+        assert_eq!(checks.get(), MAX_EXTENSIONS);
+        assert_eq!(parse_sni(&test_client_hello_padded("www.youtube.com", &[], 10)), Some("www.youtube.com".to_string()));
+    }
+}
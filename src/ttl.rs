@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::config::TtlTrackingConfig;
+
+/// 按初始 TTL 粗略推斷的系統類別
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OsClass {
+    /// 初始 TTL 64：Linux、macOS、BSD、Android、iOS
+    Unix,
+    /// 初始 TTL 128
+    Windows,
+    /// 初始 TTL 255：路由器等網絡設備
+    Network,
+}
+
+impl OsClass {
+    /// 每經過一跳 TTL 減一，按不小於觀察值的最小常見初始值（64、128、255）歸類
+    pub fn from_ttl(ttl: u8) -> Self {
+        match ttl {
+            0..=64 => OsClass::Unix,
+            65..=128 => OsClass::Windows,
+            _ => OsClass::Network,
+        }
+    }
+}
+
+impl fmt::Display for OsClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OsClass::Unix => "unix",
+            OsClass::Windows => "windows",
+            OsClass::Network => "network",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// 一個源地址最近的 TTL 及其推斷的系統
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SourceTtl {
+    pub ttl: u8,
+    pub os: OsClass,
+    /// TTL 大幅變化（可能是偽造的源地址）的次數
+    pub anomalies: u64,
+}
+
+/// TTL 變化超過閾值時的通知
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TtlAnomaly {
+    pub source: IpAddr,
+    pub previous: u8,
+    pub current: u8,
+}
+
+/// 同一源地址兩次異常警告的最短間隔
+const WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// 按源地址記錄 IP TTL（IPv6 為 hop limit），達到 `max_sources` 後不再記錄新地址
+#[derive(Debug)]
+pub struct TtlTracker {
+    change_threshold: u8,
+    max_sources: usize,
+    sources: HashMap<IpAddr, SourceTtl>,
+    /// 各源地址最近一次警告的時間
+    warned: HashMap<IpAddr, Instant>,
+}
+
+impl TtlTracker {
+    pub fn new(config: &TtlTrackingConfig) -> Self {
+        Self {
+            change_threshold: config.change_threshold,
+            max_sources: config.max_sources,
+            sources: HashMap::new(),
+            warned: HashMap::new(),
+        }
+    }
+    
+    /// 記錄一個包的 TTL；與該地址上一個包相差超過閾值時返回異常。
+    /// 路由變化一般只差幾跳，系統類別按最近的 TTL 更新
+    pub fn observe(&mut self, source: IpAddr, ttl: u8) -> Option<TtlAnomaly> {
+        if !self.sources.contains_key(&source) && self.sources.len() >= self.max_sources {
+            return None;
+        }
+        
+        let entry = self.sources.entry(source)
+            .or_insert(SourceTtl { ttl, os: OsClass::from_ttl(ttl), anomalies: 0 });
+        let previous = entry.ttl;
+        entry.ttl = ttl;
+        entry.os = OsClass::from_ttl(ttl);
+        
+        if previous.abs_diff(ttl) > self.change_threshold {
+            entry.anomalies += 1;
+            return Some(TtlAnomaly { source, previous, current: ttl });
+        }
+        None
+    }
+    
+    pub fn sources(&self) -> &HashMap<IpAddr, SourceTtl> {
+        &self.sources
+    }
+    
+    /// 源地址的異常是否該在 now 警告：同一地址在 WARNING_INTERVAL 內只警告一次，
+    /// 偽造源地址的流量不會刷屏
+    pub fn should_warn(&mut self, source: IpAddr, now: Instant) -> bool {
+        match self.warned.get(&source) {
+            Some(last) if now.saturating_duration_since(*last) < WARNING_INTERVAL => false,
+            _ => {
+                self.warned.insert(source, now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_os_class_and_ttl_changes() {
+        let mut tracker = TtlTracker::new(&TtlTrackingConfig { change_threshold: 16, max_sources: 3 });
+        let addr = |last: u8| IpAddr::from([192, 168, 1, last]);
+        
+        // 經過幾跳後的 TTL 按最近的初始值歸類
+        for (last, ttl) in [(10, 64), (11, 57), (12, 120), (13, 250)] {
+            assert_eq!(tracker.observe(addr(last), ttl), None);
+        }
+        assert_eq!(tracker.sources()[&addr(10)].os, OsClass::Unix);
+        assert_eq!(tracker.sources()[&addr(11)].os, OsClass::Unix);
+        assert_eq!(tracker.sources()[&addr(12)].os, OsClass::Windows);
+        // 超過容量的新地址不記錄
+        assert!(!tracker.sources().contains_key(&addr(13)));
+        assert_eq!(OsClass::from_ttl(250), OsClass::Network);
+        
+        // 少量跳數變化不算異常，大幅變化標記為可能偽造
+        assert_eq!(tracker.observe(addr(10), 61), None);
+        assert_eq!(tracker.observe(addr(10), 127), Some(TtlAnomaly { source: addr(10), previous: 61, current: 127 }));
+        assert_eq!(tracker.sources()[&addr(10)], SourceTtl { ttl: 127, os: OsClass::Windows, anomalies: 1 });
+        
+        // 同一地址每分鐘最多警告一次，不同地址各自計時
+        let now = Instant::now();
+        assert!(tracker.should_warn(addr(10), now));
+        assert!(!tracker.should_warn(addr(10), now + Duration::from_secs(30)));
+        assert!(tracker.should_warn(addr(11), now + Duration::from_secs(30)));
+        assert!(tracker.should_warn(addr(10), now + WARNING_INTERVAL));
+    }
+}